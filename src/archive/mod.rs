@@ -0,0 +1,117 @@
+//! Parsing of the records at the end of a zip archive, so its entries can be listed and
+//! located without reading the whole archive. Zip64 archives are not supported.
+use std::convert::TryInto;
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_LEN: usize = 46;
+
+pub(crate) const EOCD_LEN: u64 = 22;
+/// The end of central directory record is followed by a comment of at most this length.
+pub(crate) const MAX_COMMENT_LEN: u64 = u16::MAX as u64;
+pub(crate) const LOCAL_HEADER_LEN: usize = 30;
+
+pub(crate) const METHOD_STORED: u16 = 0;
+pub(crate) const METHOD_DEFLATED: u16 = 8;
+
+/// An entry of the central directory of a zip archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZipEntry {
+    pub name: String,
+    /// Size of the data as stored in the archive.
+    pub compressed_size: u64,
+    /// Size of the data after decompression.
+    pub size: u64,
+    pub(crate) method: u16,
+    pub(crate) flags: u16,
+    pub(crate) local_header_offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+/// Where the central directory is, as recorded in the end of central directory record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct CentralDirectory {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Find the end of central directory record in `tail`, the last bytes of an archive.
+/// Returns the position of the record in `tail` and the central directory it points to.
+pub(crate) fn find_eocd(tail: &[u8]) -> Option<(usize, CentralDirectory)> {
+    let last = tail.len().checked_sub(EOCD_LEN as usize)?;
+    // searched backwards, since the comment following the record may contain the signature
+    let pos = (0..=last)
+        .rev()
+        .find(|i| u32_at(tail, *i) == EOCD_SIGNATURE)?;
+    let eocd = &tail[pos..];
+    Some((
+        pos,
+        CentralDirectory {
+            size: u32_at(eocd, 12) as u64,
+            offset: u32_at(eocd, 16) as u64,
+        },
+    ))
+}
+
+pub(crate) fn parse_central_directory(
+    central: &[u8],
+) -> std::result::Result<Vec<ZipEntry>, &'static str> {
+    let mut entries = Vec::<ZipEntry>::new();
+    let mut pos = 0;
+    while pos + CENTRAL_HEADER_LEN <= central.len() {
+        if u32_at(central, pos) != CENTRAL_HEADER_SIGNATURE {
+            return Err("broken central directory");
+        }
+        let header = &central[pos..];
+        let name_len = u16_at(header, 28) as usize;
+        let extra_len = u16_at(header, 30) as usize;
+        let comment_len = u16_at(header, 32) as usize;
+        let name = central
+            .get(pos + CENTRAL_HEADER_LEN..pos + CENTRAL_HEADER_LEN + name_len)
+            .ok_or("broken central directory")?;
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            flags: u16_at(header, 8),
+            method: u16_at(header, 10),
+            compressed_size: u32_at(header, 20) as u64,
+            size: u32_at(header, 24) as u64,
+            local_header_offset: u32_at(header, 42) as u64,
+        });
+        pos += CENTRAL_HEADER_LEN + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// Offset of the data of `entry`, which follows its local header of `LOCAL_HEADER_LEN` bytes
+/// and the variable length fields the header announces.
+pub(crate) fn data_offset(
+    entry: &ZipEntry,
+    local_header: &[u8],
+) -> std::result::Result<u64, &'static str> {
+    if local_header.len() < LOCAL_HEADER_LEN || u32_at(local_header, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err("broken local file header");
+    }
+    Ok(entry.local_header_offset
+        + LOCAL_HEADER_LEN as u64
+        + u16_at(local_header, 26) as u64
+        + u16_at(local_header, 28) as u64)
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(bytes[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
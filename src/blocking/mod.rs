@@ -0,0 +1,263 @@
+//! Synchronous versions of the main free functions, for CLI tools and build scripts that
+//! are not async. Each call drives the future on a runtime shared by the whole process,
+//! so these must not be called from inside an async runtime, where they panic.
+use crate::checksum::ChecksumAlgo;
+use crate::compression::Compression;
+use crate::writer::WriteOptions;
+use crate::{mime, Existence, FileMetadata, ListOptions, Result};
+use backoff::ExponentialBackoff;
+use lazy_static::lazy_static;
+use std::future::Future;
+use tokio::runtime::Runtime;
+
+lazy_static! {
+    // one runtime for every call, so the connections pooled by the http clients stay
+    // bound to a live runtime
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    RUNTIME.block_on(f)
+}
+
+/// See `crate::get_file_contents`.
+pub fn get_file_contents(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<Vec<u8>>> {
+    block_on(crate::get_file_contents(
+        url_or_path_str,
+        backoff,
+        decompression,
+    ))
+}
+
+/// See `crate::get_file_contents_str`.
+pub fn get_file_contents_str(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<String>> {
+    block_on(crate::get_file_contents_str(
+        url_or_path_str,
+        backoff,
+        decompression,
+    ))
+}
+
+/// See `crate::get_file_range`.
+pub fn get_file_range(
+    url_or_path_str: &str,
+    offset: u64,
+    length: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    block_on(crate::get_file_range(
+        url_or_path_str,
+        offset,
+        length,
+        backoff,
+    ))
+}
+
+/// See `crate::write_contents`.
+pub fn write_contents(
+    url_or_path_str: &str,
+    body: impl Into<bytes::Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+    compression: Option<Compression>,
+) -> Result<()> {
+    block_on(crate::write_contents(
+        url_or_path_str,
+        body,
+        mime_type,
+        backoff,
+        compression,
+    ))
+}
+
+/// See `crate::write_contents_with_options`.
+pub fn write_contents_with_options(
+    url_or_path_str: &str,
+    body: impl Into<bytes::Bytes>,
+    backoff: Option<ExponentialBackoff>,
+    options: &WriteOptions,
+) -> Result<bool> {
+    block_on(crate::write_contents_with_options(
+        url_or_path_str,
+        body,
+        backoff,
+        options,
+    ))
+}
+
+/// See `crate::write_contents_if_absent`.
+pub fn write_contents_if_absent(
+    url_or_path_str: &str,
+    body: impl Into<bytes::Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    block_on(crate::write_contents_if_absent(
+        url_or_path_str,
+        body,
+        mime_type,
+        backoff,
+    ))
+}
+
+/// See `crate::append_contents`.
+pub fn append_contents(
+    url_or_path_str: &str,
+    body: impl Into<bytes::Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<()> {
+    block_on(crate::append_contents(
+        url_or_path_str,
+        body,
+        mime_type,
+        backoff,
+    ))
+}
+
+/// See `crate::list_files`.
+pub fn list_files(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    limit: Option<usize>,
+) -> Result<Vec<String>> {
+    block_on(crate::list_files(url_or_path_str, backoff, limit))
+}
+
+/// See `crate::list_files_glob`.
+pub fn list_files_glob(
+    pattern: &str,
+    backoff: Option<ExponentialBackoff>,
+    limit: Option<usize>,
+) -> Result<Vec<String>> {
+    block_on(crate::list_files_glob(pattern, backoff, limit))
+}
+
+/// See `crate::list_files_with_options`.
+pub fn list_files_with_options(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    options: &ListOptions,
+) -> Result<Vec<String>> {
+    block_on(crate::list_files_with_options(
+        url_or_path_str,
+        backoff,
+        options,
+    ))
+}
+
+/// See `crate::is_exists`.
+pub fn is_exists(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+    block_on(crate::is_exists(url_or_path_str, backoff))
+}
+
+/// See `crate::is_exists_strict`.
+pub fn is_exists_strict(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Existence {
+    block_on(crate::is_exists_strict(url_or_path_str, backoff))
+}
+
+/// See `crate::stat`.
+pub fn stat(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<FileMetadata>> {
+    block_on(crate::stat(url_or_path_str, backoff))
+}
+
+/// See `crate::checksum`.
+pub fn checksum(
+    url_or_path_str: &str,
+    algo: ChecksumAlgo,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    block_on(crate::checksum(url_or_path_str, algo, backoff))
+}
+
+/// See `crate::copy_contents`.
+pub fn copy_contents(src: &str, dst: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+    block_on(crate::copy_contents(src, dst, backoff))
+}
+
+/// See `crate::move_contents`.
+pub fn move_contents(src: &str, dst: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+    block_on(crate::move_contents(src, dst, backoff))
+}
+
+/// See `crate::delete_contents`.
+pub fn delete_contents(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Result<()> {
+    block_on(crate::delete_contents(url_or_path_str, backoff))
+}
+
+/// See `crate::delete_contents_recursive`.
+pub fn delete_contents_recursive(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    concurrency: usize,
+) -> Result<()> {
+    block_on(crate::delete_contents_recursive(
+        url_or_path_str,
+        backoff,
+        concurrency,
+    ))
+}
+
+/// See `crate::download_to_file`.
+#[cfg(feature = "fs")]
+pub fn download_to_file(
+    url: &str,
+    local_path: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    block_on(crate::download_to_file(url, local_path, backoff))
+}
+
+/// See `crate::upload_from_file`.
+#[cfg(feature = "fs")]
+pub fn upload_from_file(local_path: &str, url: &str, mime_type: mime::MimeType) -> Result<bool> {
+    block_on(crate::upload_from_file(local_path, url, mime_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn blocking_calls_on_local_files() {
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        write_contents(&path, "hello", mime::MimeType::Text, None, None).unwrap();
+        append_contents(&path, " world", mime::MimeType::Text, None).unwrap();
+        assert_eq!(
+            Some("hello world".to_string()),
+            get_file_contents_str(&path, None, None).unwrap()
+        );
+        assert_eq!(
+            Some(b"world".to_vec()),
+            get_file_range(&path, 6, 5, None).unwrap()
+        );
+        assert!(is_exists(&path, None).unwrap());
+        assert_eq!(Some(11), stat(&path, None).unwrap().unwrap().size);
+
+        let copied = dir.join("b.txt");
+        assert!(copy_contents(&path, &copied, None).unwrap());
+        assert_eq!(vec![path.clone(), copied.clone()], {
+            let mut listed = list_files(&dir.path().display().to_string(), None, None).unwrap();
+            listed.sort();
+            listed
+        });
+        delete_contents(&copied, None).unwrap();
+        assert_eq!(None, get_file_contents(&copied, None, None).unwrap());
+    }
+}
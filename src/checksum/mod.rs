@@ -0,0 +1,137 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ChecksumError {
+    #[error("file io error {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ChecksumError>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgo {
+    Sha256,
+    Md5,
+    Crc32c,
+}
+
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+impl ChecksumAlgo {
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut digest = Hasher::new(*self);
+        digest.update(bytes);
+        digest.finish()
+    }
+
+    /// Digest everything `reader` yields without holding it in memory.
+    pub fn digest_reader<R: Read>(&self, mut reader: R) -> Result<Vec<u8>> {
+        let mut digest = Hasher::new(*self);
+        let mut buf = vec![0u8; READ_BUF_SIZE];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            digest.update(&buf[..n]);
+        }
+        Ok(digest.finish())
+    }
+}
+
+/// Incremental hashing, for contents that arrive in chunks.
+pub struct Hasher(HasherState);
+
+enum HasherState {
+    Sha256(Sha256),
+    Md5(Md5),
+    Crc32c(u32),
+}
+
+impl Hasher {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        Hasher(match algo {
+            ChecksumAlgo::Sha256 => HasherState::Sha256(Sha256::new()),
+            ChecksumAlgo::Md5 => HasherState::Md5(Md5::new()),
+            ChecksumAlgo::Crc32c => HasherState::Crc32c(0),
+        })
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match &mut self.0 {
+            HasherState::Sha256(hasher) => hasher.update(bytes),
+            HasherState::Md5(hasher) => hasher.update(bytes),
+            HasherState::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self.0 {
+            HasherState::Sha256(hasher) => hasher.finalize().to_vec(),
+            HasherState::Md5(hasher) => hasher.finalize().to_vec(),
+            // big endian, as gcs reports it
+            HasherState::Crc32c(crc) => crc.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// Whether `expected`, in hex or base64 (the encoding gcs uses), is the checksum of `digest`.
+pub fn matches_checksum(digest: &[u8], expected: &str) -> bool {
+    let expected = expected.trim();
+    hex::decode(expected).is_ok_and(|decoded| decoded == digest)
+        || base64::decode(expected).is_ok_and(|decoded| decoded == digest)
+}
+
+/// Compute the checksum of the file at `path` and compare it to `expected`,
+/// given in hex or base64. The file is read in chunks.
+pub fn verify_checksum<P: AsRef<Path>>(
+    path: P,
+    expected: &str,
+    algo: ChecksumAlgo,
+) -> Result<bool> {
+    let file = BufReader::new(File::open(path)?);
+    let digest = algo.digest_reader(file)?;
+    Ok(matches_checksum(&digest, expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn verify_checksum_of_file() {
+        let dir = TempDir::new();
+        let path = dir.write("body.txt", b"hello world");
+
+        let cases = [
+            (
+                ChecksumAlgo::Sha256,
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            ),
+            (ChecksumAlgo::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            (ChecksumAlgo::Md5, "XrY7u+Ae7tCTyyK7j1rNww=="),
+            (ChecksumAlgo::Crc32c, "c99465aa"),
+            (ChecksumAlgo::Crc32c, "yZRlqg=="),
+        ];
+        for (algo, expected) in cases.iter() {
+            assert!(
+                verify_checksum(&path, expected, *algo).unwrap(),
+                "{:?} {}",
+                algo,
+                expected
+            );
+        }
+
+        assert!(
+            !verify_checksum(&path, "5eb63bbbe01eeed093cb22bb8f5acdc4", ChecksumAlgo::Md5).unwrap()
+        );
+        assert!(!verify_checksum(&path, "not a checksum", ChecksumAlgo::Sha256).unwrap());
+        assert!(verify_checksum(dir.join("missing.txt"), "", ChecksumAlgo::Md5).is_err());
+    }
+}
@@ -1,15 +1,35 @@
 use super::Result;
-use flate2::read::GzDecoder;
+use flate2::bufread::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use std::io::{Read, Write};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
 
+/// Decompress every member of the gzip stream, not only the first one, whatever the size
+/// and compression level of each. Zero bytes padding the stream after a member, as tape and
+/// block devices leave, are skipped like `gzip -d` does.
 pub(crate) fn gzip_decompress(bytes: &[u8]) -> Result<Vec<u8>> {
-    let mut gz = GzDecoder::new(bytes);
     let mut dest = Vec::<u8>::new();
+    let mut rest = bytes;
+    loop {
+        // the bufread decoder consumes exactly one member, leaving the rest
+        let mut gz = GzDecoder::new(rest);
+        gz.read_to_end(&mut dest)?;
+        rest = gz.into_inner();
 
-    gz.read_to_end(&mut dest)?;
-    Ok(dest)
+        let padding = rest.iter().take_while(|b| **b == 0).count();
+        rest = &rest[padding..];
+        if rest.is_empty() {
+            return Ok(dest);
+        }
+        if !rest.starts_with(&[0x1f, 0x8b]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing garbage after the last gzip member",
+            )
+            .into());
+        }
+    }
 }
 
 pub(crate) fn gzip_compress(bytes: &[u8]) -> Result<Vec<u8>> {
@@ -18,3 +38,165 @@ pub(crate) fn gzip_compress(bytes: &[u8]) -> Result<Vec<u8>> {
     let compressed = gz.finish()?;
     Ok(compressed)
 }
+
+/// Decompress the members of a blocked gzip (bgzip, BGZF) stream on up to `threads` threads
+/// and concatenate them in order. Streams whose members do not record their size, or that
+/// consist of a single member, are decompressed sequentially.
+pub(crate) fn gzip_decompress_parallel(bytes: &[u8], threads: usize) -> Result<Vec<u8>> {
+    let members = match bgzf_members(bytes) {
+        Some(members) if members.len() > 1 && threads > 1 => members,
+        _ => return gzip_decompress(bytes),
+    };
+
+    let group_len = members.len().div_ceil(threads);
+    let decompressed: Vec<Result<Vec<u8>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = members
+            .chunks(group_len)
+            .map(|group| {
+                scope.spawn(move || {
+                    let mut dest = Vec::<u8>::new();
+                    for member in group {
+                        GzDecoder::new(*member).read_to_end(&mut dest)?;
+                    }
+                    Ok(dest)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("gzip decompression thread panicked"))
+            .collect()
+    });
+
+    let mut dest = Vec::<u8>::new();
+    for part in decompressed {
+        dest.extend_from_slice(&part?);
+    }
+    Ok(dest)
+}
+
+const GZIP_HEADER_LEN: usize = 10;
+const FLAG_EXTRA: u8 = 0x04;
+
+/// Split a BGZF stream into its members, using the block size each member records in
+/// the `BC` subfield of its extra field. `None` when a member does not record it.
+fn bgzf_members(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut members = Vec::<&[u8]>::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let header = rest.get(..GZIP_HEADER_LEN + 2)?;
+        if header[..3] != [0x1f, 0x8b, 0x08] || header[3] & FLAG_EXTRA == 0 {
+            return None;
+        }
+        let extra_len = u16::from_le_bytes(header[10..12].try_into().ok()?) as usize;
+        let mut extra = rest.get(GZIP_HEADER_LEN + 2..GZIP_HEADER_LEN + 2 + extra_len)?;
+
+        let mut block_size = None;
+        while extra.len() >= 4 {
+            let len = u16::from_le_bytes(extra[2..4].try_into().ok()?) as usize;
+            if extra[..2] == [b'B', b'C'] && len == 2 {
+                block_size =
+                    Some(u16::from_le_bytes(extra.get(4..6)?.try_into().ok()?) as usize + 1);
+            }
+            extra = extra.get(4 + len..)?;
+        }
+
+        let (member, next) = rest.split_at_checked(block_size?)?;
+        members.push(member);
+        rest = next;
+    }
+    Some(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::GzBuilder;
+
+    /// A BGZF member: gzip with the `BC` subfield holding the member size - 1.
+    fn bgzf_member(bytes: &[u8]) -> Vec<u8> {
+        let mut gz = GzBuilder::new()
+            .extra(vec![b'B', b'C', 2, 0, 0, 0])
+            .write(Vec::new(), Compression::default());
+        gz.write_all(bytes).unwrap();
+        let mut member = gz.finish().unwrap();
+        let block_size = (member.len() - 1) as u16;
+        member[16..18].copy_from_slice(&block_size.to_le_bytes());
+        member
+    }
+
+    #[test]
+    fn decompress_multi_member() {
+        let mut concatenated = gzip_compress(b"first ").unwrap();
+        concatenated.extend(gzip_compress(b"second").unwrap());
+        assert_eq!(
+            b"first second".to_vec(),
+            gzip_decompress(&concatenated).unwrap()
+        );
+    }
+
+    #[test]
+    fn decompress_members_of_differing_levels_and_sizes() {
+        let (bodies, members): (Vec<Vec<u8>>, Vec<Vec<u8>>) =
+            [(0, 1), (1, 100_000), (9, 10), (6, 0), (9, 300_000)]
+                .iter()
+                .map(|(level, len)| {
+                    let body: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+                    let mut gz = GzEncoder::new(Vec::new(), Compression::new(*level));
+                    gz.write_all(&body).unwrap();
+                    (body, gz.finish().unwrap())
+                })
+                .unzip();
+        let expected = bodies.concat();
+
+        assert_eq!(expected, gzip_decompress(&members.concat()).unwrap());
+
+        // zero padding after members, and a blocked gzip member among plain ones
+        let mut padded = Vec::<u8>::new();
+        for member in &members {
+            padded.extend_from_slice(member);
+            padded.extend(vec![0u8; 512]);
+        }
+        padded.extend(bgzf_member(b"blocked"));
+        padded.extend(vec![0u8; 3]);
+        assert_eq!(
+            [expected.as_slice(), b"blocked"].concat(),
+            gzip_decompress(&padded).unwrap()
+        );
+        assert_eq!(
+            [expected.as_slice(), b"blocked"].concat(),
+            gzip_decompress_parallel(&padded, 4).unwrap()
+        );
+
+        let mut garbage = members.concat();
+        garbage.extend(b"garbage");
+        assert!(gzip_decompress(&garbage).is_err());
+    }
+
+    #[test]
+    fn parallel_and_sequential_decompression_are_identical() {
+        let blocks: Vec<Vec<u8>> = (0..20)
+            .map(|i| format!("block {} ", i).repeat(1000).into_bytes())
+            .collect();
+        let bgzf: Vec<u8> = blocks.iter().flat_map(|block| bgzf_member(block)).collect();
+        assert_eq!(Some(20), bgzf_members(&bgzf).map(|members| members.len()));
+
+        let sequential = gzip_decompress(&bgzf).unwrap();
+        assert_eq!(blocks.concat(), sequential);
+        for threads in [1, 3, 8, 64] {
+            assert_eq!(
+                sequential,
+                gzip_decompress_parallel(&bgzf, threads).unwrap()
+            );
+        }
+
+        // members without a recorded size fall back to sequential decompression
+        let mut plain = gzip_compress(b"first ").unwrap();
+        plain.extend(gzip_compress(b"second").unwrap());
+        assert!(bgzf_members(&plain).is_none());
+        assert_eq!(
+            b"first second".to_vec(),
+            gzip_decompress_parallel(&plain, 4).unwrap()
+        );
+    }
+}
@@ -1,4 +1,7 @@
 pub mod gzip;
+pub(crate) mod stream;
+pub mod zstd;
+use bytes::Bytes;
 use std::path::Path;
 use thiserror::Error;
 
@@ -13,50 +16,122 @@ pub type Result<T> = std::result::Result<T, CompressionError>;
 #[derive(Clone)]
 pub enum Compression {
     Gzip,
+    Zstd,
 }
 
 impl Compression {
     pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
         match *self {
             Compression::Gzip => gzip::gzip_compress(bytes),
+            Compression::Zstd => zstd::zstd_compress(bytes),
         }
     }
 
     pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
         match *self {
             Compression::Gzip => gzip::gzip_decompress(bytes),
+            Compression::Zstd => zstd::zstd_decompress(bytes),
         }
     }
 
+    /// Decompress `bytes` if they start with the magic bytes of this format, otherwise
+    /// return them unchanged, for sources where some contents are mislabeled as compressed.
+    pub fn decompress_tolerant(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match (self, Compression::from_magic_bytes(bytes)) {
+            (Compression::Gzip, Some(Compression::Gzip))
+            | (Compression::Zstd, Some(Compression::Zstd)) => self.decompress(bytes),
+            _ => {
+                log::warn!("contents are not compressed as expected, passing them through");
+                Ok(bytes.to_vec())
+            }
+        }
+    }
+
+    /// Decompress on up to `threads` threads where the format allows it: the members of a
+    /// blocked gzip (bgzip) file are independent. Other contents are decompressed sequentially.
+    /// Only large inputs benefit; each thread decompresses a contiguous run of members.
+    pub fn decompress_parallel(&self, bytes: &[u8], threads: usize) -> Result<Vec<u8>> {
+        match *self {
+            Compression::Gzip => gzip::gzip_decompress_parallel(bytes, threads),
+            Compression::Zstd => zstd::zstd_decompress(bytes),
+        }
+    }
+
+    /// Detect the compression from the extension, ignoring its case (`.GZ` as `.gz`).
     pub fn from_extention<P: AsRef<Path>>(path: P) -> Option<Compression> {
         match path
             .as_ref()
             .extension()
-            .map(|os_str| os_str.to_str().unwrap_or(""))
+            .map(|os_str| os_str.to_str().unwrap_or("").to_ascii_lowercase())
+            .as_deref()
         {
-            None => None,
-            Some(ext) => match ext {
-                "gzip" | "gz" => Some(Compression::Gzip),
-                _ => None,
-            },
+            Some("gzip" | "gz") => Some(Compression::Gzip),
+            Some("zst" | "zstd") => Some(Compression::Zstd),
+            _ => None,
         }
     }
+
+    /// Number of leading bytes `from_magic_bytes` needs to recognize every format.
+    pub const MAGIC_LEN: usize = 4;
+
+    /// Sniff the compression from the leading bytes of the contents, regardless of the file name.
+    pub fn from_magic_bytes(bytes: &[u8]) -> Option<Compression> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Detect the compression from a `Content-Type` value such as `application/gzip`.
+    /// Parameters like `; charset=binary` are ignored.
+    pub fn from_content_type(content_type: &str) -> Option<Compression> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/gzip" | "application/x-gzip" => Some(Compression::Gzip),
+            "application/zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Contents after decompression along with the size they had as fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizedContents {
+    pub contents: Vec<u8>,
+    pub raw_size: usize,
 }
 
-pub(crate) fn decompress_opt(
+pub(crate) fn decompress_opt_sized(
     data: Option<Vec<u8>>,
     decompression: Option<Compression>,
-) -> Result<Option<Vec<u8>>> {
+) -> Result<Option<SizedContents>> {
     match (data, decompression) {
         (None, _) => Ok(None),
-        (contents @ _, None) => Ok(contents),
+        (Some(contents), None) => Ok(Some(SizedContents {
+            raw_size: contents.len(),
+            contents,
+        })),
         (Some(contents), Some(compression)) => {
             let data = compression.decompress(&contents)?;
-            Ok(Some(data))
+            Ok(Some(SizedContents {
+                contents: data,
+                raw_size: contents.len(),
+            }))
         }
     }
 }
 
+/// Like `compress_opt`, handing `data` back as it is when there is no compression.
+pub(crate) fn compress_bytes(data: Bytes, compression: Option<Compression>) -> Result<Bytes> {
+    match compression {
+        None => Ok(data),
+        Some(compression) => Ok(Bytes::from(compression.compress(&data)?)),
+    }
+}
+
 pub(crate) fn compress_opt(data: &[u8], compression: Option<Compression>) -> Result<Vec<u8>> {
     match compression {
         None => Ok(data.to_vec()),
@@ -66,3 +141,59 @@ pub(crate) fn compress_opt(data: &[u8], compression: Option<Compression>) -> Res
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_extention_ignores_case() {
+        for path in ["a.json.gz", "a.json.GZ", "a.Gz", "a.GZIP"] {
+            assert!(
+                matches!(Compression::from_extention(path), Some(Compression::Gzip)),
+                "{}",
+                path
+            );
+        }
+        for path in ["a.zst", "a.ZST", "a.Zstd"] {
+            assert!(
+                matches!(Compression::from_extention(path), Some(Compression::Zstd)),
+                "{}",
+                path
+            );
+        }
+        assert!(Compression::from_extention("a.TXT").is_none());
+        assert!(Compression::from_extention("GZ").is_none());
+    }
+
+    #[test]
+    fn decompress_tolerant_passes_plain_contents_through() {
+        let text = b"plain text that is not compressed".to_vec();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&text).unwrap();
+            assert_eq!(text, compression.decompress_tolerant(&compressed).unwrap());
+            assert_eq!(text, compression.decompress_tolerant(&text).unwrap());
+            assert!(compression.decompress(&text).is_err());
+        }
+
+        let zstd = Compression::Zstd.compress(&text).unwrap();
+        assert_eq!(zstd, Compression::Gzip.decompress_tolerant(&zstd).unwrap());
+        assert!(Compression::Gzip
+            .decompress_tolerant(&[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn compress_bytes_shares_uncompressed_bodies() {
+        let body = Bytes::from(b"shared body".to_vec());
+        let kept = compress_bytes(body.clone(), None).unwrap();
+        assert_eq!(body.as_ptr(), kept.as_ptr());
+
+        let compressed = compress_bytes(body.clone(), Some(Compression::Gzip)).unwrap();
+        assert_eq!(
+            body.to_vec(),
+            Compression::Gzip.decompress(&compressed).unwrap()
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Incremental decompression and compression, for contents that arrive in chunks.
+use super::{Compression, Result};
+use flate2::write::{GzEncoder, MultiGzDecoder};
+use std::io::Write;
+
+/// Decompresses chunk by chunk, handing back what each chunk completes.
+pub(crate) enum StreamDecoder {
+    Gzip(MultiGzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl StreamDecoder {
+    pub(crate) fn new(compression: &Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::Gzip => StreamDecoder::Gzip(MultiGzDecoder::new(Vec::new())),
+            Compression::Zstd => {
+                StreamDecoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?)
+            }
+        })
+    }
+
+    /// Feed the next chunk of compressed bytes and take the bytes decompressed so far.
+    pub(crate) fn write(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let decompressed = match self {
+            StreamDecoder::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            StreamDecoder::Zstd(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(decompressed))
+    }
+
+    /// Take the rest of the decompressed bytes once the input has ended.
+    pub(crate) fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            StreamDecoder::Gzip(decoder) => Ok(decoder.finish()?),
+            StreamDecoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+/// Compresses chunk by chunk, handing back the compressed bytes produced so far.
+pub(crate) enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl StreamEncoder {
+    pub(crate) fn new(compression: &Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::Gzip => {
+                StreamEncoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            Compression::Zstd => StreamEncoder::Zstd(zstd::Encoder::new(
+                Vec::new(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        })
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self {
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(bytes)?;
+                encoder.get_mut()
+            }
+            StreamEncoder::Zstd(encoder) => {
+                encoder.write_all(bytes)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(compressed))
+    }
+
+    /// Complete the compression stream and take its remaining bytes.
+    pub(crate) fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Gzip(encoder) => Ok(encoder.finish()?),
+            StreamEncoder::Zstd(encoder) => Ok(encoder.finish()?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_in_small_chunks() {
+        let text: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut encoder = StreamEncoder::new(&compression).unwrap();
+            let mut compressed = Vec::<u8>::new();
+            for chunk in text.chunks(100) {
+                compressed.extend(encoder.write(chunk).unwrap());
+            }
+            compressed.extend(encoder.finish().unwrap());
+            assert_eq!(text, compression.decompress(&compressed).unwrap());
+
+            let mut decoder = StreamDecoder::new(&compression).unwrap();
+            let mut decompressed = Vec::<u8>::new();
+            for chunk in compressed.chunks(7) {
+                decompressed.extend(decoder.write(chunk).unwrap());
+            }
+            decompressed.extend(decoder.finish().unwrap());
+            assert_eq!(text, decompressed);
+        }
+    }
+}
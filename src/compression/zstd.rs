@@ -0,0 +1,11 @@
+use super::Result;
+
+pub(crate) fn zstd_decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    let dest = zstd::stream::decode_all(bytes)?;
+    Ok(dest)
+}
+
+pub(crate) fn zstd_compress(bytes: &[u8]) -> Result<Vec<u8>> {
+    let compressed = zstd::stream::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    Ok(compressed)
+}
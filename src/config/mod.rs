@@ -0,0 +1,297 @@
+//! Defaults set once for every operation instead of on each call: the backoff or retry
+//! policy used when an operation is given `None`, a timeout for each single request, the http and gcs
+//! clients requests go through, whether destructive operations are only a dry run, and
+//! the observer told about the progress of transfers.
+//!
+//! Set them for the whole process with `set_global`, or for one future with `with_config`,
+//! which takes precedence. Nothing is merged: the innermost config applies whole.
+use crate::copy_backoff;
+use crate::progress::ProgressObserver;
+use crate::retry::RetryPolicy;
+use backoff::ExponentialBackoff;
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[cfg(feature = "gcs")]
+use crate::gcs::client::{CloudStorageClient, GcsClient, TimeoutClient};
+
+/// Built with `ClientConfig::new()` and the `with_` methods, e.g.
+/// `ClientConfig::new().with_backoff(backoff).with_request_timeout(Duration::from_secs(10))`.
+#[derive(Default)]
+pub struct ClientConfig {
+    backoff: Option<ExponentialBackoff>,
+    request_timeout: Option<Duration>,
+    #[cfg(feature = "web")]
+    http_client: Option<reqwest::Client>,
+    #[cfg(feature = "gcs")]
+    gcs_client: Option<Arc<dyn GcsClient>>,
+    dry_run: bool,
+    progress_observer: Option<Arc<dyn ProgressObserver>>,
+    retry_policy: Option<Arc<dyn RetryPolicy>>,
+}
+
+impl ClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The backoff of operations given `None`, instead of `ExponentialBackoff::default()`.
+    pub fn with_backoff(mut self, backoff: ExponentialBackoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    /// Retry the gcs and web requests of operations given `None` as `policy` says, instead of
+    /// with the backoff, see `retry`. An operation given an `ExponentialBackoff` of its own is
+    /// still retried with that backoff; the policy does not apply to it.
+    pub fn with_retry_policy(mut self, policy: Arc<dyn RetryPolicy>) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Fail any single gcs or http request that takes longer than `timeout`, which the
+    /// backoff then retries; see `with_timeout` to bound a whole operation. It does not
+    /// apply to a client given with `with_http_client`, which has its own timeout.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// The client of the web backend, e.g. one with default headers carrying credentials
+    /// or with a proxy.
+    #[cfg(feature = "web")]
+    pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// The client of the gcs backend, e.g. one authenticating with other credentials than
+    /// those of the environment. `gcs::client::with_client` still takes precedence.
+    #[cfg(feature = "gcs")]
+    pub fn with_gcs_client(mut self, client: Arc<dyn GcsClient>) -> Self {
+        self.gcs_client = Some(client);
+        self
+    }
+
+    /// Make the free functions that write, copy, move or delete only log (at info level)
+    /// what they would do and return as if they had, e.g. to verify a batch job against
+    /// production buckets. Reads still happen, and so do the existence checks their answers
+    /// depend on. `storage::FileStorage` and the backends used directly are not affected.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Report the progress of every transfer to `observer`, see `progress`.
+    pub fn with_progress_observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.progress_observer = Some(observer);
+        self
+    }
+
+    /// Apply the request timeout to the clients, once. Should the http client with the
+    /// timeout fail to build, a default one is used and a warning logged, rather than panic.
+    fn resolve(mut self) -> Self {
+        if let Some(timeout) = self.request_timeout {
+            #[cfg(feature = "web")]
+            if self.http_client.is_none() {
+                let client = reqwest::Client::builder()
+                    .timeout(timeout)
+                    .build()
+                    .unwrap_or_else(|e| {
+                        log::warn!(
+                            "failed to build an http client with a timeout of {:?}, \
+                             using one without: {}",
+                            timeout,
+                            e
+                        );
+                        reqwest::Client::new()
+                    });
+                self.http_client = Some(client);
+            }
+            #[cfg(feature = "gcs")]
+            {
+                let inner = self
+                    .gcs_client
+                    .take()
+                    .unwrap_or_else(|| Arc::new(CloudStorageClient));
+                self.gcs_client = Some(Arc::new(TimeoutClient::new(inner, timeout)));
+            }
+            #[cfg(not(any(feature = "web", feature = "gcs")))]
+            let _ = timeout;
+        }
+        self
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL_CONFIG: RwLock<Option<Arc<ClientConfig>>> = RwLock::new(None);
+}
+
+tokio::task_local! {
+    static SCOPED_CONFIG: Arc<ClientConfig>;
+}
+
+/// Make `config` apply to every operation not run inside `with_config`, returning the one
+/// it replaces.
+pub fn set_global(config: ClientConfig) -> Option<Arc<ClientConfig>> {
+    GLOBAL_CONFIG
+        .write()
+        .unwrap()
+        .replace(Arc::new(config.resolve()))
+}
+
+/// Go back to the defaults of the crate, returning the config that applied.
+pub fn reset_global() -> Option<Arc<ClientConfig>> {
+    GLOBAL_CONFIG.write().unwrap().take()
+}
+
+/// Run `f` with `config` applying to every operation inside it instead of the global one.
+/// Like `gcs::client::with_client`, it does not reach `tokio::spawn`ed tasks.
+pub async fn with_config<F: Future>(config: ClientConfig, f: F) -> F::Output {
+    SCOPED_CONFIG.scope(Arc::new(config.resolve()), f).await
+}
+
+fn current<T>(f: impl FnOnce(&ClientConfig) -> Option<T>) -> Option<T> {
+    match SCOPED_CONFIG.try_with(|config| config.clone()) {
+        Ok(config) => f(&config),
+        Err(_) => GLOBAL_CONFIG.read().unwrap().as_deref().and_then(f),
+    }
+}
+
+/// `backoff`, or else that of the current config, or else the default one.
+pub(crate) fn backoff_or_default(backoff: Option<ExponentialBackoff>) -> ExponentialBackoff {
+    backoff
+        .or_else(|| current(|config| copy_backoff(&config.backoff)))
+        .unwrap_or_default()
+}
+
+pub(crate) fn is_dry_run() -> bool {
+    current(|config| Some(config.dry_run)).unwrap_or(false)
+}
+
+#[cfg(any(feature = "gcs", feature = "web"))]
+pub(crate) fn retry_policy() -> Option<Arc<dyn RetryPolicy>> {
+    current(|config| config.retry_policy.clone())
+}
+
+pub(crate) fn progress_observer() -> Option<Arc<dyn ProgressObserver>> {
+    current(|config| config.progress_observer.clone())
+}
+
+#[cfg(feature = "web")]
+pub(crate) fn http_client() -> Option<reqwest::Client> {
+    current(|config| config.http_client.clone())
+}
+
+#[cfg(feature = "gcs")]
+pub(crate) fn gcs_client() -> Option<Arc<dyn GcsClient>> {
+    current(|config| config.gcs_client.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gcs::MockGcsClient;
+    use crate::testing::http::{TestResponse, TestServer};
+    use crate::testing::quick_backoff;
+    use crate::testing::TempDir;
+    use crate::writer::WriteOptions;
+    use crate::{get_file_contents, mime, write_contents};
+    use futures::io::AsyncWriteExt;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn operations_use_the_scoped_config() {
+        let server = TestServer::start(|request| match request.header("x-token") {
+            Some("secret") if request.path == "/hung.txt" => {
+                TestResponse::ok("late").with_delay(Duration::from_secs(10))
+            }
+            Some("secret") => TestResponse::ok("authorized"),
+            _ => TestResponse::status(401),
+        })
+        .await;
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-token", "secret".parse().unwrap());
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let config = ClientConfig::new()
+            .with_backoff(quick_backoff())
+            .with_http_client(client);
+        with_config(config, async {
+            let contents = get_file_contents(server.url("/a.txt").as_str(), None, None)
+                .await
+                .unwrap();
+            assert_eq!(Some(b"authorized".to_vec()), contents);
+
+            // the request timeout of the client, retried with the backoff of the config
+            let started = Instant::now();
+            assert!(
+                get_file_contents(server.url("/hung.txt").as_str(), None, None)
+                    .await
+                    .is_err()
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
+        })
+        .await;
+
+        let mock = MockGcsClient::new().into_arc();
+        let config = ClientConfig::new()
+            .with_gcs_client(mock.clone())
+            .with_request_timeout(Duration::from_secs(5));
+        with_config(config, async {
+            write_contents(
+                "gs://zdb_test/zdb/a.txt",
+                "a",
+                mime::MimeType::Text,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        })
+        .await;
+        assert_eq!(
+            b"a".to_vec(),
+            mock.get("zdb_test", "zdb/a.txt").unwrap().body
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_runs_leave_files_as_they_are() {
+        let dir = TempDir::new();
+        let kept = dir.write("kept.txt", "kept");
+        let new = dir.join("new.txt");
+        with_config(ClientConfig::new().with_dry_run(true), async {
+            write_contents(&new, "new", mime::MimeType::Text, None, None)
+                .await
+                .unwrap();
+            crate::append_contents(&kept, " more", mime::MimeType::Text, None)
+                .await
+                .unwrap();
+            let mut writer = crate::open_write(&new, WriteOptions::new()).unwrap();
+            writer.write_all(b"streamed").await.unwrap();
+            writer.finalize().await.unwrap();
+
+            // as if done, and so only for sources that exist
+            assert!(crate::copy_contents(&kept, &new, None).await.unwrap());
+            assert!(!crate::copy_contents(&new, &kept, None).await.unwrap());
+            assert!(crate::move_contents(&kept, &new, None).await.unwrap());
+            crate::delete_contents(&kept, None).await.unwrap();
+            let prefix = dir.path().display().to_string();
+            let deleted = crate::delete_older_than(&prefix, Duration::ZERO, None, 1, true)
+                .await
+                .unwrap();
+            assert_eq!(1, deleted);
+        })
+        .await;
+
+        assert_eq!("kept", std::fs::read_to_string(&kept).unwrap());
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+}
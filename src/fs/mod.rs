@@ -1,8 +1,16 @@
 use super::compression;
+use crate::unblock;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 
+mod ops;
+
 #[derive(Error, Debug)]
 pub enum FileUtilFsError {
     #[error("file io error: {0}")]
@@ -10,49 +18,742 @@ pub enum FileUtilFsError {
 
     #[error("compression error: {0}")]
     CompressionError(#[from] crate::compression::CompressionError),
+
+    #[error("checksum error: {0}")]
+    ChecksumError(#[from] crate::checksum::ChecksumError),
+
+    #[error("`{0}` is a symbolic link, which is not followed")]
+    SymlinkNotFollowed(PathBuf),
 }
 
 pub type Result<T> = std::result::Result<T, FileUtilFsError>;
 
+impl FileUtilFsError {
+    /// The http status a service would answer with for this error, see
+    /// `FileUtilError::to_http_status`.
+    pub fn to_http_status(&self) -> u16 {
+        match self {
+            FileUtilFsError::IOError(e) => crate::io_error_status(e),
+            FileUtilFsError::CompressionError(_) | FileUtilFsError::ChecksumError(_) => 500,
+            FileUtilFsError::SymlinkNotFollowed(_) => 400,
+        }
+    }
+
+    /// The class of this error, see `FileUtilError::kind`.
+    pub fn kind(&self) -> crate::ErrorKind {
+        match self {
+            FileUtilFsError::IOError(e) => crate::io_error_kind(e),
+            FileUtilFsError::CompressionError(_) | FileUtilFsError::ChecksumError(_) => {
+                crate::ErrorKind::Other
+            }
+            FileUtilFsError::SymlinkNotFollowed(_) => crate::ErrorKind::InvalidPath,
+        }
+    }
+}
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How a recursive listing treats symbolic links to directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave them out of the listing.
+    Skip,
+    /// Descend into them. A directory already visited (a link cycle) is not entered again.
+    Follow,
+    /// List the link itself as an entry without descending into it.
+    Include,
+}
+
+/// How `FileAccessor::write` replaces the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Write the file in place, so a reader or a crash in the middle may see it partly
+    /// written.
+    #[default]
+    InPlace,
+    /// Write a temporary file next to it and rename that over the file once it is synced
+    /// to disk, so the file holds either the old or the new contents. The new file keeps
+    /// the permissions of the old one. A symbolic link is replaced itself, not written
+    /// through.
+    Atomic,
+}
+
+/// What the filesystem knows about a file or directory, see `FileAccessor::metadata`.
+#[derive(Debug, Clone)]
+pub struct LocalMetadata {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Not every platform and filesystem records it.
+    pub created: Option<SystemTime>,
+    pub permissions: fs::Permissions,
+    pub file_type: LocalFileType,
+    /// Whether the path is a symbolic link, whose target the other fields describe.
+    pub is_symlink: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalFileType {
+    File,
+    Dir,
+    /// A socket, fifo or device.
+    Other,
+}
+
+/// Which entries `FileAccessor::list_directory_filtered` lists, by their file name.
+#[derive(Clone)]
+pub struct NameFilter {
+    accepts: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+}
+
+impl NameFilter {
+    /// Names matching the glob `pattern`, see `list_files_glob` for its syntax. `None` when the
+    /// pattern is invalid, e.g. a class with a reversed range such as `[z-a]`.
+    pub fn glob(pattern: &str) -> Option<Self> {
+        let pattern = crate::glob::compile(pattern)?;
+        Some(Self::new(move |name: &str| pattern.is_match(name)))
+    }
+
+    /// Names `predicate` returns `true` for.
+    pub fn new(predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            accepts: Arc::new(predicate),
+        }
+    }
+
+    pub fn accepts(&self, name: &str) -> bool {
+        (self.accepts)(name)
+    }
+}
+
+#[cfg(unix)]
+type DirKey = (u64, u64);
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+/// Identifies a directory independently of the path it was reached by.
+#[cfg(unix)]
+fn dir_key(path: &Path) -> std::io::Result<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_key(path: &Path) -> std::io::Result<DirKey> {
+    fs::canonicalize(path)
+}
+
+/// The path of a `file://` url, or `url_or_path_str` itself when it is a plain path.
+pub fn local_path(url_or_path_str: &str) -> PathBuf {
+    match url::Url::parse(url_or_path_str) {
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .unwrap_or_else(|()| PathBuf::from(url_or_path_str)),
+        _ => PathBuf::from(url_or_path_str),
+    }
+}
+
+/// A local file or directory. Every operation runs on the blocking thread pool of tokio, so
+/// that a slow disk or network mount does not stall the tasks of the runtime.
+#[derive(Clone)]
 pub struct FileAccessor {
     path: PathBuf,
+    follow_symlinks: bool,
+}
+
+/// Where `FileAccessor::walk` is.
+struct Walk {
+    /// The directory to read next.
+    next_dir: Option<PathBuf>,
+    /// The entries still to visit in each directory being walked, the deepest last.
+    stack: Vec<std::vec::IntoIter<ops::Entry>>,
+    visited: HashSet<DirKey>,
 }
 
 impl FileAccessor {
     pub fn new(file_path: PathBuf) -> Result<Self> {
-        return Ok(Self { path: file_path });
+        Ok(Self {
+            path: file_path,
+            follow_symlinks: true,
+        })
+    }
+
+    /// Whether reads, listings, walks and deletions go through a symbolic link at the path,
+    /// which they do by default. Not following, they fail with
+    /// `FileUtilFsError::SymlinkNotFollowed` instead, and walks do not descend into links to
+    /// directories. Only the path itself is checked, not the directories on the way to it.
+    /// A deletion never deletes what a link points to: following, it deletes the link.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
     }
 
-    pub fn is_exists(&self) -> Result<bool> {
-        Ok(self.path.exists())
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    pub fn read(&self) -> Result<Option<Vec<u8>>> {
-        match Self::is_exists(&self) {
-            Ok(true) => {
-                let result = fs::read(&self.path).map(|body| Some(body))?;
-                Ok(result)
+    /// Run `f` with the path on the blocking thread pool.
+    async fn with_path<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Path) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let path = self.path.clone();
+        unblock(move || f(&path)).await
+    }
+
+    /// Like `with_path`, refusing a symbolic link at the path unless links are followed.
+    async fn with_target<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Path) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let follow_symlinks = self.follow_symlinks;
+        self.with_path(move |path| {
+            if !follow_symlinks {
+                ops::reject_symlink(path)?;
             }
-            Ok(false) => Ok(None),
-            Err(e) => Err(e),
-        }
+            f(path)
+        })
+        .await
+    }
+
+    pub async fn is_exists(&self) -> Result<bool> {
+        Ok(self.with_path(|path| path.exists()).await)
+    }
+
+    /// Like `is_exists`, but an error reading the metadata, e.g. a directory on the way that
+    /// may not be searched, is returned instead of taken for absence.
+    pub async fn is_exists_strict(&self) -> Result<bool> {
+        self.with_path(ops::is_exists_strict).await
     }
-    pub fn write(&self, body: &[u8], compression: Option<compression::Compression>) -> Result<()> {
-        let body = compression::compress_opt(body, compression)?;
-        fs::write(&self.path, body)?;
-        Ok(())
+
+    /// The metadata of the file or directory, following symbolic links. Returns `None` when
+    /// it does not exist, as for a link to nothing.
+    pub async fn metadata(&self) -> Result<Option<LocalMetadata>> {
+        self.with_path(ops::metadata).await
     }
 
-    pub fn list_directory(&self) -> Result<Vec<String>> {
-        let mut dirs = Vec::<String>::new();
-        for entry in fs::read_dir(self.path.as_path().as_os_str())? {
-            let entry = entry?;
-            dirs.push(entry.path().display().to_string());
+    pub async fn read(&self) -> Result<Option<Vec<u8>>> {
+        self.with_target(ops::read).await
+    }
+
+    /// Read the file as a stream of chunks. Returns `None` when the file does not exist.
+    pub async fn read_stream(&self) -> Result<Option<impl Stream<Item = Result<Bytes>>>> {
+        let file = match self.with_target(ops::open).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        Ok(Some(futures::stream::try_unfold(
+            file,
+            |mut file| async move {
+                let (chunk, file) = unblock(move || {
+                    ops::read_chunk(&mut file, READ_CHUNK_SIZE).map(|chunk| (chunk, file))
+                })
+                .await?;
+                if chunk.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some((Bytes::from(chunk), file)))
+            },
+        )))
+    }
+
+    /// Read `length` bytes (or up to the end with `None`) starting at `offset`.
+    pub async fn read_range(&self, offset: u64, length: Option<u64>) -> Result<Option<Vec<u8>>> {
+        self.with_target(move |path| ops::read_range(path, offset, length))
+            .await
+    }
+
+    /// Read the last `length` bytes of the file, or all of it when it is shorter.
+    pub async fn read_tail(&self, length: u64) -> Result<Option<Vec<u8>>> {
+        self.with_target(move |path| ops::read_tail(path, length))
+            .await
+    }
+
+    /// Read the file and decompress it, reporting the size it has on disk.
+    pub async fn read_sized(
+        &self,
+        decompression: Option<compression::Compression>,
+    ) -> Result<Option<compression::SizedContents>> {
+        self.with_target(move |path| {
+            let result = compression::decompress_opt_sized(ops::read(path)?, decompression)?;
+            Ok(result)
+        })
+        .await
+    }
+
+    pub async fn write(
+        &self,
+        body: Bytes,
+        compression: Option<compression::Compression>,
+        mode: WriteMode,
+    ) -> Result<()> {
+        self.with_path(move |path| {
+            let body = ops::compress(&body, compression)?;
+            match mode {
+                WriteMode::InPlace => ops::write(path, &body),
+                WriteMode::Atomic => ops::write_atomic(path, &body),
+            }
+        })
+        .await
+    }
+
+    /// Create the directories the file is in when they do not exist yet.
+    pub async fn create_parents(&self) -> Result<()> {
+        self.with_path(ops::create_parents).await
+    }
+
+    /// Write the file only when it does not exist yet, which is checked atomically on
+    /// creating it. Returns whether the file was written.
+    pub async fn write_new(
+        &self,
+        body: Bytes,
+        compression: Option<compression::Compression>,
+    ) -> Result<bool> {
+        self.with_path(move |path| ops::write_new(path, &ops::compress(&body, compression)?))
+            .await
+    }
+
+    /// Append `body` to the file, creating it when it does not exist.
+    pub async fn append(&self, body: Bytes) -> Result<()> {
+        self.with_path(move |path| ops::append(path, &body)).await
+    }
+
+    /// Write the file unless it already holds `body` (after compression).
+    /// Returns whether the file was written.
+    pub async fn write_if_changed(
+        &self,
+        body: Bytes,
+        compression: Option<compression::Compression>,
+    ) -> Result<bool> {
+        self.with_path(move |path| ops::write_if_changed(path, &ops::compress(&body, compression)?))
+            .await
+    }
+
+    /// Write the chunks of `body` to the file as they arrive.
+    pub async fn write_stream<S, E>(&self, body: S) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        futures::pin_mut!(body);
+        let mut file = self.with_path(ops::create).await?;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.into()))?;
+            file = unblock(move || ops::write_chunk(file, &chunk)).await?;
         }
-        Ok(dirs)
+        unblock(move || ops::finish_writing(file)).await
+    }
+
+    /// List the entries of the directory. With `limit`, reading stops after that many entries.
+    pub async fn list_directory(&self, limit: Option<usize>) -> Result<Vec<String>> {
+        self.with_target(move |path| ops::list_directory(path, limit, None))
+            .await
+    }
+
+    /// List the entries of the directory whose names `filter` accepts, e.g.
+    /// `NameFilter::glob("*.csv.gz")`. With `limit`, reading stops after that many of them.
+    pub async fn list_directory_filtered(
+        &self,
+        limit: Option<usize>,
+        filter: NameFilter,
+    ) -> Result<Vec<String>> {
+        self.with_target(move |path| ops::list_directory(path, limit, Some(&filter)))
+            .await
     }
 
-    pub fn delete(&self) -> Result<()> {
-        unimplemented!("localfile deletion is not implemented yet");
+    /// List the files under the directory and all of its subdirectories, sorted by path
+    /// within each directory. Directories are not listed themselves, except symbolic links
+    /// to directories under `SymlinkPolicy::Include`.
+    pub async fn list_directory_recursive(
+        &self,
+        symlink_dirs: SymlinkPolicy,
+    ) -> Result<Vec<String>> {
+        self.with_target(move |path| ops::list_directory_recursive(path, symlink_dirs))
+            .await
+    }
+
+    /// Stream the files under the directory, depth first and sorted by path within each
+    /// directory, reading one directory at a time. `max_depth` limits how far down the
+    /// walk goes: `Some(1)` yields only the files directly in the directory, `None` those
+    /// of every subdirectory. Symbolic links to directories are descended into when links
+    /// are followed, see `with_follow_symlinks`, each directory once however many links lead
+    /// to it, so a link cycle ends the walk down that way.
+    pub fn walk(&self, max_depth: Option<usize>) -> impl Stream<Item = Result<String>> {
+        let follow_symlinks = self.follow_symlinks;
+        let walk = Walk {
+            next_dir: (max_depth != Some(0)).then(|| self.path.clone()),
+            stack: Vec::new(),
+            visited: HashSet::new(),
+        };
+        futures::stream::try_unfold(walk, move |mut walk| async move {
+            let Walk {
+                next_dir,
+                stack,
+                visited,
+            } = &mut walk;
+            loop {
+                if let Some(dir) = next_dir.take() {
+                    let is_root = visited.is_empty();
+                    let (key, entries) = unblock(move || {
+                        if is_root && !follow_symlinks {
+                            ops::reject_symlink(&dir)?;
+                        }
+                        ops::walk_entries(&dir, follow_symlinks)
+                    })
+                    .await?;
+                    if visited.insert(key) {
+                        stack.push(entries.into_iter());
+                    }
+                }
+                let depth = stack.len();
+                let entry = match stack.last_mut() {
+                    Some(entries) => entries.next(),
+                    None => return Ok(None),
+                };
+                match entry {
+                    Some(ops::Entry::File(path)) => {
+                        return Ok(Some((path.display().to_string(), walk)))
+                    }
+                    Some(ops::Entry::Dir(path)) => {
+                        if max_depth.is_none_or(|max| depth < max) {
+                            *next_dir = Some(path);
+                        }
+                    }
+                    None => {
+                        stack.pop();
+                    }
+                }
+            }
+        })
+    }
+
+    /// List the entries of the directory, sorted: with `recursive`, those of its
+    /// subdirectories too, and with `include_directories`, the directories themselves,
+    /// marked by a trailing `/`. Symbolic links to directories are not descended into.
+    pub async fn list_entries(
+        &self,
+        recursive: bool,
+        include_directories: bool,
+    ) -> Result<Vec<String>> {
+        self.with_target(move |path| ops::list_entries(path, recursive, include_directories))
+            .await
+    }
+
+    /// Total size of the files under the directory and its subdirectories.
+    /// Symbolic links to directories are not followed.
+    pub async fn directory_size(&self) -> Result<u64> {
+        self.with_path(ops::directory_size).await
+    }
+
+    /// Delete the files under the directory and its subdirectories last modified before
+    /// `cutoff`. Symbolic links to directories are not followed. Returns how many were deleted.
+    pub async fn delete_modified_before(&self, cutoff: SystemTime) -> Result<usize> {
+        self.with_path(move |path| ops::delete_modified_before(path, cutoff))
+            .await
+    }
+
+    /// List the entries of the directory, keeping what was read before an error.
+    pub async fn list_directory_best_effort(&self) -> (Vec<String>, Option<FileUtilFsError>) {
+        self.with_path(ops::list_directory_best_effort).await
+    }
+
+    /// Copy the file to `destination` with its permissions. With `overwrite`, the copy is
+    /// made to a temporary file beside the destination, which replaces it once complete.
+    /// Otherwise an existing destination fails the copy with
+    /// `std::io::ErrorKind::AlreadyExists`, which is checked atomically on creating it.
+    /// Returns `Ok(false)` when the file does not exist.
+    pub async fn copy_to(&self, destination: &FileAccessor, overwrite: bool) -> Result<bool> {
+        let dst = destination.path.clone();
+        self.with_target(move |path| ops::copy(path, &dst, overwrite))
+            .await
+    }
+
+    /// Rename the file or directory to `destination`, which has to be on the same filesystem;
+    /// across filesystems it fails with `std::io::ErrorKind::CrossesDevices`. An existing
+    /// destination is replaced with `overwrite`, as far as the platform allows, and otherwise
+    /// fails the rename with `std::io::ErrorKind::AlreadyExists`. That is checked just
+    /// before renaming, so a destination created meanwhile is still replaced. A symbolic link
+    /// is renamed itself. Returns `Ok(false)` when the file does not exist.
+    pub async fn rename_to(&self, destination: &FileAccessor, overwrite: bool) -> Result<bool> {
+        let dst = destination.path.clone();
+        self.with_target(move |path| ops::rename(path, &dst, overwrite))
+            .await
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        self.with_target(ops::delete).await
+    }
+
+    /// Delete the file, or the directory with everything in it. A symbolic link is deleted
+    /// itself, never what it points to.
+    pub async fn delete_recursive(&self) -> Result<()> {
+        self.with_target(ops::delete_recursive).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use futures::{FutureExt, TryStreamExt};
+    use std::os::unix::fs::symlink;
+
+    #[tokio::test]
+    async fn list_recursive_with_symlink_cycle() {
+        let dir = TempDir::new();
+        let file = dir.write("a/file.txt", "x");
+        let nested = dir.write("a/b/nested.txt", "x");
+        let outside = TempDir::new();
+        let linked = outside.write("linked.txt", "x");
+        symlink(dir.path(), dir.path().join("a/b/loop")).unwrap();
+        symlink(outside.path(), dir.path().join("a/outside")).unwrap();
+        let accessor = FileAccessor::new(dir.path().to_path_buf()).unwrap();
+
+        let followed = accessor
+            .list_directory_recursive(SymlinkPolicy::Follow)
+            .await
+            .unwrap();
+        let via_link = dir.join("a/outside/linked.txt");
+        assert_eq!(vec![nested.clone(), file.clone(), via_link], followed);
+        assert!(!followed.contains(&linked));
+
+        let skipped = accessor
+            .list_directory_recursive(SymlinkPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!(vec![nested.clone(), file.clone()], skipped);
+
+        let included = accessor
+            .list_directory_recursive(SymlinkPolicy::Include)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![dir.join("a/b/loop"), nested, file, dir.join("a/outside")],
+            included
+        );
+    }
+
+    #[tokio::test]
+    async fn walk_down_to_the_max_depth() {
+        let dir = TempDir::new();
+        let top = dir.write("top.txt", "x");
+        let nested = dir.write("a/nested.txt", "x");
+        let deep = dir.write("a/b/deep.txt", "x");
+        let outside = TempDir::new();
+        outside.write("linked.txt", "x");
+        symlink(outside.path(), dir.path().join("a/outside")).unwrap();
+        let accessor = FileAccessor::new(dir.path().to_path_buf())
+            .unwrap()
+            .with_follow_symlinks(false);
+        let walked = |max_depth| {
+            accessor
+                .walk(max_depth)
+                .try_collect::<Vec<_>>()
+                .map(|files| files.unwrap())
+        };
+
+        assert_eq!(vec![deep, nested.clone(), top.clone()], walked(None).await);
+        assert_eq!(vec![nested, top.clone()], walked(Some(2)).await);
+        assert_eq!(vec![top], walked(Some(1)).await);
+        assert!(walked(Some(0)).await.is_empty());
+
+        let missing = FileAccessor::new(dir.path().join("missing")).unwrap();
+        assert!(missing.walk(None).try_collect::<Vec<_>>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_directory_filtered_by_name() {
+        let dir = TempDir::new();
+        let csv = dir.write("a.csv.gz", "x");
+        dir.write("a.csv", "x");
+        dir.write("nested/b.csv.gz", "x");
+        let accessor = FileAccessor::new(dir.path().to_path_buf()).unwrap();
+
+        let filter = NameFilter::glob("*.csv.gz").unwrap();
+        let listed = accessor
+            .list_directory_filtered(None, filter)
+            .await
+            .unwrap();
+        assert_eq!(vec![csv], listed);
+
+        let filter = NameFilter::new(|name| name.starts_with('a'));
+        let mut listed = accessor
+            .list_directory_filtered(None, filter)
+            .await
+            .unwrap();
+        listed.sort();
+        assert_eq!(vec![dir.join("a.csv"), dir.join("a.csv.gz")], listed);
+        let filter = NameFilter::new(|name| name.starts_with('a'));
+        let listed = accessor
+            .list_directory_filtered(Some(1), filter)
+            .await
+            .unwrap();
+        assert_eq!(1, listed.len());
+
+        assert!(NameFilter::glob("[z-a]").is_none());
+    }
+
+    #[tokio::test]
+    async fn metadata_of_files_directories_and_links() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", "hello");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink(&path, dir.path().join("link.txt")).unwrap();
+        symlink(dir.path().join("missing"), dir.path().join("dangling")).unwrap();
+        let accessor = |name: &str| FileAccessor::new(dir.path().join(name)).unwrap();
+
+        let metadata = accessor("a.txt").metadata().await.unwrap().unwrap();
+        assert_eq!(5, metadata.size);
+        assert_eq!(LocalFileType::File, metadata.file_type);
+        assert_eq!(0o640, metadata.permissions.mode() & 0o777);
+        assert!(metadata.modified.is_some() && !metadata.is_symlink);
+
+        let linked = accessor("link.txt").metadata().await.unwrap().unwrap();
+        assert_eq!((5, true), (linked.size, linked.is_symlink));
+        let root = FileAccessor::new(dir.path().to_path_buf()).unwrap();
+        let metadata = root.metadata().await.unwrap().unwrap();
+        assert_eq!(LocalFileType::Dir, metadata.file_type);
+        assert!(accessor("dangling").metadata().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn symlinks_followed_or_refused() {
+        let dir = TempDir::new();
+        let top = dir.write("top.txt", "x");
+        let outside = TempDir::new();
+        let linked = outside.write("linked.txt", "linked");
+        symlink(outside.path(), dir.path().join("data")).unwrap();
+        symlink(dir.path(), outside.path().join("back")).unwrap();
+        let following = |name: &str| FileAccessor::new(dir.path().join(name)).unwrap();
+        let refusing = |name: &str| following(name).with_follow_symlinks(false);
+
+        let walked: Vec<String> = following("").walk(None).try_collect().await.unwrap();
+        assert_eq!(vec![dir.join("data/linked.txt"), top], walked);
+        let through_link = following("data/linked.txt").read().await.unwrap();
+        assert_eq!(Some(b"linked".to_vec()), through_link);
+
+        let refused =
+            |result: Result<_>| matches!(result, Err(FileUtilFsError::SymlinkNotFollowed(_)));
+        symlink(&linked, dir.path().join("link.txt")).unwrap();
+        assert!(refused(refusing("link.txt").read().await.map(|_| ())));
+        assert!(refused(
+            refusing("data").list_directory(None).await.map(|_| ())
+        ));
+        assert!(refused(refusing("link.txt").delete().await));
+        assert!(refused(refusing("data").delete_recursive().await));
+        assert!(refusing("top.txt").read().await.unwrap().is_some());
+
+        following("link.txt").delete().await.unwrap();
+        assert!(!dir.path().join("link.txt").exists());
+        assert!(Path::new(&linked).exists());
+    }
+
+    #[tokio::test]
+    async fn copy_and_rename_with_overwrite_control() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new();
+        let src = dir.write("a.txt", "new");
+        let taken = dir.write("taken.txt", "old");
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+        let accessor = |name: &str| FileAccessor::new(dir.path().join(name)).unwrap();
+        let already_exists = |result: Result<bool>| match result {
+            Err(FileUtilFsError::IOError(e)) => e.kind() == std::io::ErrorKind::AlreadyExists,
+            _ => false,
+        };
+
+        assert!(already_exists(
+            accessor("a.txt")
+                .copy_to(&accessor("taken.txt"), false)
+                .await
+        ));
+        assert_eq!("old", fs::read_to_string(&taken).unwrap());
+        assert!(accessor("a.txt")
+            .copy_to(&accessor("b.txt"), false)
+            .await
+            .unwrap());
+        let mode = fs::metadata(dir.join("b.txt"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(accessor("a.txt")
+            .copy_to(&accessor("taken.txt"), true)
+            .await
+            .unwrap());
+        assert_eq!("new", fs::read_to_string(&taken).unwrap());
+
+        assert!(already_exists(
+            accessor("b.txt").rename_to(&accessor("a.txt"), false).await
+        ));
+        assert!(accessor("b.txt")
+            .rename_to(&accessor("c.txt"), false)
+            .await
+            .unwrap());
+        assert!(accessor("c.txt")
+            .rename_to(&accessor("a.txt"), true)
+            .await
+            .unwrap());
+        let listed = accessor("").list_entries(false, false).await.unwrap();
+        assert_eq!(vec![src, taken], listed);
+
+        let missing = accessor("missing.txt");
+        assert!(!missing.copy_to(&accessor("d.txt"), true).await.unwrap());
+        assert!(!missing.rename_to(&accessor("d.txt"), true).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn delete_recursive_keeps_what_links_point_to() {
+        let dir = TempDir::new();
+        let outside = TempDir::new();
+        let linked = outside.write("linked.txt", "x");
+        dir.write("a/b/nested.txt", "x");
+        symlink(outside.path(), dir.path().join("a/outside")).unwrap();
+        symlink(&linked, dir.path().join("link.txt")).unwrap();
+
+        FileAccessor::new(dir.path().join("link.txt"))
+            .unwrap()
+            .delete_recursive()
+            .await
+            .unwrap();
+        FileAccessor::new(dir.path().join("a"))
+            .unwrap()
+            .delete_recursive()
+            .await
+            .unwrap();
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("link.txt").exists());
+        assert!(Path::new(&linked).exists());
+    }
+
+    #[tokio::test]
+    async fn atomic_writes_replace_the_file_whole() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", "old");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let accessor = FileAccessor::new(PathBuf::from(&path)).unwrap();
+
+        accessor
+            .write(Bytes::from("new"), None, WriteMode::Atomic)
+            .await
+            .unwrap();
+        assert_eq!(b"new".to_vec(), fs::read(&path).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let listed = FileAccessor::new(dir.path().to_path_buf())
+            .unwrap()
+            .list_directory(None)
+            .await
+            .unwrap();
+        assert_eq!(vec![path], listed);
+
+        let missing = FileAccessor::new(dir.path().join("missing/a.txt")).unwrap();
+        let result = missing
+            .write(Bytes::from("x"), None, WriteMode::Atomic)
+            .await;
+        assert!(result.is_err());
     }
 }
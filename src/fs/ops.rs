@@ -0,0 +1,448 @@
+//! The blocking file operations behind `FileAccessor`, which runs them on the blocking
+//! thread pool of tokio.
+use super::{
+    dir_key, DirKey, FileUtilFsError, LocalFileType, LocalMetadata, NameFilter, Result,
+    SymlinkPolicy,
+};
+use crate::checksum::ChecksumAlgo;
+use crate::compression;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+pub(super) fn is_exists_strict(path: &Path) -> Result<bool> {
+    match fs::metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(super) fn metadata(path: &Path) -> Result<Option<LocalMetadata>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let file_type = if metadata.is_file() {
+        LocalFileType::File
+    } else if metadata.is_dir() {
+        LocalFileType::Dir
+    } else {
+        LocalFileType::Other
+    };
+    Ok(Some(LocalMetadata {
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+        created: metadata.created().ok(),
+        permissions: metadata.permissions(),
+        file_type,
+        is_symlink: fs::symlink_metadata(path)?.file_type().is_symlink(),
+    }))
+}
+
+pub(super) fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read(path)?))
+}
+
+/// The file opened for reading, `None` when it does not exist.
+pub(super) fn open(path: &Path) -> Result<Option<fs::File>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::File::open(path)?))
+}
+
+/// The next chunk of at most `size` bytes of `file`, empty at its end.
+pub(super) fn read_chunk(file: &mut fs::File, size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; size];
+    let n = file.read(&mut buf)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+pub(super) fn read_range(path: &Path, offset: u64, length: Option<u64>) -> Result<Option<Vec<u8>>> {
+    let mut file = match open(path)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut body = Vec::<u8>::new();
+    match length {
+        Some(length) => file.take(length).read_to_end(&mut body)?,
+        None => file.read_to_end(&mut body)?,
+    };
+    Ok(Some(body))
+}
+
+pub(super) fn read_tail(path: &Path, length: u64) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let size = fs::metadata(path)?.len();
+    read_range(path, size.saturating_sub(length), None)
+}
+
+pub(super) fn write(path: &Path, body: &[u8]) -> Result<()> {
+    fs::write(path, body)?;
+    Ok(())
+}
+
+pub(super) fn create_parents(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Ok(fs::create_dir_all(parent)?),
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn write_atomic(path: &Path, body: &[u8]) -> Result<()> {
+    let temp = temp_sibling(path);
+    let result = write_synced(&temp, path, body).and_then(|()| Ok(fs::rename(&temp, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+        return result;
+    }
+    sync_parent(path);
+    Ok(())
+}
+
+/// A path next to `path` that no other write uses, hidden on unix.
+fn temp_sibling(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), unique))
+}
+
+/// Write `body` to `temp` with the permissions of `original` if it exists, and sync it.
+fn write_synced(temp: &Path, original: &Path, body: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp)?;
+    file.write_all(body)?;
+    match fs::metadata(original) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Sync the directory of `path` so the rename survives a crash. Not every filesystem
+/// supports it, so a failure is ignored.
+#[cfg(unix)]
+fn sync_parent(path: &Path) {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let _ = fs::File::open(parent).and_then(|dir| dir.sync_all());
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) {}
+
+pub(super) fn write_new(path: &Path, body: &[u8]) -> Result<bool> {
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    file.write_all(body)?;
+    Ok(true)
+}
+
+pub(super) fn append(path: &Path, body: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    file.write_all(body)?;
+    file.flush()?;
+    Ok(())
+}
+
+pub(super) fn write_if_changed(path: &Path, body: &[u8]) -> Result<bool> {
+    if has_same_contents(path, body)? {
+        return Ok(false);
+    }
+    fs::write(path, body)?;
+    Ok(true)
+}
+
+fn has_same_contents(path: &Path, body: &[u8]) -> Result<bool> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_file() || metadata.len() != body.len() as u64 {
+        return Ok(false);
+    }
+    let digest = ChecksumAlgo::Md5.digest_reader(fs::File::open(path)?)?;
+    Ok(digest == ChecksumAlgo::Md5.digest(body))
+}
+
+pub(super) fn compress(
+    body: &[u8],
+    compression: Option<compression::Compression>,
+) -> Result<Vec<u8>> {
+    Ok(compression::compress_opt(body, compression)?)
+}
+
+pub(super) fn list_directory(
+    path: &Path,
+    limit: Option<usize>,
+    filter: Option<&NameFilter>,
+) -> Result<Vec<String>> {
+    let mut dirs = Vec::<String>::new();
+    for entry in fs::read_dir(path.as_os_str())? {
+        if limit.is_some_and(|limit| dirs.len() >= limit) {
+            break;
+        }
+        let entry = entry?;
+        if filter.is_some_and(|filter| !filter.accepts(&entry.file_name().to_string_lossy())) {
+            continue;
+        }
+        dirs.push(entry.path().display().to_string());
+    }
+    Ok(dirs)
+}
+
+pub(super) fn list_directory_recursive(
+    path: &Path,
+    symlink_dirs: SymlinkPolicy,
+) -> Result<Vec<String>> {
+    let mut files = Vec::<String>::new();
+    let mut visited = HashSet::<DirKey>::new();
+    visited.insert(dir_key(path)?);
+    walk(path, symlink_dirs, &mut visited, &mut files)?;
+    Ok(files)
+}
+
+pub(super) fn list_entries(
+    path: &Path,
+    recursive: bool,
+    include_directories: bool,
+) -> Result<Vec<String>> {
+    let mut entries = Vec::<String>::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_symlink = fs::symlink_metadata(&path)?.file_type().is_symlink();
+            let is_dir = fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+            if !is_dir {
+                entries.push(path.display().to_string());
+                continue;
+            }
+            if include_directories {
+                entries.push(format!("{}/", path.display()));
+            }
+            if recursive && !is_symlink {
+                dirs.push(path);
+            }
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+pub(super) fn directory_size(path: &Path) -> Result<u64> {
+    let mut total = 0u64;
+    for path in list_directory_recursive(path, SymlinkPolicy::Skip)? {
+        // dangling links are listed but have no size
+        if let Ok(metadata) = fs::metadata(&path) {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+pub(super) fn delete_modified_before(path: &Path, cutoff: SystemTime) -> Result<usize> {
+    let mut deleted = 0usize;
+    for path in list_directory_recursive(path, SymlinkPolicy::Skip)? {
+        if fs::symlink_metadata(&path)?.modified()? < cutoff {
+            fs::remove_file(&path)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+fn walk(
+    dir: &Path,
+    symlink_dirs: SymlinkPolicy,
+    visited: &mut HashSet<DirKey>,
+    files: &mut Vec<String>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+
+    for path in entries {
+        let is_symlink = fs::symlink_metadata(&path)?.file_type().is_symlink();
+        // a dangling link is listed as a file
+        let is_dir = fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+        if !is_dir {
+            files.push(path.display().to_string());
+            continue;
+        }
+        if is_symlink {
+            match symlink_dirs {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Include => {
+                    files.push(path.display().to_string());
+                    continue;
+                }
+                SymlinkPolicy::Follow => {}
+            }
+        }
+        if visited.insert(dir_key(&path)?) {
+            walk(&path, symlink_dirs, visited, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// An entry of a directory being walked.
+pub(super) enum Entry {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+/// Fail when `path` is a symbolic link.
+pub(super) fn reject_symlink(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            Err(FileUtilFsError::SymlinkNotFollowed(path.to_path_buf()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The key of `dir` and its entries sorted by path, with symbolic links to directories only
+/// when `follow_symlinks`. A dangling link is taken for a file.
+pub(super) fn walk_entries(dir: &Path, follow_symlinks: bool) -> Result<(DirKey, Vec<Entry>)> {
+    let key = dir_key(dir)?;
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let is_symlink = fs::symlink_metadata(&path)?.file_type().is_symlink();
+        let is_dir = fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+        match (is_dir, is_symlink) {
+            (false, _) => entries.push(Entry::File(path)),
+            (true, true) if !follow_symlinks => {}
+            (true, _) => entries.push(Entry::Dir(path)),
+        }
+    }
+    Ok((key, entries))
+}
+
+pub(super) fn list_directory_best_effort(path: &Path) -> (Vec<String>, Option<FileUtilFsError>) {
+    let mut dirs = Vec::<String>::new();
+    let entries = match fs::read_dir(path.as_os_str()) {
+        Ok(entries) => entries,
+        Err(e) => return (dirs, Some(e.into())),
+    };
+    for entry in entries {
+        match entry {
+            Ok(entry) => dirs.push(entry.path().display().to_string()),
+            Err(e) => return (dirs, Some(e.into())),
+        }
+    }
+    (dirs, None)
+}
+
+pub(super) fn copy(src: &Path, dst: &Path, overwrite: bool) -> Result<bool> {
+    if !src.exists() {
+        return Ok(false);
+    }
+    if overwrite {
+        let temp = temp_sibling(dst);
+        let copied = fs::copy(src, &temp).and_then(|_| fs::rename(&temp, dst));
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        return Ok(true);
+    }
+    let mut source = fs::File::open(src)?;
+    let mut destination = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    let copied = std::io::copy(&mut source, &mut destination)
+        .and_then(|_| destination.set_permissions(source.metadata()?.permissions()));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(dst);
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+pub(super) fn rename(src: &Path, dst: &Path, overwrite: bool) -> Result<bool> {
+    if fs::symlink_metadata(src).is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound) {
+        return Ok(false);
+    }
+    if !overwrite && fs::symlink_metadata(dst).is_ok() {
+        return Err(already_exists(dst).into());
+    }
+    fs::rename(src, dst)?;
+    Ok(true)
+}
+
+fn already_exists(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("`{}` already exists", path.display()),
+    )
+}
+
+pub(super) fn delete(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+pub(super) fn delete_recursive(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Write `chunk` to `file`, handing it back for the next one.
+pub(super) fn write_chunk(mut file: fs::File, chunk: &[u8]) -> Result<fs::File> {
+    file.write_all(chunk)?;
+    Ok(file)
+}
+
+pub(super) fn finish_writing(mut file: fs::File) -> Result<()> {
+    file.flush()?;
+    Ok(())
+}
+
+pub(super) fn create(path: &Path) -> Result<fs::File> {
+    Ok(fs::File::create(path)?)
+}
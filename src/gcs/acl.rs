@@ -0,0 +1,65 @@
+//! Access control applied to objects written by this crate.
+use cloud_storage::object_access_control::{Entity, NewObjectAccessControl, Role};
+
+/// The predefined ACLs of gcs. See https://cloud.google.com/storage/docs/access-control/lists#predefined-acl
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PredefinedAcl {
+    AuthenticatedRead,
+    BucketOwnerFullControl,
+    BucketOwnerRead,
+    Private,
+    ProjectPrivate,
+    PublicRead,
+}
+
+impl PredefinedAcl {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PredefinedAcl::AuthenticatedRead => "authenticatedRead",
+            PredefinedAcl::BucketOwnerFullControl => "bucketOwnerFullControl",
+            PredefinedAcl::BucketOwnerRead => "bucketOwnerRead",
+            PredefinedAcl::Private => "private",
+            PredefinedAcl::ProjectPrivate => "projectPrivate",
+            PredefinedAcl::PublicRead => "publicRead",
+        }
+    }
+}
+
+/// What ACL an object gets when written.
+/// Overwriting an object resets its ACL to the bucket default unless told otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum AclBehavior {
+    #[default]
+    BucketDefault,
+    /// Keep the ACL the object had before it was overwritten.
+    Preserve,
+    Set(PredefinedAcl),
+}
+
+/// `Role` is not `Clone`.
+pub(crate) fn copy_acl_entry(entry: &NewObjectAccessControl) -> NewObjectAccessControl {
+    NewObjectAccessControl {
+        entity: entry.entity.clone(),
+        role: match entry.role {
+            Role::Owner => Role::Owner,
+            Role::Writer => Role::Writer,
+            Role::Reader => Role::Reader,
+        },
+    }
+}
+
+/// The entries a predefined ACL adds on top of the owner, for the ACLs expressible
+/// as a set of entries without knowing the project.
+pub(crate) fn predefined_acl_entries(acl: PredefinedAcl) -> Option<Vec<NewObjectAccessControl>> {
+    match acl {
+        PredefinedAcl::PublicRead => Some(vec![NewObjectAccessControl {
+            entity: Entity::AllUsers,
+            role: Role::Reader,
+        }]),
+        PredefinedAcl::AuthenticatedRead => Some(vec![NewObjectAccessControl {
+            entity: Entity::AllAuthenticatedUsers,
+            role: Role::Reader,
+        }]),
+        _ => None,
+    }
+}
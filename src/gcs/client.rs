@@ -0,0 +1,886 @@
+//! The raw gcs operations this crate relies on, behind a trait so that the
+//! backend can be swapped (e.g. for an in-memory one in tests).
+//!
+//! Calls go to `CloudStorageClient` unless a future is run inside `with_client`, or a
+//! client is set with `config::ClientConfig::with_gcs_client`.
+use super::acl::{predefined_acl_entries, PredefinedAcl};
+use super::json_api::JsonApi;
+use async_trait::async_trait;
+use bytes::Bytes;
+use cloud_storage::object::{ComposeRequest, ObjectList, SourceObject};
+use cloud_storage::object_access_control::{NewObjectAccessControl, ObjectAccessControl};
+use cloud_storage::{ListRequest, Object, Result};
+use futures::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use reqwest::StatusCode;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A body that is uploaded chunk by chunk.
+pub type ByteStream =
+    Pin<Box<dyn Stream<Item = std::result::Result<Bytes, BoxError>> + Send + Sync>>;
+
+/// A downloaded body, chunk by chunk.
+pub type DownloadStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+#[async_trait]
+pub trait GcsClient: Send + Sync {
+    async fn read_object(&self, bucket: &str, name: &str) -> Result<Object>;
+
+    /// Fetch a single page of a listing. `request.page_token` selects the page.
+    async fn list_objects_page(&self, bucket: &str, request: ListRequest) -> Result<ObjectList>;
+
+    async fn download_object(&self, bucket: &str, name: &str) -> Result<Vec<u8>>;
+
+    async fn download_object_streamed(&self, bucket: &str, name: &str) -> Result<DownloadStream>;
+
+    /// Download `length` bytes (or up to the end with `None`) starting at `offset`.
+    /// A range starting beyond the end yields an empty body.
+    async fn download_object_range(
+        &self,
+        bucket: &str,
+        name: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Vec<u8>>;
+
+    /// Create an object from `body`, which retries can hand again without copying it.
+    async fn create_object(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object>;
+
+    /// `create_object` only while the object is at `generation`, 0 being that it does not
+    /// exist, as the `ifGenerationMatch` precondition of gcs. Fails with `conditionNotMet`
+    /// otherwise. Implementations that can should make it atomic; the default one can not.
+    async fn create_object_if_generation_match(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+        generation: i64,
+    ) -> Result<Object>;
+
+    /// Create an object from a stream of chunks without holding the whole body in memory.
+    async fn create_object_streamed(
+        &self,
+        bucket: &str,
+        body: ByteStream,
+        length: Option<u64>,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object>;
+
+    /// Copy `name` to `destination_bucket`/`destination_name` within gcs.
+    async fn rewrite_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        destination_bucket: &str,
+        destination_name: &str,
+    ) -> Result<Object>;
+
+    /// Concatenate `source_names`, in order, into `destination_name`, all in `bucket`.
+    /// The destination may be one of the sources.
+    async fn compose_objects(
+        &self,
+        bucket: &str,
+        source_names: &[String],
+        destination_name: &str,
+    ) -> Result<Object>;
+
+    /// Replace the metadata of the object `object` names with that of `object`, such as its
+    /// cache control and custom metadata. The contents stay as they are.
+    async fn update_object(&self, object: &Object) -> Result<Object>;
+
+    async fn delete_object(&self, bucket: &str, name: &str) -> Result<()>;
+
+    /// Fetch a page of the soft-deleted objects whose names start with `prefix`.
+    async fn list_soft_deleted_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        page_token: Option<String>,
+    ) -> Result<ObjectList>;
+
+    /// Bring back the soft-deleted `generation` of the object as its live version.
+    async fn restore_object(&self, bucket: &str, name: &str, generation: i64) -> Result<Object>;
+
+    async fn read_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Vec<NewObjectAccessControl>>;
+
+    async fn add_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+        entry: NewObjectAccessControl,
+    ) -> Result<()>;
+
+    async fn set_predefined_acl(&self, bucket: &str, name: &str, acl: PredefinedAcl) -> Result<()>;
+}
+
+/// `GcsClient` backed by the global client of `cloud-storage`.
+pub struct CloudStorageClient;
+
+#[async_trait]
+impl GcsClient for CloudStorageClient {
+    async fn read_object(&self, bucket: &str, name: &str) -> Result<Object> {
+        Object::read(bucket, name).await
+    }
+
+    async fn list_objects_page(&self, bucket: &str, request: ListRequest) -> Result<ObjectList> {
+        let pages = Object::list(bucket, request).await?;
+        futures::pin_mut!(pages);
+        match pages.next().await {
+            Some(page) => page,
+            None => Ok(ObjectList {
+                kind: "storage#objects".to_string(),
+                items: vec![],
+                prefixes: vec![],
+                next_page_token: None,
+            }),
+        }
+    }
+
+    async fn download_object(&self, bucket: &str, name: &str) -> Result<Vec<u8>> {
+        Object::download(bucket, name).await
+    }
+
+    /// `Object::download_streamed` yields a byte at a time, so this streams the chunks of
+    /// a short lived signed url instead.
+    async fn download_object_streamed(&self, bucket: &str, name: &str) -> Result<DownloadStream> {
+        let object = Object::read(bucket, name).await?;
+        let url = object.download_url(SIGNED_URL_DURATION_SEC)?;
+        let response = SIGNED_URL_HTTP_CLI.get(&url).send().await?;
+        if !response.status().is_success() {
+            return Err(cloud_storage::Error::Other(response.text().await?));
+        }
+        Ok(Box::pin(
+            response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(Into::into)),
+        ))
+    }
+
+    /// `cloud-storage` has no ranged download, so this fetches through a short lived
+    /// signed url with a `Range` header.
+    async fn download_object_range(
+        &self,
+        bucket: &str,
+        name: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        if length == Some(0) {
+            return Ok(vec![]);
+        }
+        let object = Object::read(bucket, name).await?;
+        let url = object.download_url(SIGNED_URL_DURATION_SEC)?;
+        let range = match length {
+            Some(length) => format!("bytes={}-{}", offset, offset + length - 1),
+            None => format!("bytes={}-", offset),
+        };
+
+        let response = SIGNED_URL_HTTP_CLI
+            .get(&url)
+            .header(reqwest::header::RANGE, range)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => Ok(response.bytes().await?.to_vec()),
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(vec![]),
+            status if status.is_success() => {
+                // the whole object was returned; cut out the requested range
+                let body = response.bytes().await?;
+                Ok(slice_range(&body, offset, length).to_vec())
+            }
+            _ => Err(cloud_storage::Error::Other(response.text().await?)),
+        }
+    }
+
+    async fn create_object(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        // the same upload as `Object::create`, which would take the body as a `Vec`
+        let length = body.len() as u64;
+        let body = futures::stream::once(async { Ok::<_, std::convert::Infallible>(body) });
+        Object::create_streamed(bucket, body, length, name, mime_type).await
+    }
+
+    /// `cloud-storage` can not send `ifGenerationMatch`, so this is best effort: the
+    /// generation is checked with a read before the upload, see `create_if_at_generation`.
+    async fn create_object_if_generation_match(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+        generation: i64,
+    ) -> Result<Object> {
+        let current = async {
+            match Object::read(bucket, name).await {
+                Ok(object) => Ok(object.generation),
+                Err(cloud_storage::Error::Google(response))
+                    if response.errors_has_reason(&cloud_storage::Reason::NotFound) =>
+                {
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            }
+        };
+        create_if_at_generation(current, generation, || {
+            self.create_object(bucket, body, name, mime_type)
+        })
+        .await
+    }
+
+    async fn create_object_streamed(
+        &self,
+        bucket: &str,
+        body: ByteStream,
+        length: Option<u64>,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        Object::create_streamed(bucket, body, length, name, mime_type).await
+    }
+
+    async fn rewrite_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        destination_bucket: &str,
+        destination_name: &str,
+    ) -> Result<Object> {
+        let object = Object::read(bucket, name).await?;
+        object.rewrite(destination_bucket, destination_name).await
+    }
+
+    async fn compose_objects(
+        &self,
+        bucket: &str,
+        source_names: &[String],
+        destination_name: &str,
+    ) -> Result<Object> {
+        let request = ComposeRequest {
+            kind: "storage#composeRequest".to_string(),
+            source_objects: source_names
+                .iter()
+                .map(|name| SourceObject {
+                    name: name.clone(),
+                    generation: None,
+                    object_preconditions: None,
+                })
+                .collect(),
+            destination: None,
+        };
+        Object::compose(bucket, &request, destination_name).await
+    }
+
+    async fn update_object(&self, object: &Object) -> Result<Object> {
+        object.update().await
+    }
+
+    async fn delete_object(&self, bucket: &str, name: &str) -> Result<()> {
+        Object::delete(bucket, name).await
+    }
+
+    /// `cloud-storage` has no soft delete support, so this is sent to the JSON API directly.
+    async fn list_soft_deleted_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        page_token: Option<String>,
+    ) -> Result<ObjectList> {
+        JSON_API
+            .list_soft_deleted_page(bucket, prefix, page_token)
+            .await
+    }
+
+    /// See `list_soft_deleted_page`.
+    async fn restore_object(&self, bucket: &str, name: &str, generation: i64) -> Result<Object> {
+        JSON_API.restore_object(bucket, name, generation).await
+    }
+
+    async fn read_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Vec<NewObjectAccessControl>> {
+        let entries = ObjectAccessControl::list(bucket, name).await?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| NewObjectAccessControl {
+                entity: entry.entity,
+                role: entry.role,
+            })
+            .collect())
+    }
+
+    async fn add_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+        entry: NewObjectAccessControl,
+    ) -> Result<()> {
+        ObjectAccessControl::create(bucket, name, &entry).await?;
+        Ok(())
+    }
+
+    /// `cloud-storage` can not send `predefinedAcl`, so the ACLs that are a fixed set of
+    /// entries are added entry by entry and the others are rejected.
+    async fn set_predefined_acl(&self, bucket: &str, name: &str, acl: PredefinedAcl) -> Result<()> {
+        let entries = predefined_acl_entries(acl).ok_or_else(|| {
+            cloud_storage::Error::Other(format!(
+                "predefined acl `{}` can not be applied through cloud-storage",
+                acl.as_str()
+            ))
+        })?;
+        for entry in entries {
+            ObjectAccessControl::create(bucket, name, &entry).await?;
+        }
+        Ok(())
+    }
+}
+
+const SIGNED_URL_DURATION_SEC: u32 = 600;
+
+/// Wraps a `GcsClient`, failing any single call that takes longer than `timeout` with a
+/// transient error, which the callers retry. Streamed uploads are not bounded.
+pub struct TimeoutClient {
+    inner: Arc<dyn GcsClient>,
+    timeout: Duration,
+}
+
+impl TimeoutClient {
+    pub fn new(inner: Arc<dyn GcsClient>, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+
+    /// Fail a call that runs past the timeout as gcs does a request that timed out on its
+    /// side, so that it is classed as transient and retried.
+    async fn bounded<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::time::timeout(self.timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                Err(google_error(
+                    "gatewayTimeout",
+                    504,
+                    &format!("gcs request timed out after {:?}", self.timeout),
+                ))
+            })
+    }
+}
+
+#[async_trait]
+impl GcsClient for TimeoutClient {
+    async fn read_object(&self, bucket: &str, name: &str) -> Result<Object> {
+        self.bounded(self.inner.read_object(bucket, name)).await
+    }
+
+    async fn list_objects_page(&self, bucket: &str, request: ListRequest) -> Result<ObjectList> {
+        self.bounded(self.inner.list_objects_page(bucket, request))
+            .await
+    }
+
+    async fn download_object(&self, bucket: &str, name: &str) -> Result<Vec<u8>> {
+        self.bounded(self.inner.download_object(bucket, name)).await
+    }
+
+    async fn download_object_streamed(&self, bucket: &str, name: &str) -> Result<DownloadStream> {
+        self.bounded(self.inner.download_object_streamed(bucket, name))
+            .await
+    }
+
+    async fn download_object_range(
+        &self,
+        bucket: &str,
+        name: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        self.bounded(
+            self.inner
+                .download_object_range(bucket, name, offset, length),
+        )
+        .await
+    }
+
+    async fn create_object(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        self.bounded(self.inner.create_object(bucket, body, name, mime_type))
+            .await
+    }
+
+    async fn create_object_if_generation_match(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+        generation: i64,
+    ) -> Result<Object> {
+        self.bounded(
+            self.inner
+                .create_object_if_generation_match(bucket, body, name, mime_type, generation),
+        )
+        .await
+    }
+
+    async fn create_object_streamed(
+        &self,
+        bucket: &str,
+        body: ByteStream,
+        length: Option<u64>,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        self.inner
+            .create_object_streamed(bucket, body, length, name, mime_type)
+            .await
+    }
+
+    async fn rewrite_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        destination_bucket: &str,
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.bounded(
+            self.inner
+                .rewrite_object(bucket, name, destination_bucket, destination_name),
+        )
+        .await
+    }
+
+    async fn compose_objects(
+        &self,
+        bucket: &str,
+        source_names: &[String],
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.bounded(
+            self.inner
+                .compose_objects(bucket, source_names, destination_name),
+        )
+        .await
+    }
+
+    async fn update_object(&self, object: &Object) -> Result<Object> {
+        self.bounded(self.inner.update_object(object)).await
+    }
+
+    async fn delete_object(&self, bucket: &str, name: &str) -> Result<()> {
+        self.bounded(self.inner.delete_object(bucket, name)).await
+    }
+
+    async fn list_soft_deleted_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        page_token: Option<String>,
+    ) -> Result<ObjectList> {
+        self.bounded(
+            self.inner
+                .list_soft_deleted_page(bucket, prefix, page_token),
+        )
+        .await
+    }
+
+    async fn restore_object(&self, bucket: &str, name: &str, generation: i64) -> Result<Object> {
+        self.bounded(self.inner.restore_object(bucket, name, generation))
+            .await
+    }
+
+    async fn read_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Vec<NewObjectAccessControl>> {
+        self.bounded(self.inner.read_object_acl(bucket, name)).await
+    }
+
+    async fn add_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+        entry: NewObjectAccessControl,
+    ) -> Result<()> {
+        self.bounded(self.inner.add_object_acl(bucket, name, entry))
+            .await
+    }
+
+    async fn set_predefined_acl(&self, bucket: &str, name: &str, acl: PredefinedAcl) -> Result<()> {
+        self.bounded(self.inner.set_predefined_acl(bucket, name, acl))
+            .await
+    }
+}
+
+type ObjectKey = (String, String);
+
+/// `Object` is not `Clone`; copy it through its serialized form. It deserializes only what
+/// the api sends: numbers as strings and absent rather than null optional fields.
+fn copy_object(object: &Object) -> Option<Object> {
+    let value = serde_json::to_value(object).ok()?;
+    let fields: serde_json::Map<String, serde_json::Value> = value
+        .as_object()?
+        .iter()
+        .filter(|(_, field)| !field.is_null())
+        .map(|(key, field)| match field {
+            serde_json::Value::Number(number) => {
+                (key.clone(), serde_json::Value::String(number.to_string()))
+            }
+            field => (key.clone(), field.clone()),
+        })
+        .collect();
+    serde_json::from_value(serde_json::Value::Object(fields)).ok()
+}
+
+/// Wraps a `GcsClient`, answering metadata reads (and so existence checks) of recently read
+/// objects from memory for `ttl`. At most `capacity` objects are kept, evicting the least
+/// recently used. Writes and deletes through this client drop the entry of the object;
+/// changes made by others go unnoticed until the entry expires. Missing objects are not cached.
+pub struct MetadataCacheClient {
+    inner: Arc<dyn GcsClient>,
+    ttl: Duration,
+    capacity: usize,
+    // most recently used first
+    entries: Mutex<VecDeque<(ObjectKey, Instant, Object)>>,
+}
+
+impl MetadataCacheClient {
+    pub fn new(inner: Arc<dyn GcsClient>, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn cached(&self, bucket: &str, name: &str) -> Option<Object> {
+        let mut entries = self.entries.lock().unwrap();
+        let i = entries
+            .iter()
+            .position(|((b, n), _, _)| b == bucket && n == name)?;
+        let entry = entries.remove(i)?;
+        if entry.1.elapsed() >= self.ttl {
+            return None;
+        }
+        let object = copy_object(&entry.2);
+        entries.push_front(entry);
+        object
+    }
+
+    fn store(&self, bucket: &str, name: &str, object: Object) {
+        self.invalidate(bucket, name);
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front((
+            (bucket.to_string(), name.to_string()),
+            Instant::now(),
+            object,
+        ));
+        entries.truncate(self.capacity);
+    }
+
+    fn invalidate(&self, bucket: &str, name: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|((b, n), _, _)| b != bucket || n != name);
+    }
+}
+
+#[async_trait]
+impl GcsClient for MetadataCacheClient {
+    async fn read_object(&self, bucket: &str, name: &str) -> Result<Object> {
+        if let Some(object) = self.cached(bucket, name) {
+            return Ok(object);
+        }
+        let object = self.inner.read_object(bucket, name).await?;
+        if let Some(copy) = copy_object(&object) {
+            self.store(bucket, name, copy);
+        }
+        Ok(object)
+    }
+
+    async fn list_objects_page(&self, bucket: &str, request: ListRequest) -> Result<ObjectList> {
+        self.inner.list_objects_page(bucket, request).await
+    }
+
+    async fn download_object(&self, bucket: &str, name: &str) -> Result<Vec<u8>> {
+        self.inner.download_object(bucket, name).await
+    }
+
+    async fn download_object_streamed(&self, bucket: &str, name: &str) -> Result<DownloadStream> {
+        self.inner.download_object_streamed(bucket, name).await
+    }
+
+    async fn download_object_range(
+        &self,
+        bucket: &str,
+        name: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        self.inner
+            .download_object_range(bucket, name, offset, length)
+            .await
+    }
+
+    async fn create_object(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        self.invalidate(bucket, name);
+        self.inner
+            .create_object(bucket, body, name, mime_type)
+            .await
+    }
+
+    async fn create_object_if_generation_match(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+        generation: i64,
+    ) -> Result<Object> {
+        self.invalidate(bucket, name);
+        self.inner
+            .create_object_if_generation_match(bucket, body, name, mime_type, generation)
+            .await
+    }
+
+    async fn create_object_streamed(
+        &self,
+        bucket: &str,
+        body: ByteStream,
+        length: Option<u64>,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        self.invalidate(bucket, name);
+        self.inner
+            .create_object_streamed(bucket, body, length, name, mime_type)
+            .await
+    }
+
+    async fn rewrite_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        destination_bucket: &str,
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.invalidate(destination_bucket, destination_name);
+        self.inner
+            .rewrite_object(bucket, name, destination_bucket, destination_name)
+            .await
+    }
+
+    async fn compose_objects(
+        &self,
+        bucket: &str,
+        source_names: &[String],
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.invalidate(bucket, destination_name);
+        self.inner
+            .compose_objects(bucket, source_names, destination_name)
+            .await
+    }
+
+    async fn update_object(&self, object: &Object) -> Result<Object> {
+        self.invalidate(&object.bucket, &object.name);
+        self.inner.update_object(object).await
+    }
+
+    async fn delete_object(&self, bucket: &str, name: &str) -> Result<()> {
+        self.invalidate(bucket, name);
+        self.inner.delete_object(bucket, name).await
+    }
+
+    async fn list_soft_deleted_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        page_token: Option<String>,
+    ) -> Result<ObjectList> {
+        self.inner
+            .list_soft_deleted_page(bucket, prefix, page_token)
+            .await
+    }
+
+    async fn restore_object(&self, bucket: &str, name: &str, generation: i64) -> Result<Object> {
+        self.invalidate(bucket, name);
+        self.inner.restore_object(bucket, name, generation).await
+    }
+
+    async fn read_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Vec<NewObjectAccessControl>> {
+        self.inner.read_object_acl(bucket, name).await
+    }
+
+    async fn add_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+        entry: NewObjectAccessControl,
+    ) -> Result<()> {
+        self.invalidate(bucket, name);
+        self.inner.add_object_acl(bucket, name, entry).await
+    }
+
+    async fn set_predefined_acl(&self, bucket: &str, name: &str, acl: PredefinedAcl) -> Result<()> {
+        self.invalidate(bucket, name);
+        self.inner.set_predefined_acl(bucket, name, acl).await
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_CLIENT: Arc<dyn GcsClient> = Arc::new(CloudStorageClient);
+    static ref SIGNED_URL_HTTP_CLI: reqwest::Client = reqwest::Client::new();
+    static ref JSON_API: JsonApi = JsonApi::new();
+}
+
+/// Run `create` when `current` finds the object at `generation`, and fail with
+/// `conditionNotMet` otherwise. The check and the write are separate requests, so this is
+/// not atomic: writers that all check before any of them writes all succeed, and the last
+/// one to write wins.
+async fn create_if_at_generation<T, C, F, Fut>(current: C, generation: i64, create: F) -> Result<T>
+where
+    C: Future<Output = Result<i64>>,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    if current.await? != generation {
+        return Err(condition_not_met());
+    }
+    create().await
+}
+
+/// The error gcs answers a failed precondition with.
+fn condition_not_met() -> cloud_storage::Error {
+    google_error("conditionNotMet", 412, "Precondition Failed")
+}
+
+/// An error as gcs answers it, with a single `reason`.
+fn google_error(reason: &str, code: u16, message: &str) -> cloud_storage::Error {
+    let response = serde_json::from_value(serde_json::json!({
+        "error": {
+            "errors": [{
+                "domain": "global",
+                "reason": reason,
+                "message": message,
+            }],
+            "code": code,
+            "message": message,
+        }
+    }))
+    .expect("a well formed gcs error response");
+    cloud_storage::Error::Google(response)
+}
+
+/// Cut `offset..offset + length` out of `body`, clamped to its end.
+pub(crate) fn slice_range(body: &[u8], offset: u64, length: Option<u64>) -> &[u8] {
+    let start = (offset as usize).min(body.len());
+    let end = match length {
+        Some(length) => start.saturating_add(length as usize).min(body.len()),
+        None => body.len(),
+    };
+    &body[start..end]
+}
+
+tokio::task_local! {
+    static SCOPED_CLIENT: Arc<dyn GcsClient>;
+}
+
+/// Run `f` with every gcs call inside it routed to `client`.
+/// The override is bound to the current task, so it does not leak into `tokio::spawn`ed tasks.
+pub async fn with_client<F: Future>(client: Arc<dyn GcsClient>, f: F) -> F::Output {
+    SCOPED_CLIENT.scope(client, f).await
+}
+
+/// Run `f` with every single gcs call inside it bounded by `timeout`, see `TimeoutClient`.
+pub async fn with_request_timeout<F: Future>(timeout: Duration, f: F) -> F::Output {
+    with_client(Arc::new(TimeoutClient::new(current_client(), timeout)), f).await
+}
+
+/// Run `f` with the metadata of the objects it reads cached, see `MetadataCacheClient`.
+pub async fn with_metadata_cache<F: Future>(ttl: Duration, capacity: usize, f: F) -> F::Output {
+    let client = MetadataCacheClient::new(current_client(), ttl, capacity);
+    with_client(Arc::new(client), f).await
+}
+
+pub fn current_client() -> Arc<dyn GcsClient> {
+    SCOPED_CLIENT
+        .try_with(|client| client.clone())
+        .ok()
+        .or_else(crate::config::gcs_client)
+        .unwrap_or_else(|| DEFAULT_CLIENT.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use tokio::sync::Barrier;
+
+    #[tokio::test]
+    async fn generation_checks_race_with_each_other() {
+        let generation = AtomicI64::new(1);
+        let barrier = Barrier::new(2);
+        // both writers check the generation before either of them writes
+        let write = || {
+            create_if_at_generation(
+                async {
+                    let current = generation.load(Ordering::SeqCst);
+                    barrier.wait().await;
+                    Ok(current)
+                },
+                1,
+                || async { Ok(generation.fetch_add(1, Ordering::SeqCst) + 1) },
+            )
+        };
+        let (first, second) = futures::join!(write(), write());
+        assert_eq!(vec![2, 3], {
+            let mut written = vec![first.unwrap(), second.unwrap()];
+            written.sort();
+            written
+        });
+
+        // once the write is seen, the check fails
+        let stale = create_if_at_generation(async { Ok(3) }, 1, || async { Ok::<_, _>(()) }).await;
+        assert!(matches!(stale, Err(cloud_storage::Error::Google(_))));
+    }
+}
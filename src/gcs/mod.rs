@@ -1,23 +1,39 @@
+pub mod acl;
+pub mod client;
+mod json_api;
+pub mod reader;
+pub mod zip;
+
 use crate::compression::*;
+use crate::copy_backoff;
+use crate::ErrorKind;
 
+use crate::checksum::{matches_checksum, ChecksumAlgo};
 use crate::mime;
 use crate::mime::MimeType;
-use backoff::future::retry;
+use crate::writer::WriteOptions;
+use acl::AclBehavior;
+use backoff::backoff::Backoff;
 use backoff::{Error as BackoffError, ExponentialBackoff};
+use bytes::Bytes;
 use cloud_storage::bucket::{Location, MultiRegion};
 use cloud_storage::{
     Bucket, Error as CloudStorageError, ListRequest, NewBucket, Object,
     Reason as CloudStorageErrorReason,
 };
-use futures::future;
-use futures::stream::TryStreamExt;
+use futures::{future, StreamExt, TryStreamExt};
 use futures_util::future::TryFutureExt;
 use lazy_static::lazy_static;
 use log;
 use regex::Regex;
 use std::convert::Into;
 use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+use tokio::time::Instant;
 use url::Url;
 
 #[derive(Error, Debug)]
@@ -36,9 +52,157 @@ pub enum FileUtilGcsError {
 
     #[error("compression error: {0}")]
     CompressionError(#[from] CompressionError),
+
+    #[error("listing timed out: {0}")]
+    ListTimeout(String),
+
+    #[error("object not found: {0}")]
+    ObjectNotFound(String),
+
+    #[error("invalid seek: {0}")]
+    InvalidSeek(String),
+
+    #[error("unexpected end of object: {0}")]
+    UnexpectedEof(String),
+
+    #[error("invalid zip archive: {0}")]
+    InvalidZip(String),
+
+    #[error("invalid bucket location: {0}")]
+    InvalidLocation(String),
+
+    #[error("rename not verified, the source is kept: {0}")]
+    RenameNotVerified(String),
+
+    #[error("write error: {0}")]
+    IOError(#[from] std::io::Error),
 }
 pub type Result<T> = std::result::Result<T, FileUtilGcsError>;
 
+impl FileUtilGcsError {
+    /// The http status a service would answer with for this error, see
+    /// `FileUtilError::to_http_status`.
+    pub fn to_http_status(&self) -> u16 {
+        match self {
+            FileUtilGcsError::GcsInvalidBucketPathError(_)
+            | FileUtilGcsError::UrlParseError(_)
+            | FileUtilGcsError::InvalidGcsUrl(_)
+            | FileUtilGcsError::InvalidSeek(_)
+            | FileUtilGcsError::InvalidLocation(_) => 400,
+            FileUtilGcsError::ObjectNotFound(_) => 404,
+            FileUtilGcsError::StorageAccessError(e) => storage_error_status(e),
+            FileUtilGcsError::ListTimeout(_) => 504,
+            FileUtilGcsError::UnexpectedEof(_) | FileUtilGcsError::RenameNotVerified(_) => 502,
+            FileUtilGcsError::CompressionError(_) | FileUtilGcsError::InvalidZip(_) => 500,
+            FileUtilGcsError::IOError(e) => crate::io_error_status(e),
+        }
+    }
+
+    /// The class of this error, see `FileUtilError::kind`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FileUtilGcsError::GcsInvalidBucketPathError(_)
+            | FileUtilGcsError::UrlParseError(_)
+            | FileUtilGcsError::InvalidGcsUrl(_) => ErrorKind::InvalidPath,
+            FileUtilGcsError::ObjectNotFound(_) => ErrorKind::NotFound,
+            FileUtilGcsError::StorageAccessError(e) => storage_error_kind(e),
+            FileUtilGcsError::ListTimeout(_)
+            | FileUtilGcsError::UnexpectedEof(_)
+            | FileUtilGcsError::RenameNotVerified(_) => ErrorKind::Transient,
+            FileUtilGcsError::InvalidSeek(_)
+            | FileUtilGcsError::InvalidLocation(_)
+            | FileUtilGcsError::CompressionError(_)
+            | FileUtilGcsError::InvalidZip(_) => ErrorKind::Other,
+            FileUtilGcsError::IOError(e) => crate::io_error_kind(e),
+        }
+    }
+}
+
+fn storage_error_kind(e: &CloudStorageError) -> ErrorKind {
+    let error_response = match e {
+        CloudStorageError::Google(error_response) => error_response,
+        CloudStorageError::Reqwest(e) if e.is_builder() => return ErrorKind::InvalidPath,
+        CloudStorageError::Reqwest(_) => return ErrorKind::Transient,
+        _ => return ErrorKind::Other,
+    };
+    let has_reason = |reasons: &[CloudStorageErrorReason]| {
+        reasons
+            .iter()
+            .any(|reason| error_response.errors_has_reason(reason))
+    };
+    if has_reason(&[
+        CloudStorageErrorReason::NotFound,
+        CloudStorageErrorReason::Gone,
+    ]) {
+        ErrorKind::NotFound
+    } else if is_rate_limited(e) || has_reason(&[CloudStorageErrorReason::QuotaExceeded]) {
+        ErrorKind::RateLimited
+    } else if has_reason(&[
+        CloudStorageErrorReason::AuthError,
+        CloudStorageErrorReason::AuthenticationRequiredRequesterPays,
+        CloudStorageErrorReason::LockedDomainExpired,
+    ]) {
+        ErrorKind::Unauthorized
+    } else if has_reason(&[
+        CloudStorageErrorReason::AccountDisabled,
+        CloudStorageErrorReason::Forbidden,
+        CloudStorageErrorReason::InsufficientPermissions,
+        CloudStorageErrorReason::UserProjectAccessDenied,
+    ]) {
+        ErrorKind::PermissionDenied
+    } else if has_reason(&[
+        CloudStorageErrorReason::BackendError,
+        CloudStorageErrorReason::InternalError,
+        CloudStorageErrorReason::GatewayTimeout,
+        CloudStorageErrorReason::UploadBrokenConnection,
+    ]) {
+        ErrorKind::Transient
+    } else {
+        ErrorKind::Other
+    }
+}
+
+fn storage_error_status(e: &CloudStorageError) -> u16 {
+    let error_response = match e {
+        CloudStorageError::Google(error_response) => error_response,
+        CloudStorageError::Reqwest(e) if e.is_timeout() => return 504,
+        _ => return 502,
+    };
+    let has_reason = |reasons: &[CloudStorageErrorReason]| {
+        reasons
+            .iter()
+            .any(|reason| error_response.errors_has_reason(reason))
+    };
+    if has_reason(&[
+        CloudStorageErrorReason::NotFound,
+        CloudStorageErrorReason::Gone,
+    ]) {
+        404
+    } else if is_rate_limited(e) || has_reason(&[CloudStorageErrorReason::QuotaExceeded]) {
+        503
+    } else if has_reason(&[
+        CloudStorageErrorReason::AuthError,
+        CloudStorageErrorReason::AuthenticationRequiredRequesterPays,
+        CloudStorageErrorReason::LockedDomainExpired,
+        CloudStorageErrorReason::AccountDisabled,
+        CloudStorageErrorReason::Forbidden,
+        CloudStorageErrorReason::InsufficientPermissions,
+        CloudStorageErrorReason::UserProjectAccessDenied,
+    ]) {
+        403
+    } else if has_reason(&[CloudStorageErrorReason::Conflict]) {
+        409
+    } else if has_reason(&[CloudStorageErrorReason::ConditionNotMet]) {
+        412
+    } else if has_reason(&[CloudStorageErrorReason::RequestedRangeNotSatisfiable]) {
+        416
+    } else if has_reason(&[CloudStorageErrorReason::GatewayTimeout]) {
+        504
+    } else {
+        502
+    }
+}
+
 lazy_static! {
     static ref GCS_BUCKET_RE: Regex = Regex::new(r"gs://(?P<bucket>[^/]*)/?(?P<name>.*)").unwrap();
 }
@@ -127,18 +291,65 @@ impl GcsFile {
         Self::new_with_url(&url)
     }
 
+    /// Build from a bucket and an object name without going through a `gs://` url.
+    /// The same constraints as url parsing apply, and the name must not end with `/`.
+    pub fn from_bucket_name(bucket: impl Into<String>, name: impl Into<String>) -> Result<Self> {
+        let bucket = bucket.into();
+        let name = name.into();
+
+        if bucket.is_empty() || bucket.contains('/') {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "invalid bucket name: `{}`",
+                bucket
+            )));
+        }
+        if name.is_empty() || name.starts_with('/') || name.ends_with('/') {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "invalid object name: `{}`",
+                name
+            )));
+        }
+
+        Ok(Self {
+            bucket,
+            name,
+            trailing_slash: false,
+        })
+    }
+
+    /// List objects under this path. With `limit`, at most that many entries are returned
+    /// and no further pages are requested once it is reached.
     pub async fn list_objects_with_retry(
         &self,
         backoff: Option<ExponentialBackoff>,
+        limit: Option<usize>,
     ) -> Result<Vec<String>> {
-        retry(backoff.unwrap_or_default(), || async {
-            let name = if self.trailing_slash {
-                format!("{}/", self.name)
-            } else {
-                self.name.to_string()
-            };
-            let objects = match list_objects(&self.bucket, &name).await {
+        self.list_objects_with_timeout_with_retry(backoff, limit, None)
+            .await
+    }
+
+    /// `list_timeout` bounds the total time spent paginating, retries included.
+    /// When it is exceeded the listing fails with `FileUtilGcsError::ListTimeout`.
+    pub async fn list_objects_with_timeout_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+        limit: Option<usize>,
+        list_timeout: Option<Duration>,
+    ) -> Result<Vec<String>> {
+        let deadline = list_timeout.map(|list_timeout| Instant::now() + list_timeout);
+        retry(backoff, || async {
+            let objects = match list_objects_until(
+                &self.bucket,
+                &self.list_prefix(),
+                limit,
+                deadline,
+            )
+            .await
+            {
                 Ok(objects) => objects,
+                Err(e @ FileUtilGcsError::ListTimeout(_)) => {
+                    return Err(BackoffError::Permanent(e));
+                }
                 Err(e) => {
                     log::warn!("list object failed {}", e);
                     return Err(BackoffError::Transient(e));
@@ -147,26 +358,169 @@ impl GcsFile {
 
             Ok(objects
                 .into_iter()
-                .map(|obj| {
-                    let name = obj.name;
-                    let (name, trailing_slash) = if name.ends_with("/") {
-                        (name[0..name.len() - 1].to_string(), true)
-                    } else {
-                        (name, false)
-                    };
-
-                    Self {
-                        bucket: obj.bucket,
-                        trailing_slash,
-                        name,
-                    }
-                    .to_string()
-                })
+                .map(|obj| Self::from_object(obj).to_string())
                 .collect())
         })
         .await
     }
 
+    /// List the entries under this path taken as a directory, as sorted `gs://` urls: with
+    /// `recursive`, the whole tree under it, and with `include_directories`, the prefixes one
+    /// level deeper (every level with `recursive`), which end with `/`. Folder placeholders
+    /// are listed as directories, never as objects.
+    pub async fn list_directory_with_retry(
+        &self,
+        recursive: bool,
+        include_directories: bool,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Vec<String>> {
+        let dir_prefix = format!("{}/", self.name);
+        let (objects, prefixes) = retry(backoff, || async {
+            let listed = if recursive {
+                list_objects_until(&self.bucket, &dir_prefix, None, None)
+                    .await
+                    .map(|objects| {
+                        let names: Vec<String> =
+                            objects.into_iter().map(|object| object.name).collect();
+                        (names, vec![])
+                    })
+            } else {
+                list_level(&self.bucket, &dir_prefix).await.map(|level| {
+                    let names = level.objects.into_iter().map(|object| object.name);
+                    (names.collect(), level.prefixes)
+                })
+            };
+            listed.map_err(|e| {
+                log::warn!("list directory failed {}", e);
+                BackoffError::Transient(e)
+            })
+        })
+        .await?;
+
+        let mut names = std::collections::BTreeSet::<String>::new();
+        for name in objects {
+            if include_directories {
+                // every level down to the object is a directory, whether or not it has a placeholder
+                let relative = &name[dir_prefix.len()..];
+                for (i, _) in relative.match_indices('/') {
+                    names.insert(format!("{}{}/", dir_prefix, &relative[..i]));
+                }
+            }
+            if !name.ends_with('/') {
+                names.insert(name);
+            }
+        }
+        if include_directories {
+            names.extend(prefixes);
+        }
+        Ok(names
+            .into_iter()
+            .map(|name| format!("gs://{}/{}", self.bucket, name))
+            .collect())
+    }
+
+    /// List objects under this path, retrying each page on its own.
+    /// When a page still fails after retrying, the entries of the preceding pages are
+    /// returned together with the error instead of being discarded.
+    pub async fn list_objects_best_effort_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> (Vec<String>, Option<FileUtilGcsError>) {
+        let entries = self.list_objects_stream_with_retry(backoff);
+        futures::pin_mut!(entries);
+        let mut result = Vec::<String>::new();
+        loop {
+            match entries.try_next().await {
+                Ok(Some(entry)) => result.push(entry),
+                Ok(None) => return (result, None),
+                Err(e) => return (result, Some(e)),
+            }
+        }
+    }
+
+    /// List objects under this path as a stream. A page is requested only once the entries
+    /// of the previous one are consumed, and each page is retried on its own.
+    pub fn list_objects_stream_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> impl futures::Stream<Item = Result<String>> + Send + 'static {
+        self.list_object_info_stream_with_retry(backoff)
+            .map_ok(|object| GcsFile::from_object_name(&object.bucket, object.name).to_string())
+    }
+
+    /// Like `list_objects_stream_with_retry`, yielding the objects.
+    pub fn list_object_info_stream_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> impl futures::Stream<Item = Result<Object>> + Send + 'static {
+        let gcs_client = client::current_client();
+        let bucket = self.bucket.clone();
+        let request = Some(list_prefix_request(self.list_prefix()));
+        futures::stream::try_unfold(request, move |request| {
+            let gcs_client = gcs_client.clone();
+            let bucket = bucket.clone();
+            let backoff = copy_backoff(&backoff);
+            async move {
+                let mut request = match request {
+                    Some(request) => request,
+                    None => return Ok(None),
+                };
+                let page = retry(backoff, || async {
+                    gcs_client
+                        .list_objects_page(&bucket, request.clone())
+                        .await
+                        .map_err(|e| {
+                            log::warn!("list object page failed {}", e);
+                            BackoffError::Transient(FileUtilGcsError::from(e))
+                        })
+                })
+                .await?;
+
+                let objects: Vec<Result<Object>> = page.items.into_iter().map(Ok).collect();
+                let next = page.next_page_token.map(|token| {
+                    request.page_token = Some(token);
+                    request
+                });
+                Ok::<_, FileUtilGcsError>(Some((futures::stream::iter(objects), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Total size of the objects under this path. Pages are summed as they arrive, each
+    /// retried on its own, so the listing is never held in memory.
+    pub async fn total_size_with_retry(&self, backoff: Option<ExponentialBackoff>) -> Result<u64> {
+        self.list_object_info_stream_with_retry(backoff)
+            .try_fold(0u64, |total, object| async move { Ok(total + object.size) })
+            .await
+    }
+
+    fn list_prefix(&self) -> String {
+        if self.trailing_slash {
+            format!("{}/", self.name)
+        } else {
+            self.name.to_string()
+        }
+    }
+
+    fn from_object(obj: Object) -> Self {
+        Self::from_object_name(&obj.bucket, obj.name)
+    }
+
+    fn from_object_name(bucket: &str, name: String) -> Self {
+        let (name, trailing_slash) = if name.ends_with("/") {
+            (name[0..name.len() - 1].to_string(), true)
+        } else {
+            (name, false)
+        };
+
+        Self {
+            bucket: bucket.to_string(),
+            trailing_slash,
+            name,
+        }
+    }
+
     pub fn new_with_url(url: &Url) -> Result<Self> {
         let url_str = url.as_str();
 
@@ -193,7 +547,7 @@ impl GcsFile {
             )));
         }
 
-        retry(backoff.unwrap_or_default(), || async {
+        retry(backoff, || async {
             match object_exists(&self.bucket, &self.name).await {
                 Ok(v) => Ok(v),
                 Err(e) => {
@@ -210,9 +564,81 @@ impl GcsFile {
         .await
     }
 
+    /// Like `is_exists_with_retry`, but an error that would only be answered again, such as
+    /// a denied permission, is returned at once instead of retried.
+    pub async fn is_exists_strict_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<bool> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        retry(backoff, || async {
+            object_exists(&self.bucket, &self.name)
+                .await
+                .map_err(|e| match e.kind() {
+                    ErrorKind::Transient | ErrorKind::RateLimited => BackoffError::Transient(e),
+                    _ => BackoffError::Permanent(e),
+                })
+        })
+        .await
+    }
+
+    /// The generation of the object, or `None` when it does not exist. Only the metadata is
+    /// read, so this is cheap to call before an operation conditioned on the generation.
+    pub async fn current_generation(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<i64>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        let object = self.read_metadata(backoff).await?;
+        Ok(object.map(|object| object.generation))
+    }
+
+    /// The metadata of the object, or `None` when it does not exist.
+    pub async fn metadata_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<Object>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        self.read_metadata(backoff).await
+    }
+
+    /// Whether any object is named under this path followed by `/`, which is what makes it
+    /// a directory; gcs has no directories of its own.
+    pub async fn is_directory_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<bool> {
+        let prefix = format!("{}/", self.name);
+        retry(backoff, || async {
+            match list_objects_with_limit(&self.bucket, &prefix, Some(1)).await {
+                Ok(objects) => Ok(!objects.is_empty()),
+                Err(e) => Err(BackoffError::Transient(e)),
+            }
+        })
+        .await
+    }
+
     async fn download(bucket: &str, name: &str) -> Result<Option<Vec<u8>>> {
         if let Ok(true) = object_exists(bucket, name).await {
-            download_object(&bucket, &name).await.map(|body| Some(body))
+            download_object(bucket, name).await.map(Some)
         } else {
             Ok(None)
         }
@@ -223,6 +649,18 @@ impl GcsFile {
         backoff: Option<ExponentialBackoff>,
         decompression: Option<Compression>,
     ) -> Result<Option<Vec<u8>>> {
+        let sized = self
+            .download_sized_with_retry(backoff, decompression)
+            .await?;
+        Ok(sized.map(|sized| sized.contents))
+    }
+
+    /// Same as `download_with_retry`, also reporting the size of the object as stored.
+    pub async fn download_sized_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+        decompression: Option<Compression>,
+    ) -> Result<Option<SizedContents>> {
         if self.trailing_slash {
             return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
                 "object path must not be ends with `/` : {}",
@@ -230,7 +668,7 @@ impl GcsFile {
             )));
         }
 
-        let contents: Option<Vec<u8>> = retry(backoff.unwrap_or_default(), || async {
+        let contents: Option<Vec<u8>> = retry(backoff, || async {
             match GcsFile::download(&self.bucket, &self.name).await {
                 Ok(v) => Ok(v),
                 Err(e) => {
@@ -245,13 +683,163 @@ impl GcsFile {
             }
         })
         .await?;
-        let result = decompress_opt(contents, decompression)?;
+        let result = decompress_opt_sized(contents, decompression)?;
         Ok(result)
     }
 
+    /// Start a download and return the object as a stream of chunks, without buffering it.
+    /// Only starting the download is retried. Returns `None` when the object does not exist.
+    pub async fn download_stream_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<client::DownloadStream>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        let gcs_client = client::current_client();
+        retry(backoff, || async {
+            match gcs_client
+                .download_object_streamed(&self.bucket, &self.name)
+                .await
+            {
+                Ok(stream) => Ok(Some(stream)),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => {
+                    log::warn!(
+                        "streamed download from gcs failed. Retring. [{}/{}] error:{:?}",
+                        self.bucket,
+                        self.name,
+                        e
+                    );
+                    Err(BackoffError::Transient(e.into()))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Download `length` bytes (or up to the end with `None`) starting at `offset`.
+    /// Returns `None` if the object does not exist.
+    pub async fn download_range_with_retry(
+        &self,
+        offset: u64,
+        length: Option<u64>,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        retry(backoff, || async {
+            match client::current_client()
+                .download_object_range(&self.bucket, &self.name, offset, length)
+                .await
+            {
+                Ok(v) => Ok(Some(v)),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => {
+                    log::warn!(
+                        "range download from gcs failed. Retring. [{}/{}] error:{:?}",
+                        self.bucket,
+                        self.name,
+                        e
+                    );
+                    Err(BackoffError::Transient(e.into()))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Download the last `length` bytes of the object, or all of it when it is shorter.
+    /// The size is read from the metadata first.
+    pub async fn download_tail_with_retry(
+        &self,
+        length: u64,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        match self.read_metadata(copy_backoff(&backoff)).await? {
+            Some(object) => {
+                self.download_range_with_retry(object.size.saturating_sub(length), None, backoff)
+                    .await
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Write the object, then give it an ACL according to `acl_behavior`.
+    /// With `AclBehavior::Preserve` the ACL of the object being overwritten is read
+    /// beforehand and re-applied after the write.
+    pub async fn write_with_acl_with_retry(
+        &self,
+        body: impl Into<Bytes>,
+        mime_type: mime::MimeType,
+        backoff: Option<ExponentialBackoff>,
+        compression: Option<Compression>,
+        acl_behavior: AclBehavior,
+    ) -> Result<()> {
+        let gcs_client = client::current_client();
+        let preserved = match acl_behavior {
+            AclBehavior::Preserve => {
+                retry(copy_backoff(&backoff), || async {
+                    match gcs_client.read_object_acl(&self.bucket, &self.name).await {
+                        Ok(entries) => Ok(entries),
+                        Err(e) if is_not_found(&e) => Ok(vec![]),
+                        Err(e) => Err(BackoffError::Transient(FileUtilGcsError::from(e))),
+                    }
+                })
+                .await?
+            }
+            _ => vec![],
+        };
+
+        self.write_with_retry(body, mime_type, copy_backoff(&backoff), compression)
+            .await?;
+
+        match acl_behavior {
+            AclBehavior::BucketDefault => Ok(()),
+            AclBehavior::Preserve => {
+                for entry in preserved.iter() {
+                    retry(copy_backoff(&backoff), || async {
+                        gcs_client
+                            .add_object_acl(&self.bucket, &self.name, acl::copy_acl_entry(entry))
+                            .await
+                            .map_err(|e| BackoffError::Transient(FileUtilGcsError::from(e)))
+                    })
+                    .await?;
+                }
+                Ok(())
+            }
+            AclBehavior::Set(predefined) => {
+                retry(backoff, || async {
+                    gcs_client
+                        .set_predefined_acl(&self.bucket, &self.name, predefined)
+                        .await
+                        .map_err(|e| BackoffError::Transient(FileUtilGcsError::from(e)))
+                })
+                .await
+            }
+        }
+    }
+
+    /// Write `body` as the object. The body is shared, not copied, between the attempts.
     pub async fn write_with_retry(
         &self,
-        body: &[u8],
+        body: impl Into<Bytes>,
         mime_type: mime::MimeType,
         backoff: Option<ExponentialBackoff>,
         compression: Option<Compression>,
@@ -263,10 +851,181 @@ impl GcsFile {
             )));
         }
 
+        let body = compress_bytes(body.into(), compression)?;
+
+        retry(backoff, || async {
+            create_object(&self.bucket, &self.name, body.clone(), mime_type.clone())
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    log::warn!("gcs write error {:?}", e);
+                    BackoffError::Transient(e)
+                })
+        })
+        .await
+    }
+
+    /// Write `body` as the object as `options` say. `if_not_exists` and `if_generation_match`
+    /// are checked with `GcsClient::create_object_if_generation_match`, so the object is left
+    /// as it is when another writer already changed it. The default client checks with a read
+    /// before the upload, which a writer racing this one can still slip between.
+    /// `if_match` applies to web urls only and is ignored.
+    /// The cache control and custom metadata are set with a second request once the
+    /// contents are written.
+    /// Returns whether the object was written, i.e. `false` when a precondition failed.
+    pub async fn write_with_options_with_retry(
+        &self,
+        body: impl Into<Bytes>,
+        options: &WriteOptions,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<bool> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+        let generation = if options.if_not_exists {
+            Some(0)
+        } else {
+            options.if_generation_match
+        };
+
+        let body = compress_bytes(body.into(), options.compression.clone())?;
+        let mime_type = options.mime_type_for(&self.name);
+        let created = retry(copy_backoff(&backoff), || async {
+            let created = match generation {
+                Some(generation) => {
+                    create_object_if_generation_match(
+                        &self.bucket,
+                        &self.name,
+                        body.clone(),
+                        mime_type.clone(),
+                        generation,
+                    )
+                    .await
+                }
+                None => {
+                    create_object(&self.bucket, &self.name, body.clone(), mime_type.clone()).await
+                }
+            };
+            created.map_err(|e| match &e {
+                FileUtilGcsError::StorageAccessError(cause) if is_condition_not_met(cause) => {
+                    BackoffError::Permanent(e)
+                }
+                _ => {
+                    log::warn!("gcs write error {:?}", e);
+                    BackoffError::Transient(e)
+                }
+            })
+        })
+        .await;
+        let mut object = match created {
+            Err(FileUtilGcsError::StorageAccessError(e)) if is_condition_not_met(&e) => {
+                return Ok(false)
+            }
+            created => created?,
+        };
+        if options.cache_control.is_none() && options.metadata.is_empty() {
+            return Ok(true);
+        }
+
+        object.cache_control = options.cache_control.clone();
+        object.metadata = Some(options.metadata.clone().into_iter().collect());
+        retry(backoff, || async {
+            client::current_client()
+                .update_object(&object)
+                .await
+                .map(|_| ())
+                .map_err(|e| BackoffError::Transient(FileUtilGcsError::from(e)))
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Append `body` to the object, creating it as `mime_type` when it does not exist.
+    /// gcs objects cannot be appended to, so `body` is uploaded as a temporary object beside
+    /// this one, the two are composed into this one, and the temporary object is deleted.
+    /// A composite object holds at most 1024 components, one more per append, and appends
+    /// made concurrently to the same object can lose one another.
+    pub async fn append_with_retry(
+        &self,
+        body: impl Into<Bytes>,
+        mime_type: mime::MimeType,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+        let body = body.into();
+        if self.read_metadata(copy_backoff(&backoff)).await?.is_none() {
+            return self.write_with_retry(body, mime_type, backoff, None).await;
+        }
+
+        let part = format!("{}.{:016x}.append", self.name, rand::random::<u64>());
+        retry(copy_backoff(&backoff), || async {
+            create_object(&self.bucket, &part, body.clone(), mime_type.clone())
+                .await
+                .map(|_| ())
+                .map_err(BackoffError::Transient)
+        })
+        .await?;
+
+        let sources = vec![self.name.clone(), part.clone()];
+        let composed = retry(copy_backoff(&backoff), || async {
+            client::current_client()
+                .compose_objects(&self.bucket, &sources, &self.name)
+                .await
+                .map(|_| ())
+                .map_err(|e| BackoffError::Transient(FileUtilGcsError::from(e)))
+        })
+        .await;
+        let deleted = retry(backoff, || async {
+            delete_object(&self.bucket, &part)
+                .await
+                .map_err(BackoffError::Transient)
+        })
+        .await;
+        composed?;
+        if let Err(e) = deleted {
+            log::warn!("failed to delete gs://{}/{} {}", self.bucket, part, e);
+        }
+        Ok(())
+    }
+
+    /// Write the object unless it already holds `body` (after compression), judged by the
+    /// md5 in its metadata, or crc32c for composite objects that have none, so the existing
+    /// contents are never downloaded. Returns whether the object was written.
+    pub async fn write_if_changed_with_retry(
+        &self,
+        body: &[u8],
+        mime_type: mime::MimeType,
+        backoff: Option<ExponentialBackoff>,
+        compression: Option<Compression>,
+    ) -> Result<bool> {
         let body = compress_opt(body, compression)?;
 
-        retry(backoff.unwrap_or_default(), || async {
-            create_object(&self.bucket, &self.name, body.to_vec(), mime_type.clone())
+        if !self.trailing_slash {
+            let existing = self.read_metadata(copy_backoff(&backoff)).await?;
+            if existing.is_some_and(|object| has_same_contents(&object, &body)) {
+                return Ok(false);
+            }
+        }
+
+        self.write_with_retry(body, mime_type, backoff, None)
+            .await?;
+        Ok(true)
+    }
+
+    /// Write the zero-byte `name/` object that gcs backed filesystems and the console
+    /// show as an (empty) folder. This is the one write allowed on a path ending with `/`.
+    pub async fn create_directory_marker(&self, backoff: Option<ExponentialBackoff>) -> Result<()> {
+        let marker = format!("{}/", self.name);
+        retry(backoff, || async {
+            create_object(&self.bucket, &marker, Bytes::new(), MimeType::OctetStream)
                 .await
                 .map(|_| ())
                 .map_err(|e| {
@@ -274,11 +1033,253 @@ impl GcsFile {
                     BackoffError::Transient(e)
                 })
         })
-        .await
+        .await
+    }
+
+    /// Whether the object was composed, and from how many components.
+    /// Returns `None` when the object does not exist.
+    ///
+    /// There is no `download_components` to go with it: gcs records only the count, not
+    /// which objects were composed, and the source objects may have been deleted or
+    /// overwritten since. A composed object can only be downloaded as a whole, with
+    /// `download_with_retry`.
+    pub async fn list_components(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Option<Composition>> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        let object = self.read_metadata(backoff).await?;
+        Ok(object.map(|object| match object.component_count {
+            Some(component_count) if component_count > 1 => {
+                Composition::Composed { component_count }
+            }
+            _ => Composition::Simple,
+        }))
+    }
+
+    /// Restore the soft-deleted `generation` of this object, as listed by `list_soft_deleted`.
+    /// The restored object is live again under a new generation.
+    pub async fn restore(
+        &self,
+        generation: i64,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<ObjectInfo> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        let gcs_client = client::current_client();
+        let object = retry(backoff, || async {
+            match gcs_client
+                .restore_object(&self.bucket, &self.name, generation)
+                .await
+            {
+                Ok(object) => Ok(object),
+                Err(e) if is_not_found(&e) => Err(BackoffError::Permanent(
+                    FileUtilGcsError::ObjectNotFound(format!("{}#{}", self, generation)),
+                )),
+                Err(e) => Err(BackoffError::Transient(FileUtilGcsError::from(e))),
+            }
+        })
+        .await?;
+        Ok(ObjectInfo::from(object))
+    }
+
+    /// Copy the object to `destination` within gcs, without the contents passing through
+    /// this process. Returns `false` when the object does not exist.
+    pub async fn copy_to(
+        &self,
+        destination: &GcsFile,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<bool> {
+        if self.trailing_slash || destination.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {} -> {}",
+                self.name, destination.name
+            )));
+        }
+
+        if self.read_metadata(copy_backoff(&backoff)).await?.is_none() {
+            return Ok(false);
+        }
+        self.rewrite_to(destination, backoff).await?;
+        Ok(true)
+    }
+
+    async fn rewrite_to(
+        &self,
+        destination: &GcsFile,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        let gcs_client = client::current_client();
+        retry(backoff, || async {
+            gcs_client
+                .rewrite_object(
+                    &self.bucket,
+                    &self.name,
+                    &destination.bucket,
+                    &destination.name,
+                )
+                .await
+                .map_err(|e| {
+                    log::warn!("gcs rewrite error {:?}", e);
+                    BackoffError::Transient(FileUtilGcsError::from(e))
+                })
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Move the object to `destination` by rewriting it there and then deleting it.
+    /// The source is deleted only after the checksum of the destination (md5, or crc32c for
+    /// composite objects) is confirmed to match, so an interrupted rewrite never loses data;
+    /// otherwise this fails with `FileUtilGcsError::RenameNotVerified`, leaving both objects.
+    pub async fn rename_to(
+        &self,
+        destination: &GcsFile,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        if self.trailing_slash || destination.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {} -> {}",
+                self.name, destination.name
+            )));
+        }
+
+        let source = self
+            .read_metadata(copy_backoff(&backoff))
+            .await?
+            .ok_or_else(|| FileUtilGcsError::ObjectNotFound(self.to_string()))?;
+        self.rewrite_to(destination, copy_backoff(&backoff)).await?;
+
+        let rewritten = destination.read_metadata(copy_backoff(&backoff)).await?;
+        let verified = rewritten.is_some_and(|rewritten| match source.md5_hash.as_ref() {
+            Some(md5_hash) => rewritten.md5_hash.as_ref() == Some(md5_hash),
+            None => rewritten.crc32c == source.crc32c,
+        });
+        if !verified {
+            return Err(FileUtilGcsError::RenameNotVerified(format!(
+                "{} -> {}",
+                self, destination
+            )));
+        }
+        self.delete_with_retry(backoff).await
+    }
+
+    async fn read_metadata(&self, backoff: Option<ExponentialBackoff>) -> Result<Option<Object>> {
+        let gcs_client = client::current_client();
+        retry(backoff, || async {
+            match gcs_client.read_object(&self.bucket, &self.name).await {
+                Ok(object) => Ok(Some(object)),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(BackoffError::Transient(FileUtilGcsError::from(e))),
+            }
+        })
+        .await
+    }
+
+    /// Upload the chunks of `body` as this object without buffering the whole payload.
+    /// The stream can be consumed only once, so a failed upload is not retried.
+    pub async fn upload_stream(
+        &self,
+        body: client::ByteStream,
+        mime_type: mime::MimeType,
+    ) -> Result<()> {
+        if self.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                self.name
+            )));
+        }
+
+        log::debug!("Class A Object::create_streamed() in upload_stream()");
+        client::current_client()
+            .create_object_streamed(&self.bucket, body, None, &self.name, mime_type.into())
+            .await?;
+        Ok(())
+    }
+
+    /// Delete the objects under this path last updated before `cutoff`, deleting up to
+    /// `concurrency` at a time. Folder placeholders (names ending with `/`) are kept.
+    /// Returns how many objects were deleted.
+    pub async fn delete_updated_before(
+        &self,
+        cutoff: SystemTime,
+        backoff: Option<ExponentialBackoff>,
+        concurrency: usize,
+    ) -> Result<usize> {
+        let objects = retry(copy_backoff(&backoff), || async {
+            list_objects(&self.bucket, &self.list_prefix())
+                .await
+                .map_err(BackoffError::Transient)
+        })
+        .await?;
+        let stale: Vec<GcsFile> = objects
+            .into_iter()
+            .filter(|object| !object.name.ends_with('/'))
+            .filter(|object| SystemTime::from(object.updated) < cutoff)
+            .map(Self::from_object)
+            .collect();
+
+        futures::stream::iter(stale.iter())
+            .map(|file| file.delete_with_retry(copy_backoff(&backoff)))
+            .buffer_unordered(concurrency.max(1))
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok(stale.len())
+    }
+
+    /// Delete this object, if there is one, and every object under this path taken as a
+    /// directory, folder placeholders included, up to `concurrency` at a time.
+    /// Returns how many objects were deleted.
+    pub async fn delete_recursive_with_retry(
+        &self,
+        backoff: Option<ExponentialBackoff>,
+        concurrency: usize,
+    ) -> Result<usize> {
+        let dir_prefix = format!("{}/", self.name);
+        let objects = retry(copy_backoff(&backoff), || async {
+            list_objects(&self.bucket, &dir_prefix)
+                .await
+                .map_err(BackoffError::Transient)
+        })
+        .await?;
+        let mut names: Vec<String> = objects.into_iter().map(|object| object.name).collect();
+        if !self.trailing_slash && self.is_exists_with_retry(copy_backoff(&backoff)).await? {
+            names.push(self.name.clone());
+        }
+
+        let gcs_client = client::current_client();
+        futures::stream::iter(names.iter())
+            .map(|name| {
+                let gcs_client = gcs_client.clone();
+                retry(copy_backoff(&backoff), move || {
+                    let gcs_client = gcs_client.clone();
+                    async move {
+                        gcs_client
+                            .delete_object(&self.bucket, name)
+                            .await
+                            .map_err(|e| BackoffError::Transient(FileUtilGcsError::from(e)))
+                    }
+                })
+            })
+            .buffer_unordered(concurrency.max(1))
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok(names.len())
     }
 
     pub async fn delete_with_retry(&self, backoff: Option<ExponentialBackoff>) -> Result<()> {
-        retry(backoff.unwrap_or_default(), || async {
+        retry(backoff, || async {
             delete_object(&self.bucket, &self.name)
                 .await
                 .map(|_| ())
@@ -295,6 +1296,196 @@ impl fmt::Display for GcsFile {
     }
 }
 
+/// The `algo` checksum gcs keeps in the metadata of `object`: md5, which composite objects
+/// have none of, and crc32c. `None` for the other algorithms.
+pub fn object_checksum(object: &Object, algo: ChecksumAlgo) -> Option<Vec<u8>> {
+    let encoded = match algo {
+        ChecksumAlgo::Md5 => object.md5_hash.as_ref()?,
+        ChecksumAlgo::Crc32c => &object.crc32c,
+        ChecksumAlgo::Sha256 => return None,
+    };
+    base64::decode(encoded).ok()
+}
+
+fn has_same_contents(object: &Object, body: &[u8]) -> bool {
+    match object.md5_hash.as_ref() {
+        Some(md5_hash) => matches_checksum(&ChecksumAlgo::Md5.digest(body), md5_hash),
+        None => matches_checksum(&ChecksumAlgo::Crc32c.digest(body), &object.crc32c),
+    }
+}
+
+fn is_not_found(e: &CloudStorageError) -> bool {
+    match e {
+        CloudStorageError::Google(error_response) => {
+            error_response.errors_has_reason(&CloudStorageErrorReason::NotFound)
+        }
+        _ => false,
+    }
+}
+
+/// Rate-limit responses (429) carry one of these reasons.
+fn is_rate_limited(e: &CloudStorageError) -> bool {
+    match e {
+        CloudStorageError::Google(error_response) => [
+            CloudStorageErrorReason::RateLimitExceeded,
+            CloudStorageErrorReason::UserRateLimitExceeded,
+            CloudStorageErrorReason::UsageLimitsRateLimitExceeded,
+        ]
+        .iter()
+        .any(|reason| error_response.errors_has_reason(reason)),
+        _ => false,
+    }
+}
+
+fn is_condition_not_met(e: &CloudStorageError) -> bool {
+    match e {
+        CloudStorageError::Google(error_response) => {
+            error_response.errors_has_reason(&CloudStorageErrorReason::ConditionNotMet)
+        }
+        _ => false,
+    }
+}
+
+/// How many times the initial interval the first retry after a rate-limit response waits.
+const RATE_LIMIT_INTERVAL_FACTOR: u32 = 4;
+
+/// `ExponentialBackoff` that waits longer after rate-limit responses: at least
+/// `RATE_LIMIT_INTERVAL_FACTOR` times the initial interval, growing by the multiplier with
+/// every consecutive rate-limit response, as gcs asks clients to back off from a hot key.
+/// Also reports every retry to the hook of `with_retry_hook`, if any.
+struct RateLimitBackoff {
+    inner: ExponentialBackoff,
+    rate_limited: Arc<AtomicBool>,
+    rate_limit_interval: Option<Duration>,
+    hook: Option<RetryHook>,
+    attempts: usize,
+}
+
+impl RateLimitBackoff {
+    fn new(inner: ExponentialBackoff, rate_limited: Arc<AtomicBool>) -> Self {
+        Self {
+            inner,
+            rate_limited,
+            rate_limit_interval: None,
+            hook: RETRY_HOOK.try_with(|hook| hook.clone()).ok(),
+            attempts: 0,
+        }
+    }
+
+    fn interval(&mut self) -> Option<Duration> {
+        let interval = self.inner.next_backoff()?;
+        if !self.rate_limited.swap(false, Ordering::SeqCst) {
+            self.rate_limit_interval = None;
+            return Some(interval);
+        }
+        let max_interval = self.inner.max_interval * RATE_LIMIT_INTERVAL_FACTOR;
+        let rate_limit_interval = match self.rate_limit_interval {
+            None => self.inner.initial_interval * RATE_LIMIT_INTERVAL_FACTOR,
+            Some(previous) => previous.mul_f64(self.inner.multiplier).min(max_interval),
+        };
+        self.rate_limit_interval = Some(rate_limit_interval);
+        Some(interval.max(rate_limit_interval))
+    }
+}
+
+impl Backoff for RateLimitBackoff {
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.rate_limit_interval = None;
+        self.attempts = 0;
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        let next_delay = self.interval()?;
+        self.attempts += 1;
+        if let Some(hook) = self.hook.as_ref() {
+            let elapsed = self.inner.get_elapsed_time();
+            let state = RetryState {
+                attempts: self.attempts,
+                elapsed,
+                remaining: self
+                    .inner
+                    .max_elapsed_time
+                    .map(|max_elapsed_time| max_elapsed_time.saturating_sub(elapsed)),
+                next_delay,
+            };
+            if !hook(&state) {
+                return None;
+            }
+        }
+        Some(next_delay)
+    }
+}
+
+/// Where a retried gcs operation stands when one of its attempts has failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryState {
+    /// Failed attempts so far, the one just failed included.
+    pub attempts: usize,
+    /// Time since the first attempt started.
+    pub elapsed: Duration,
+    /// What is left of the `max_elapsed_time` of the backoff, `None` when it is unbounded or
+    /// a `RetryPolicy` retries instead.
+    pub remaining: Option<Duration>,
+    /// How long the backoff waits before the next attempt.
+    pub next_delay: Duration,
+}
+
+/// Called before every retry; returning `false` gives up, failing with the last error.
+pub type RetryHook = Arc<dyn Fn(&RetryState) -> bool + Send + Sync>;
+
+tokio::task_local! {
+    static RETRY_HOOK: RetryHook;
+}
+
+/// Run `f` with `hook` told about every retry of the gcs operations inside it, e.g. to log
+/// projections or to abort once the remaining budget cannot cover what is left to do.
+/// Like `client::with_client`, the hook does not reach `tokio::spawn`ed tasks.
+pub async fn with_retry_hook<F: Future>(hook: RetryHook, f: F) -> F::Output {
+    RETRY_HOOK.scope(hook, f).await
+}
+
+/// `backoff::future::retry` that backs off further after rate-limit responses, or, when
+/// `backoff` is `None` and the config has a `RetryPolicy`, retries as the policy says.
+async fn retry<I, Fn, Fut>(backoff: Option<ExponentialBackoff>, mut operation: Fn) -> Result<I>
+where
+    Fn: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<I, BackoffError<FileUtilGcsError>>>,
+{
+    if backoff.is_none() {
+        if let Some(policy) = crate::config::retry_policy() {
+            let hook = RETRY_HOOK.try_with(|hook| hook.clone()).ok();
+            let on_retry = |attempts, elapsed, next_delay| match hook.as_ref() {
+                Some(hook) => hook(&RetryState {
+                    attempts,
+                    elapsed,
+                    remaining: None,
+                    next_delay,
+                }),
+                None => true,
+            };
+            return crate::retry::retry_with_policy(policy, operation, on_retry).await;
+        }
+    }
+    let rate_limited = Arc::new(AtomicBool::new(false));
+    let backoff = RateLimitBackoff::new(
+        crate::config::backoff_or_default(backoff),
+        rate_limited.clone(),
+    );
+    backoff::future::retry(backoff, move || {
+        let attempt = operation();
+        let rate_limited = rate_limited.clone();
+        async move {
+            let result = attempt.await;
+            if let Err(BackoffError::Transient(FileUtilGcsError::StorageAccessError(e))) = &result {
+                rate_limited.store(is_rate_limited(e), Ordering::SeqCst);
+            }
+            result
+        }
+    })
+    .await
+}
+
 pub async fn object_exists(bucket: &str, name: &str) -> Result<bool> {
     if name.ends_with("/") {
         return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
@@ -304,7 +1495,7 @@ pub async fn object_exists(bucket: &str, name: &str) -> Result<bool> {
     }
 
     log::debug!("Class B Object::read() in object_exists() ");
-    let result = Object::read(bucket, name).await;
+    let result = client::current_client().read_object(bucket, name).await;
 
     match result {
         Ok(_) => Ok(true),
@@ -325,48 +1516,48 @@ pub async fn object_exists(bucket: &str, name: &str) -> Result<bool> {
 
 fn list_prefix_request(prefix: String) -> ListRequest {
     ListRequest {
-        /// When specified, allows the `list` to operate like a directory listing by splitting the
-        /// object location on this delimiter.
+        // When specified, allows the `list` to operate like a directory listing by splitting the
+        // object location on this delimiter.
         delimiter: None,
 
-        /// Filter results to objects whose names are lexicographically before `end_offset`.
-        /// If `start_offset` is also set, the objects listed have names between `start_offset`
-        /// (inclusive) and `end_offset` (exclusive).
+        // Filter results to objects whose names are lexicographically before `end_offset`.
+        // If `start_offset` is also set, the objects listed have names between `start_offset`
+        // (inclusive) and `end_offset` (exclusive).
         end_offset: None,
 
-        /// If true, objects that end in exactly one instance of `delimiter` have their metadata
-        /// included in `items` in addition to the relevant part of the object name appearing in
-        /// `prefixes`.
+        // If true, objects that end in exactly one instance of `delimiter` have their metadata
+        // included in `items` in addition to the relevant part of the object name appearing in
+        // `prefixes`.
         include_trailing_delimiter: None,
 
-        /// Maximum combined number of entries in `items` and `prefixes` to return in a single
-        /// page of responses. Because duplicate entries in `prefixes` are omitted, fewer total
-        /// results may be returned than requested. The service uses this parameter or 1,000
-        /// items, whichever is smaller.
+        // Maximum combined number of entries in `items` and `prefixes` to return in a single
+        // page of responses. Because duplicate entries in `prefixes` are omitted, fewer total
+        // results may be returned than requested. The service uses this parameter or 1,000
+        // items, whichever is smaller.
         max_results: None,
 
-        /// A previously-returned page token representing part of the larger set of results to view.
-        /// The `page_token` is an encoded field that marks the name and generation of the last object
-        /// in the returned list. In a subsequent request using the `page_token`, items that come after
-        /// the `page_token` are shown (up to `max_results`).
-        ///
-        /// If the page token is provided, all objects starting at that page token are queried
+        // A previously-returned page token representing part of the larger set of results to view.
+        // The `page_token` is an encoded field that marks the name and generation of the last object
+        // in the returned list. In a subsequent request using the `page_token`, items that come after
+        // the `page_token` are shown (up to `max_results`).
+        //
+        // If the page token is provided, all objects starting at that page token are queried
         page_token: None,
 
-        /// Filter results to include only objects whose names begin with this prefix.
+        // Filter results to include only objects whose names begin with this prefix.
         prefix: Some(prefix),
 
-        /// Set of properties to return. Defaults to `NoAcl`.
+        // Set of properties to return. Defaults to `NoAcl`.
         projection: None,
 
-        /// Filter results to objects whose names are lexicographically equal to or after
-        /// `start_offset`. If `end_offset` is also set, the objects listed have names between
-        /// `start_offset` (inclusive) and `end_offset` (exclusive).
+        // Filter results to objects whose names are lexicographically equal to or after
+        // `start_offset`. If `end_offset` is also set, the objects listed have names between
+        // `start_offset` (inclusive) and `end_offset` (exclusive).
         start_offset: None,
 
-        /// If true, lists all versions of an object as distinct results in order of increasing
-        /// generation number. The default value for versions is false. For more information, see
-        /// Object Versioning.
+        // If true, lists all versions of an object as distinct results in order of increasing
+        // generation number. The default value for versions is false. For more information, see
+        // Object Versioning.
         versions: None,
     }
 }
@@ -380,33 +1571,189 @@ pub async fn find_object(bucket: &str, name: &str) -> Result<Option<Object>> {
     }
 
     log::debug!("Class A Object::list() in find_object() ... that  trying reduing..");
-    //TODO(tacogips) it's unsafficient to use `await` for performance
-    let object_chunks = Object::list(bucket, list_prefix_request(name.to_string()))
-        .and_then(|objs_stream| objs_stream.try_collect::<Vec<_>>())
-        .await?;
-    for each_objs in object_chunks.into_iter() {
-        let found = each_objs
+    let client = client::current_client();
+    let mut request = list_prefix_request(name.to_string());
+    loop {
+        let page = client.list_objects_page(bucket, request.clone()).await?;
+        let found = page
             .items
             .into_iter()
             .find(|each_obj| each_obj.name == name);
         if found.is_some() {
             return Ok(found);
         }
+        match page.next_page_token {
+            Some(token) => request.page_token = Some(token),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// How an object was assembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Composition {
+    /// Uploaded as a whole; the object is its only component.
+    Simple,
+    /// Composed from `component_count` objects. Gcs does not record which objects they were.
+    Composed { component_count: i32 },
+}
+
+impl Composition {
+    pub fn component_count(&self) -> i32 {
+        match self {
+            Composition::Simple => 1,
+            Composition::Composed { component_count } => *component_count,
+        }
+    }
+}
+
+/// The metadata of a listed object that callers usually need.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    pub generation: i64,
+    pub content_type: Option<String>,
+}
+
+impl From<Object> for ObjectInfo {
+    fn from(object: Object) -> Self {
+        Self {
+            name: object.name,
+            size: object.size,
+            generation: object.generation,
+            content_type: object.content_type,
+        }
+    }
+}
+
+/// A soft-deleted object, which can be restored until its retention ends.
+/// A type of its own so that it is not mistaken for a live object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoftDeletedObject {
+    pub name: String,
+    /// The generation to pass to `GcsFile::restore`.
+    pub generation: i64,
+    pub size: u64,
+}
+
+/// One level of a `/` separated hierarchy: the objects directly under a prefix and
+/// the prefixes (folders, ending with `/`) one level deeper.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ListResult {
+    pub objects: Vec<ObjectInfo>,
+    pub prefixes: Vec<String>,
+}
+
+/// List one level under `prefix`, which should end with `/` unless it is empty.
+pub async fn list_level(bucket: &str, prefix: &str) -> Result<ListResult> {
+    let client = client::current_client();
+    let mut request = ListRequest {
+        delimiter: Some("/".to_string()),
+        ..list_prefix_request(prefix.to_string())
+    };
+    let mut result = ListResult::default();
+    loop {
+        let page = client.list_objects_page(bucket, request.clone()).await?;
+        result
+            .objects
+            .extend(page.items.into_iter().map(ObjectInfo::from));
+        result.prefixes.extend(page.prefixes);
+        match page.next_page_token {
+            Some(token) => request.page_token = Some(token),
+            None => return Ok(result),
+        }
+    }
+}
+
+/// List the soft-deleted objects whose names start with `prefix`, in a bucket with
+/// soft delete enabled. Live objects are not included.
+pub async fn list_soft_deleted(bucket: &str, prefix: &str) -> Result<Vec<SoftDeletedObject>> {
+    let client = client::current_client();
+    let mut page_token = None;
+    let mut result = Vec::<SoftDeletedObject>::new();
+    loop {
+        let page = client
+            .list_soft_deleted_page(bucket, prefix, page_token)
+            .await?;
+        result.extend(page.items.into_iter().map(|object| SoftDeletedObject {
+            name: object.name,
+            generation: object.generation,
+            size: object.size,
+        }));
+        match page.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => return Ok(result),
+        }
     }
-    Ok(None)
 }
 
 pub async fn list_objects(bucket: &str, name: &str) -> Result<Vec<Object>> {
-    //TODO(tacogips) it's unsafficient to use `await` for performance
+    list_objects_with_limit(bucket, name, None).await
+}
 
-    log::debug!("Class A Object::list() in list_object()");
-    let object_chunks = Object::list(bucket, list_prefix_request(name.to_string()))
-        .and_then(|objs_stream| objs_stream.try_collect::<Vec<_>>())
-        .await?;
+/// List objects whose names start with `name`, requesting pages until `limit` is reached.
+pub async fn list_objects_with_limit(
+    bucket: &str,
+    name: &str,
+    limit: Option<usize>,
+) -> Result<Vec<Object>> {
+    list_objects_until(bucket, name, limit, None).await
+}
+
+/// Like `list_objects_with_limit`, failing with `FileUtilGcsError::ListTimeout` when
+/// paginating takes longer than `list_timeout`.
+pub async fn list_objects_with_timeout(
+    bucket: &str,
+    name: &str,
+    limit: Option<usize>,
+    list_timeout: Option<Duration>,
+) -> Result<Vec<Object>> {
+    let deadline = list_timeout.map(|list_timeout| Instant::now() + list_timeout);
+    list_objects_until(bucket, name, limit, deadline).await
+}
 
+async fn list_objects_until(
+    bucket: &str,
+    name: &str,
+    limit: Option<usize>,
+    deadline: Option<Instant>,
+) -> Result<Vec<Object>> {
+    log::debug!("Class A Object::list() in list_object()");
+    let client = client::current_client();
+    let mut request = list_prefix_request(name.to_string());
     let mut result = Vec::<Object>::new();
-    for mut each_objs_list in object_chunks.into_iter() {
-        result.append(&mut each_objs_list.items);
+    loop {
+        if let Some(limit) = limit {
+            if result.len() >= limit {
+                result.truncate(limit);
+                return Ok(result);
+            }
+            request.max_results = Some(limit - result.len());
+        }
+
+        let page = client.list_objects_page(bucket, request.clone());
+        let mut page = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, page)
+                .await
+                .map_err(|_| {
+                    FileUtilGcsError::ListTimeout(format!(
+                        "gs://{}/{} after listing {} objects",
+                        bucket,
+                        name,
+                        result.len()
+                    ))
+                })??,
+            None => page.await?,
+        };
+        result.append(&mut page.items);
+        match page.next_page_token {
+            Some(token) => request.page_token = Some(token),
+            None => break,
+        }
+    }
+    if let Some(limit) = limit {
+        result.truncate(limit);
     }
     Ok(result)
 }
@@ -419,18 +1766,37 @@ pub async fn download_object(bucket: &str, name: &str) -> Result<Vec<u8>> {
         )));
     }
 
-    let result = Object::download(bucket, name).await?;
+    let result = client::current_client()
+        .download_object(bucket, name)
+        .await?;
     Ok(result)
 }
 
 pub async fn create_object(
     bucket: &str,
     path: &str,
-    body: Vec<u8>,
+    body: impl Into<Bytes>,
     mime_type: MimeType,
 ) -> Result<Object> {
     log::debug!("Class A Object::create() in create_object()");
-    let object = Object::create(bucket, body, path, mime_type.into()).await?;
+    let object = client::current_client()
+        .create_object(bucket, body.into(), path, mime_type.into())
+        .await?;
+    Ok(object)
+}
+
+/// `create_object` only while the object is at `generation`, 0 being that it does not exist.
+pub async fn create_object_if_generation_match(
+    bucket: &str,
+    path: &str,
+    body: impl Into<Bytes>,
+    mime_type: MimeType,
+    generation: i64,
+) -> Result<Object> {
+    log::debug!("Class A Object::create() in create_object_if_generation_match()");
+    let object = client::current_client()
+        .create_object_if_generation_match(bucket, body.into(), path, mime_type.into(), generation)
+        .await?;
     Ok(object)
 }
 
@@ -442,14 +1808,52 @@ pub async fn delete_object(bucket: &str, path: &str) -> Result<()> {
         )));
     }
 
-    Object::delete(bucket, path).await?;
+    client::current_client().delete_object(bucket, path).await?;
     Ok(())
 }
 
+/// Write the contents of `files` to `writer` one after another, in the order given, while
+/// downloading up to `concurrency` of them at once. At most `concurrency` objects are held
+/// in memory waiting for their turn. Returns the number of bytes written.
+pub async fn merge_objects_ordered<W: std::io::Write>(
+    files: &[GcsFile],
+    concurrency: usize,
+    writer: &mut W,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<u64> {
+    let downloads = futures::stream::iter(files)
+        .map(|file| {
+            let backoff = copy_backoff(&backoff);
+            async move {
+                file.download_with_retry(backoff, None)
+                    .await?
+                    .ok_or_else(|| FileUtilGcsError::ObjectNotFound(file.to_string()))
+            }
+        })
+        .buffered(concurrency.max(1));
+    futures::pin_mut!(downloads);
+
+    let mut written = 0u64;
+    while let Some(contents) = downloads.try_next().await? {
+        writer.write_all(&contents)?;
+        written += contents.len() as u64;
+    }
+    Ok(written)
+}
+
 pub async fn create_bucket(bucket: &str) -> Result<Bucket> {
+    create_bucket_in(bucket, Location::Multi(MultiRegion::Asia)).await
+}
+
+/// Create a bucket at the location named by `location_hint`, e.g. `asia`, `nam4` or `us-east1`.
+pub async fn create_bucket_with_location_hint(bucket: &str, location_hint: &str) -> Result<Bucket> {
+    create_bucket_in(bucket, parse_location(location_hint)?).await
+}
+
+pub async fn create_bucket_in(bucket: &str, location: Location) -> Result<Bucket> {
     let new_bucket = NewBucket {
         name: bucket.to_owned(), // this is the only mandatory field
-        location: Location::Multi(MultiRegion::Asia),
+        location,
         ..Default::default()
     };
 
@@ -458,6 +1862,15 @@ pub async fn create_bucket(bucket: &str) -> Result<Bucket> {
     Ok(bucket)
 }
 
+/// Parse a gcs location name, case insensitively: a multi-region (`ASIA`, `EU`, `US`),
+/// a dual-region (`EUR4`, `NAM4`) or a single region (`ASIA-NORTHEAST1`, ...)
+/// among the ones `cloud-storage` knows.
+pub fn parse_location(location_hint: &str) -> Result<Location> {
+    let name = location_hint.trim().to_ascii_uppercase();
+    serde_json::from_value(serde_json::Value::String(name))
+        .map_err(|_| FileUtilGcsError::InvalidLocation(location_hint.to_string()))
+}
+
 pub async fn bucket_exists(bucket: &str) -> bool {
     let a = find_bucket(bucket)
         .and_then(|found_or_not| future::ok(found_or_not.is_some()))
@@ -659,6 +2072,594 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn gcs_file_from_bucket_name() {
+        let result = GcsFile::from_bucket_name("zdb_test", "zdb/path").unwrap();
+        assert_eq!(
+            result,
+            GcsFile {
+                bucket: "zdb_test".to_string(),
+                name: "zdb/path".to_string(),
+                trailing_slash: false,
+            }
+        );
+        assert_eq!("gs://zdb_test/zdb/path", result.to_string());
+    }
+
+    #[test]
+    fn gcs_file_from_invalid_bucket_name() {
+        assert!(GcsFile::from_bucket_name("", "zdb").is_err());
+        assert!(GcsFile::from_bucket_name("zdb_test/sub", "zdb").is_err());
+        assert!(GcsFile::from_bucket_name("zdb_test", "").is_err());
+        assert!(GcsFile::from_bucket_name("zdb_test", "/zdb").is_err());
+        assert!(GcsFile::from_bucket_name("zdb_test", "zdb/").is_err());
+    }
+
+    #[tokio::test]
+    async fn write_only_changed_contents() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/same", "body");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/same").unwrap();
+        let write = |body: &'static [u8]| {
+            file.write_if_changed_with_retry(
+                body,
+                MimeType::Text,
+                Some(crate::testing::quick_backoff()),
+                None,
+            )
+        };
+
+        client::with_client(mock.clone(), async {
+            assert!(!write(b"body").await.unwrap());
+            assert_eq!(0, mock.call_count("create"));
+            assert_eq!(0, mock.call_count("download"));
+
+            assert!(write(b"changed").await.unwrap());
+            assert_eq!(1, mock.call_count("create"));
+            assert_eq!(
+                b"changed".to_vec(),
+                mock.get("zdb_test", "zdb/same").unwrap().body
+            );
+
+            let new_file = GcsFile::from_bucket_name("zdb_test", "zdb/new").unwrap();
+            assert!(new_file
+                .write_if_changed_with_retry(b"body", MimeType::Text, None, None)
+                .await
+                .unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn current_generation_of_object() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/gen", "first");
+        mock.insert("zdb_test", "zdb/gen", "second");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/gen").unwrap();
+
+        client::with_client(mock.clone(), async {
+            let generation = file.current_generation(None).await.unwrap();
+            let object = client::current_client()
+                .read_object("zdb_test", "zdb/gen")
+                .await
+                .unwrap();
+            assert_eq!(Some(object.generation), generation);
+            assert_eq!(
+                mock.get("zdb_test", "zdb/gen").unwrap().generation,
+                object.generation
+            );
+
+            let missing = GcsFile::from_bucket_name("zdb_test", "zdb/missing").unwrap();
+            assert_eq!(None, missing.current_generation(None).await.unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn merge_objects_in_order() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        let mut files = Vec::<GcsFile>::new();
+        let mut expected = Vec::<u8>::new();
+        for i in 0..7 {
+            let name = format!("zdb/shard_{}", i);
+            let body = format!("shard {} ", i).repeat(10 * (7 - i));
+            mock.insert("zdb_test", &name, body.clone());
+            files.push(GcsFile::from_bucket_name("zdb_test", name).unwrap());
+            expected.extend(body.into_bytes());
+        }
+
+        client::with_client(mock.clone(), async {
+            let mut merged = Vec::<u8>::new();
+            let written = merge_objects_ordered(&files, 3, &mut merged, None)
+                .await
+                .unwrap();
+            assert_eq!(expected, merged);
+            assert_eq!(expected.len() as u64, written);
+            assert_eq!(7, mock.call_count("download"));
+
+            files.insert(
+                2,
+                GcsFile::from_bucket_name("zdb_test", "zdb/missing").unwrap(),
+            );
+            let result = merge_objects_ordered(&files, 3, &mut Vec::new(), None).await;
+            assert!(matches!(result, Err(FileUtilGcsError::ObjectNotFound(_))));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn list_level_separates_objects_and_prefixes() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().with_page_size(2).into_arc();
+        for name in [
+            "root/a.txt",
+            "root/b/1.txt",
+            "root/b/2.txt",
+            "root/b/c/3.txt",
+            "root/c.txt",
+            "root/d/4.txt",
+            "root/e/",
+            "other/5.txt",
+        ] {
+            mock.insert("zdb_test", name, "x");
+        }
+
+        let listed = client::with_client(mock.clone(), list_level("zdb_test", "root/"))
+            .await
+            .unwrap();
+        let names: Vec<&str> = listed.objects.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(vec!["root/a.txt", "root/c.txt"], names);
+        assert_eq!(vec!["root/b/", "root/d/", "root/e/"], listed.prefixes);
+        assert_eq!(1, listed.objects[0].size);
+        assert_eq!(3, mock.call_count("list"));
+
+        let nested = client::with_client(mock, list_level("zdb_test", "root/b/"))
+            .await
+            .unwrap();
+        assert_eq!(2, nested.objects.len());
+        assert_eq!(vec!["root/b/c/"], nested.prefixes);
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_source_unless_verified() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let source = GcsFile::from_bucket_name("zdb_test", "zdb/source").unwrap();
+        let destination = GcsFile::from_bucket_name("zdb_test", "zdb/destination").unwrap();
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/source", "contents to move");
+        client::with_client(mock.clone(), source.rename_to(&destination, None))
+            .await
+            .unwrap();
+        assert!(mock.get("zdb_test", "zdb/source").is_none());
+        assert_eq!(
+            b"contents to move".to_vec(),
+            mock.get("zdb_test", "zdb/destination").unwrap().body
+        );
+
+        let interrupted = MockGcsClient::new().with_interrupted_rewrites().into_arc();
+        interrupted.insert("zdb_test", "zdb/source", "contents to move");
+        let result =
+            client::with_client(interrupted.clone(), source.rename_to(&destination, None)).await;
+        assert!(matches!(
+            result,
+            Err(FileUtilGcsError::RenameNotVerified(_))
+        ));
+        assert_eq!(
+            b"contents to move".to_vec(),
+            interrupted.get("zdb_test", "zdb/source").unwrap().body
+        );
+        assert_eq!(0, interrupted.call_count("delete"));
+    }
+
+    #[tokio::test]
+    async fn list_components_of_composed_and_simple_objects() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert_composed("zdb_test", "zdb/composed", "part1part2part3", 3);
+        mock.insert("zdb_test", "zdb/simple", "whole");
+        let components = |name: &str| {
+            let file = GcsFile::from_bucket_name("zdb_test", name).unwrap();
+            async move { file.list_components(None).await.unwrap() }
+        };
+
+        client::with_client(mock, async {
+            let composed = components("zdb/composed").await.unwrap();
+            assert_eq!(Composition::Composed { component_count: 3 }, composed);
+            assert_eq!(3, composed.component_count());
+
+            let simple = components("zdb/simple").await.unwrap();
+            assert_eq!(Composition::Simple, simple);
+            assert_eq!(1, simple.component_count());
+
+            assert_eq!(None, components("zdb/missing").await);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn request_timeout_retries_slow_attempt() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new()
+            .with_slow_reads(1, Duration::from_secs(5))
+            .into_arc();
+        mock.insert("zdb_test", "zdb/slow", "x");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/slow").unwrap();
+
+        let started = Instant::now();
+        let generation = client::with_client(
+            mock.clone(),
+            client::with_request_timeout(
+                Duration::from_millis(50),
+                file.current_generation(Some(crate::testing::quick_backoff())),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(generation.is_some());
+        assert_eq!(2, mock.call_count("read"), "the slow attempt is retried");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn timed_out_requests_are_retried_by_policies() {
+        use crate::config::{with_config, ClientConfig};
+        use crate::retry::FixedRetry;
+        use crate::testing::gcs::MockGcsClient;
+        use client::GcsClient;
+
+        let mock = MockGcsClient::new()
+            .with_slow_reads(1, Duration::from_secs(5))
+            .into_arc();
+        mock.insert("zdb_test", "zdb/slow", "x");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/slow").unwrap();
+
+        let timed_out = client::TimeoutClient::new(mock, Duration::from_millis(50))
+            .read_object("zdb_test", "zdb/slow")
+            .await
+            .unwrap_err();
+        let timed_out = FileUtilGcsError::from(timed_out);
+        assert_eq!(ErrorKind::Transient, timed_out.kind());
+        assert_eq!(504, timed_out.to_http_status());
+
+        let mock = MockGcsClient::new()
+            .with_slow_reads(1, Duration::from_secs(5))
+            .into_arc();
+        mock.insert("zdb_test", "zdb/slow", "x");
+        let policy = FixedRetry {
+            interval: Duration::from_millis(1),
+            max_attempts: 3,
+        };
+        let config = ClientConfig::new()
+            .with_gcs_client(mock.clone())
+            .with_request_timeout(Duration::from_millis(50))
+            .with_retry_policy(Arc::new(policy));
+        let generation = with_config(config, file.current_generation(None))
+            .await
+            .unwrap();
+        assert!(generation.is_some());
+        assert_eq!(
+            2,
+            mock.call_count("read"),
+            "the timed out attempt is retried"
+        );
+    }
+
+    #[tokio::test]
+    async fn metadata_cache_short_circuits_existence_checks() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/cached", "x");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/cached").unwrap();
+
+        client::with_client(mock.clone(), async {
+            client::with_metadata_cache(Duration::from_secs(60), 10, async {
+                assert!(file.is_exists_with_retry(None).await.unwrap());
+                assert!(file.is_exists_with_retry(None).await.unwrap());
+                assert_eq!(1, mock.call_count("read"), "served from the cache");
+
+                file.delete_with_retry(None).await.unwrap();
+                assert!(!file.is_exists_with_retry(None).await.unwrap());
+                assert_eq!(2, mock.call_count("read"), "invalidated by the delete");
+            })
+            .await;
+
+            client::with_metadata_cache(Duration::ZERO, 10, async {
+                mock.insert("zdb_test", "zdb/cached", "x");
+                assert!(file.is_exists_with_retry(None).await.unwrap());
+                assert!(file.is_exists_with_retry(None).await.unwrap());
+                assert_eq!(4, mock.call_count("read"), "expired entries are read again");
+            })
+            .await;
+        })
+        .await;
+    }
+
+    #[test]
+    fn rate_limit_backoff_waits_longer() {
+        let rate_limited = Arc::new(AtomicBool::new(false));
+        let inner = ExponentialBackoff {
+            initial_interval: Duration::from_millis(10),
+            randomization_factor: 0.0,
+            multiplier: 2.0,
+            max_interval: Duration::from_millis(50),
+            max_elapsed_time: None,
+            ..ExponentialBackoff::default()
+        };
+        let mut backoff = RateLimitBackoff::new(inner, rate_limited.clone());
+        backoff.reset();
+        let mut next = |is_rate_limited: bool| {
+            rate_limited.store(is_rate_limited, Ordering::SeqCst);
+            backoff.next_backoff().unwrap().as_millis()
+        };
+
+        assert_eq!(40, next(true));
+        // other errors keep the plain exponential intervals, and reset the rate-limit ones
+        assert_eq!(20, next(false));
+        assert_eq!(40, next(true));
+        assert_eq!(80, next(true));
+        assert_eq!(160, next(true));
+        // capped at `RATE_LIMIT_INTERVAL_FACTOR` times the max interval
+        assert_eq!(200, next(true));
+    }
+
+    #[tokio::test]
+    async fn retry_hook_sees_the_remaining_budget() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::quick_backoff;
+        use std::sync::Mutex;
+
+        let mock = MockGcsClient::new().failing_list_after(0).into_arc();
+        let file = GcsFile::new("gs://zdb_test/zdb/".to_string()).unwrap();
+        let states = Arc::new(Mutex::new(Vec::<RetryState>::new()));
+        let recorder = states.clone();
+        let hook: RetryHook = Arc::new(move |state| {
+            recorder.lock().unwrap().push(state.clone());
+            true
+        });
+
+        client::with_client(mock.clone(), async {
+            let listed = with_retry_hook(
+                hook,
+                file.list_objects_with_retry(Some(quick_backoff()), None),
+            );
+            assert!(listed.await.is_err());
+        })
+        .await;
+        let states = states.lock().unwrap().clone();
+        assert!(states.len() >= 3, "{:?}", states);
+        assert_eq!(states.len() + 1, mock.call_count("list"));
+        for (i, pair) in states.windows(2).enumerate() {
+            assert_eq!(i + 1, pair[0].attempts);
+            assert!(pair[0].elapsed < pair[1].elapsed);
+            assert!(pair[0].remaining.unwrap() > pair[1].remaining.unwrap());
+        }
+        assert!(states[0].remaining.unwrap() <= Duration::from_millis(500));
+
+        // the hook can give up before the budget is spent
+        let mock = MockGcsClient::new().failing_list_after(0).into_arc();
+        let hook: RetryHook = Arc::new(|state| state.attempts < 2);
+        client::with_client(mock.clone(), async {
+            let listed = with_retry_hook(
+                hook,
+                file.list_objects_with_retry(Some(quick_backoff()), None),
+            );
+            assert!(listed.await.is_err());
+        })
+        .await;
+        assert_eq!(2, mock.call_count("list"));
+    }
+
+    #[tokio::test]
+    async fn retry_rate_limited_reads() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::quick_backoff;
+
+        let mock = MockGcsClient::new().with_rate_limited_reads(3).into_arc();
+        mock.insert("zdb_test", "zdb/object", "body");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/object").unwrap();
+
+        client::with_client(mock.clone(), async {
+            let started = std::time::Instant::now();
+            assert!(file
+                .is_exists_with_retry(Some(quick_backoff()))
+                .await
+                .unwrap());
+            // 40ms + 60ms + 90ms, where plain retries wait from 10ms
+            assert!(started.elapsed() >= Duration::from_millis(190));
+            assert_eq!(4, mock.call_count("read"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn list_and_restore_soft_deleted() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/deleted", "first");
+        mock.insert("zdb_test", "zdb/live", "live");
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/deleted").unwrap();
+
+        client::with_client(mock.clone(), async {
+            file.delete_with_retry(None).await.unwrap();
+            let deleted = list_soft_deleted("zdb_test", "zdb/").await.unwrap();
+            assert_eq!(
+                vec![SoftDeletedObject {
+                    name: "zdb/deleted".to_string(),
+                    generation: 1,
+                    size: 5,
+                }],
+                deleted
+            );
+
+            let result = file.restore(deleted[0].generation + 1, None).await;
+            assert!(matches!(result, Err(FileUtilGcsError::ObjectNotFound(_))));
+
+            let restored = file.restore(deleted[0].generation, None).await.unwrap();
+            assert_eq!("zdb/deleted", restored.name);
+            assert_ne!(deleted[0].generation, restored.generation);
+            assert_eq!(
+                b"first".to_vec(),
+                mock.get("zdb_test", "zdb/deleted").unwrap().body
+            );
+            assert!(list_soft_deleted("zdb_test", "zdb/")
+                .await
+                .unwrap()
+                .is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn list_objects_exceeding_timeout() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new()
+            .with_page_size(1)
+            .with_list_delay(Duration::from_millis(50))
+            .into_arc();
+        for i in 0..5 {
+            mock.insert("zdb_test", &format!("dir/file_{}", i), "body");
+        }
+        let dir = GcsFile::new_with_url(&Url::parse("gs://zdb_test/dir/").unwrap()).unwrap();
+
+        client::with_client(mock.clone(), async {
+            let result = dir
+                .list_objects_with_timeout_with_retry(
+                    Some(crate::testing::quick_backoff()),
+                    None,
+                    Some(Duration::from_millis(120)),
+                )
+                .await;
+            assert!(matches!(result, Err(FileUtilGcsError::ListTimeout(_))));
+            assert!(
+                mock.call_count("list") <= 3,
+                "stops paginating and is not retried after the timeout"
+            );
+
+            let listed = dir
+                .list_objects_with_timeout_with_retry(None, None, Some(Duration::from_secs(10)))
+                .await
+                .unwrap();
+            assert_eq!(5, listed.len());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_and_list_directory_marker() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        let dir =
+            GcsFile::new_with_url(&Url::parse("gs://zdb_test/parent/empty/").unwrap()).unwrap();
+        let parent = GcsFile::new_with_url(&Url::parse("gs://zdb_test/parent/").unwrap()).unwrap();
+
+        client::with_client(mock.clone(), async {
+            dir.create_directory_marker(None).await.unwrap();
+            let listed = parent.list_objects_with_retry(None, None).await.unwrap();
+            assert_eq!(vec!["gs://zdb_test/parent/empty/"], listed);
+        })
+        .await;
+        assert!(mock
+            .get("zdb_test", "parent/empty/")
+            .unwrap()
+            .body
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn overwrite_acl_behavior() {
+        use crate::testing::gcs::MockGcsClient;
+        use cloud_storage::object_access_control::Entity;
+
+        let mock = MockGcsClient::new().into_arc();
+        let file = GcsFile::from_bucket_name("zdb_test", "zdb/acl").unwrap();
+        let public = AclBehavior::Set(acl::PredefinedAcl::PublicRead);
+        let write = |acl_behavior| {
+            file.write_with_acl_with_retry(
+                "body",
+                MimeType::Text,
+                Some(crate::testing::quick_backoff()),
+                None,
+                acl_behavior,
+            )
+        };
+        let public_entries = || {
+            mock.get("zdb_test", "zdb/acl")
+                .unwrap()
+                .acl
+                .into_iter()
+                .filter(|(entity, role)| *entity == Entity::AllUsers && role == "READER")
+                .count()
+        };
+
+        client::with_client(mock.clone(), async {
+            write(public).await.unwrap();
+            assert_eq!(1, public_entries());
+
+            write(AclBehavior::Preserve).await.unwrap();
+            assert_eq!(1, public_entries());
+
+            write(AclBehavior::BucketDefault).await.unwrap();
+            assert_eq!(0, public_entries());
+
+            // nothing to preserve on a new object
+            let new_file = GcsFile::from_bucket_name("zdb_test", "zdb/acl_new").unwrap();
+            new_file
+                .write_with_acl_with_retry(
+                    "body",
+                    MimeType::Text,
+                    None,
+                    None,
+                    AclBehavior::Preserve,
+                )
+                .await
+                .unwrap();
+            assert!(mock.get("zdb_test", "zdb/acl_new").unwrap().acl.is_empty());
+        })
+        .await;
+    }
+
+    #[test]
+    fn parse_bucket_location() {
+        use cloud_storage::bucket::{AsiaLocation, DualRegion, SingleRegion};
+
+        assert_eq!(
+            Location::Multi(MultiRegion::Asia),
+            parse_location("asia").unwrap()
+        );
+        assert_eq!(
+            Location::Multi(MultiRegion::Us),
+            parse_location("US").unwrap()
+        );
+        assert_eq!(
+            Location::Dual(DualRegion::Nam4),
+            parse_location("nam4").unwrap()
+        );
+        assert_eq!(
+            Location::Single(SingleRegion::Asia(AsiaLocation::Tokyo)),
+            parse_location(" asia-northeast1 ").unwrap()
+        );
+        assert!(matches!(
+            parse_location("mars-central1"),
+            Err(FileUtilGcsError::InvalidLocation(_))
+        ));
+        assert!(parse_location("").is_err());
+    }
+
     #[test]
     fn parse_gcs_invalid() {
         let url = Url::parse("gs://zdb_test//").unwrap();
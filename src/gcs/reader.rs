@@ -0,0 +1,215 @@
+//! Random access to a gcs object, for formats that seek around a file (Parquet, indexed files).
+use super::{client, is_not_found, retry, FileUtilGcsError, GcsFile, Result};
+use crate::copy_backoff;
+use backoff::{Error as BackoffError, ExponentialBackoff};
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+
+const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+const DEFAULT_CACHE_BLOCKS: usize = 8;
+
+/// Reads an object with ranged downloads of the blocks the caller seeks to and reads.
+/// The most recently fetched blocks are kept, so nearby reads do not hit gcs again.
+pub struct GcsReader {
+    file: GcsFile,
+    backoff: Option<ExponentialBackoff>,
+    size: u64,
+    position: u64,
+    block_size: u64,
+    cache_blocks: usize,
+    // (block index, contents), most recently used first
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl GcsReader {
+    /// Open the object, reading its size from the metadata.
+    pub async fn open(file: &GcsFile, backoff: Option<ExponentialBackoff>) -> Result<Self> {
+        if file.trailing_slash {
+            return Err(FileUtilGcsError::GcsInvalidBucketPathError(format!(
+                "object path must not be ends with `/` : {}",
+                file.name
+            )));
+        }
+
+        let gcs_client = client::current_client();
+        let object = retry(copy_backoff(&backoff), || async {
+            match gcs_client.read_object(&file.bucket, &file.name).await {
+                Ok(object) => Ok(Some(object)),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(BackoffError::Transient(FileUtilGcsError::from(e))),
+            }
+        })
+        .await?
+        .ok_or_else(|| FileUtilGcsError::ObjectNotFound(file.to_string()))?;
+
+        Ok(Self {
+            file: GcsFile {
+                bucket: file.bucket.clone(),
+                name: file.name.clone(),
+                trailing_slash: false,
+            },
+            backoff,
+            size: object.size,
+            position: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            cache: VecDeque::new(),
+        })
+    }
+
+    /// Bytes fetched by a single ranged download.
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self.cache.clear();
+        self
+    }
+
+    /// Number of fetched blocks kept in memory.
+    pub fn with_cache_blocks(mut self, cache_blocks: usize) -> Self {
+        self.cache_blocks = cache_blocks;
+        self.cache.truncate(cache_blocks);
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Move the position as `std::io::Seek` does. Nothing is fetched until the next read.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(FileUtilGcsError::InvalidSeek(format!(
+                "{:?} from {} in {}",
+                pos, self.position, self.file
+            ))),
+        }
+    }
+
+    /// Read from the current position as `std::io::Read` does.
+    /// Returns 0 at the end of the object.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let index = self.position / self.block_size;
+        let in_block = (self.position % self.block_size) as usize;
+        let block = self.block(index).await?;
+        if in_block >= block.len() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(block.len() - in_block);
+        buf[..n].copy_from_slice(&block[in_block..in_block + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    /// Fill `buf` entirely, failing if the object ends first.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let n = self.read(buf).await?;
+            if n == 0 {
+                return Err(FileUtilGcsError::UnexpectedEof(format!(
+                    "{} at {}",
+                    self.file, self.position
+                )));
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    async fn block(&mut self, index: u64) -> Result<&[u8]> {
+        match self.cache.iter().position(|(cached, _)| *cached == index) {
+            Some(0) => {}
+            Some(i) => {
+                let entry = self.cache.remove(i).unwrap();
+                self.cache.push_front(entry);
+            }
+            None => {
+                let contents = self
+                    .file
+                    .download_range_with_retry(
+                        index * self.block_size,
+                        Some(self.block_size),
+                        copy_backoff(&self.backoff),
+                    )
+                    .await?
+                    .ok_or_else(|| FileUtilGcsError::ObjectNotFound(self.file.to_string()))?;
+                self.cache.push_front((index, contents));
+                self.cache.truncate(self.cache_blocks.max(1));
+            }
+        }
+        Ok(&self.cache[0].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gcs::MockGcsClient;
+
+    #[tokio::test]
+    async fn seek_and_read() {
+        let local: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "data.bin", local.clone());
+        let file = GcsFile::from_bucket_name("zdb_test", "data.bin").unwrap();
+
+        client::with_client(mock.clone(), async {
+            let mut reader = GcsReader::open(&file, None)
+                .await
+                .unwrap()
+                .with_block_size(64)
+                .with_cache_blocks(2);
+            assert_eq!(1000, reader.size());
+
+            let mut buf = [0u8; 100];
+            for pos in [
+                SeekFrom::Start(10),
+                SeekFrom::Start(500),
+                SeekFrom::Current(-300),
+                SeekFrom::End(-100),
+            ] {
+                let start = reader.seek(pos).unwrap() as usize;
+                reader.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&local[start..start + 100], &buf[..], "{:?}", pos);
+            }
+
+            let fetched = mock.call_count("download_range");
+            reader.seek(SeekFrom::End(-50)).unwrap();
+            reader.read_exact(&mut buf[..50]).await.unwrap();
+            assert_eq!(
+                fetched,
+                mock.call_count("download_range"),
+                "served from cache"
+            );
+
+            assert_eq!(0, reader.read(&mut buf).await.unwrap());
+            assert!(reader.read_exact(&mut buf).await.is_err());
+            assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn open_missing_object() {
+        let mock = MockGcsClient::new().into_arc();
+        let file = GcsFile::from_bucket_name("zdb_test", "missing.bin").unwrap();
+        let result = client::with_client(mock, GcsReader::open(&file, None)).await;
+        assert!(matches!(result, Err(FileUtilGcsError::ObjectNotFound(_))));
+    }
+}
@@ -0,0 +1,154 @@
+//! Extract single entries of a zip archive in gcs with ranged reads: the central directory
+//! is read from the tail of the object, then only the bytes of the requested entry.
+//! Zip64 and encrypted archives are not supported.
+use super::reader::GcsReader;
+use super::{FileUtilGcsError, GcsFile, Result};
+use crate::archive::{self, EOCD_LEN, MAX_COMMENT_LEN, METHOD_DEFLATED, METHOD_STORED};
+use backoff::ExponentialBackoff;
+use flate2::read::DeflateDecoder;
+use std::io::{Read, SeekFrom};
+
+const READ_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Read the entry `entry_name` of the zip archive `file`.
+/// Returns `None` when the archive has no such entry.
+pub async fn read_zip_entry(
+    file: &GcsFile,
+    entry_name: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    let mut reader = GcsReader::open(file, backoff)
+        .await?
+        .with_block_size(READ_BLOCK_SIZE);
+    let size = reader.size();
+
+    let tail_len = size.min(EOCD_LEN + MAX_COMMENT_LEN);
+    let tail = read_at(&mut reader, size - tail_len, tail_len).await?;
+    let (_, central) = archive::find_eocd(&tail)
+        .ok_or_else(|| invalid_zip(file, "end of central directory is not found"))?;
+    if central.offset + central.size > size {
+        return Err(invalid_zip(file, "central directory is out of the object"));
+    }
+
+    let central = read_at(&mut reader, central.offset, central.size).await?;
+    let entry = match archive::parse_central_directory(&central)
+        .map_err(|e| invalid_zip(file, e))?
+        .into_iter()
+        .find(|entry| entry.name == entry_name)
+    {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    if entry.is_encrypted() {
+        return Err(invalid_zip(file, "encrypted entries are not supported"));
+    }
+
+    let local = read_at(
+        &mut reader,
+        entry.local_header_offset,
+        archive::LOCAL_HEADER_LEN as u64,
+    )
+    .await?;
+    let data_offset = archive::data_offset(&entry, &local).map_err(|e| invalid_zip(file, e))?;
+    let compressed = read_at(&mut reader, data_offset, entry.compressed_size).await?;
+
+    match entry.method {
+        METHOD_STORED => Ok(Some(compressed)),
+        METHOD_DEFLATED => {
+            let mut contents = Vec::<u8>::new();
+            DeflateDecoder::new(compressed.as_slice())
+                .read_to_end(&mut contents)
+                .map_err(|e| invalid_zip(file, &e.to_string()))?;
+            Ok(Some(contents))
+        }
+        method => Err(invalid_zip(
+            file,
+            &format!("compression method {} is not supported", method),
+        )),
+    }
+}
+
+async fn read_at(reader: &mut GcsReader, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; length as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid_zip(file: &GcsFile, reason: &str) -> FileUtilGcsError {
+    FileUtilGcsError::InvalidZip(format!("{} {}", file, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcs::client;
+    use crate::testing::gcs::MockGcsClient;
+    use std::io::Write;
+
+    fn fixture_zip() -> Vec<u8> {
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::<u8>::new()));
+        let deflated =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+        archive.start_file("big.bin", stored).unwrap();
+        archive.write_all(&vec![7u8; 200_000]).unwrap();
+        archive.start_file("dir/wanted.txt", deflated).unwrap();
+        archive
+            .write_all(b"wanted contents ".repeat(100).as_slice())
+            .unwrap();
+        archive.start_file("stored.txt", stored).unwrap();
+        archive.write_all(b"stored contents").unwrap();
+        archive.set_comment("fixture");
+        archive.finish().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn read_single_entry() {
+        let archive = fixture_zip();
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "archive.zip", archive.clone());
+        let file = GcsFile::from_bucket_name("zdb_test", "archive.zip").unwrap();
+
+        client::with_client(mock.clone(), async {
+            let wanted = read_zip_entry(&file, "dir/wanted.txt", None).await.unwrap();
+            assert_eq!(Some(b"wanted contents ".repeat(100)), wanted);
+            assert_eq!(
+                0,
+                mock.call_count("download"),
+                "never downloads the whole archive"
+            );
+
+            let stored = read_zip_entry(&file, "stored.txt", None).await.unwrap();
+            assert_eq!(Some(b"stored contents".to_vec()), stored);
+
+            assert_eq!(
+                None,
+                read_zip_entry(&file, "missing.txt", None).await.unwrap()
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn read_entry_of_non_zip() {
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "not.zip", "plain text");
+        mock.insert("zdb_test", "tiny.zip", "PK");
+
+        client::with_client(mock, async {
+            for name in ["not.zip", "tiny.zip"] {
+                let file = GcsFile::from_bucket_name("zdb_test", name).unwrap();
+                let result = read_zip_entry(&file, "a.txt", None).await;
+                assert!(
+                    matches!(result, Err(FileUtilGcsError::InvalidZip(_))),
+                    "{}",
+                    name
+                );
+            }
+        })
+        .await;
+    }
+}
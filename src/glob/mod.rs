@@ -0,0 +1,186 @@
+//! Glob patterns in urls and paths, matched against the entries a listing returns.
+use regex::Regex;
+
+const META_CHARACTERS: &[char] = &['*', '?', '['];
+
+/// A url or path with glob patterns in it: the literal directory in front of the first
+/// pattern, and the pattern the path of each entry relative to it has to match.
+///
+/// `*` and `?` match within a path component, `**` matches any number of components and
+/// `[...]` (`[!...]` to negate) matches one character of a set. Names starting with `.` are
+/// matched like any other. A `\` takes the character after it literally, e.g. `\[` a bracket.
+pub(crate) struct Glob {
+    pub base: String,
+    /// Whether the pattern reaches below the direct entries of `base`.
+    pub recursive: bool,
+    pattern: Regex,
+}
+
+impl Glob {
+    /// `None` when `url_or_path` has no glob pattern in it.
+    pub fn parse(url_or_path: &str) -> Option<Self> {
+        let first = first_pattern(url_or_path)?;
+        let (base, pattern) = match url_or_path[..first].rfind('/') {
+            Some(0) => ("/", &url_or_path[1..]),
+            Some(i) => (&url_or_path[..i], &url_or_path[i + 1..]),
+            None => (".", url_or_path),
+        };
+        let pattern = compile(pattern)?;
+        Some(Self {
+            base: unescape(base),
+            recursive: url_or_path[first..].contains('/') || url_or_path.contains("**"),
+            pattern,
+        })
+    }
+
+    /// Whether `relative`, a path under `base` without a trailing `/`, matches the pattern.
+    pub fn matches(&self, relative: &str) -> bool {
+        self.pattern.is_match(relative)
+    }
+}
+
+/// The index of the first character of `url_or_path` that is not escaped and starts a pattern.
+fn first_pattern(url_or_path: &str) -> Option<usize> {
+    let mut chars = url_or_path.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if META_CHARACTERS.contains(&c) => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `literal` without the `\` escaping its characters.
+pub(crate) fn unescape(literal: &str) -> String {
+    let mut unescaped = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// The regex matching what `pattern` does, `None` when it is invalid, e.g. a class with a
+/// reversed range such as `[z-a]`.
+pub(crate) fn compile(pattern: &str) -> Option<Regex> {
+    Regex::new(&to_regex(pattern)).ok()
+}
+
+fn to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut regex = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let whole_component = i == 0 || chars[i - 1] == '/';
+                if whole_component && chars.get(i + 2) == Some(&'/') {
+                    // `**/` also matches no component at all
+                    regex.push_str("(?:[^/]*/)*");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '\\' if i + 1 < chars.len() => {
+                regex.push_str(&regex::escape(&chars[i + 1].to_string()));
+                i += 2;
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                if let Some((class, len)) = character_class(&chars[i + 1..]) {
+                    regex.push_str(&class);
+                    i += len + 1;
+                    continue;
+                }
+                regex.push_str(r"\[");
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+/// The regex of the character class whose contents start `chars`, right after its `[`,
+/// and how many characters it takes up to its `]`. `None` when it is not closed.
+fn character_class(chars: &[char]) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut i = 0;
+    if matches!(chars.first(), Some('!') | Some('^')) {
+        class.push('^');
+        i = 1;
+    }
+    let start = i;
+    while let Some(&c) = chars.get(i) {
+        match c {
+            // a `]` first in the class is one of its characters
+            ']' if i > start => {
+                class.push(']');
+                return Some((class, i + 1));
+            }
+            '\\' | '[' | ']' | '^' | '&' | '~' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_match_patterns() {
+        let glob = Glob::parse("gs://bucket/data/2024-*/**/*.json.gz").unwrap();
+        assert_eq!("gs://bucket/data", glob.base);
+        assert!(glob.recursive);
+        assert!(glob.matches("2024-01/a.json.gz"));
+        assert!(glob.matches("2024-01/x/y/a.json.gz"));
+        assert!(!glob.matches("2024-01/a.json"));
+        assert!(!glob.matches("2023-01/a.json.gz"));
+        assert!(!glob.matches("2024-01"));
+
+        let glob = Glob::parse("./logs/app-?.[0-9][!a]").unwrap();
+        assert_eq!("./logs", glob.base);
+        assert!(!glob.recursive);
+        assert!(glob.matches("app-x.1b"));
+        assert!(!glob.matches("app-x.1a"));
+        assert!(!glob.matches("app-xy.1b"));
+        assert!(!glob.matches("sub/app-x.1b"));
+
+        let glob = Glob::parse("*.log").unwrap();
+        assert_eq!(".", glob.base);
+        assert!(glob.matches("a.log") && !glob.matches("a.log.1"));
+        assert_eq!("/", Glob::parse("/*.log").unwrap().base);
+
+        // an unclosed class is taken literally, as are regex characters
+        let glob = Glob::parse("dir/a[b+(c)").unwrap();
+        assert!(glob.matches("a[b+(c)"));
+
+        assert!(Glob::parse("gs://bucket/data/2024/a.json").is_none());
+
+        // escaped characters are literal, in the base as in the pattern
+        assert!(Glob::parse("gs://bucket/report\\[1\\].csv").is_none());
+        let glob = Glob::parse("gs://bucket/\\[raw\\]/report\\[*\\].csv").unwrap();
+        assert_eq!("gs://bucket/[raw]", glob.base);
+        assert!(glob.matches("report[1].csv"));
+        assert!(!glob.matches("report1.csv"));
+    }
+}
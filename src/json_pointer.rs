@@ -0,0 +1,123 @@
+//! RFC 6901 JSON pointers, resolved while the document is parsed so that only the value
+//! they point to is built; everything else is skipped as it is read.
+use serde::de::{
+    self as de, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::io::Read;
+
+/// The reference tokens of `pointer`, unescaped, or `None` when it is not a pointer.
+pub(crate) fn tokens(pointer: &str) -> Option<Vec<String>> {
+    if pointer.is_empty() {
+        return Some(vec![]);
+    }
+    let rest = pointer.strip_prefix('/')?;
+    Some(
+        rest.split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+    )
+}
+
+/// Parse the whole document from `reader`, failing when it is not valid JSON, and return
+/// the value at `tokens`, or `None` when they do not resolve.
+pub(crate) fn read_pointer<R: Read>(
+    reader: R,
+    tokens: &[String],
+) -> serde_json::Result<Option<Value>> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let value = PointerSeed { tokens }.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Deserializes the value at `tokens` below the current one, skipping the rest.
+struct PointerSeed<'a> {
+    tokens: &'a [String],
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for PointerSeed<'a> {
+    type Value = Option<Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        if self.tokens.is_empty() {
+            Value::deserialize(deserializer).map(Some)
+        } else {
+            deserializer.deserialize_any(self)
+        }
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for PointerSeed<'a> {
+    type Value = Option<Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (token, rest) = self.tokens.split_first().expect("a token to resolve");
+        let mut found = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == *token {
+                // the last of duplicated keys wins, as when parsing into a `Value`
+                found = map.next_value_seed(PointerSeed { tokens: rest })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let (token, rest) = self.tokens.split_first().expect("a token to resolve");
+        // no leading zeros, and `-` (past the end) never resolves
+        let index = match token.as_str() {
+            "0" => Some(0),
+            digits if !digits.starts_with('0') => digits.parse::<usize>().ok(),
+            _ => None,
+        };
+        let mut found = None;
+        let mut i = 0;
+        loop {
+            if Some(i) == index {
+                match seq.next_element_seed(PointerSeed { tokens: rest })? {
+                    Some(value) => found = value,
+                    None => break,
+                }
+            } else if seq.next_element::<IgnoredAny>()?.is_none() {
+                break;
+            }
+            i += 1;
+        }
+        Ok(found)
+    }
+
+    // a scalar has nothing below it to point to
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+}
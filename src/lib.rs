@@ -5,12 +5,38 @@ pub mod gcs;
 #[cfg(feature = "web")]
 pub mod web;
 
+pub mod archive;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod checksum;
 pub mod compression;
+pub mod config;
+mod glob;
+#[cfg(feature = "serde_json")]
+mod json_pointer;
 pub mod mime;
+pub mod progress;
+pub mod retry;
+pub mod storage;
+pub mod uri_path;
+pub mod writer;
+
+pub use encoding_rs;
+
+#[cfg(test)]
+mod testing;
 
 use backoff::ExponentialBackoff;
+use bytes::Bytes;
+use checksum::ChecksumAlgo;
 use compression::*;
+use futures::io::AsyncWriteExt;
+use futures::stream::BoxStream;
+use futures::{Future, SinkExt, Stream, StreamExt, TryStreamExt};
+use rand::Rng;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+use tokio::time::Instant;
 
 #[derive(Error, Debug)]
 pub enum FileUtilError {
@@ -22,158 +48,5645 @@ pub enum FileUtilError {
 
     #[error("fs error: {0}")]
     FsError(#[from] fs::FileUtilFsError),
+
+    #[error("compression error: {0}")]
+    CompressionError(#[from] CompressionError),
+
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("invalid zip archive `{path}`: {reason}")]
+    InvalidZip { path: String, reason: &'static str },
+
+    #[error("`{path}` exceeds the size limit of {max_bytes} bytes")]
+    SizeLimitExceeded { path: String, max_bytes: u64 },
+
+    #[error("reading `{path}` exceeds the time limit of {max_duration:?}")]
+    TimeLimitExceeded {
+        path: String,
+        max_duration: Duration,
+    },
+
+    #[error("the operation did not complete by its deadline")]
+    DeadlineExceeded,
+
+    #[error("`{path}` is not valid utf-8: {source}")]
+    InvalidUtf8 {
+        path: String,
+        #[source]
+        source: std::string::FromUtf8Error,
+    },
+
+    #[cfg(feature = "serde_json")]
+    #[error("`{path}` is not valid json: {source}")]
+    InvalidJson {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("{op} is not supported for `{path}`")]
+    Unsupported { path: String, op: &'static str },
+
+    #[error("refusing to delete everything under `{path}` with a zero age without force")]
+    ZeroAgeNotForced { path: String },
+
+    #[error("checksum mismatch of `{path}`: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+
+    /// A backend error together with the operation and the url or path it failed on.
+    #[error("failed to {op} `{path}`: {source}")]
+    Operation {
+        path: String,
+        op: &'static str,
+        #[source]
+        source: Box<FileUtilError>,
+    },
+}
+
+/// The class of an error, for handling it without matching the messages of each backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The file, object, bucket or url does not exist.
+    NotFound,
+    /// The caller is authenticated but not allowed to access it.
+    PermissionDenied,
+    /// The credentials are missing, invalid or expired.
+    Unauthorized,
+    /// The storage is throttling requests; retry later, more slowly.
+    RateLimited,
+    /// A failure that may go away on retry: a timeout, a network or server error.
+    Transient,
+    /// The url or path is malformed.
+    InvalidPath,
+    /// The operation is not supported for this kind of location.
+    Unsupported,
+    /// Anything else, e.g. contents that fail to decompress or parse.
+    Other,
+}
+
+impl FileUtilError {
+    /// The class of this error. An error in `FileUtilError::Operation` is classed by its source.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FileUtilError::GcsError(e) => e.kind(),
+            FileUtilError::WebError(e) => e.kind(),
+            FileUtilError::FsError(e) => e.kind(),
+            FileUtilError::IOError(e) => io_error_kind(e),
+            FileUtilError::TimeLimitExceeded { .. }
+            | FileUtilError::DeadlineExceeded
+            | FileUtilError::ChecksumMismatch { .. } => ErrorKind::Transient,
+            FileUtilError::Unsupported { .. } => ErrorKind::Unsupported,
+            FileUtilError::CompressionError(_)
+            | FileUtilError::InvalidZip { .. }
+            | FileUtilError::SizeLimitExceeded { .. }
+            | FileUtilError::InvalidUtf8 { .. }
+            | FileUtilError::ZeroAgeNotForced { .. } => ErrorKind::Other,
+            #[cfg(feature = "serde_json")]
+            FileUtilError::InvalidJson { .. } => ErrorKind::Other,
+            FileUtilError::Operation { source, .. } => source.kind(),
+        }
+    }
+
+    /// The http status a service built on this crate would answer with for this error:
+    /// 404 when the file does not exist, 403 when access is denied, 502 or 504 when the
+    /// storage behind failed or timed out (503 when it is rate limiting), 4xx for invalid
+    /// requests, and 500 otherwise.
+    pub fn to_http_status(&self) -> u16 {
+        match self {
+            FileUtilError::GcsError(e) => e.to_http_status(),
+            FileUtilError::WebError(e) => e.to_http_status(),
+            FileUtilError::FsError(e) => e.to_http_status(),
+            FileUtilError::IOError(e) => io_error_status(e),
+            FileUtilError::SizeLimitExceeded { .. } => 413,
+            FileUtilError::TimeLimitExceeded { .. } | FileUtilError::DeadlineExceeded => 504,
+            FileUtilError::ChecksumMismatch { .. } => 502,
+            FileUtilError::ZeroAgeNotForced { .. } => 400,
+            FileUtilError::Unsupported { .. } => 501,
+            FileUtilError::CompressionError(_)
+            | FileUtilError::InvalidZip { .. }
+            | FileUtilError::InvalidUtf8 { .. } => 500,
+            #[cfg(feature = "serde_json")]
+            FileUtilError::InvalidJson { .. } => 500,
+            FileUtilError::Operation { source, .. } => source.to_http_status(),
+        }
+    }
+}
+
+pub(crate) fn io_error_status(e: &std::io::Error) -> u16 {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => 404,
+        std::io::ErrorKind::PermissionDenied => 403,
+        std::io::ErrorKind::TimedOut => 504,
+        _ => 500,
+    }
+}
+
+pub(crate) fn io_error_kind(e: &std::io::Error) -> ErrorKind {
+    use std::io::ErrorKind as IoErrorKind;
+    match e.kind() {
+        IoErrorKind::NotFound => ErrorKind::NotFound,
+        IoErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        IoErrorKind::TimedOut
+        | IoErrorKind::Interrupted
+        | IoErrorKind::WouldBlock
+        | IoErrorKind::ConnectionReset
+        | IoErrorKind::ConnectionAborted
+        | IoErrorKind::BrokenPipe
+        | IoErrorKind::UnexpectedEof => ErrorKind::Transient,
+        IoErrorKind::Unsupported => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// Wrap a backend error into `FileUtilError::Operation`, unless it already carries the context.
+pub(crate) fn in_operation<'a, E: Into<FileUtilError>>(
+    op: &'static str,
+    path: &'a str,
+) -> impl FnOnce(E) -> FileUtilError + 'a {
+    move |e| match e.into() {
+        e @ FileUtilError::Operation { .. } => e,
+        e => FileUtilError::Operation {
+            path: path.to_string(),
+            op,
+            source: Box::new(e),
+        },
+    }
 }
 
 pub type Result<T> = std::result::Result<T, FileUtilError>;
 use url::Url;
 
-pub async fn list_files(
-    url_or_path_str: &str,
-    backoff: Option<ExponentialBackoff>,
-) -> Result<Vec<String>> {
+/// The url of a remote file, `None` for local paths. `file://` urls and Windows drive paths
+/// such as `C:\data\x.txt`, which `Url::parse` accepts with the drive letter as the scheme,
+/// are local.
+pub(crate) fn remote_url(url_or_path_str: &str) -> Option<Url> {
+    let url = Url::parse(url_or_path_str).ok()?;
+    if url.scheme() == "file" || url.scheme().len() == 1 {
+        return None;
+    }
+    Some(url)
+}
+
+/// The backend a url or path is served by, see `backend`.
+pub(crate) enum Backend {
+    /// A storage registered with `storage::register_scheme`.
+    Registered(Box<dyn storage::FileStorage>),
+    #[cfg(feature = "gcs")]
+    Gcs(gcs::GcsFile),
+    #[cfg(feature = "web")]
+    Web(Url),
+    #[cfg(feature = "fs")]
+    Local(std::path::PathBuf),
+}
+
+/// Choose the backend of a url or path. Registered schemes take precedence over the built-in
+/// backends, so every function that dispatches on the backend starts here.
+pub(crate) fn backend(url_or_path_str: &str) -> Result<Backend> {
+    if let Some(storage) = storage::registered_storage(url_or_path_str)? {
+        return Ok(Backend::Registered(storage));
+    }
+
     #[cfg(any(feature = "gcs", feature = "web"))]
-    if let Ok(url) = Url::parse(url_or_path_str.as_ref()) {
+    if let Some(url) = remote_url(url_or_path_str) {
         #[cfg(feature = "gcs")]
         if let Ok(gcs_file) = gcs::GcsFile::new_with_url(&url) {
-            let gcs_data = gcs_file.list_objects_with_retry(backoff).await?;
-            return Ok(gcs_data);
+            return Ok(Backend::Gcs(gcs_file));
         }
 
         #[cfg(feature = "web")]
         {
-            unimplemented!(
-                "listing directories under a url is not implemented yet. {}",
-                url_or_path_str
-            )
+            return Ok(Backend::Web(url));
         }
     };
 
     #[cfg(feature = "fs")]
     {
-        let local_file = fs::FileAccessor::new(url_or_path_str.into())?;
-        let result = local_file.list_directory()?;
-        Ok(result)
+        Ok(Backend::Local(fs::local_path(url_or_path_str)))
     }
 }
 
-pub async fn get_file_contents_str(
+/// Whether the current config is a dry run, see `ClientConfig::with_dry_run`, in which case
+/// what the operation would have done, `what`, is logged instead.
+fn dry_run(what: impl FnOnce() -> String) -> bool {
+    if !config::is_dry_run() {
+        return false;
+    }
+    log::info!("dry run: would {}", what());
+    true
+}
+
+/// `ExponentialBackoff` is not `Clone`. Copy its settings for operations that retry
+/// several steps independently.
+pub(crate) fn copy_backoff(backoff: &Option<ExponentialBackoff>) -> Option<ExponentialBackoff> {
+    backoff.as_ref().map(|backoff| ExponentialBackoff {
+        current_interval: backoff.initial_interval,
+        initial_interval: backoff.initial_interval,
+        randomization_factor: backoff.randomization_factor,
+        multiplier: backoff.multiplier,
+        max_interval: backoff.max_interval,
+        max_elapsed_time: backoff.max_elapsed_time,
+        ..ExponentialBackoff::default()
+    })
+}
+
+/// `target` relative to `base` when it lies under it, as in `gs://bucket/a/b/c` under
+/// `gs://bucket/a`, giving `b/c`. `None` when it does not, including when `target` equals
+/// `base` or the two are of different schemes, buckets or hosts.
+pub fn relative_path(base: &str, target: &str) -> Option<String> {
+    match (Url::parse(base), Url::parse(target)) {
+        (Ok(base), Ok(target)) => {
+            if base.scheme() != target.scheme()
+                || base.host_str() != target.host_str()
+                || base.port() != target.port()
+            {
+                return None;
+            }
+            let base_path = base.path().trim_end_matches('/');
+            let relative = target.path().strip_prefix(base_path)?.strip_prefix('/')?;
+            if relative.is_empty() {
+                return None;
+            }
+            Some(relative.to_string())
+        }
+        (Err(_), Err(_)) => {
+            let relative = std::path::Path::new(target)
+                .strip_prefix(base)
+                .ok()?
+                .display()
+                .to_string();
+            if relative.is_empty() {
+                return None;
+            }
+            Some(relative)
+        }
+        _ => None,
+    }
+}
+
+/// List the entries under a directory or prefix.
+/// With `limit`, at most that many entries are returned and listing stops as soon as it is reached.
+/// Web urls have no listing and fail with `FileUtilError::Unsupported`.
+/// The url or path is taken literally; see `list_files_glob` for listing by a pattern.
+pub async fn list_files(
     url_or_path_str: &str,
     backoff: Option<ExponentialBackoff>,
-    decompression: Option<Compression>,
-) -> Result<Option<String>> {
-    match get_file_contents(url_or_path_str, backoff, decompression).await {
-        Err(e) => Err(e),
-        Ok(c) => Ok(c.map(|contents| std::str::from_utf8(contents.as_ref()).unwrap().to_string())),
+    limit: Option<usize>,
+) -> Result<Vec<String>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let mut entries = storage.list(backoff).await?;
+            if let Some(limit) = limit {
+                entries.truncate(limit);
+            }
+            Ok(entries)
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let gcs_data = gcs_file
+                .list_objects_with_retry(backoff, limit)
+                .await
+                .map_err(in_operation("list", url_or_path_str))?;
+            Ok(gcs_data)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "listing",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("list", url_or_path_str))?;
+            let result = local_file
+                .list_directory(limit)
+                .await
+                .map_err(in_operation("list", url_or_path_str))?;
+            Ok(result)
+        }
     }
 }
 
-pub async fn get_file_contents(
+/// List the files and directories (with a trailing `/`) matching a url or path with glob
+/// patterns, e.g. `gs://bucket/data/2024-*/**/*.json.gz` or `./logs/**/*.log`, sorted.
+/// The directory in front of the first pattern is listed, recursively when the pattern spans
+/// components, and `limit` applies to the matching entries. A `\` escapes the character
+/// after it, e.g. `gs://bucket/report\[1\].csv`; without patterns this is `list_files`.
+pub async fn list_files_glob(
+    pattern: &str,
+    backoff: Option<ExponentialBackoff>,
+    limit: Option<usize>,
+) -> Result<Vec<String>> {
+    let glob = match glob::Glob::parse(pattern) {
+        Some(glob) => glob,
+        None => return list_files(&glob::unescape(pattern), backoff, limit).await,
+    };
+    let options = ListOptions {
+        recursive: glob.recursive,
+        include_directories: true,
+        ..Default::default()
+    };
+    let base = format!("{}/", glob.base.trim_end_matches('/'));
+    let mut entries: Vec<String> = list_files_with_options(&glob.base, backoff, &options)
+        .await
+        .map_err(in_operation("list", pattern))?
+        .into_iter()
+        .filter(|entry| {
+            let relative = entry.strip_prefix(&base).unwrap_or(entry);
+            glob.matches(relative.trim_end_matches('/'))
+        })
+        .collect();
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+    Ok(entries)
+}
+
+/// Options for `list_files_with_options`, applied alike to local directories and gcs prefixes.
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// List the whole tree under the directory instead of its direct entries.
+    pub recursive: bool,
+
+    /// Return at most this many entries, the first ones in sorted order.
+    pub max_results: Option<usize>,
+
+    /// Keep the entries whose path relative to the listed directory starts with this.
+    pub prefix: Option<String>,
+
+    /// Keep the entries ending with this, e.g. `.json.gz`. A directory is matched without
+    /// its trailing `/`.
+    pub suffix: Option<String>,
+
+    /// List directories too, with a trailing `/`. On gcs these are the prefixes objects are
+    /// named under, with or without a folder placeholder.
+    pub include_directories: bool,
+}
+
+/// List the entries under a directory, or a gcs prefix taken as one, as `options` says.
+/// Unlike `list_files`, folder placeholders are never listed as files, and the entries are
+/// sorted on both backends.
+pub async fn list_files_with_options(
     url_or_path_str: &str,
     backoff: Option<ExponentialBackoff>,
-    decompression: Option<Compression>,
-) -> Result<Option<Vec<u8>>> {
-    #[cfg(any(feature = "gcs", feature = "web"))]
-    if let Ok(url) = Url::parse(url_or_path_str.as_ref()) {
+    options: &ListOptions,
+) -> Result<Vec<String>> {
+    let (base, entries) = list_directory_entries(url_or_path_str, backoff, options)
+        .await
+        .map_err(in_operation("list", url_or_path_str))?;
+
+    let mut entries: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| {
+            let relative = entry.strip_prefix(&base).unwrap_or(entry);
+            let name = entry.strip_suffix('/').unwrap_or(entry);
+            options
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| relative.starts_with(prefix.as_str()))
+                && options
+                    .suffix
+                    .as_ref()
+                    .is_none_or(|suffix| name.ends_with(suffix.as_str()))
+        })
+        .collect();
+    entries.sort();
+    if let Some(max_results) = options.max_results {
+        entries.truncate(max_results);
+    }
+    Ok(entries)
+}
+
+/// The entries under the directory, along with the prefix they share, for
+/// matching `ListOptions::prefix` against the rest.
+async fn list_directory_entries(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    options: &ListOptions,
+) -> Result<(String, Vec<String>)> {
+    let base = format!("{}/", url_or_path_str.trim_end_matches('/'));
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => Ok((base, storage.list(backoff).await?)),
         #[cfg(feature = "gcs")]
-        if let Ok(gcs_file) = gcs::GcsFile::new_with_url(&url) {
-            let gcs_data = gcs_file
-                .download_with_retry(backoff, decompression.clone())
+        Backend::Gcs(gcs_file) => {
+            let entries = gcs_file
+                .list_directory_with_retry(options.recursive, options.include_directories, backoff)
                 .await?;
-            return Ok(gcs_data);
+            Ok((base, entries))
         }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "listing",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_dir = fs::FileAccessor::new(path)?;
+            let entries = local_dir
+                .list_entries(options.recursive, options.include_directories)
+                .await?;
+            Ok((base, entries))
+        }
+    }
+}
+
+/// Like `list_files`, but the entries are yielded as they are listed, so processing can start
+/// before a listing of millions of objects is complete. Gcs pages are requested as the
+/// stream is consumed, each retried on its own, and local directories are read lazily.
+pub fn list_files_stream(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> impl Stream<Item = Result<String>> + Send + 'static {
+    let path = url_or_path_str.to_string();
+    list_entries_stream(url_or_path_str, backoff)
+        .unwrap_or_else(|e| futures::stream::once(async { Err(e) }).boxed())
+        .map_err(move |e| in_operation("list", &path)(e))
+}
 
+fn list_entries_stream(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<BoxStream<'static, Result<String>>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            Ok(
+                futures::stream::once(async move { storage.list(backoff).await })
+                    .map_ok(|entries| futures::stream::iter(entries.into_iter().map(Ok)))
+                    .try_flatten()
+                    .boxed(),
+            )
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => Ok(gcs_file
+            .list_objects_stream_with_retry(backoff)
+            .err_into()
+            .boxed()),
         #[cfg(feature = "web")]
-        {
-            let web_data = web::download_from_url_with_retry(url, backoff, decompression).await?;
-            return Ok(web_data);
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "listing",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let mut dir = Some(path);
+            let mut read_dir: Option<std::fs::ReadDir> = None;
+            let entries = std::iter::from_fn(move || {
+                if let Some(dir) = dir.take() {
+                    match std::fs::read_dir(dir) {
+                        Ok(opened) => read_dir = Some(opened),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                let entry = read_dir.as_mut()?.next()?;
+                Some(entry.map(|entry| entry.path().display().to_string()))
+            });
+            Ok(blocking_stream(entries)
+                .map_err(|e| FileUtilError::from(fs::FileUtilFsError::from(e)))
+                .boxed())
         }
-    };
+    }
+}
 
-    #[cfg(feature = "fs")]
-    {
-        let local_file = fs::FileAccessor::new(url_or_path_str.into())?;
-        let result = local_file.read()?;
-        Ok(result)
+/// Run the blocking `f` on the blocking thread pool, resuming its panic if it panics.
+#[cfg(any(feature = "fs", feature = "web"))]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
     }
 }
 
-pub async fn is_exists(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
-    #[cfg(any(feature = "gcs", feature = "web"))]
-    if let Ok(url) = Url::parse(url_or_path_str.as_ref()) {
+/// The items of `entries`, taken on the blocking pool `BLOCKING_BATCH_LEN` at a time as the
+/// stream is consumed, so that reading directories does not block the runtime.
+#[cfg(feature = "fs")]
+fn blocking_stream<T, I>(entries: I) -> impl Stream<Item = T> + Send + 'static
+where
+    T: Send + 'static,
+    I: Iterator<Item = T> + Send + 'static,
+{
+    futures::stream::unfold(entries, |mut entries| async move {
+        let (batch, entries) = unblock(move || {
+            let batch: Vec<T> = entries.by_ref().take(BLOCKING_BATCH_LEN).collect();
+            (batch, entries)
+        })
+        .await;
+        (!batch.is_empty()).then(|| (futures::stream::iter(batch), entries))
+    })
+    .flatten()
+}
+
+#[cfg(feature = "fs")]
+const BLOCKING_BATCH_LEN: usize = 256;
+
+/// Whether a `WalkEntry` is a file or a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// An entry found by `walk`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalkEntry {
+    /// The url or path of the entry. Directories end with `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    /// The size of a file; `None` for directories and the files of registered storages.
+    pub size: Option<u64>,
+}
+
+/// Walk the tree under a directory, or a gcs prefix taken as one, yielding every file and
+/// directory in it as it is found. Gcs pages are requested as the stream is consumed, and
+/// directories are the prefixes objects are named under, each yielded before its first
+/// entry. Local directories are read one at a time, in no particular order, and symbolic
+/// links to directories are yielded but not descended into. A storage registered with
+/// `storage::register_scheme` yields what it lists, as files.
+pub fn walk(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> impl Stream<Item = Result<WalkEntry>> + Send + 'static {
+    let path = url_or_path_str.to_string();
+    walk_entries(url_or_path_str, backoff)
+        .unwrap_or_else(|e| futures::stream::once(async { Err(e) }).boxed())
+        .map_err(move |e| in_operation("walk", &path)(e))
+}
+
+fn walk_entries(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<BoxStream<'static, Result<WalkEntry>>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            Ok(
+                futures::stream::once(async move { storage.list(backoff).await })
+                    .map_ok(|entries| {
+                        futures::stream::iter(entries.into_iter().map(|path| {
+                            Ok(WalkEntry {
+                                path,
+                                kind: EntryKind::File,
+                                size: None,
+                            })
+                        }))
+                    })
+                    .try_flatten()
+                    .boxed(),
+            )
+        }
         #[cfg(feature = "gcs")]
-        if let Ok(gcs_file) = gcs::GcsFile::new_with_url(&url) {
-            let gcs_data = gcs_file.is_exists_with_retry(backoff).await?;
-            return Ok(gcs_data);
+        Backend::Gcs(gcs_file) => {
+            let dir = gcs::GcsFile {
+                trailing_slash: true,
+                ..gcs_file
+            };
+            let dir_prefix = format!("{}/", dir.name);
+            let bucket = dir.bucket.clone();
+            let mut walked_dirs = std::collections::HashSet::<String>::new();
+            let objects = dir.list_object_info_stream_with_retry(backoff);
+            Ok(objects
+                .err_into::<FileUtilError>()
+                .map_ok(move |object| {
+                    let relative = &object.name[dir_prefix.len()..];
+                    let mut entries = Vec::<Result<WalkEntry>>::new();
+                    for (i, _) in relative.match_indices('/') {
+                        let dir_name = format!("{}{}/", dir_prefix, &relative[..i]);
+                        if walked_dirs.insert(dir_name.clone()) {
+                            entries.push(Ok(WalkEntry {
+                                path: format!("gs://{}/{}", bucket, dir_name),
+                                kind: EntryKind::Directory,
+                                size: None,
+                            }));
+                        }
+                    }
+                    // a folder placeholder is taken for its directory, yielded above
+                    if !object.name.ends_with('/') {
+                        entries.push(Ok(WalkEntry {
+                            path: format!("gs://{}/{}", bucket, object.name),
+                            kind: EntryKind::File,
+                            size: Some(object.size),
+                        }));
+                    }
+                    futures::stream::iter(entries)
+                })
+                .try_flatten()
+                .boxed())
         }
-
         #[cfg(feature = "web")]
-        {
-            let web_data = web::url_exists_with_retry(url, backoff).await?;
-            return Ok(web_data);
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "walking",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let mut dirs = vec![path];
+            let mut current: Option<std::fs::ReadDir> = None;
+            let entries = std::iter::from_fn(move || loop {
+                let read_dir = match current.as_mut() {
+                    Some(read_dir) => read_dir,
+                    None => match std::fs::read_dir(dirs.pop()?) {
+                        Ok(read_dir) => current.insert(read_dir),
+                        Err(e) => return Some(Err(e)),
+                    },
+                };
+                let path = match read_dir.next() {
+                    Some(Ok(entry)) => entry.path(),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {
+                        current = None;
+                        continue;
+                    }
+                };
+                let entry = (|| {
+                    let is_symlink = std::fs::symlink_metadata(&path)?.file_type().is_symlink();
+                    Ok(match std::fs::metadata(&path) {
+                        Ok(metadata) if metadata.is_dir() => {
+                            if !is_symlink {
+                                dirs.push(path.clone());
+                            }
+                            WalkEntry {
+                                path: format!("{}/", path.display()),
+                                kind: EntryKind::Directory,
+                                size: None,
+                            }
+                        }
+                        metadata => WalkEntry {
+                            path: path.display().to_string(),
+                            kind: EntryKind::File,
+                            // a dangling link has no size
+                            size: metadata.ok().map(|metadata| metadata.len()),
+                        },
+                    })
+                })();
+                return Some(entry);
+            });
+            Ok(blocking_stream(entries)
+                .map_err(FileUtilError::from)
+                .boxed())
         }
-    };
+    }
+}
 
-    #[cfg(feature = "fs")]
-    {
-        let local_file = fs::FileAccessor::new(url_or_path_str.into())?;
-        let result = local_file.is_exists()?;
-        Ok(result)
+/// List several directories or prefixes, at most `concurrency` at a time, and concatenate
+/// the entries in the order of `prefixes`.
+/// With `dedup`, the merged entries are sorted and duplicates (from overlapping prefixes) removed.
+pub async fn list_files_multi(
+    prefixes: &[String],
+    concurrency: usize,
+    backoff: Option<ExponentialBackoff>,
+    dedup: bool,
+) -> Result<Vec<String>> {
+    let listings: Vec<Vec<String>> = futures::stream::iter(prefixes.iter())
+        .map(|prefix| list_files(prefix, copy_backoff(&backoff), None))
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    let mut entries = listings.concat();
+    if dedup {
+        entries.sort();
+        entries.dedup();
     }
+    Ok(entries)
 }
 
-pub async fn write_contents<'a>(
-    url_or_path_str: &'a str,
-    body: &[u8],
-    mime_type: mime::MimeType,
+/// Like `list_files`, but a failure part way through a long listing does not lose the
+/// entries gathered so far: they are returned along with the error that stopped the listing.
+pub async fn list_files_best_effort(
+    url_or_path_str: &str,
     backoff: Option<ExponentialBackoff>,
-    compression: Option<compression::Compression>,
-) -> Result<()> {
-    #[cfg(any(feature = "gcs", feature = "web"))]
-    if let Ok(url) = Url::parse(url_or_path_str.as_ref()) {
+) -> (Vec<String>, Option<FileUtilError>) {
+    let backend = match backend(url_or_path_str) {
+        Ok(backend) => backend,
+        Err(e) => return (vec![], Some(in_operation("list", url_or_path_str)(e))),
+    };
+    match backend {
+        Backend::Registered(storage) => match storage.list(backoff).await {
+            Ok(entries) => (entries, None),
+            Err(e) => (vec![], Some(in_operation("list", url_or_path_str)(e))),
+        },
         #[cfg(feature = "gcs")]
-        if let Ok(gcs_file) = gcs::GcsFile::new_with_url(&url) {
-            gcs_file
-                .write_with_retry(body, mime_type, backoff, compression)
-                .await?;
-            return Ok(());
+        Backend::Gcs(gcs_file) => {
+            let (entries, err) = gcs_file.list_objects_best_effort_with_retry(backoff).await;
+            (entries, err.map(in_operation("list", url_or_path_str)))
         }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => {
+            let unsupported = FileUtilError::Unsupported {
+                path: url_or_path_str.to_string(),
+                op: "listing",
+            };
+            (vec![], Some(unsupported))
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => match fs::FileAccessor::new(path) {
+            Ok(local_file) => {
+                let (entries, err) = local_file.list_directory_best_effort().await;
+                (entries, err.map(in_operation("list", url_or_path_str)))
+            }
+            Err(e) => (vec![], Some(in_operation("list", url_or_path_str)(e))),
+        },
+    }
+}
 
+/// Total size in bytes of the files under a directory or the objects under a prefix.
+/// Web urls and storages registered with `storage::register_scheme` fail with
+/// `FileUtilError::Unsupported`.
+pub async fn directory_size(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<u64> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "size",
+        }),
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let size = gcs_file
+                .total_size_with_retry(backoff)
+                .await
+                .map_err(in_operation("size", url_or_path_str))?;
+            Ok(size)
+        }
         #[cfg(feature = "web")]
-        {
-            unimplemented!("writing at url is not implemented yet. {}", url_or_path_str)
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "size",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("size", url_or_path_str))?;
+            let size = local_file
+                .directory_size()
+                .await
+                .map_err(in_operation("size", url_or_path_str))?;
+            Ok(size)
+        }
+    }
+}
+
+/// Read the file as UTF-8 text. Contents that are not UTF-8 fail with
+/// `FileUtilError::InvalidUtf8`; see `get_file_contents_str_with_encoding` for other encodings.
+pub async fn get_file_contents_str(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<String>> {
+    get_file_contents_str_with_encoding(url_or_path_str, backoff, decompression, TextEncoding::Utf8)
+        .await
+}
+
+/// How `get_file_contents_str_with_encoding` turns the contents into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    /// Fail with `FileUtilError::InvalidUtf8` on contents that are not UTF-8.
+    Utf8,
+    /// Replace invalid UTF-8 sequences with U+FFFD.
+    Utf8Lossy,
+    /// Decode from a charset of `encoding_rs`, e.g. `encoding_rs::SHIFT_JIS`, or
+    /// `encoding_rs::WINDOWS_1252` for Latin-1. Malformed sequences are replaced with U+FFFD,
+    /// and a UTF-8 or UTF-16 byte order mark takes precedence over the charset.
+    Charset(&'static encoding_rs::Encoding),
+}
+
+/// Read the file as text in `encoding`, after decompressing it.
+pub async fn get_file_contents_str_with_encoding(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+    encoding: TextEncoding,
+) -> Result<Option<String>> {
+    let contents = match get_file_contents(url_or_path_str, backoff, decompression).await? {
+        Some(contents) => contents,
+        None => return Ok(None),
+    };
+    let text = match encoding {
+        TextEncoding::Utf8 => {
+            String::from_utf8(contents).map_err(|source| FileUtilError::InvalidUtf8 {
+                path: url_or_path_str.to_string(),
+                source,
+            })?
         }
+        TextEncoding::Utf8Lossy => String::from_utf8_lossy(&contents).into_owned(),
+        TextEncoding::Charset(charset) => charset.decode(&contents).0.into_owned(),
     };
+    Ok(Some(text))
+}
 
-    #[cfg(feature = "fs")]
+/// Read a text file such as a configuration, decompressing it when its magic bytes say it is
+/// compressed whatever its name, and dropping a leading byte order mark.
+/// Contents that are not UTF-8 fail with `FileUtilError::InvalidUtf8`.
+pub async fn read_config_str(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<String>> {
+    let raw = match get_file_contents(url_or_path_str, backoff, None).await? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let contents = match Compression::from_magic_bytes(&raw) {
+        Some(compression) => compression
+            .decompress(&raw)
+            .map_err(in_operation("decompress", url_or_path_str))?,
+        None => raw,
+    };
+    let text = String::from_utf8(contents).map_err(|source| FileUtilError::InvalidUtf8 {
+        path: url_or_path_str.to_string(),
+        source,
+    })?;
+    match text.strip_prefix('\u{feff}') {
+        Some(stripped) => Ok(Some(stripped.to_string())),
+        None => Ok(Some(text)),
+    }
+}
+
+/// The value at the RFC 6901 `pointer` (e.g. `/items/0/name`) of a JSON file, or `None` when
+/// the file does not exist or the pointer does not resolve. The file is streamed through the
+/// parser, decompressed on the fly with `decompression`, and only the value at the pointer
+/// is built, so memory is bound by that value and the longest string skipped over, not by
+/// the size of the file. The whole document is still read, to be checked as JSON.
+/// Only opening the file is retried.
+#[cfg(feature = "serde_json")]
+pub async fn get_json_pointer(
+    url_or_path_str: &str,
+    pointer: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<serde_json::Value>> {
+    let tokens = match json_pointer::tokens(pointer) {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+    let source = match open_read_stream(url_or_path_str, backoff)
+        .await
+        .map_err(in_operation("read", url_or_path_str))?
     {
-        let local_file = fs::FileAccessor::new(url_or_path_str.into())?;
-        local_file.write(body, compression)?;
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let decoder = decompression
+        .as_ref()
+        .map(stream::StreamDecoder::new)
+        .transpose()
+        .map_err(in_operation("decompress", url_or_path_str))?;
+    let mut reader = BlockingReader {
+        source,
+        decoder,
+        buffer: Bytes::new(),
+        ended: false,
+        path: url_or_path_str.to_string(),
+        error: None,
+    };
+    let (parsed, error) = unblock(move || {
+        let parsed = json_pointer::read_pointer(&mut reader, &tokens);
+        (parsed, reader.error)
+    })
+    .await;
+    match (parsed, error) {
+        (Ok(value), _) => Ok(value),
+        // the parser only saw an io error, the cause is kept aside
+        (Err(_), Some(error)) => Err(error),
+        (Err(source), None) => Err(FileUtilError::InvalidJson {
+            path: url_or_path_str.to_string(),
+            source,
+        }),
+    }
+}
+
+/// A `Read` over the chunks of a stream, decompressed with `decoder`, which blocks on the
+/// stream for each chunk; to be read on the blocking pool. An error of the stream is kept
+/// in `error`, as the reader can only pass it on as an `std::io::Error`.
+#[cfg(feature = "serde_json")]
+struct BlockingReader {
+    source: BoxStream<'static, Result<Bytes>>,
+    decoder: Option<stream::StreamDecoder>,
+    /// Bytes read from the stream, not yet read from this.
+    buffer: Bytes,
+    ended: bool,
+    path: String,
+    error: Option<FileUtilError>,
+}
+
+#[cfg(feature = "serde_json")]
+impl BlockingReader {
+    /// Refill the buffer, leaving it empty at the end of the stream.
+    fn fill(&mut self) -> Result<()> {
+        while self.buffer.is_empty() && !self.ended {
+            let chunk = futures::executor::block_on(self.source.try_next())
+                .map_err(in_operation("read", &self.path))?;
+            self.buffer = match (chunk, self.decoder.as_mut()) {
+                (Some(chunk), Some(decoder)) => decoder
+                    .write(&chunk)
+                    .map_err(in_operation("decompress", &self.path))?
+                    .into(),
+                (Some(chunk), None) => chunk,
+                (None, _) => {
+                    self.ended = true;
+                    match self.decoder.take() {
+                        Some(decoder) => decoder
+                            .finish()
+                            .map_err(in_operation("decompress", &self.path))?
+                            .into(),
+                        None => Bytes::new(),
+                    }
+                }
+            };
+        }
         Ok(())
     }
 }
 
-pub async fn delete_contents(
+#[cfg(feature = "serde_json")]
+impl std::io::Read for BlockingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Err(e) = self.fill() {
+            let io_error = std::io::Error::other(e.to_string());
+            self.error = Some(e);
+            return Err(io_error);
+        }
+        let n = buf.len().min(self.buffer.len());
+        buf[..n].copy_from_slice(&self.buffer.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Read a JSON file into `T`, decompressing it first, or `None` when it does not exist.
+/// A leading byte order mark is skipped.
+#[cfg(feature = "serde_json")]
+pub async fn get_json<T: serde::de::DeserializeOwned>(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<T>> {
+    let contents = match get_file_contents(url_or_path_str, backoff, decompression).await? {
+        Some(contents) => contents,
+        None => return Ok(None),
+    };
+    let json = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&contents);
+    let value = serde_json::from_slice(json).map_err(|source| FileUtilError::InvalidJson {
+        path: url_or_path_str.to_string(),
+        source,
+    })?;
+    Ok(Some(value))
+}
+
+/// Write `value` as JSON, typed `MimeType::Json`, compressing it with `compression`.
+#[cfg(feature = "serde_json")]
+pub async fn put_json<T: serde::Serialize + ?Sized>(
     url_or_path_str: &str,
+    value: &T,
     backoff: Option<ExponentialBackoff>,
+    compression: Option<Compression>,
 ) -> Result<()> {
-    #[cfg(any(feature = "gcs", feature = "web"))]
-    if let Ok(url) = Url::parse(url_or_path_str.as_ref()) {
-        #[cfg(feature = "gcs")]
-        if let Ok(gcs_file) = gcs::GcsFile::new_with_url(&url) {
-            gcs_file.delete_with_retry(backoff).await?;
-            return Ok(());
+    let body = serde_json::to_vec(value).map_err(|source| FileUtilError::InvalidJson {
+        path: url_or_path_str.to_string(),
+        source,
+    })?;
+    write_contents(
+        url_or_path_str,
+        body,
+        mime::MimeType::Json,
+        backoff,
+        compression,
+    )
+    .await
+}
+
+pub async fn get_file_contents(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<Vec<u8>>> {
+    let sized = get_file_contents_with_size(url_or_path_str, backoff, decompression).await?;
+    Ok(sized.map(|sized| sized.contents))
+}
+
+/// Read several files, at most `concurrency` at a time, returning the result of each in
+/// the order of `urls`: `None` for a file that does not exist, or the error its read failed
+/// with after its retries. One failure does not stop the others.
+pub async fn get_many(
+    urls: &[String],
+    concurrency: usize,
+    backoff: Option<ExponentialBackoff>,
+) -> Vec<Result<Option<Vec<u8>>>> {
+    futures::stream::iter(urls.iter())
+        .map(|url_or_path_str| get_file_contents(url_or_path_str, copy_backoff(&backoff), None))
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Try the locations in order and return the contents of the first one that exists,
+/// along with that location, e.g. a local override before a remote default.
+/// Errors other than "not found" stop the search.
+pub async fn get_first_existing(
+    paths: &[String],
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<(String, Vec<u8>)>> {
+    for path in paths {
+        let contents =
+            get_file_contents(path, copy_backoff(&backoff), decompression.clone()).await?;
+        if let Some(contents) = contents {
+            return Ok(Some((path.clone(), contents)));
         }
+    }
+    Ok(None)
+}
+
+/// Same as `get_file_contents`, also reporting the size of the contents as fetched
+/// (before decompression) so that callers can observe the compression ratio.
+pub async fn get_file_contents_with_size(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<SizedContents>> {
+    let transfer = progress::Transfer::start(url_or_path_str, progress::Direction::Download, None);
+    let result = read_sized(url_or_path_str, backoff, decompression).await;
+    let transferred = result
+        .as_ref()
+        .ok()
+        .map(|sized| sized.as_ref().map_or(0, |sized| sized.raw_size as u64));
+    progress::report_whole(transfer, transferred);
+    result
+}
 
+async fn read_sized(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<SizedContents>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let contents = storage.read(backoff).await?;
+            let sized = decompress_opt_sized(contents, decompression)
+                .map_err(in_operation("read", url_or_path_str))?;
+            Ok(sized)
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let gcs_data = gcs_file
+                .download_sized_with_retry(backoff, decompression.clone())
+                .await
+                .map_err(in_operation("read", url_or_path_str))?;
+            Ok(gcs_data)
+        }
         #[cfg(feature = "web")]
-        {
-            unimplemented!("deleting url is not implemented yet. {}", url_or_path_str)
+        Backend::Web(url) => {
+            let web_data = web::download_from_url_sized(
+                url,
+                backoff,
+                decompression,
+                &web::DownloadOptions::default(),
+            )
+            .await
+            .map_err(in_operation("read", url_or_path_str))?;
+            Ok(web_data)
         }
-    };
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("read", url_or_path_str))?;
+            let result = local_file
+                .read_sized(decompression)
+                .await
+                .map_err(in_operation("read", url_or_path_str))?;
+            Ok(result)
+        }
+    }
+}
 
-    #[cfg(feature = "fs")]
+/// Stream the contents into `writer` while hashing them, so that the file is never held
+/// in memory, and fail with `FileUtilError::ChecksumMismatch` when the hash differs from
+/// `expected` (hex or base64). The writer, an `AsyncWrite` (the `futures` one) such as the
+/// one of `open_write`, has received and flushed every byte by then.
+/// Only starting the download is retried. Returns the number of bytes written,
+/// or `None` when the file does not exist.
+pub async fn download_to_writer_verified<W: futures::io::AsyncWrite + Unpin>(
+    url_or_path_str: &str,
+    writer: &mut W,
+    expected: &str,
+    algo: ChecksumAlgo,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<u64>> {
+    let stream = match open_read_stream(url_or_path_str, backoff)
+        .await
+        .map_err(in_operation("read", url_or_path_str))?
     {
-        let local_file = fs::FileAccessor::new(url_or_path_str.into())?;
-        local_file.delete()?;
-        Ok(())
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+    futures::pin_mut!(stream);
+
+    let mut hasher = checksum::Hasher::new(algo);
+    let mut written = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(in_operation("read", url_or_path_str))?;
+        hasher.update(&chunk);
+        writer
+            .write_all(&chunk)
+            .await
+            .map_err(in_operation("read", url_or_path_str))?;
+        written += chunk.len() as u64;
+    }
+    writer
+        .flush()
+        .await
+        .map_err(in_operation("read", url_or_path_str))?;
+
+    let digest = hasher.finish();
+    if !checksum::matches_checksum(&digest, expected) {
+        return Err(FileUtilError::ChecksumMismatch {
+            path: url_or_path_str.to_string(),
+            expected: expected.to_string(),
+            actual: hex::encode(digest),
+        });
+    }
+    Ok(Some(written))
+}
+
+/// The `algo` checksum of the file as it is stored, i.e. not decompressed, or `None` when
+/// it does not exist, e.g. to check an upload without downloading it again. Gcs objects
+/// answer with the md5 or crc32c in their metadata and web urls with the digest headers of
+/// a HEAD request, see `web::digest_from_headers`, when they have one of `algo`. Otherwise,
+/// and for local files, the contents are streamed through the hash, never held whole.
+pub async fn checksum(
+    url_or_path_str: &str,
+    algo: ChecksumAlgo,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    match backend(url_or_path_str)? {
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let object = gcs_file
+                .metadata_with_retry(copy_backoff(&backoff))
+                .await
+                .map_err(in_operation("checksum", url_or_path_str))?;
+            match object.map(|object| gcs::object_checksum(&object, algo)) {
+                None => Ok(None),
+                Some(Some(digest)) => Ok(Some(digest)),
+                Some(None) => hash_contents(url_or_path_str, algo, backoff).await,
+            }
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let headers = web::head_with_retry(url, copy_backoff(&backoff))
+                .await
+                .map_err(in_operation("checksum", url_or_path_str))?;
+            match headers.map(|headers| web::digest_from_headers(&headers, algo)) {
+                None => Ok(None),
+                Some(Some(digest)) => Ok(Some(digest)),
+                Some(None) => hash_contents(url_or_path_str, algo, backoff).await,
+            }
+        }
+        _ => hash_contents(url_or_path_str, algo, backoff).await,
+    }
+}
+
+async fn hash_contents(
+    url_or_path_str: &str,
+    algo: ChecksumAlgo,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    let stream = match open_read_stream(url_or_path_str, backoff)
+        .await
+        .map_err(in_operation("checksum", url_or_path_str))?
+    {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+    futures::pin_mut!(stream);
+
+    let mut hasher = checksum::Hasher::new(algo);
+    while let Some(chunk) = stream.next().await {
+        hasher.update(&chunk.map_err(in_operation("checksum", url_or_path_str))?);
+    }
+    Ok(Some(hasher.finish()))
+}
+
+/// Read a file, giving up once it turns out larger than `max_bytes` or once reading has
+/// taken `max_duration`, whichever comes first, for locations supplied by untrusted users.
+/// The contents are streamed, so no more than `max_bytes` (plus a chunk) is ever held.
+/// Fails with `FileUtilError::SizeLimitExceeded` or `FileUtilError::TimeLimitExceeded`.
+pub async fn get_file_contents_guarded(
+    url_or_path_str: &str,
+    max_bytes: u64,
+    max_duration: Duration,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    let read = async {
+        let stream = match open_read_stream(url_or_path_str, backoff)
+            .await
+            .map_err(in_operation("read", url_or_path_str))?
+        {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        futures::pin_mut!(stream);
+
+        let mut contents = Vec::<u8>::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(in_operation("read", url_or_path_str))?;
+            if contents.len() as u64 + chunk.len() as u64 > max_bytes {
+                return Err(FileUtilError::SizeLimitExceeded {
+                    path: url_or_path_str.to_string(),
+                    max_bytes,
+                });
+            }
+            contents.extend_from_slice(&chunk);
+        }
+        Ok(Some(contents))
+    };
+
+    tokio::time::timeout(max_duration, read)
+        .await
+        .unwrap_or_else(|_| {
+            Err(FileUtilError::TimeLimitExceeded {
+                path: url_or_path_str.to_string(),
+                max_duration,
+            })
+        })
+}
+
+/// Open the file for reading as it is stored, without holding it whole in memory, e.g. to
+/// process multi-gigabyte files. Only opening it is retried.
+/// This is the `futures` `AsyncRead`; tokio code can adapt it with `tokio-util`'s `compat`.
+/// Returns `None` when the file does not exist.
+pub async fn open_read(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<impl futures::io::AsyncRead + Send + Unpin>> {
+    let stream = open_read_stream(url_or_path_str, backoff)
+        .await
+        .map_err(in_operation("read", url_or_path_str))?;
+    let path = url_or_path_str.to_string();
+    Ok(stream.map(move |stream| {
+        stream
+            .map_err(move |e| std::io::Error::other(in_operation("read", &path)(e)))
+            .into_async_read()
+    }))
+}
+
+async fn open_read_stream(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<BoxStream<'static, Result<Bytes>>>> {
+    if config::progress_observer().is_none() {
+        return open_backend_stream(url_or_path_str, backoff).await;
+    }
+    let total = match stat(url_or_path_str, copy_backoff(&backoff)).await {
+        Ok(metadata) => metadata.and_then(|metadata| metadata.size),
+        Err(_) => None,
+    };
+    let stream = open_backend_stream(url_or_path_str, backoff).await?;
+    Ok(stream.map(|stream| {
+        progress::observe_stream(
+            url_or_path_str,
+            progress::Direction::Download,
+            total,
+            stream,
+        )
+        .boxed()
+    }))
+}
+
+async fn open_backend_stream(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<BoxStream<'static, Result<Bytes>>>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let contents = storage.read(backoff).await?;
+            Ok(contents
+                .map(|contents| futures::stream::once(async { Ok(Bytes::from(contents)) }).boxed()))
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let stream = gcs_file.download_stream_with_retry(backoff).await?;
+            Ok(stream.map(|stream| {
+                stream
+                    .map_err(|e| FileUtilError::from(gcs::FileUtilGcsError::from(e)))
+                    .boxed()
+            }))
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let stream = web::download_stream_from_url_with_retry(url, backoff).await?;
+            Ok(stream.map(|stream| stream.map_err(FileUtilError::from).boxed()))
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file = fs::FileAccessor::new(path)?;
+            let stream = local_file.read_stream().await?;
+            Ok(stream.map(|stream| stream.map_err(FileUtilError::from).boxed()))
+        }
+    }
+}
+
+/// Read `length` bytes starting at `offset`, e.g. the header of a huge file, with a `Range`
+/// request for web urls, a ranged download for gcs and a seek for local files. Fewer bytes
+/// are returned when the file ends first, and none when `offset` is past its end. The bytes
+/// are as stored, not decompressed. A storage registered with `storage::register_scheme`
+/// is read whole and cut. Returns `None` when the file does not exist.
+pub async fn get_file_range(
+    url_or_path_str: &str,
+    offset: u64,
+    length: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    read_range(url_or_path_str, offset, Some(length), backoff).await
+}
+
+/// Read the last `length` bytes, e.g. the footer of a huge file, or all of a shorter file,
+/// ranged like `get_file_range`. Returns `None` when the file does not exist.
+pub async fn get_file_tail(
+    url_or_path_str: &str,
+    length: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    read_tail(url_or_path_str, length, backoff).await
+}
+
+/// Read `length` bytes (or up to the end with `None`) starting at `offset`,
+/// without fetching the rest of the file where the backend allows it.
+pub(crate) async fn read_range(
+    url_or_path_str: &str,
+    offset: u64,
+    length: Option<u64>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let contents = storage.read(backoff).await?;
+            Ok(contents.map(|contents| {
+                let start = (offset as usize).min(contents.len());
+                let end = match length {
+                    Some(length) => start.saturating_add(length as usize).min(contents.len()),
+                    None => contents.len(),
+                };
+                contents[start..end].to_vec()
+            }))
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let gcs_data = gcs_file
+                .download_range_with_retry(offset, length, backoff)
+                .await
+                .map_err(in_operation("read range of", url_or_path_str))?;
+            Ok(gcs_data)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let web_data = web::download_range_from_url_with_retry(url, offset, length, backoff)
+                .await
+                .map_err(in_operation("read range of", url_or_path_str))?;
+            Ok(web_data)
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file = fs::FileAccessor::new(path)
+                .map_err(in_operation("read range of", url_or_path_str))?;
+            let result = local_file
+                .read_range(offset, length)
+                .await
+                .map_err(in_operation("read range of", url_or_path_str))?;
+            Ok(result)
+        }
+    }
+}
+
+/// Read the last `length` bytes (or all of a shorter file) without fetching the rest
+/// where the backend allows it.
+pub(crate) async fn read_tail(
+    url_or_path_str: &str,
+    length: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let contents = storage.read(backoff).await?;
+            Ok(contents.map(|contents| {
+                let start = contents.len().saturating_sub(length as usize);
+                contents[start..].to_vec()
+            }))
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let gcs_data = gcs_file
+                .download_tail_with_retry(length, backoff)
+                .await
+                .map_err(in_operation("read tail of", url_or_path_str))?;
+            Ok(gcs_data)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let web_data = web::download_tail_from_url_with_retry(url, length, backoff)
+                .await
+                .map_err(in_operation("read tail of", url_or_path_str))?;
+            Ok(web_data)
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file = fs::FileAccessor::new(path)
+                .map_err(in_operation("read tail of", url_or_path_str))?;
+            let result = local_file
+                .read_tail(length)
+                .await
+                .map_err(in_operation("read tail of", url_or_path_str))?;
+            Ok(result)
+        }
+    }
+}
+
+/// Bytes fetched first from the end of a zip archive, enough for the end of central directory
+/// record and, usually, the central directory itself.
+const ZIP_TAIL_GUESS_LEN: u64 = 8 * 1024;
+
+/// List the entries of a zip archive reading only its end: the end of central directory record
+/// and the central directory. Returns `None` when the archive does not exist.
+pub async fn list_zip_entries_remote(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<archive::ZipEntry>>> {
+    let invalid_zip = |reason| FileUtilError::InvalidZip {
+        path: url_or_path_str.to_string(),
+        reason,
+    };
+
+    let mut tail =
+        match read_tail(url_or_path_str, ZIP_TAIL_GUESS_LEN, copy_backoff(&backoff)).await? {
+            Some(tail) => tail,
+            None => return Ok(None),
+        };
+    let mut found = archive::find_eocd(&tail);
+    if found.is_none() && tail.len() as u64 == ZIP_TAIL_GUESS_LEN {
+        // a long archive comment puts the record further from the end
+        let max_tail_len = archive::EOCD_LEN + archive::MAX_COMMENT_LEN;
+        tail = read_tail(url_or_path_str, max_tail_len, copy_backoff(&backoff))
+            .await?
+            .unwrap_or_default();
+        found = archive::find_eocd(&tail);
+    }
+    let (eocd_pos, central) =
+        found.ok_or_else(|| invalid_zip("end of central directory is not found"))?;
+
+    // the central directory normally ends where the record starts, so it may have been read already
+    let in_tail = (eocd_pos as u64)
+        .checked_sub(central.size)
+        .and_then(|start| archive::parse_central_directory(&tail[start as usize..eocd_pos]).ok());
+    if let Some(entries) = in_tail {
+        return Ok(Some(entries));
+    }
+    let central =
+        match read_range(url_or_path_str, central.offset, Some(central.size), backoff).await? {
+            Some(central) => central,
+            None => return Ok(None),
+        };
+    let entries = archive::parse_central_directory(&central).map_err(invalid_zip)?;
+    Ok(Some(entries))
+}
+
+/// Read the file as consecutive windows of `window_size` bytes, the last one possibly shorter,
+/// fetching each with a ranged read so only one window is held at a time. When a web server
+/// ignores the range, the windows are cut from the body it answers with instead.
+/// A missing file yields no windows.
+pub fn read_windows(
+    url_or_path_str: &str,
+    window_size: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> impl Stream<Item = Result<Vec<u8>>> {
+    let url_or_path = url_or_path_str.to_string();
+    let window_size = window_size.max(1);
+    futures::stream::try_unfold(Windows::Ranged(0), move |windows| {
+        let url_or_path = url_or_path.clone();
+        let backoff = copy_backoff(&backoff);
+        async move { next_window(windows, &url_or_path, window_size, backoff).await }
+    })
+}
+
+/// Where `read_windows` reads its next window from.
+enum Windows {
+    /// A ranged read at this offset.
+    Ranged(u64),
+    /// The whole body, of which `skip` more bytes are before the window and `pending` were
+    /// read past the last one.
+    Body {
+        body: BoxStream<'static, Result<Bytes>>,
+        pending: Vec<u8>,
+        skip: u64,
+    },
+    Done,
+}
+
+async fn next_window(
+    windows: Windows,
+    url_or_path_str: &str,
+    window_size: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<(Vec<u8>, Windows)>> {
+    let offset = match windows {
+        Windows::Ranged(offset) => offset,
+        Windows::Body {
+            body,
+            pending,
+            skip,
+        } => return body_window(body, pending, skip, url_or_path_str, window_size).await,
+        Windows::Done => return Ok(None),
+    };
+
+    #[cfg(feature = "web")]
+    if let Backend::Web(url) = backend(url_or_path_str)? {
+        let ranged = web::download_range_or_body_from_url_with_retry(
+            url,
+            offset,
+            Some(window_size),
+            backoff,
+        )
+        .await
+        .map_err(in_operation("read", url_or_path_str))?;
+        let window = match ranged {
+            Some(web::RangedBody::Range(window)) => window,
+            Some(web::RangedBody::Full(body)) => {
+                let body = body.err_into().boxed();
+                return body_window(body, vec![], offset, url_or_path_str, window_size).await;
+            }
+            None => vec![],
+        };
+        return Ok(ranged_window(window, offset, window_size));
+    }
+
+    let window = read_range(url_or_path_str, offset, Some(window_size), backoff)
+        .await?
+        .unwrap_or_default();
+    Ok(ranged_window(window, offset, window_size))
+}
+
+/// The next window cut from `body`, after skipping `skip` bytes of it and starting with
+/// `pending`.
+async fn body_window(
+    mut body: BoxStream<'static, Result<Bytes>>,
+    mut pending: Vec<u8>,
+    mut skip: u64,
+    url_or_path_str: &str,
+    window_size: u64,
+) -> Result<Option<(Vec<u8>, Windows)>> {
+    while (pending.len() as u64) < window_size {
+        let chunk = match body
+            .try_next()
+            .await
+            .map_err(in_operation("read", url_or_path_str))?
+        {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        let skipped = skip.min(chunk.len() as u64);
+        skip -= skipped;
+        pending.extend_from_slice(&chunk[skipped as usize..]);
+    }
+    if pending.is_empty() {
+        return Ok(None);
+    }
+    let rest = pending.split_off(pending.len().min(window_size as usize));
+    let next = if pending.len() as u64 == window_size {
+        Windows::Body {
+            body,
+            pending: rest,
+            skip,
+        }
+    } else {
+        Windows::Done
+    };
+    Ok(Some((pending, next)))
+}
+
+/// The window read at `offset`, followed by the one after it unless this one came short.
+fn ranged_window(window: Vec<u8>, offset: u64, window_size: u64) -> Option<(Vec<u8>, Windows)> {
+    if window.is_empty() {
+        return None;
+    }
+    let next = if window.len() as u64 == window_size {
+        Windows::Ranged(offset + window_size)
+    } else {
+        Windows::Done
+    };
+    Some((window, next))
+}
+
+/// Stream the lines of a text file, decompressing it on the fly with `decompression`, so a
+/// large log is never held whole. Lines end with `\n` or `\r\n`, which are stripped, and the
+/// last one may end without either. A line that is not utf-8 fails with
+/// `FileUtilError::InvalidUtf8`. Only opening the file is retried.
+/// Returns `None` when the file does not exist.
+pub async fn read_lines(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<impl Stream<Item = Result<String>> + Send + 'static>> {
+    let source = match open_read_stream(url_or_path_str, backoff)
+        .await
+        .map_err(in_operation("read", url_or_path_str))?
+    {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let decoder = decompression
+        .as_ref()
+        .map(stream::StreamDecoder::new)
+        .transpose()
+        .map_err(in_operation("decompress", url_or_path_str))?;
+    let reader = LineReader {
+        source,
+        decoder,
+        buffer: Vec::new(),
+        lines: std::collections::VecDeque::new(),
+        ended: false,
+    };
+    let path = url_or_path_str.to_string();
+    Ok(Some(futures::stream::try_unfold(
+        reader,
+        move |mut reader| {
+            let path = path.clone();
+            async move {
+                loop {
+                    if let Some(line) = reader.lines.pop_front() {
+                        let line = String::from_utf8(line).map_err(|source| {
+                            FileUtilError::InvalidUtf8 {
+                                path: path.clone(),
+                                source,
+                            }
+                        })?;
+                        return Ok(Some((line, reader)));
+                    }
+                    if reader.ended {
+                        return Ok(None);
+                    }
+                    reader.read_chunk(&path).await?;
+                }
+            }
+        },
+    )))
+}
+
+/// Stream the records of an NDJSON (JSON lines) file read with `read_lines`, skipping
+/// blank lines. A record that does not parse fails with `FileUtilError::InvalidJson`.
+/// Returns `None` when the file does not exist.
+#[cfg(feature = "serde_json")]
+pub async fn read_ndjson<T: serde::de::DeserializeOwned + Send + 'static>(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> Result<Option<impl Stream<Item = Result<T>> + Send + 'static>> {
+    let lines = match read_lines(url_or_path_str, backoff, decompression).await? {
+        Some(lines) => lines,
+        None => return Ok(None),
+    };
+    let path = url_or_path_str.to_string();
+    Ok(Some(
+        lines
+            .try_filter(|line| futures::future::ready(!line.trim().is_empty()))
+            .and_then(move |line| {
+                let parsed =
+                    serde_json::from_str(&line).map_err(|source| FileUtilError::InvalidJson {
+                        path: path.clone(),
+                        source,
+                    });
+                futures::future::ready(parsed)
+            }),
+    ))
+}
+
+/// The state of `read_lines`: the chunks still to read, and the lines split off so far.
+struct LineReader {
+    source: BoxStream<'static, Result<Bytes>>,
+    decoder: Option<stream::StreamDecoder>,
+    /// Bytes of the line not yet ended.
+    buffer: Vec<u8>,
+    /// Lines split off, not yet checked to be utf-8.
+    lines: std::collections::VecDeque<Vec<u8>>,
+    ended: bool,
+}
+
+impl LineReader {
+    async fn read_chunk(&mut self, path: &str) -> Result<()> {
+        match self
+            .source
+            .try_next()
+            .await
+            .map_err(in_operation("read", path))?
+        {
+            Some(chunk) => match self.decoder.as_mut() {
+                Some(decoder) => {
+                    let decompressed = decoder
+                        .write(&chunk)
+                        .map_err(in_operation("decompress", path))?;
+                    self.buffer.extend(decompressed);
+                }
+                None => self.buffer.extend_from_slice(&chunk),
+            },
+            None => {
+                if let Some(decoder) = self.decoder.take() {
+                    let rest = decoder.finish().map_err(in_operation("decompress", path))?;
+                    self.buffer.extend(rest);
+                }
+                self.ended = true;
+            }
+        }
+
+        let mut start = 0;
+        while let Some(end) = self.buffer[start..].iter().position(|b| *b == b'\n') {
+            let line = &self.buffer[start..start + end];
+            self.lines
+                .push_back(line.strip_suffix(b"\r").unwrap_or(line).to_vec());
+            start += end + 1;
+        }
+        self.buffer.drain(..start);
+        if self.ended && !self.buffer.is_empty() {
+            self.lines.push_back(std::mem::take(&mut self.buffer));
+        }
+        Ok(())
+    }
+}
+
+/// Read every file under a directory or prefix, subdirectories included, yielding each path
+/// with its contents in listing order while up to `concurrency` reads are in flight.
+/// A file that fails to read yields its error and the stream goes on; a failed listing
+/// yields a single error. Files removed since the listing are left out.
+pub fn read_all_under_prefix(
+    url_or_path_prefix: &str,
+    concurrency: usize,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+) -> impl Stream<Item = Result<(String, Vec<u8>)>> {
+    let prefix = url_or_path_prefix.to_string();
+    let list_backoff = copy_backoff(&backoff);
+    futures::stream::once(async move { list_files_recursive(&prefix, list_backoff).await })
+        .map(move |listed| -> BoxStream<'static, Result<String>> {
+            match listed {
+                Ok(paths) => futures::stream::iter(paths.into_iter().map(Ok)).boxed(),
+                Err(e) => futures::stream::once(async { Err(e) }).boxed(),
+            }
+        })
+        .flatten()
+        .map(move |path| {
+            let backoff = copy_backoff(&backoff);
+            let decompression = decompression.clone();
+            async move {
+                let path = path?;
+                let contents = get_file_contents(&path, backoff, decompression)
+                    .await
+                    .map_err(in_operation("read", &path))?;
+                Ok(contents.map(|contents| (path, contents)))
+            }
+        })
+        .buffered(concurrency.max(1))
+        .filter_map(|read| async move { read.transpose() })
+}
+
+/// Paths of the files under a directory or prefix, subdirectories included.
+async fn list_files_recursive(
+    url_or_path_prefix: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Vec<String>> {
+    match backend(url_or_path_prefix)? {
+        Backend::Registered(storage) => storage.list(backoff).await,
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let names = gcs_file
+                .list_objects_with_retry(backoff, None)
+                .await
+                .map_err(in_operation("list", url_or_path_prefix))?;
+            // folder placeholders have no contents of their own
+            Ok(names
+                .into_iter()
+                .filter(|name| !name.ends_with('/'))
+                .collect())
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_prefix.to_string(),
+            op: "listing",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_dir =
+                fs::FileAccessor::new(path).map_err(in_operation("list", url_or_path_prefix))?;
+            let paths = local_dir
+                .list_directory_recursive(fs::SymlinkPolicy::Skip)
+                .await
+                .map_err(in_operation("list", url_or_path_prefix))?;
+            Ok(paths)
+        }
+    }
+}
+
+/// Sniff the actual compression of a file from its magic number, fetching only its first bytes.
+/// Unlike `Compression::from_extention` this does not trust the file name.
+/// Returns `None` for uncompressed or missing files.
+pub async fn detect_compression(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Compression>> {
+    let head = read_range(
+        url_or_path_str,
+        0,
+        Some(Compression::MAGIC_LEN as u64),
+        backoff,
+    )
+    .await?;
+    Ok(head.and_then(|head| Compression::from_magic_bytes(&head)))
+}
+
+pub async fn is_exists(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => storage.exists(backoff).await,
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let gcs_data = gcs_file
+                .is_exists_with_retry(backoff)
+                .await
+                .map_err(in_operation("check existence of", url_or_path_str))?;
+            Ok(gcs_data)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let web_data = web::url_exists_with_retry(url, backoff)
+                .await
+                .map_err(in_operation("check existence of", url_or_path_str))?;
+            Ok(web_data)
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file = fs::FileAccessor::new(path)
+                .map_err(in_operation("check existence of", url_or_path_str))?;
+            let result = local_file
+                .is_exists()
+                .await
+                .map_err(in_operation("check existence of", url_or_path_str))?;
+            Ok(result)
+        }
+    }
+}
+
+/// Whether a file exists, as `is_exists_strict` found out.
+#[derive(Debug)]
+pub enum Existence {
+    Exists,
+    NotFound,
+    /// The backend failed to tell, e.g. because access was denied.
+    Indeterminate(FileUtilError),
+}
+
+impl Existence {
+    /// `None` when it is indeterminate.
+    pub fn exists(&self) -> Option<bool> {
+        match self {
+            Existence::Exists => Some(true),
+            Existence::NotFound => Some(false),
+            Existence::Indeterminate(_) => None,
+        }
+    }
+}
+
+/// Like `is_exists`, but only an answer that the file is not there is taken for absence,
+/// so that a 403 is not mistaken for it. Web urls answering any status but 404 or 410 to
+/// a GET, gcs errors but not found, local paths whose metadata can not be read, and any
+/// error left once the retries are over are `Existence::Indeterminate`. Errors that only
+/// would be answered again are not retried.
+pub async fn is_exists_strict(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Existence {
+    match exists_strict(url_or_path_str, backoff).await {
+        Ok(true) => Existence::Exists,
+        Ok(false) => Existence::NotFound,
+        Err(e) => Existence::Indeterminate(e),
+    }
+}
+
+async fn exists_strict(url_or_path_str: &str, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => storage.exists(backoff).await,
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => gcs_file
+            .is_exists_strict_with_retry(backoff)
+            .await
+            .map_err(in_operation("check existence of", url_or_path_str)),
+        #[cfg(feature = "web")]
+        Backend::Web(url) => web::url_exists_strict_with_retry(url, backoff)
+            .await
+            .map_err(in_operation("check existence of", url_or_path_str)),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file = fs::FileAccessor::new(path)
+                .map_err(in_operation("check existence of", url_or_path_str))?;
+            local_file
+                .is_exists_strict()
+                .await
+                .map_err(in_operation("check existence of", url_or_path_str))
+        }
+    }
+}
+
+/// What `stat` knows about a file, whichever backend holds it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMetadata {
+    /// `None` when a web server does not send a `Content-Length`.
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+    /// When the gcs object or local file was created. Web servers do not tell, nor does
+    /// every filesystem.
+    pub created: Option<SystemTime>,
+    pub content_type: Option<String>,
+    /// The `ETag` header of a web response or the etag of a gcs object. Local files have none.
+    pub etag: Option<String>,
+    pub is_dir: bool,
+}
+
+impl FileMetadata {
+    fn directory() -> Self {
+        Self {
+            size: None,
+            modified: None,
+            created: None,
+            content_type: None,
+            etag: None,
+            is_dir: true,
+        }
+    }
+}
+
+/// The metadata of a file or directory, read from the fs, from the gcs object or from the
+/// headers of a HEAD request, without reading the contents. A gcs path is a directory when
+/// objects are named under it. Returns `None` when it does not exist.
+pub async fn stat(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<FileMetadata>> {
+    match backend(url_or_path_str)? {
+        Backend::Registered(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "stat",
+        }),
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            if !gcs_file.trailing_slash {
+                let object = gcs_file
+                    .metadata_with_retry(copy_backoff(&backoff))
+                    .await
+                    .map_err(in_operation("stat", url_or_path_str))?;
+                if let Some(object) = object {
+                    return Ok(Some(FileMetadata {
+                        size: Some(object.size),
+                        modified: Some(SystemTime::from(object.updated)),
+                        created: Some(SystemTime::from(object.time_created)),
+                        content_type: object.content_type,
+                        etag: Some(object.etag),
+                        is_dir: false,
+                    }));
+                }
+            }
+            let is_dir = gcs_file
+                .is_directory_with_retry(backoff)
+                .await
+                .map_err(in_operation("stat", url_or_path_str))?;
+            Ok(is_dir.then(FileMetadata::directory))
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let headers = web::head_with_retry(url, backoff)
+                .await
+                .map_err(in_operation("stat", url_or_path_str))?;
+            Ok(headers.map(|headers| {
+                let header = |name| {
+                    headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                FileMetadata {
+                    size: header(http::header::CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+                    modified: header(http::header::LAST_MODIFIED)
+                        .and_then(|date| web::parse_http_date(&date)),
+                    created: None,
+                    content_type: header(http::header::CONTENT_TYPE),
+                    etag: header(http::header::ETAG),
+                    is_dir: false,
+                }
+            }))
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("stat", url_or_path_str))?;
+            let metadata = local_file
+                .metadata()
+                .await
+                .map_err(in_operation("stat", url_or_path_str))?;
+            Ok(metadata.map(|metadata| FileMetadata {
+                size: Some(metadata.size),
+                modified: metadata.modified,
+                created: metadata.created,
+                content_type: None,
+                etag: None,
+                is_dir: metadata.file_type == fs::LocalFileType::Dir,
+            }))
+        }
+    }
+}
+
+/// Poll `is_exists` every `poll_interval` (with ±20% jitter, so that many waiters do not poll
+/// in lockstep) until the file appears or `timeout` elapses.
+/// `backoff` applies to each existence check. Returns whether the file appeared.
+pub async fn wait_for_existence(
+    url_or_path_str: &str,
+    poll_interval: Duration,
+    timeout: Duration,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if is_exists(url_or_path_str, copy_backoff(&backoff)).await? {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let jittered = poll_interval.mul_f64(rand::thread_rng().gen_range(0.8..=1.2));
+        tokio::time::sleep(jittered.min(deadline - now)).await;
+    }
+}
+
+/// Run `operation`, e.g. `get_file_contents(..)`, `list_files(..)` or `write_contents(..)`,
+/// failing with `FileUtilError::DeadlineExceeded` unless it completes within `timeout`.
+/// Unlike the `max_elapsed_time` of the backoff, which is checked only between attempts,
+/// this bounds the whole operation, so a hung request is given up too. The operation is
+/// dropped once the timeout elapses; a write given up that way may or may not have
+/// happened, except that a local file opened with `open_write` is left as it was.
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    with_deadline(Instant::now() + timeout, operation).await
+}
+
+/// Like `with_timeout`, with the time `operation` must complete by, e.g. to share one
+/// deadline among several operations.
+pub async fn with_deadline<T>(
+    deadline: Instant,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout_at(deadline, operation)
+        .await
+        .unwrap_or(Err(FileUtilError::DeadlineExceeded))
+}
+
+/// Write `body` as the file. A `Vec<u8>` or `Bytes` body is taken without copying it, and
+/// is shared between the attempts of a retried write. A local file is written only into
+/// an existing directory; `write_contents_with_options` with `WriteOptions::create_parents`
+/// creates the missing ones.
+pub async fn write_contents(
+    url_or_path_str: &str,
+    body: impl Into<Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+    compression: Option<compression::Compression>,
+) -> Result<()> {
+    let options = writer::WriteOptions {
+        mime_type: Some(mime_type),
+        compression,
+        ..writer::WriteOptions::default()
+    };
+    write_contents_with_options(url_or_path_str, body, backoff, &options)
+        .await
+        .map(|_| ())
+}
+
+/// Write `body` as the file unless it exists, e.g. to claim a lock or a job once. The
+/// check and the write are one atomic operation for local files and web urls, but only best
+/// effort for gcs objects, see `write_contents_with_options`.
+/// Returns whether the file was written.
+pub async fn write_contents_if_absent(
+    url_or_path_str: &str,
+    body: impl Into<Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    let options = writer::WriteOptions::new()
+        .with_mime_type(mime_type)
+        .with_if_not_exists(true);
+    write_contents_with_options(url_or_path_str, body, backoff, &options).await
+}
+
+/// Write `body` as the file as `options` say, e.g. only when it does not exist yet.
+/// Local files and storages registered with `storage::register_scheme` have no place for
+/// the cache control and custom metadata, and are written without them.
+///
+/// `if_not_exists` creates a local file exclusively (`O_EXCL`), and is sent to web servers
+/// as `If-None-Match: *`, both atomically; `if_match` applies to web urls, which are written
+/// with a PUT. For gcs objects `if_not_exists` means generation 0 and `if_generation_match`
+/// that generation, which the gcs client checks with a read before the upload, so that is
+/// best effort: writers that check at the same time can all succeed, and the last one wins.
+/// Only a client set with `config::ClientConfig::with_gcs_client` that sends the
+/// `ifGenerationMatch` precondition makes it atomic. A registered storage is checked for
+/// `if_not_exists` before it is written, and takes no other precondition.
+/// Returns whether the file was written, i.e. `false` only when a precondition kept it.
+pub async fn write_contents_with_options(
+    url_or_path_str: &str,
+    body: impl Into<Bytes>,
+    backoff: Option<ExponentialBackoff>,
+    options: &writer::WriteOptions,
+) -> Result<bool> {
+    if dry_run(|| format!("write {}", url_or_path_str)) {
+        return Ok(true);
+    }
+    let body = body.into();
+    let length = body.len() as u64;
+    let transfer =
+        progress::Transfer::start(url_or_path_str, progress::Direction::Upload, Some(length));
+    let result = write_with_options(url_or_path_str, body, backoff, options).await;
+    progress::report_whole(transfer, result.as_ref().ok().map(|_| length));
+    result
+}
+
+async fn write_with_options(
+    url_or_path_str: &str,
+    body: Bytes,
+    backoff: Option<ExponentialBackoff>,
+    options: &writer::WriteOptions,
+) -> Result<bool> {
+    let compression = options.compression.clone();
+    let unsupported = || FileUtilError::Unsupported {
+        path: url_or_path_str.to_string(),
+        op: "conditional write",
+    };
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            if options.if_generation_match.is_some() || options.if_match.is_some() {
+                return Err(unsupported());
+            }
+            if options.if_not_exists && storage.exists(copy_backoff(&backoff)).await? {
+                return Ok(false);
+            }
+            let body =
+                compress_opt(&body, compression).map_err(in_operation("write", url_or_path_str))?;
+            storage
+                .write(&body, options.mime_type_for(url_or_path_str), backoff)
+                .await?;
+            Ok(true)
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            if options.if_match.is_some() {
+                return Err(unsupported());
+            }
+            gcs_file
+                .write_with_options_with_retry(body, options, backoff)
+                .await
+                .map_err(in_operation("write", url_or_path_str))
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            if options.if_generation_match.is_some() {
+                return Err(unsupported());
+            }
+            let body =
+                compress_opt(&body, compression).map_err(in_operation("write", url_or_path_str))?;
+            web::put_with_retry(
+                url,
+                body.into(),
+                options.mime_type_for(url_or_path_str),
+                options.if_not_exists,
+                options.if_match.as_deref(),
+                backoff,
+            )
+            .await
+            .map_err(in_operation("write", url_or_path_str))
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            if options.if_generation_match.is_some() || options.if_match.is_some() {
+                return Err(unsupported());
+            }
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("write", url_or_path_str))?;
+            if options.create_parents {
+                local_file
+                    .create_parents()
+                    .await
+                    .map_err(in_operation("write", url_or_path_str))?;
+            }
+            if options.if_not_exists {
+                return local_file
+                    .write_new(body, compression)
+                    .await
+                    .map_err(in_operation("write", url_or_path_str));
+            }
+            let mode = if options.atomic {
+                fs::WriteMode::Atomic
+            } else {
+                fs::WriteMode::InPlace
+            };
+            local_file
+                .write(body, compression, mode)
+                .await
+                .map_err(in_operation("write", url_or_path_str))?;
+            Ok(true)
+        }
+    }
+}
+
+/// Append `body` to the file, creating it as `mime_type` when it does not exist, e.g. for
+/// logs written incrementally. Local files are appended to natively; gcs objects are
+/// composed with an upload of `body`, see `GcsFile::append_with_retry` for its limits.
+/// Web urls and storages registered with `storage::register_scheme` fail with
+/// `FileUtilError::Unsupported`.
+pub async fn append_contents(
+    url_or_path_str: &str,
+    body: impl Into<Bytes>,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<()> {
+    let body = body.into();
+    let unsupported = || FileUtilError::Unsupported {
+        path: url_or_path_str.to_string(),
+        op: "append to",
+    };
+    if dry_run(|| format!("append to {}", url_or_path_str)) {
+        return Ok(());
+    }
+    match backend(url_or_path_str)? {
+        Backend::Registered(_) => Err(unsupported()),
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => gcs_file
+            .append_with_retry(body, mime_type, backoff)
+            .await
+            .map_err(in_operation("append to", url_or_path_str)),
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(unsupported()),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("append to", url_or_path_str))?;
+            local_file
+                .append(body)
+                .await
+                .map_err(in_operation("append to", url_or_path_str))
+        }
+    }
+}
+
+/// Write every entry of `entries`, at most `concurrency` at a time, returning the result of
+/// each in the order of `entries`. Unlike `write_batch_atomic`, one failure does not stop
+/// or roll back the others; its error names the destination that failed.
+pub async fn write_many<I>(
+    entries: I,
+    concurrency: usize,
+    backoff: Option<ExponentialBackoff>,
+) -> Vec<Result<()>>
+where
+    I: IntoIterator<Item = (String, Vec<u8>, mime::MimeType)>,
+{
+    futures::stream::iter(entries)
+        .map(|(url_or_path_str, body, mime_type)| {
+            let backoff = copy_backoff(&backoff);
+            async move {
+                write_contents(&url_or_path_str, body, mime_type, backoff, None)
+                    .await
+                    .map_err(in_operation("write", &url_or_path_str))
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Write every entry of `entries`, or none of them: when a write fails, the entries written
+/// before it are rolled back, restoring the contents they had (read up front) or deleting
+/// the ones that did not exist. The backends have no transactions, so this is best effort;
+/// other writers can observe the intermediate state, and a failing rollback is only logged.
+/// Restored contents are written with the mime type of the entry.
+pub async fn write_batch_atomic(
+    entries: Vec<(String, Vec<u8>, mime::MimeType)>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<()> {
+    // shared, so that each write and rollback does not copy its body
+    let entries: Vec<(String, Bytes, mime::MimeType)> = entries
+        .into_iter()
+        .map(|(url_or_path_str, body, mime_type)| (url_or_path_str, Bytes::from(body), mime_type))
+        .collect();
+    let mut snapshots = Vec::<Option<Vec<u8>>>::new();
+    for (url_or_path_str, _, _) in entries.iter() {
+        snapshots.push(get_file_contents(url_or_path_str, copy_backoff(&backoff), None).await?);
+    }
+
+    for (written, (url_or_path_str, body, mime_type)) in entries.iter().enumerate() {
+        let result = write_contents(
+            url_or_path_str,
+            body.clone(),
+            mime_type.clone(),
+            copy_backoff(&backoff),
+            None,
+        )
+        .await;
+        if let Err(e) = result {
+            for ((url_or_path_str, _, mime_type), snapshot) in
+                entries[..written].iter().zip(snapshots).rev()
+            {
+                let rollback = match snapshot {
+                    Some(previous) => {
+                        write_contents(
+                            url_or_path_str,
+                            previous,
+                            mime_type.clone(),
+                            copy_backoff(&backoff),
+                            None,
+                        )
+                        .await
+                    }
+                    None => delete_contents(url_or_path_str, copy_backoff(&backoff)).await,
+                };
+                if let Err(rollback_error) = rollback {
+                    log::warn!(
+                        "failed to roll back {} : {}",
+                        url_or_path_str,
+                        rollback_error
+                    );
+                }
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Same as `write_contents`, but skips the write when the destination already holds the
+/// same contents. Gcs objects are compared by the checksum in their metadata; a web url is
+/// read with a GET and, when it differs, written with a PUT like `write_contents` does.
+/// Returns whether a write actually occurred.
+pub async fn write_contents_if_changed(
+    url_or_path_str: &str,
+    body: &[u8],
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+    compression: Option<compression::Compression>,
+) -> Result<bool> {
+    if dry_run(|| format!("write {}", url_or_path_str)) {
+        return Ok(true);
+    }
+
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let body =
+                compress_opt(body, compression).map_err(in_operation("write", url_or_path_str))?;
+            if storage.read(copy_backoff(&backoff)).await?.as_deref() == Some(body.as_slice()) {
+                return Ok(false);
+            }
+            storage.write(&body, mime_type, backoff).await?;
+            Ok(true)
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let written = gcs_file
+                .write_if_changed_with_retry(body, mime_type, backoff, compression)
+                .await
+                .map_err(in_operation("write", url_or_path_str))?;
+            Ok(written)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            let body =
+                compress_opt(body, compression).map_err(in_operation("write", url_or_path_str))?;
+            let current =
+                web::download_from_url_with_retry(url.clone(), copy_backoff(&backoff), None)
+                    .await
+                    .map_err(in_operation("read", url_or_path_str))?;
+            if current.as_deref() == Some(body.as_slice()) {
+                return Ok(false);
+            }
+            web::put_with_retry(url, body.into(), mime_type, false, None, backoff)
+                .await
+                .map_err(in_operation("write", url_or_path_str))?;
+            Ok(true)
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("write", url_or_path_str))?;
+            let written = local_file
+                .write_if_changed(Bytes::copy_from_slice(body), compression)
+                .await
+                .map_err(in_operation("write", url_or_path_str))?;
+            Ok(written)
+        }
+    }
+}
+
+/// Upload a stream of chunks without materializing the whole payload,
+/// e.g. records serialized on the fly.
+/// The stream can be consumed only once, so unlike `write_contents` a failed upload is not retried.
+pub async fn upload_stream<S>(
+    url_or_path_str: &str,
+    body: S,
+    mime_type: mime::MimeType,
+) -> Result<()>
+where
+    S: Stream<Item = Result<Bytes>> + Send + Sync + 'static,
+{
+    if dry_run(|| format!("upload to {}", url_or_path_str)) {
+        return Ok(());
+    }
+    let body = progress::observe_stream(url_or_path_str, progress::Direction::Upload, None, body);
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => {
+            let chunks: Vec<Bytes> = body.try_collect().await?;
+            storage.write(&chunks.concat(), mime_type, None).await
+        }
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let body = body.map_err(|e| Box::new(e) as gcs::client::BoxError);
+            gcs_file
+                .upload_stream(Box::pin(body), mime_type)
+                .await
+                .map_err(in_operation("upload to", url_or_path_str))?;
+            Ok(())
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(url) => {
+            web::upload_stream_to_url(url, body, mime_type)
+                .await
+                .map_err(in_operation("upload to", url_or_path_str))?;
+            Ok(())
+        }
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("upload to", url_or_path_str))?;
+            local_file
+                .write_stream(body)
+                .await
+                .map_err(in_operation("upload to", url_or_path_str))?;
+            Ok(())
+        }
+    }
+}
+
+/// Open the file for writing with an `AsyncWrite` (the `futures` one) that streams what is
+/// written to the backend, instead of taking the whole body like `write_contents`.
+/// Complete the write with `finalize`, or give it up with `abort`. A local file is written
+/// to a temporary file beside it, which replaces it only on `finalize`.
+/// Like `upload_stream`, a failed upload is not retried.
+pub fn open_write(
+    url_or_path_str: &str,
+    options: writer::WriteOptions,
+) -> Result<writer::StreamWriter> {
+    let mime_type = options.mime_type_for(url_or_path_str);
+    let compression = options.compression;
+    let path = url_or_path_str.to_string();
+    if dry_run(|| format!("write {}", url_or_path_str)) {
+        return writer::StreamWriter::new(compression, None, |body| -> writer::Upload {
+            Box::pin(body.try_for_each(|_| futures::future::ready(Ok(()))))
+        })
+        .map_err(in_operation("write", url_or_path_str));
+    }
+    match backend(url_or_path_str)? {
+        #[cfg(feature = "fs")]
+        Backend::Local(destination) => {
+            let file_name = destination.file_name().ok_or_else(|| {
+                let e = std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file path");
+                in_operation("write", url_or_path_str)(e)
+            })?;
+            let temp_path = destination.with_file_name(format!(
+                ".{}.{:016x}.tmp",
+                file_name.to_string_lossy(),
+                rand::random::<u64>()
+            ));
+            let temp = temp_path.clone();
+            writer::StreamWriter::new(
+                compression,
+                Some(temp_path),
+                move |body| -> writer::Upload {
+                    Box::pin(async move {
+                        let body = progress::observe_stream(
+                            &path,
+                            progress::Direction::Upload,
+                            None,
+                            body,
+                        );
+                        let written = match fs::FileAccessor::new(temp.clone()) {
+                            Ok(local_file) => local_file.write_stream(body).await,
+                            Err(e) => Err(e),
+                        };
+                        let result = match written {
+                            Ok(()) => {
+                                let (temp, destination) = (temp.clone(), destination.clone());
+                                unblock(move || std::fs::rename(temp, destination))
+                                    .await
+                                    .map_err(FileUtilError::from)
+                            }
+                            Err(e) => Err(FileUtilError::from(e)),
+                        };
+                        if result.is_err() {
+                            let temp = temp.clone();
+                            let _ = unblock(move || std::fs::remove_file(temp)).await;
+                        }
+                        result.map_err(in_operation("write", &path))
+                    })
+                },
+            )
+            .map_err(in_operation("write", url_or_path_str))
+        }
+        _ => writer::StreamWriter::new(compression, None, move |body| -> writer::Upload {
+            Box::pin(async move { upload_stream(&path, body, mime_type).await })
+        })
+        .map_err(in_operation("write", url_or_path_str)),
+    }
+}
+
+/// Copy `src` to `dst`, which can be on different backends, e.g. a local file to gcs or an
+/// http url to a local file. The contents are streamed through with `open_write`, and
+/// stored as `application/octet-stream`. Between objects of the same gcs bucket the copy is
+/// made by gcs itself; a rewrite to another bucket can take several calls, which the gcs
+/// client does not make, so those are streamed too. A local file is copied to a local path
+/// by `FileAccessor::copy_to`, keeping its permissions.
+/// Only opening the source is retried; a failed write is not.
+/// Returns `Ok(false)` when `src` does not exist.
+pub async fn copy_contents(
+    src: &str,
+    dst: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    if dry_run(|| format!("copy {} to {}", src, dst)) {
+        return is_exists(src, backoff).await;
+    }
+    #[cfg(feature = "gcs")]
+    if let (Some(source), Some(destination)) = (gcs_file_of(src), gcs_file_of(dst)) {
+        if source.bucket == destination.bucket {
+            return source
+                .copy_to(&destination, backoff)
+                .await
+                .map_err(in_operation("copy", src));
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    if let Some((source, destination)) = local_files_of(src, dst)? {
+        return source
+            .copy_to(&destination, true)
+            .await
+            .map_err(in_operation("copy", src));
+    }
+
+    let options = writer::WriteOptions::new().with_mime_type(mime::MimeType::OctetStream);
+    stream_contents(src, dst, backoff, options).await
+}
+
+/// Stream `src` through `open_write` to `dst`. Returns `Ok(false)` when `src` does not exist.
+async fn stream_contents(
+    src: &str,
+    dst: &str,
+    backoff: Option<ExponentialBackoff>,
+    options: writer::WriteOptions,
+) -> Result<bool> {
+    let mut source = match open_read_stream(src, backoff)
+        .await
+        .map_err(in_operation("read", src))?
+    {
+        Some(source) => source,
+        None => return Ok(false),
+    };
+    let mut destination = open_write(dst, options)?;
+    while let Some(chunk) = source.next().await {
+        let written = match chunk {
+            Ok(chunk) => destination.write_all(&chunk).await,
+            Err(e) => {
+                destination.abort().await?;
+                return Err(in_operation("read", src)(e));
+            }
+        };
+        if let Err(e) = written {
+            destination.abort().await?;
+            return Err(in_operation("write", dst)(e));
+        }
+    }
+    destination.finalize().await?;
+    Ok(true)
+}
+
+/// Move `src` to `dst`, which can be on different backends. A local file moved to a local
+/// path on the same filesystem is renamed, and an object moved within its gcs bucket is
+/// rewritten with `GcsFile::rename_to`, which deletes it only once the copy is verified.
+/// Anything else is copied with `copy_contents` and then deleted; when the delete fails,
+/// both are left in place.
+/// Returns `Ok(false)` when `src` does not exist.
+pub async fn move_contents(
+    src: &str,
+    dst: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    if dry_run(|| format!("move {} to {}", src, dst)) {
+        return is_exists(src, backoff).await;
+    }
+    #[cfg(feature = "gcs")]
+    if let (Some(source), Some(destination)) = (gcs_file_of(src), gcs_file_of(dst)) {
+        if source.bucket == destination.bucket {
+            return match source.rename_to(&destination, backoff).await {
+                Ok(()) => Ok(true),
+                Err(gcs::FileUtilGcsError::ObjectNotFound(_)) => Ok(false),
+                Err(e) => Err(in_operation("move", src)(e)),
+            };
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    if let Some((source, destination)) = local_files_of(src, dst)? {
+        match source.rename_to(&destination, true).await {
+            Ok(renamed) => return Ok(renamed),
+            // on another filesystem, it is copied instead
+            Err(fs::FileUtilFsError::IOError(e))
+                if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+            Err(e) => return Err(in_operation("move", src)(e)),
+        }
+    }
+
+    if !copy_contents(src, dst, copy_backoff(&backoff)).await? {
+        return Ok(false);
+    }
+    storage::storage_for(src)?
+        .delete(backoff)
+        .await
+        .map_err(in_operation("delete", src))?;
+    Ok(true)
+}
+
+/// Download `url` to the local file `local_path` without holding the whole contents,
+/// creating its parent directories. The contents go to a temporary file beside
+/// `local_path`, which replaces it only once complete, so a failed download leaves
+/// `local_path` as it was.
+/// Returns `Ok(false)` when `url` does not exist.
+#[cfg(feature = "fs")]
+pub async fn download_to_file(
+    url: &str,
+    local_path: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    if !is_local_path(local_path)? {
+        return Err(FileUtilError::Unsupported {
+            path: local_path.to_string(),
+            op: "download to",
+        });
+    }
+    if dry_run(|| format!("download {} to {}", url, local_path)) {
+        return is_exists(url, backoff).await;
+    }
+    fs::FileAccessor::new(fs::local_path(local_path))
+        .map_err(in_operation("download to", local_path))?
+        .create_parents()
+        .await
+        .map_err(in_operation("download to", local_path))?;
+    stream_contents(url, local_path, backoff, writer::WriteOptions::default()).await
+}
+
+/// Upload the local file `local_path` to `url` as `mime_type`, streaming it instead of
+/// reading it whole. Like `upload_stream`, a failed upload is not retried, and the object
+/// appears at `url` only once it is complete. A local `url` is written to a temporary file
+/// beside it first, as by `download_to_file`.
+/// Returns `Ok(false)` when `local_path` does not exist.
+#[cfg(feature = "fs")]
+pub async fn upload_from_file(
+    local_path: &str,
+    url: &str,
+    mime_type: mime::MimeType,
+) -> Result<bool> {
+    if !is_local_path(local_path)? {
+        return Err(FileUtilError::Unsupported {
+            path: local_path.to_string(),
+            op: "upload from",
+        });
+    }
+    if dry_run(|| format!("upload {} to {}", local_path, url)) {
+        return is_exists(local_path, None).await;
+    }
+    let options = writer::WriteOptions::new().with_mime_type(mime_type);
+    stream_contents(local_path, url, None, options).await
+}
+
+#[cfg(feature = "fs")]
+fn is_local_path(url_or_path_str: &str) -> Result<bool> {
+    Ok(matches!(backend(url_or_path_str)?, Backend::Local(_)))
+}
+
+/// The local files `src` and `dst` are, when both are.
+#[cfg(feature = "fs")]
+fn local_files_of(src: &str, dst: &str) -> Result<Option<(fs::FileAccessor, fs::FileAccessor)>> {
+    if !is_local_path(src)? || !is_local_path(dst)? {
+        return Ok(None);
+    }
+    let source = fs::FileAccessor::new(fs::local_path(src)).map_err(in_operation("read", src))?;
+    let destination =
+        fs::FileAccessor::new(fs::local_path(dst)).map_err(in_operation("write", dst))?;
+    Ok(Some((source, destination)))
+}
+
+#[cfg(feature = "gcs")]
+fn gcs_file_of(url_or_path_str: &str) -> Option<gcs::GcsFile> {
+    match backend(url_or_path_str).ok()? {
+        Backend::Gcs(gcs_file) => Some(gcs_file),
+        _ => None,
+    }
+}
+
+/// Re-encode `src` into `dst`, decompressing it as `from` and compressing it as `to`, e.g. to
+/// migrate gzip files to zstd. With `line_transform`, every line (without its `\n`) of the
+/// decompressed contents, which must be utf-8, is replaced by what the function returns.
+/// The contents are streamed through, so the whole file is never held. Only opening the
+/// source is retried; like `upload_stream`, a failed write is not.
+/// Returns `Ok(false)` when `src` does not exist.
+pub async fn transcode<F>(
+    src: &str,
+    dst: &str,
+    from: Compression,
+    to: Compression,
+    mut line_transform: Option<F>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool>
+where
+    F: FnMut(&str) -> String,
+{
+    if dry_run(|| format!("transcode {} to {}", src, dst)) {
+        return is_exists(src, backoff).await;
+    }
+    let source = match open_read_stream(src, backoff)
+        .await
+        .map_err(in_operation("read", src))?
+    {
+        Some(source) => source,
+        None => return Ok(false),
+    };
+    let (mut sender, receiver) = futures::channel::mpsc::channel::<Result<Bytes>>(4);
+
+    let mut chunks = sender.clone();
+    let pump = async move {
+        futures::pin_mut!(source);
+        let mut decoder = stream::StreamDecoder::new(&from)?;
+        let mut encoder = stream::StreamEncoder::new(&to)?;
+        let mut partial_line = Vec::<u8>::new();
+        let mut transform = |decompressed: Vec<u8>, end: bool| match line_transform.as_mut() {
+            Some(line_transform) => {
+                partial_line.extend(decompressed);
+                transform_lines(&mut partial_line, end, line_transform).map_err(|source| {
+                    FileUtilError::InvalidUtf8 {
+                        path: src.to_string(),
+                        source,
+                    }
+                })
+            }
+            None => Ok(decompressed),
+        };
+
+        while let Some(chunk) = source.try_next().await.map_err(in_operation("read", src))? {
+            let compressed = encoder.write(&transform(decoder.write(&chunk)?, false)?)?;
+            if !compressed.is_empty() && chunks.send(Ok(Bytes::from(compressed))).await.is_err() {
+                // the upload has failed, which it reports itself
+                return Ok(());
+            }
+        }
+        let mut compressed = encoder.write(&transform(decoder.finish()?, true)?)?;
+        compressed.extend(encoder.finish()?);
+        let _ = chunks.send(Ok(Bytes::from(compressed))).await;
+        Ok(())
+    };
+    let pump = async move {
+        let result: Result<()> = pump.await;
+        if result.is_err() {
+            // make the upload fail rather than store a truncated file
+            let aborted = std::io::Error::other("transcoding the source failed");
+            let _ = sender.send(Err(FileUtilError::from(aborted))).await;
+        }
+        result
+    };
+
+    let (pumped, uploaded) = futures::join!(
+        pump,
+        upload_stream(dst, receiver, mime::MimeType::OctetStream)
+    );
+    pumped.map_err(in_operation("transcode", src))?;
+    uploaded?;
+    Ok(true)
+}
+
+/// Transform the complete lines at the head of `pending`, leaving a trailing partial line
+/// in it, or transform everything with `end`.
+fn transform_lines<F>(
+    pending: &mut Vec<u8>,
+    end: bool,
+    transform: &mut F,
+) -> std::result::Result<Vec<u8>, std::string::FromUtf8Error>
+where
+    F: FnMut(&str) -> String,
+{
+    let complete = match pending.iter().rposition(|b| *b == b'\n') {
+        _ if end => pending.len(),
+        Some(i) => i + 1,
+        None => return Ok(Vec::new()),
+    };
+    let rest = pending.split_off(complete);
+    let text = String::from_utf8(std::mem::replace(pending, rest))?;
+
+    let mut transformed = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        match line.strip_suffix('\n') {
+            Some(line) => {
+                transformed.push_str(&transform(line));
+                transformed.push('\n');
+            }
+            None => transformed.push_str(&transform(line)),
+        }
+    }
+    Ok(transformed.into_bytes())
+}
+
+/// Delete the file or object. A local directory is not deleted, see
+/// `delete_contents_recursive`. Web urls fail with `FileUtilError::Unsupported`.
+pub async fn delete_contents(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<()> {
+    if dry_run(|| format!("delete {}", url_or_path_str)) {
+        return Ok(());
+    }
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => storage.delete(backoff).await,
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            gcs_file
+                .delete_with_retry(backoff)
+                .await
+                .map_err(in_operation("delete", url_or_path_str))?;
+            Ok(())
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "delete",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("delete", url_or_path_str))?;
+            local_file
+                .delete()
+                .await
+                .map_err(in_operation("delete", url_or_path_str))
+        }
+    }
+}
+
+/// Delete the file or object, or, opting in to what `delete_contents` refuses, a whole
+/// directory: a local directory with everything in it, or the gcs object and every object
+/// under it taken as a directory, up to `concurrency` at a time. Web urls fail with
+/// `FileUtilError::Unsupported`.
+pub async fn delete_contents_recursive(
+    url_or_path_str: &str,
+    backoff: Option<ExponentialBackoff>,
+    concurrency: usize,
+) -> Result<()> {
+    if dry_run(|| format!("delete everything under {}", url_or_path_str)) {
+        return Ok(());
+    }
+    match backend(url_or_path_str)? {
+        Backend::Registered(storage) => storage.delete(backoff).await,
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            gcs_file
+                .delete_recursive_with_retry(backoff, concurrency)
+                .await
+                .map_err(in_operation("delete", url_or_path_str))?;
+            Ok(())
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_str.to_string(),
+            op: "delete",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                fs::FileAccessor::new(path).map_err(in_operation("delete", url_or_path_str))?;
+            local_file
+                .delete_recursive()
+                .await
+                .map_err(in_operation("delete", url_or_path_str))
+        }
+    }
+}
+
+/// Delete several files or objects, at most `concurrency` at a time, returning the result of
+/// each in the order of `urls`. One failure does not stop the others; its error names the
+/// file that could not be deleted.
+pub async fn delete_many(
+    urls: &[String],
+    concurrency: usize,
+    backoff: Option<ExponentialBackoff>,
+) -> Vec<Result<()>> {
+    futures::stream::iter(urls.iter())
+        .map(|url_or_path_str| {
+            let backoff = copy_backoff(&backoff);
+            async move {
+                delete_contents(url_or_path_str, backoff)
+                    .await
+                    .map_err(in_operation("delete", url_or_path_str))
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Delete the objects or files under a prefix or directory last modified more than `age`
+/// ago, for retention cleanup. Gcs objects are judged by their `updated` time and deleted up
+/// to `concurrency` at a time, local files by their modification time.
+/// A zero `age` would delete everything, so it fails with `FileUtilError::ZeroAgeNotForced`
+/// unless `force` is set. Returns how many were deleted. Web urls and storages registered
+/// with `storage::register_scheme`, which have no modification times, fail with
+/// `FileUtilError::Unsupported`.
+pub async fn delete_older_than(
+    url_or_path_prefix: &str,
+    age: Duration,
+    backoff: Option<ExponentialBackoff>,
+    concurrency: usize,
+    force: bool,
+) -> Result<usize> {
+    if age.is_zero() && !force {
+        return Err(FileUtilError::ZeroAgeNotForced {
+            path: url_or_path_prefix.to_string(),
+        });
+    }
+    let cutoff = match SystemTime::now().checked_sub(age) {
+        Some(cutoff) => cutoff,
+        None => return Ok(0),
+    };
+    if config::is_dry_run() {
+        let mut deleted = 0;
+        for path in list_files_recursive(url_or_path_prefix, copy_backoff(&backoff)).await? {
+            let modified = stat(&path, copy_backoff(&backoff))
+                .await?
+                .and_then(|metadata| metadata.modified);
+            if modified.is_some_and(|modified| modified < cutoff) {
+                log::info!("dry run: would delete {}", path);
+                deleted += 1;
+            }
+        }
+        return Ok(deleted);
+    }
+
+    match backend(url_or_path_prefix)? {
+        Backend::Registered(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_prefix.to_string(),
+            op: "delete",
+        }),
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => {
+            let deleted = gcs_file
+                .delete_updated_before(cutoff, backoff, concurrency)
+                .await
+                .map_err(in_operation("delete", url_or_path_prefix))?;
+            Ok(deleted)
+        }
+        #[cfg(feature = "web")]
+        Backend::Web(_) => Err(FileUtilError::Unsupported {
+            path: url_or_path_prefix.to_string(),
+            op: "delete",
+        }),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_dir =
+                fs::FileAccessor::new(path).map_err(in_operation("delete", url_or_path_prefix))?;
+            let deleted = local_dir
+                .delete_modified_before(cutoff)
+                .await
+                .map_err(in_operation("delete", url_or_path_prefix))?;
+            Ok(deleted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn delete_older_than_fs() {
+        let dir = TempDir::new();
+        let old = dir.write("old.txt", "x");
+        let nested_old = dir.write("nested/old.txt", "x");
+        let fresh = dir.write("nested/fresh.txt", "x");
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 3600);
+        for path in [&old, &nested_old] {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(two_hours_ago)
+                .unwrap();
+        }
+        let dir_path = dir.path().display().to_string();
+
+        let refused = delete_older_than(&dir_path, Duration::ZERO, None, 4, false).await;
+        assert!(matches!(
+            refused,
+            Err(FileUtilError::ZeroAgeNotForced { .. })
+        ));
+        assert!(std::path::Path::new(&fresh).exists());
+
+        let deleted = delete_older_than(&dir_path, Duration::from_secs(3600), None, 4, false)
+            .await
+            .unwrap();
+        assert_eq!(2, deleted);
+        assert!(!std::path::Path::new(&old).exists());
+        assert!(!std::path::Path::new(&nested_old).exists());
+        assert!(std::path::Path::new(&fresh).exists());
+
+        let deleted = delete_older_than(&dir_path, Duration::ZERO, None, 4, true)
+            .await
+            .unwrap();
+        assert_eq!(1, deleted);
+        assert!(!std::path::Path::new(&fresh).exists());
+
+        let unsupported =
+            delete_older_than("https://example.com/logs/", Duration::ZERO, None, 4, true).await;
+        assert!(matches!(
+            unsupported,
+            Err(FileUtilError::Unsupported { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn delete_older_than_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        for name in [
+            "logs/old-1",
+            "logs/old-2",
+            "logs/fresh",
+            "logs/dir/",
+            "other/old",
+        ] {
+            mock.insert("zdb_test", name, "x");
+        }
+        let recent = SystemTime::now() - Duration::from_secs(60);
+        mock.set_updated("zdb_test", "logs/fresh", recent);
+
+        gcs::client::with_client(mock.clone(), async {
+            let deleted = delete_older_than(
+                "gs://zdb_test/logs/",
+                Duration::from_secs(3600),
+                None,
+                2,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(2, deleted);
+        })
+        .await;
+        assert!(mock.get("zdb_test", "logs/old-1").is_none());
+        assert!(mock.get("zdb_test", "logs/old-2").is_none());
+        assert!(mock.get("zdb_test", "logs/fresh").is_some());
+        assert!(mock.get("zdb_test", "logs/dir/").is_some());
+        assert!(mock.get("zdb_test", "other/old").is_some());
+    }
+
+    #[tokio::test]
+    async fn list_files_of_a_url_is_unsupported() {
+        let listed = list_files("https://example.com/data/", None, None).await;
+        assert!(matches!(
+            listed,
+            Err(FileUtilError::Unsupported { op: "listing", .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_all_under_prefix_of_a_url_is_unsupported() {
+        let read: Vec<Result<(String, Vec<u8>)>> =
+            read_all_under_prefix("https://example.com/data/", 2, None, None)
+                .collect()
+                .await;
+        assert!(matches!(
+            read.as_slice(),
+            [Err(FileUtilError::Unsupported { op: "listing", .. })]
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_all_under_prefix_fs() {
+        let dir = TempDir::new();
+        let a = dir.write("a.txt", "first");
+        let nested = dir.write("nested/b.txt", "second");
+        let unreadable = dir.write("nested/c.gz", "not gzip");
+        let dir_path = dir.path().display().to_string();
+
+        let read: Vec<Result<(String, Vec<u8>)>> = read_all_under_prefix(&dir_path, 2, None, None)
+            .collect()
+            .await;
+        let read: Vec<(String, Vec<u8>)> = read.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            vec![
+                (a.clone(), b"first".to_vec()),
+                (nested.clone(), b"second".to_vec()),
+                (unreadable.clone(), b"not gzip".to_vec()),
+            ],
+            read
+        );
+
+        // a file that fails to decompress yields its error, the others still come through
+        let gzipped = Compression::Gzip.compress(b"third").unwrap();
+        let c = dir.write("nested/d.gz", gzipped);
+        std::fs::remove_file(&a).unwrap();
+        std::fs::remove_file(&nested).unwrap();
+        let read: Vec<Result<(String, Vec<u8>)>> =
+            read_all_under_prefix(&dir_path, 2, None, Some(Compression::Gzip))
+                .collect()
+                .await;
+        assert_eq!(2, read.len());
+        assert!(matches!(
+            &read[0],
+            Err(FileUtilError::Operation { path, .. }) if *path == unreadable
+        ));
+        assert_eq!((c, b"third".to_vec()), *read[1].as_ref().unwrap());
+
+        let missing = dir.join("missing");
+        let read: Vec<Result<(String, Vec<u8>)>> = read_all_under_prefix(&missing, 2, None, None)
+            .collect()
+            .await;
+        assert_eq!(1, read.len());
+        assert!(read[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn http_status_of_errors() {
+        use crate::testing::gcs::google_error;
+        use crate::testing::http::{TestResponse, TestServer};
+        use crate::testing::quick_backoff;
+        use std::io::ErrorKind;
+
+        let gcs_status = |reason: &str, code: u16| {
+            FileUtilError::from(gcs::FileUtilGcsError::from(google_error(reason, code)))
+                .to_http_status()
+        };
+        assert_eq!(404, gcs_status("notFound", 404));
+        assert_eq!(403, gcs_status("forbidden", 403));
+        assert_eq!(403, gcs_status("insufficientPermissions", 403));
+        assert_eq!(503, gcs_status("rateLimitExceeded", 429));
+        assert_eq!(412, gcs_status("conditionNotMet", 412));
+        assert_eq!(502, gcs_status("backendError", 500));
+        let list_timeout = gcs::FileUtilGcsError::ListTimeout("gs://b/p".to_string());
+        assert_eq!(504, FileUtilError::from(list_timeout).to_http_status());
+        let invalid = gcs::FileUtilGcsError::InvalidGcsUrl("gs:/b".to_string());
+        assert_eq!(400, FileUtilError::from(invalid).to_http_status());
+
+        let io_status = |kind: ErrorKind| {
+            FileUtilError::from(fs::FileUtilFsError::from(std::io::Error::from(kind)))
+                .to_http_status()
+        };
+        assert_eq!(404, io_status(ErrorKind::NotFound));
+        assert_eq!(403, io_status(ErrorKind::PermissionDenied));
+        assert_eq!(500, io_status(ErrorKind::InvalidData));
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/forbidden" => TestResponse::status(403),
+            _ => TestResponse::status(503),
+        })
+        .await;
+        for (path, expected) in [("/forbidden", 403), ("/unavailable", 502)] {
+            let e = web::send_with_retry(
+                http::Method::POST,
+                server.url(path),
+                vec![],
+                mime::MimeType::Json,
+                None,
+                Some(quick_backoff()),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(expected, FileUtilError::from(e).to_http_status());
+        }
+
+        // the context of an operation does not change the status
+        let e = in_operation("read", "gs://b/o")(gcs::FileUtilGcsError::ObjectNotFound(
+            "gs://b/o".to_string(),
+        ));
+        assert_eq!(404, e.to_http_status());
+        let e = FileUtilError::SizeLimitExceeded {
+            path: "gs://b/o".to_string(),
+            max_bytes: 1,
+        };
+        assert_eq!(413, e.to_http_status());
+        assert_eq!(
+            500,
+            FileUtilError::InvalidZip {
+                path: "a.zip".to_string(),
+                reason: "truncated",
+            }
+            .to_http_status()
+        );
+    }
+
+    #[tokio::test]
+    async fn kind_of_errors() {
+        use crate::testing::gcs::google_error;
+        use crate::testing::http::{TestResponse, TestServer};
+        use crate::testing::quick_backoff;
+
+        let gcs_kind = |reason: &str, code: u16| {
+            FileUtilError::from(gcs::FileUtilGcsError::from(google_error(reason, code))).kind()
+        };
+        assert_eq!(ErrorKind::NotFound, gcs_kind("notFound", 404));
+        assert_eq!(ErrorKind::Unauthorized, gcs_kind("authError", 401));
+        assert_eq!(ErrorKind::PermissionDenied, gcs_kind("forbidden", 403));
+        assert_eq!(ErrorKind::RateLimited, gcs_kind("rateLimitExceeded", 429));
+        assert_eq!(ErrorKind::Transient, gcs_kind("backendError", 500));
+        assert_eq!(ErrorKind::Other, gcs_kind("conditionNotMet", 412));
+        let invalid = gcs::FileUtilGcsError::InvalidGcsUrl("gs:/b".to_string());
+        assert_eq!(ErrorKind::InvalidPath, FileUtilError::from(invalid).kind());
+
+        let io_kind = |kind: std::io::ErrorKind| {
+            FileUtilError::from(fs::FileUtilFsError::from(std::io::Error::from(kind))).kind()
+        };
+        assert_eq!(ErrorKind::NotFound, io_kind(std::io::ErrorKind::NotFound));
+        assert_eq!(
+            ErrorKind::PermissionDenied,
+            io_kind(std::io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(ErrorKind::Transient, io_kind(std::io::ErrorKind::TimedOut));
+        assert_eq!(ErrorKind::Other, io_kind(std::io::ErrorKind::InvalidData));
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/unauthorized" => TestResponse::status(401),
+            "/throttled" => TestResponse::status(429),
+            _ => TestResponse::status(503),
+        })
+        .await;
+        for (path, expected) in [
+            ("/unauthorized", ErrorKind::Unauthorized),
+            ("/throttled", ErrorKind::RateLimited),
+            ("/unavailable", ErrorKind::Transient),
+        ] {
+            let e = web::send_with_retry(
+                http::Method::POST,
+                server.url(path),
+                vec![],
+                mime::MimeType::Json,
+                None,
+                Some(quick_backoff()),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(expected, FileUtilError::from(e).kind(), "{}", path);
+        }
+
+        // the context of an operation does not change the kind
+        let e = in_operation("read", "gs://b/o")(gcs::FileUtilGcsError::ObjectNotFound(
+            "gs://b/o".to_string(),
+        ));
+        assert_eq!(ErrorKind::NotFound, e.kind());
+        let e = FileUtilError::Unsupported {
+            path: "http://example.com/".to_string(),
+            op: "listing",
+        };
+        assert_eq!(ErrorKind::Unsupported, e.kind());
+    }
+
+    #[tokio::test]
+    async fn open_read_fs_and_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+        use futures::io::AsyncReadExt;
+
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.write("large.bin", &contents);
+        let mut reader = open_read(&path, None).await.unwrap().unwrap();
+        let mut head = vec![0u8; 10];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(&contents[..10], head.as_slice());
+        let mut rest = Vec::<u8>::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(&contents[10..], rest.as_slice());
+        assert!(open_read(&dir.join("missing"), None)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/large.bin", contents.clone());
+        gcs::client::with_client(mock, async {
+            let mut reader = open_read("gs://zdb_test/zdb/large.bin", None)
+                .await
+                .unwrap()
+                .unwrap();
+            let mut read = Vec::<u8>::new();
+            reader.read_to_end(&mut read).await.unwrap();
+            assert_eq!(contents, read);
+            assert!(open_read("gs://zdb_test/zdb/missing", None)
+                .await
+                .unwrap()
+                .is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn open_write_fs_and_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+        use futures::io::AsyncWriteExt;
+
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        let mut writer = open_write(&path, writer::WriteOptions::default()).unwrap();
+        for chunk in contents.chunks(10_000) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        assert!(!std::path::Path::new(&path).exists());
+        writer.finalize().await.unwrap();
+        assert_eq!(contents, std::fs::read(&path).unwrap());
+
+        let mut writer = open_write(&path, writer::WriteOptions::default()).unwrap();
+        writer.write_all(b"replacement").await.unwrap();
+        writer.abort().await.unwrap();
+        assert_eq!(contents, std::fs::read(&path).unwrap());
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            let options = writer::WriteOptions::new()
+                .with_mime_type(mime::MimeType::OctetStream)
+                .with_compression(Compression::Gzip);
+            let mut writer = open_write("gs://zdb_test/zdb/large.bin.gz", options).unwrap();
+            for chunk in contents.chunks(10_000) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer.finalize().await.unwrap();
+            let stored = get_file_contents(
+                "gs://zdb_test/zdb/large.bin.gz",
+                None,
+                Some(Compression::Gzip),
+            )
+            .await
+            .unwrap();
+            assert_eq!(Some(contents.clone()), stored);
+
+            let mut writer =
+                open_write("gs://zdb_test/zdb/aborted", writer::WriteOptions::default()).unwrap();
+            writer.write_all(b"partial").await.unwrap();
+            writer.abort().await.unwrap();
+            assert!(!is_exists("gs://zdb_test/zdb/aborted", None).await.unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stat_fs_gcs_and_web() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+        use crate::testing::quick_backoff;
+
+        let dir = TempDir::new();
+        let path = dir.write("a/file.txt", "contents");
+        let metadata = stat(&path, None).await.unwrap().unwrap();
+        assert_eq!(Some(8), metadata.size);
+        assert!(!metadata.is_dir);
+        assert!(metadata.modified.is_some());
+        assert!(stat(&dir.join("a"), None).await.unwrap().unwrap().is_dir);
+        assert_eq!(None, stat(&dir.join("missing"), None).await.unwrap());
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/a/file.txt", b"contents".to_vec());
+        let updated = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        mock.set_updated("zdb_test", "zdb/a/file.txt", updated);
+        gcs::client::with_client(mock, async {
+            let metadata = stat("gs://zdb_test/zdb/a/file.txt", None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(Some(8), metadata.size);
+            assert_eq!(Some(updated), metadata.modified);
+            assert!(metadata.etag.is_some());
+            assert!(!metadata.is_dir);
+            for directory in ["gs://zdb_test/zdb/a", "gs://zdb_test/zdb/a/"] {
+                assert!(stat(directory, None).await.unwrap().unwrap().is_dir);
+            }
+            assert_eq!(None, stat("gs://zdb_test/zdb/b", None).await.unwrap());
+        })
+        .await;
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/file.txt" => TestResponse::ok("contents")
+                .with_header("content-type", "text/plain")
+                .with_header("etag", "\"abc\"")
+                .with_header("last-modified", "Sun, 13 Sep 2020 12:26:40 GMT"),
+            _ => TestResponse::status(404),
+        })
+        .await;
+        let metadata = stat(server.url("file.txt").as_str(), Some(quick_backoff()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            FileMetadata {
+                size: Some(8),
+                modified: Some(updated),
+                created: None,
+                content_type: Some("text/plain".to_string()),
+                etag: Some("\"abc\"".to_string()),
+                is_dir: false,
+            },
+            metadata
+        );
+        assert_eq!("HEAD", server.requests()[0].method);
+        assert_eq!(
+            None,
+            stat(server.url("missing").as_str(), Some(quick_backoff()))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_lines_of_compressed_files_lazily() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let text: String = (0..20_000)
+            .map(|i| format!("line {}\r\n", i))
+            .chain(["last".to_string()])
+            .collect();
+        let compressed = Compression::Gzip.compress(text.as_bytes()).unwrap();
+        let dir = TempDir::new();
+        let local = dir.write("app.log.gz", &compressed);
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/app.log.gz", compressed);
+
+        gcs::client::with_client(mock, async {
+            for url in [local.as_str(), "gs://zdb_test/zdb/app.log.gz"] {
+                let lines = read_lines(url, None, Some(Compression::Gzip))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let lines: Vec<String> = lines.try_collect().await.unwrap();
+                assert_eq!(20_001, lines.len(), "{}", url);
+                assert_eq!("line 0", lines[0]);
+                assert_eq!("line 19999", lines[19_999]);
+                assert_eq!("last", lines[20_000]);
+            }
+        })
+        .await;
+
+        let first = read_lines(&local, None, Some(Compression::Gzip))
+            .await
+            .unwrap()
+            .unwrap();
+        futures::pin_mut!(first);
+        assert_eq!("line 0", first.try_next().await.unwrap().unwrap());
+
+        let binary = dir.write("binary.txt", [b'a', b'\n', 0xff, b'\n']);
+        let lines: Vec<Result<String>> = read_lines(&binary, None, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!("a", lines[0].as_ref().unwrap());
+        assert!(matches!(lines[1], Err(FileUtilError::InvalidUtf8 { .. })));
+        assert!(read_lines(&dir.join("missing"), None, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[tokio::test]
+    async fn read_ndjson_records() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Event {
+            id: u32,
+        }
+
+        let dir = TempDir::new();
+        let path = dir.write("events.ndjson", "{\"id\":1}\n\n{\"id\":2}\n{broken\n");
+        let records: Vec<Result<Event>> = read_ndjson(&path, None, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(3, records.len());
+        assert_eq!(&Event { id: 1 }, records[0].as_ref().unwrap());
+        assert_eq!(&Event { id: 2 }, records[1].as_ref().unwrap());
+        assert!(matches!(records[2], Err(FileUtilError::InvalidJson { .. })));
+    }
+
+    #[tokio::test]
+    async fn append_contents_to_fs_and_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let log = dir.join("app.log");
+        append_contents(&log, "one\n", mime::MimeType::Text, None)
+            .await
+            .unwrap();
+        append_contents(&log, "two\n", mime::MimeType::Text, None)
+            .await
+            .unwrap();
+        assert_eq!("one\ntwo\n", std::fs::read_to_string(&log).unwrap());
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            let url = "gs://zdb_test/zdb/app.log";
+            for line in ["one\n", "two\n", "three\n"] {
+                append_contents(url, line.as_bytes(), mime::MimeType::Text, None)
+                    .await
+                    .unwrap();
+            }
+            let listed = list_files("gs://zdb_test/zdb", None, None).await.unwrap();
+            assert_eq!(vec![url.to_string()], listed);
+        })
+        .await;
+        let object = mock.get("zdb_test", "zdb/app.log").unwrap();
+        assert_eq!(b"one\ntwo\nthree\n".to_vec(), object.body);
+        assert_eq!("text/plain", object.content_type);
+        assert_eq!(Some(3), object.component_count);
+        assert_eq!(2, mock.call_count("compose"));
+
+        assert!(matches!(
+            append_contents(
+                "https://example.com/app.log",
+                "x",
+                mime::MimeType::Text,
+                None
+            )
+            .await,
+            Err(FileUtilError::Unsupported { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn write_contents_into_missing_directories() {
+        let dir = TempDir::new();
+        let path = dir.join("a/b/c.txt");
+        assert!(write_contents(&path, "x", mime::MimeType::Text, None, None)
+            .await
+            .is_err());
+
+        let options = writer::WriteOptions::new().with_create_parents(true);
+        assert!(write_contents_with_options(&path, "x", None, &options)
+            .await
+            .unwrap());
+        assert_eq!("x", std::fs::read_to_string(&path).unwrap());
+        let nested = dir.join("a/d/e.txt");
+        let options = options.with_atomic(true);
+        assert!(write_contents_with_options(&nested, "y", None, &options)
+            .await
+            .unwrap());
+        assert_eq!("y", std::fs::read_to_string(&nested).unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_contents_with_options_to_fs_and_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        let once = writer::WriteOptions::new().with_if_not_exists(true);
+        assert!(write_contents_with_options(&path, "first", None, &once)
+            .await
+            .unwrap());
+        assert!(!write_contents_with_options(&path, "second", None, &once)
+            .await
+            .unwrap());
+        assert_eq!("first", std::fs::read_to_string(&path).unwrap());
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            let options = writer::WriteOptions::new()
+                .with_compression(Compression::Gzip)
+                .with_cache_control("no-cache")
+                .with_metadata("source", "export");
+            let written =
+                write_contents_with_options("gs://zdb_test/zdb/a.json", "{}", None, &options)
+                    .await
+                    .unwrap();
+            assert!(written);
+            assert_eq!(
+                Some(b"{}".to_vec()),
+                get_file_contents("gs://zdb_test/zdb/a.json", None, Some(Compression::Gzip))
+                    .await
+                    .unwrap()
+            );
+
+            let options = options.with_if_not_exists(true);
+            let written =
+                write_contents_with_options("gs://zdb_test/zdb/a.json", "[]", None, &options)
+                    .await
+                    .unwrap();
+            assert!(!written);
+        })
+        .await;
+        let object = mock.get("zdb_test", "zdb/a.json").unwrap();
+        assert_eq!("application/json", object.content_type);
+        assert_eq!(Some("no-cache".to_string()), object.cache_control);
+        assert_eq!(
+            Some("export"),
+            object
+                .metadata
+                .as_ref()
+                .unwrap()
+                .get("source")
+                .map(String::as_str)
+        );
+        assert_eq!(1, mock.call_count("create"));
+    }
+
+    #[tokio::test]
+    async fn conditional_writes() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let dir = TempDir::new();
+        let path = dir.join("lock");
+        assert!(
+            write_contents_if_absent(&path, "a", mime::MimeType::Text, None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !write_contents_if_absent(&path, "b", mime::MimeType::Text, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!("a", std::fs::read_to_string(&path).unwrap());
+        let versioned = writer::WriteOptions::new().with_if_generation_match(1);
+        assert_eq!(
+            ErrorKind::Unsupported,
+            write_contents_with_options(&path, "c", None, &versioned)
+                .await
+                .unwrap_err()
+                .kind()
+        );
+
+        let mock = MockGcsClient::new().into_arc();
+        let url = "gs://zdb_test/zdb/lock";
+        gcs::client::with_client(mock.clone(), async {
+            assert!(
+                write_contents_if_absent(url, "a", mime::MimeType::Text, None)
+                    .await
+                    .unwrap()
+            );
+            assert!(
+                !write_contents_if_absent(url, "b", mime::MimeType::Text, None)
+                    .await
+                    .unwrap()
+            );
+            let generation = mock.get("zdb_test", "zdb/lock").unwrap().generation;
+            let stale = writer::WriteOptions::new().with_if_generation_match(generation - 1);
+            assert!(!write_contents_with_options(url, "c", None, &stale)
+                .await
+                .unwrap());
+            let current = writer::WriteOptions::new().with_if_generation_match(generation);
+            assert!(write_contents_with_options(url, "d", None, &current)
+                .await
+                .unwrap());
+        })
+        .await;
+        assert_eq!(
+            b"d".to_vec(),
+            mock.get("zdb_test", "zdb/lock").unwrap().body
+        );
+        assert_eq!(0, mock.call_count("read"));
+
+        let server = TestServer::start(|request| {
+            let exists = request.path == "/taken.txt";
+            let failed = (exists && request.header("if-none-match") == Some("*"))
+                || request
+                    .header("if-match")
+                    .is_some_and(|etag| etag != "\"v2\"");
+            TestResponse::status(if failed { 412 } else { 201 })
+        })
+        .await;
+        let free = server.url("/free.txt").to_string();
+        let taken = server.url("/taken.txt").to_string();
+        assert!(
+            write_contents_if_absent(&free, "a", mime::MimeType::Text, None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !write_contents_if_absent(&taken, "a", mime::MimeType::Text, None)
+                .await
+                .unwrap()
+        );
+        let stale = writer::WriteOptions::new().with_if_match("\"v1\"");
+        assert!(!write_contents_with_options(&taken, "b", None, &stale)
+            .await
+            .unwrap());
+        let current = writer::WriteOptions::new().with_if_match("\"v2\"");
+        assert!(write_contents_with_options(&taken, "b", None, &current)
+            .await
+            .unwrap());
+        let puts = server.requests();
+        assert!(puts.iter().all(|request| request.method == "PUT"));
+        assert_eq!(b"b".to_vec(), puts.last().unwrap().body);
+    }
+
+    #[tokio::test]
+    async fn download_to_and_upload_from_files() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let local = dir.write("local.bin", &contents);
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            assert!(
+                upload_from_file(&local, "gs://zdb_test/zdb/a.bin", mime::MimeType::Text)
+                    .await
+                    .unwrap()
+            );
+            let uploaded = mock.get("zdb_test", "zdb/a.bin").unwrap();
+            assert_eq!(contents, uploaded.body);
+            assert_eq!("text/plain", uploaded.content_type);
+            assert!(!upload_from_file(
+                &dir.join("missing"),
+                "gs://zdb_test/zdb/b.bin",
+                mime::MimeType::Text
+            )
+            .await
+            .unwrap());
+
+            let downloaded = dir.join("nested/dir/downloaded.bin");
+            assert!(
+                download_to_file("gs://zdb_test/zdb/a.bin", &downloaded, None)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(contents, std::fs::read(&downloaded).unwrap());
+            assert!(
+                !download_to_file("gs://zdb_test/zdb/missing", &downloaded, None)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(contents, std::fs::read(&downloaded).unwrap());
+
+            assert!(matches!(
+                download_to_file("gs://zdb_test/zdb/a.bin", "gs://zdb_test/zdb/c.bin", None).await,
+                Err(FileUtilError::Unsupported { .. })
+            ));
+            assert!(matches!(
+                upload_from_file(
+                    "gs://zdb_test/zdb/a.bin",
+                    "gs://zdb_test/zdb/c.bin",
+                    mime::MimeType::Text
+                )
+                .await,
+                Err(FileUtilError::Unsupported { .. })
+            ));
+        })
+        .await;
+
+        let entries: Vec<_> = std::fs::read_dir(dir.join("nested/dir"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec![std::ffi::OsString::from("downloaded.bin")], entries);
+    }
+
+    #[tokio::test]
+    async fn copy_contents_across_backends() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let local = dir.write("local.bin", &contents);
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            assert!(copy_contents(&local, "gs://zdb_test/zdb/a.bin", None)
+                .await
+                .unwrap());
+            assert!(
+                copy_contents("gs://zdb_test/zdb/a.bin", "gs://zdb_test/zdb/b.bin", None)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(1, mock.call_count("rewrite"));
+            assert!(
+                copy_contents("gs://zdb_test/zdb/b.bin", "gs://other/c.bin", None)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(1, mock.call_count("rewrite"));
+
+            let copied = dir.join("copied.bin");
+            assert!(copy_contents("gs://other/c.bin", &copied, None)
+                .await
+                .unwrap());
+            assert_eq!(contents, std::fs::read(&copied).unwrap());
+            assert!(!copy_contents("gs://zdb_test/zdb/missing", &copied, None)
+                .await
+                .unwrap());
+        })
+        .await;
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/file.txt" => TestResponse::ok("from the web"),
+            _ => TestResponse::status(404),
+        })
+        .await;
+        let downloaded = dir.join("downloaded.txt");
+        assert!(
+            copy_contents(server.url("file.txt").as_str(), &downloaded, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "from the web",
+            std::fs::read_to_string(&downloaded).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn move_contents_across_backends() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let local = dir.write("local.txt", "contents");
+        let renamed = dir.join("renamed.txt");
+        assert!(move_contents(&local, &renamed, None).await.unwrap());
+        assert!(!std::path::Path::new(&local).exists());
+        assert_eq!("contents", std::fs::read_to_string(&renamed).unwrap());
+        assert!(!move_contents(&local, &renamed, None).await.unwrap());
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            assert!(move_contents(&renamed, "gs://zdb_test/zdb/a.txt", None)
+                .await
+                .unwrap());
+            assert!(!std::path::Path::new(&renamed).exists());
+
+            assert!(
+                move_contents("gs://zdb_test/zdb/a.txt", "gs://zdb_test/zdb/b.txt", None)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(1, mock.call_count("rewrite"));
+            assert!(!is_exists("gs://zdb_test/zdb/a.txt", None).await.unwrap());
+            assert!(
+                !move_contents("gs://zdb_test/zdb/a.txt", "gs://zdb_test/zdb/c.txt", None)
+                    .await
+                    .unwrap()
+            );
+
+            assert!(move_contents("gs://zdb_test/zdb/b.txt", &local, None)
+                .await
+                .unwrap());
+            assert!(!is_exists("gs://zdb_test/zdb/b.txt", None).await.unwrap());
+        })
+        .await;
+        assert_eq!("contents", std::fs::read_to_string(&local).unwrap());
+    }
+
+    #[tokio::test]
+    async fn get_many_keeps_order_and_failures() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let local = dir.write("local.txt", "local");
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/a.txt", b"a".to_vec());
+        mock.insert("zdb_test", "zdb/b.txt", b"b".to_vec());
+        let urls = vec![
+            "gs://zdb_test/zdb/a.txt".to_string(),
+            local,
+            dir.join("missing"),
+            dir.path().display().to_string(),
+            "gs://zdb_test/zdb/b.txt".to_string(),
+        ];
+        let results = gcs::client::with_client(mock, get_many(&urls, 2, None)).await;
+        assert_eq!(5, results.len());
+        assert_eq!(Some(b"a".to_vec()), *results[0].as_ref().unwrap());
+        assert_eq!(Some(b"local".to_vec()), *results[1].as_ref().unwrap());
+        assert_eq!(None, *results[2].as_ref().unwrap());
+        assert!(results[3].is_err());
+        assert_eq!(Some(b"b".to_vec()), *results[4].as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_many_reports_each_entry() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let local = dir.join("local.txt");
+        let unwritable = dir.join("missing/dir/file.txt");
+        let entries = vec![
+            ("gs://zdb_test/zdb/a.txt".to_string(), b"a".to_vec()),
+            (local.clone(), b"local".to_vec()),
+            (unwritable.clone(), b"x".to_vec()),
+            ("gs://zdb_test/zdb/b.txt".to_string(), b"b".to_vec()),
+        ]
+        .into_iter()
+        .map(|(path, body)| (path, body, mime::MimeType::Text));
+
+        let mock = MockGcsClient::new().into_arc();
+        let results = gcs::client::with_client(mock.clone(), write_many(entries, 3, None)).await;
+        assert_eq!(4, results.len());
+        assert!(results[0].is_ok() && results[1].is_ok() && results[3].is_ok());
+        match &results[2] {
+            Err(FileUtilError::Operation { path, op, .. }) => {
+                assert_eq!(&unwritable, path);
+                assert_eq!("write", *op);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!("local", std::fs::read_to_string(&local).unwrap());
+        assert_eq!(
+            b"b".to_vec(),
+            mock.get("zdb_test", "zdb/b.txt").unwrap().body
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_many_reports_each_deletion() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let local = dir.write("local.txt", "local");
+        let mock = MockGcsClient::new().into_arc();
+        for i in 0..20 {
+            mock.insert("zdb_test", &format!("zdb/{}.txt", i), b"x".to_vec());
+        }
+        let mut urls: Vec<String> = (0..20)
+            .map(|i| format!("gs://zdb_test/zdb/{}.txt", i))
+            .collect();
+        urls.push(local.clone());
+        urls.push(dir.join("missing"));
+
+        let results = gcs::client::with_client(mock.clone(), delete_many(&urls, 8, None)).await;
+        assert_eq!(22, results.len());
+        assert!(results[..21].iter().all(|result| result.is_ok()));
+        assert!(results[21].is_err());
+        assert_eq!(20, mock.call_count("delete"));
+        assert!(!std::path::Path::new(&local).exists());
+    }
+
+    #[tokio::test]
+    async fn list_files_stream_pages_lazily() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().with_page_size(3).into_arc();
+        for i in 0..10 {
+            mock.insert("zdb_test", &format!("zdb/{:02}.txt", i), b"x".to_vec());
+        }
+        gcs::client::with_client(mock.clone(), async {
+            let mut entries = Box::pin(list_files_stream("gs://zdb_test/zdb/", None));
+            let first = entries.next().await.unwrap().unwrap();
+            assert_eq!("gs://zdb_test/zdb/00.txt", first);
+            assert_eq!(1, mock.call_count("list"));
+
+            let rest: Vec<String> = entries.try_collect().await.unwrap();
+            assert_eq!(9, rest.len());
+            assert_eq!(4, mock.call_count("list"));
+            let mut all = vec![first];
+            all.extend(rest);
+            assert_eq!(
+                list_files("gs://zdb_test/zdb/", None, None).await.unwrap(),
+                all
+            );
+        })
+        .await;
+
+        let dir = TempDir::new();
+        let mut written = vec![dir.write("a.txt", "a"), dir.write("b.txt", "b")];
+        let mut listed: Vec<String> = list_files_stream(&dir.path().display().to_string(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        written.sort();
+        listed.sort();
+        assert_eq!(written, listed);
+        let missing: Vec<Result<String>> = list_files_stream(&dir.join("missing"), None)
+            .collect()
+            .await;
+        assert!(matches!(
+            missing.as_slice(),
+            [Err(FileUtilError::Operation { op: "list", .. })]
+        ));
+    }
+
+    #[tokio::test]
+    async fn list_options_apply_alike_to_fs_and_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let names = [
+            "a.json.gz",
+            "b.txt",
+            "logs/2024/x.json.gz",
+            "logs/2024/y.txt",
+            "logs/z.json.gz",
+        ];
+        let dir = TempDir::new();
+        let mock = MockGcsClient::new().with_page_size(2).into_arc();
+        for name in names {
+            dir.write(name, "x");
+            mock.insert("zdb_test", &format!("zdb/{}", name), b"x".to_vec());
+        }
+        // a folder placeholder, which a local directory has no counterpart of
+        mock.insert("zdb_test", "zdb/logs/", b"".to_vec());
+        let local_dir = dir.path().display().to_string();
+
+        let cases: Vec<(ListOptions, Vec<&str>)> = vec![
+            (ListOptions::default(), vec!["a.json.gz", "b.txt"]),
+            (
+                ListOptions {
+                    include_directories: true,
+                    ..Default::default()
+                },
+                vec!["a.json.gz", "b.txt", "logs/"],
+            ),
+            (
+                ListOptions {
+                    recursive: true,
+                    suffix: Some(".json.gz".to_string()),
+                    ..Default::default()
+                },
+                vec!["a.json.gz", "logs/2024/x.json.gz", "logs/z.json.gz"],
+            ),
+            (
+                ListOptions {
+                    recursive: true,
+                    include_directories: true,
+                    prefix: Some("logs/".to_string()),
+                    max_results: Some(4),
+                    ..Default::default()
+                },
+                vec![
+                    "logs/",
+                    "logs/2024/",
+                    "logs/2024/x.json.gz",
+                    "logs/2024/y.txt",
+                ],
+            ),
+        ];
+        gcs::client::with_client(mock, async {
+            for (options, expected) in cases {
+                for base in [local_dir.as_str(), "gs://zdb_test/zdb"] {
+                    let listed = list_files_with_options(base, None, &options).await.unwrap();
+                    let relative: Vec<&str> = listed
+                        .iter()
+                        .map(|entry| entry.strip_prefix(&format!("{}/", base)).unwrap())
+                        .collect();
+                    assert_eq!(expected, relative, "{} {:?}", base, options);
+                }
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn list_files_glob_expands_patterns() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let names = [
+            "data/2023-12/a.json.gz",
+            "data/2024-01/a.json.gz",
+            "data/2024-01/b.txt",
+            "data/2024-02/x/c.json.gz",
+            "data/report[1].csv",
+            "data/report1.csv",
+            "data/[raw]/x.csv",
+        ];
+        let dir = TempDir::new();
+        let mock = MockGcsClient::new().into_arc();
+        for name in names {
+            dir.write(name, "x");
+            mock.insert("zdb_test", &format!("zdb/{}", name), b"x".to_vec());
+        }
+        let local_dir = dir.path().display().to_string();
+
+        gcs::client::with_client(mock, async {
+            for base in [local_dir.as_str(), "gs://zdb_test/zdb"] {
+                let listed =
+                    list_files_glob(&format!("{}/data/2024-*/**/*.json.gz", base), None, None)
+                        .await
+                        .unwrap();
+                assert_eq!(
+                    vec![
+                        format!("{}/data/2024-01/a.json.gz", base),
+                        format!("{}/data/2024-02/x/c.json.gz", base),
+                    ],
+                    listed
+                );
+                let listed = list_files_glob(&format!("{}/data/202?-*", base), None, Some(2))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    vec![
+                        format!("{}/data/2023-12/", base),
+                        format!("{}/data/2024-01/", base)
+                    ],
+                    listed
+                );
+
+                // brackets are literal but in patterns, unless escaped
+                let raw = format!("{}/data/[raw]", base);
+                assert_eq!(
+                    vec![format!("{}/x.csv", raw)],
+                    list_files(&raw, None, None).await.unwrap()
+                );
+                let report = format!("{}/data/report[1].csv", base);
+                let escaped = format!("{}/data/report\\[1\\].*", base);
+                assert_eq!(
+                    vec![report.clone()],
+                    list_files_glob(&escaped, None, None).await.unwrap()
+                );
+                let pattern = format!("{}/data/report[1].csv", base);
+                assert_eq!(
+                    vec![format!("{}/data/report1.csv", base)],
+                    list_files_glob(&pattern, None, None).await.unwrap()
+                );
+            }
+        })
+        .await;
+
+        // the query of a web url is not a pattern in `list_files`
+        assert!(matches!(
+            list_files("https://example.com/data/?page=*", None, None).await,
+            Err(FileUtilError::Unsupported { op: "listing", .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn walk_fs_and_gcs_trees() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let names = ["a.txt", "logs/2024/x.log", "logs/y.log"];
+        let dir = TempDir::new();
+        let mock = MockGcsClient::new().with_page_size(2).into_arc();
+        for name in names {
+            dir.write(name, "xyz");
+            mock.insert("zdb_test", &format!("zdb/{}", name), b"xyz".to_vec());
+        }
+        mock.insert("zdb_test", "zdb/logs/", b"".to_vec());
+        let local_dir = dir.path().display().to_string();
+
+        let expected = vec![
+            ("a.txt".to_string(), EntryKind::File, Some(3)),
+            ("logs/".to_string(), EntryKind::Directory, None),
+            ("logs/2024/".to_string(), EntryKind::Directory, None),
+            ("logs/2024/x.log".to_string(), EntryKind::File, Some(3)),
+            ("logs/y.log".to_string(), EntryKind::File, Some(3)),
+        ];
+        gcs::client::with_client(mock, async {
+            for base in [local_dir.as_str(), "gs://zdb_test/zdb"] {
+                let prefix = format!("{}/", base);
+                let mut walked: Vec<(String, EntryKind, Option<u64>)> = walk(base, None)
+                    .map_ok(|entry| {
+                        let relative = entry.path.strip_prefix(&prefix).unwrap().to_string();
+                        (relative, entry.kind, entry.size)
+                    })
+                    .try_collect()
+                    .await
+                    .unwrap();
+                walked.sort_by(|a, b| a.0.cmp(&b.0));
+                assert_eq!(expected, walked, "{}", base);
+            }
+        })
+        .await;
+
+        let missing: Vec<Result<WalkEntry>> = walk(&dir.join("missing"), None).collect().await;
+        assert!(matches!(
+            missing.as_slice(),
+            [Err(FileUtilError::Operation { op: "walk", .. })]
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_file_contents_str_in_encodings() {
+        let dir = TempDir::new();
+        let binary = dir.write("binary.bin", [0x66u8, 0x6f, 0xff, 0x6f]);
+        assert!(matches!(
+            get_file_contents_str(&binary, None, None).await,
+            Err(FileUtilError::InvalidUtf8 { .. })
+        ));
+        let lossy =
+            get_file_contents_str_with_encoding(&binary, None, None, TextEncoding::Utf8Lossy)
+                .await
+                .unwrap();
+        assert_eq!(Some("fo\u{fffd}o".to_string()), lossy);
+
+        for (bytes, charset, expected) in [
+            (
+                vec![0x93u8, 0xfa, 0x96, 0x7b],
+                encoding_rs::SHIFT_JIS,
+                "日本",
+            ),
+            (
+                vec![0x63u8, 0x61, 0x66, 0xe9],
+                encoding_rs::WINDOWS_1252,
+                "café",
+            ),
+        ] {
+            let path = dir.write("text.txt", bytes);
+            let text = get_file_contents_str_with_encoding(
+                &path,
+                None,
+                None,
+                TextEncoding::Charset(charset),
+            )
+            .await
+            .unwrap();
+            assert_eq!(Some(expected.to_string()), text);
+        }
+
+        let utf8 = dir.write("utf8.txt", "日本");
+        assert_eq!(
+            Some("日本".to_string()),
+            get_file_contents_str(&utf8, None, None).await.unwrap()
+        );
+        assert_eq!(
+            None,
+            get_file_contents_str(&dir.join("missing"), None, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn relative_path_of_nested_locations() {
+        assert_eq!(
+            Some("b/c.txt".to_string()),
+            relative_path("gs://bucket/a", "gs://bucket/a/b/c.txt")
+        );
+        assert_eq!(
+            Some("b/".to_string()),
+            relative_path("gs://bucket/a/", "gs://bucket/a/b/")
+        );
+        assert_eq!(
+            Some("a/b".to_string()),
+            relative_path("gs://bucket", "gs://bucket/a/b")
+        );
+        assert_eq!(
+            Some("b/c.txt".to_string()),
+            relative_path("/tmp/a/", "/tmp/a/b/c.txt")
+        );
+        assert_eq!(Some("c.txt".to_string()), relative_path("a/b", "a/b/c.txt"));
+    }
+
+    #[test]
+    fn relative_path_of_unrelated_locations() {
+        // a name prefix is not a parent
+        assert_eq!(None, relative_path("gs://bucket/a", "gs://bucket/ab/c"));
+        assert_eq!(None, relative_path("/tmp/a", "/tmp/ab/c"));
+        assert_eq!(None, relative_path("gs://bucket/a", "gs://bucket/a"));
+        assert_eq!(None, relative_path("/tmp/a", "/tmp/a/"));
+        assert_eq!(None, relative_path("gs://bucket/a", "gs://other/a/b"));
+        assert_eq!(None, relative_path("gs://bucket/b", "gs://bucket/a/b"));
+
+        // cross scheme
+        assert_eq!(None, relative_path("gs://bucket/a", "https://bucket/a/b"));
+        assert_eq!(None, relative_path("/a", "gs://bucket/a/b"));
+        assert_eq!(None, relative_path("gs://bucket/a", "/a/b"));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[tokio::test]
+    async fn get_and_put_json_round_trip() {
+        use crate::testing::gcs::MockGcsClient;
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Settings {
+            name: String,
+            retries: u32,
+        }
+        let settings = Settings {
+            name: "zdb".to_string(),
+            retries: 3,
+        };
+
+        let dir = TempDir::new();
+        let local = dir.join("settings.json");
+        put_json(&local, &settings, None, None).await.unwrap();
+        assert_eq!(
+            Some(&settings),
+            get_json::<Settings>(&local, None, None)
+                .await
+                .unwrap()
+                .as_ref()
+        );
+        let with_bom = dir.write("bom.json", b"\xef\xbb\xbf{\"name\":\"a\",\"retries\":1}");
+        let read: Option<Settings> = get_json(&with_bom, None, None).await.unwrap();
+        assert_eq!(1, read.unwrap().retries);
+        let broken = dir.write("broken.json", "{");
+        assert!(matches!(
+            get_json::<Settings>(&broken, None, None).await,
+            Err(FileUtilError::InvalidJson { .. })
+        ));
+        let missing: Option<Settings> = get_json(&dir.join("missing.json"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(None, missing);
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock.clone(), async {
+            let url = "gs://zdb_test/zdb/settings.json.gz";
+            put_json(url, &settings, None, Some(Compression::Gzip))
+                .await
+                .unwrap();
+            let read: Option<Settings> =
+                get_json(url, None, Some(Compression::Gzip)).await.unwrap();
+            assert_eq!(Some(settings), read);
+        })
+        .await;
+        let stored = mock.get("zdb_test", "zdb/settings.json.gz").unwrap();
+        assert_eq!("application/json", stored.content_type);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[tokio::test]
+    async fn get_json_pointer_of_nested_values() {
+        let dir = TempDir::new();
+        let document = serde_json::json!({
+            "name": "dataset",
+            "a/b": {"m~n": 1},
+            "items": [{"id": 1, "tags": ["x", "y"]}, {"id": 2, "tags": []}],
+        });
+        let path = dir.join("document.json.gz");
+        std::fs::write(
+            &path,
+            Compression::Gzip
+                .compress(document.to_string().as_bytes())
+                .unwrap(),
+        )
+        .unwrap();
+        let get = |pointer: &'static str| {
+            let path = path.clone();
+            async move {
+                get_json_pointer(&path, pointer, None, Some(Compression::Gzip))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(Some(serde_json::json!("y")), get("/items/0/tags/1").await);
+        assert_eq!(Some(serde_json::json!([])), get("/items/1/tags").await);
+        assert_eq!(Some(serde_json::json!(1)), get("/a~1b/m~0n").await);
+        assert_eq!(Some(document.clone()), get("").await);
+
+        assert_eq!(None, get("/items/2").await);
+        assert_eq!(None, get("/missing/name").await);
+        assert_eq!(None, get("/name/0").await);
+        assert_eq!(None, get("/items/01").await);
+        assert_eq!(None, get("name").await);
+
+        let missing = dir.join("missing.json");
+        assert_eq!(
+            None,
+            get_json_pointer(&missing, "/name", None, None)
+                .await
+                .unwrap()
+        );
+
+        let invalid = dir.write("invalid.json", "{\"name\":");
+        assert!(matches!(
+            get_json_pointer(&invalid, "/name", None, None).await,
+            Err(FileUtilError::InvalidJson { .. })
+        ));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[tokio::test]
+    async fn get_json_pointer_streams_web_documents() {
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let skipped: Vec<_> = (0..20_000).map(|i| format!("item-{}", i)).collect();
+        let document = serde_json::json!({"skipped": skipped, "meta": {"count": 20_000}});
+        let body = document.to_string();
+        let server = TestServer::start(move |_| TestResponse::ok(body.clone())).await;
+        let url = server.url("/document.json").to_string();
+
+        assert_eq!(
+            Some(serde_json::json!(20_000)),
+            get_json_pointer(&url, "/meta/count", None, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(serde_json::json!("item-19999")),
+            get_json_pointer(&url, "/skipped/19999", None, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn list_files_fs_with_limit() {
+        let dir = TempDir::new();
+        for i in 0..5 {
+            dir.write(&format!("file_{}", i), "x");
+        }
+        let dir_path = dir.path().display().to_string();
+
+        let limited = list_files(&dir_path, None, Some(3)).await.unwrap();
+        assert_eq!(3, limited.len());
+
+        let all = list_files(&dir_path, None, None).await.unwrap();
+        assert_eq!(5, all.len());
+    }
+
+    #[tokio::test]
+    async fn get_file_contents_reports_raw_size() {
+        let dir = TempDir::new();
+        let text = "a line that compresses well\n".repeat(100);
+        let gzipped = Compression::Gzip.compress(text.as_bytes()).unwrap();
+        let path = dir.write("fixture.txt.gz", &gzipped);
+
+        let sized = get_file_contents_with_size(&path, None, Some(Compression::Gzip))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(text.as_bytes(), sized.contents.as_slice());
+        assert_eq!(gzipped.len(), sized.raw_size);
+        assert!(sized.raw_size < sized.contents.len());
+    }
+
+    fn generated_chunks() -> (impl Stream<Item = Result<Bytes>>, Vec<u8>) {
+        let chunks: Vec<Bytes> = (0..20)
+            .map(|i| Bytes::from(format!("record {}\n", i)))
+            .collect();
+        let expected = chunks.concat();
+        (futures::stream::iter(chunks.into_iter().map(Ok)), expected)
+    }
+
+    #[tokio::test]
+    async fn transcode_gzip_to_zstd_with_line_transform() {
+        let dir = TempDir::new();
+        // large enough to be read in several chunks, ending without a newline
+        let text: String = (0..100_000)
+            .map(|i| format!("line {} of {}", i, rand::random::<u32>()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let src = dir.join("text.txt.gz");
+        std::fs::write(&src, Compression::Gzip.compress(text.as_bytes()).unwrap()).unwrap();
+        assert!(std::fs::metadata(&src).unwrap().len() > 64 * 1024);
+
+        let dst = dir.join("text.txt.zst");
+        let transcoded = transcode(
+            &src,
+            &dst,
+            Compression::Gzip,
+            Compression::Zstd,
+            Some(|line: &str| line.to_uppercase()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(transcoded);
+        let stored = Compression::Zstd
+            .decompress(&std::fs::read(&dst).unwrap())
+            .unwrap();
+        assert_eq!(text.to_uppercase().into_bytes(), stored);
+
+        let plain = dir.join("plain.txt.gz");
+        let transcoded = transcode(
+            &dst,
+            &plain,
+            Compression::Zstd,
+            Compression::Gzip,
+            None::<fn(&str) -> String>,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(transcoded);
+        let stored = Compression::Gzip
+            .decompress(&std::fs::read(&plain).unwrap())
+            .unwrap();
+        assert_eq!(text.to_uppercase().into_bytes(), stored);
+
+        let missing = dir.join("missing.gz");
+        let transcoded = transcode(
+            &missing,
+            &plain,
+            Compression::Gzip,
+            Compression::Zstd,
+            None::<fn(&str) -> String>,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!transcoded);
+    }
+
+    #[tokio::test]
+    async fn upload_stream_to_fs() {
+        let dir = TempDir::new();
+        let path = dir.join("uploaded.txt");
+        let (body, expected) = generated_chunks();
+
+        upload_stream(&path, body, mime::MimeType::Text)
+            .await
+            .unwrap();
+        assert_eq!(expected, std::fs::read(&path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_contents_to_fs_only_if_changed() {
+        let dir = TempDir::new();
+        let path = dir.join("body.txt.gz");
+        let write = |body: &'static [u8]| {
+            write_contents_if_changed(
+                &path,
+                body,
+                mime::MimeType::Text,
+                None,
+                Some(Compression::Gzip),
+            )
+        };
+
+        assert!(write(b"body").await.unwrap());
+        assert!(!write(b"body").await.unwrap());
+        assert!(write(b"changed").await.unwrap());
+        let contents = get_file_contents(&path, None, Some(Compression::Gzip))
+            .await
+            .unwrap();
+        assert_eq!(Some(b"changed".to_vec()), contents);
+    }
+
+    #[tokio::test]
+    async fn write_contents_to_web_only_if_changed() {
+        use crate::testing::http::{TestResponse, TestServer};
+        use std::sync::{Arc, Mutex};
+
+        let stored = Arc::new(Mutex::new(b"body".to_vec()));
+        let resource = stored.clone();
+        let server = TestServer::start(move |request| {
+            let mut resource = resource.lock().unwrap();
+            match request.method.as_str() {
+                "PUT" => {
+                    *resource = request.body.clone();
+                    TestResponse::status(204)
+                }
+                _ => TestResponse::ok(resource.clone()),
+            }
+        })
+        .await;
+        let url = server.url("body.txt").to_string();
+        let write = |body: &'static [u8]| {
+            write_contents_if_changed(&url, body, mime::MimeType::Text, None, None)
+        };
+
+        assert!(!write(b"body").await.unwrap());
+        assert!(write(b"changed").await.unwrap());
+        assert_eq!(b"changed".to_vec(), *stored.lock().unwrap());
+        let methods: Vec<String> = server.requests().into_iter().map(|r| r.method).collect();
+        assert_eq!(vec!["GET", "GET", "PUT"], methods);
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn upload_stream_to_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        let (body, expected) = generated_chunks();
+
+        gcs::client::with_client(
+            mock.clone(),
+            upload_stream("gs://bucket/uploaded.txt", body, mime::MimeType::Text),
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, mock.call_count("create_streamed"));
+        assert_eq!(expected, mock.get("bucket", "uploaded.txt").unwrap().body);
+    }
+
+    #[tokio::test]
+    async fn detect_compression_by_magic_bytes() {
+        let dir = TempDir::new();
+        let gzipped = Compression::Gzip.compress(b"some text").unwrap();
+        let named = dir.write("named.txt.gz", &gzipped);
+        let misnamed = dir.write("misnamed.txt", &gzipped);
+        let plain = dir.write("plain.gz", "some text");
+        let zstd = dir.write(
+            "misnamed.bin",
+            Compression::Zstd.compress(b"some text").unwrap(),
+        );
+
+        assert!(matches!(
+            detect_compression(&zstd, None).await.unwrap(),
+            Some(Compression::Zstd)
+        ));
+        assert!(matches!(
+            detect_compression(&named, None).await.unwrap(),
+            Some(Compression::Gzip)
+        ));
+        assert!(matches!(
+            detect_compression(&misnamed, None).await.unwrap(),
+            Some(Compression::Gzip)
+        ));
+        assert!(detect_compression(&plain, None).await.unwrap().is_none());
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn detect_compression_gcs_fetches_a_range() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert(
+            "bucket",
+            "data.bin",
+            Compression::Gzip.compress(b"x").unwrap(),
+        );
+
+        let detected = gcs::client::with_client(
+            mock.clone(),
+            detect_compression("gs://bucket/data.bin", None),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(detected, Some(Compression::Gzip)));
+        assert_eq!(vec!["download_range"], mock.calls());
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn list_files_best_effort_keeps_earlier_pages() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::quick_backoff;
+
+        let mock = MockGcsClient::new()
+            .with_page_size(2)
+            .failing_list_after(2)
+            .into_arc();
+        for i in 0..6 {
+            mock.insert("bucket", &format!("dir/file_{}", i), "x");
+        }
+
+        let (entries, err) = gcs::client::with_client(
+            mock.clone(),
+            list_files_best_effort("gs://bucket/dir/", Some(quick_backoff())),
+        )
+        .await;
+        assert_eq!(4, entries.len(), "the first two pages are kept");
+        assert!(err.is_some());
+    }
+
+    #[tokio::test]
+    async fn list_files_best_effort_fs() {
+        let dir = TempDir::new();
+        dir.write("a", "x");
+        dir.write("b", "x");
+
+        let (entries, err) = list_files_best_effort(&dir.path().display().to_string(), None).await;
+        assert_eq!(2, entries.len());
+        assert!(err.is_none());
+
+        let (entries, err) = list_files_best_effort(&dir.join("missing"), None).await;
+        assert!(entries.is_empty());
+        assert!(err.is_some());
+
+        let (entries, err) = list_files_best_effort("https://example.com/dir/", None).await;
+        assert!(entries.is_empty());
+        assert!(matches!(err, Some(FileUtilError::Unsupported { .. })));
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn list_files_gcs_with_limit() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().with_page_size(2).into_arc();
+        for i in 0..10 {
+            mock.insert("bucket", &format!("dir/file_{}", i), "x");
+        }
+
+        let limited =
+            gcs::client::with_client(mock.clone(), list_files("gs://bucket/dir/", None, Some(3)))
+                .await
+                .unwrap();
+        assert_eq!(
+            vec![
+                "gs://bucket/dir/file_0",
+                "gs://bucket/dir/file_1",
+                "gs://bucket/dir/file_2"
+            ],
+            limited
+        );
+        assert_eq!(
+            2,
+            mock.call_count("list"),
+            "stops paginating once the limit is reached"
+        );
+
+        let all =
+            gcs::client::with_client(mock.clone(), list_files("gs://bucket/dir/", None, None))
+                .await
+                .unwrap();
+        assert_eq!(10, all.len());
+    }
+
+    /// A zip archive of `entries` (stored uncompressed) with an archive comment of `comment_len` bytes.
+    fn fixture_zip(entries: &[(&str, usize)], comment_len: usize) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::<u8>::new()));
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, size) in entries {
+            archive.start_file(*name, stored).unwrap();
+            archive.write_all(&vec![b'z'; *size]).unwrap();
+        }
+        archive.set_comment("c".repeat(comment_len));
+        archive.finish().unwrap().into_inner()
+    }
+
+    fn entry_names_and_sizes(entries: Vec<archive::ZipEntry>) -> Vec<(String, u64)> {
+        entries
+            .into_iter()
+            .map(|entry| (entry.name, entry.size))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn list_zip_entries_of_fs_archive() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "archive.zip",
+            fixture_zip(&[("a.txt", 3), ("dir/b.bin", 100)], 0),
+        );
+
+        let entries = list_zip_entries_remote(&path, None).await.unwrap().unwrap();
+        assert_eq!(
+            vec![("a.txt".to_string(), 3), ("dir/b.bin".to_string(), 100)],
+            entry_names_and_sizes(entries)
+        );
+        assert!(list_zip_entries_remote(&dir.join("missing.zip"), None)
+            .await
+            .unwrap()
+            .is_none());
+
+        let not_zip = dir.write("not.zip", "plain text");
+        assert!(matches!(
+            list_zip_entries_remote(&not_zip, None).await,
+            Err(FileUtilError::InvalidZip { .. })
+        ));
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn list_zip_entries_of_gcs_archive() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let names: Vec<String> = (0..200)
+            .map(|i| format!("dir/entry_{:03}.txt", i))
+            .collect();
+        let entries: Vec<(&str, usize)> =
+            names.iter().map(|name| (name.as_str(), 50_000)).collect();
+        let mock = MockGcsClient::new().into_arc();
+        // the central directory exceeds the first tail read
+        mock.insert("bucket", "large.zip", fixture_zip(&entries, 0));
+        // the comment pushes the end of central directory record out of the first tail read
+        mock.insert(
+            "bucket",
+            "commented.zip",
+            fixture_zip(&entries[..2], 20_000),
+        );
+
+        gcs::client::with_client(mock.clone(), async {
+            let listed = list_zip_entries_remote("gs://bucket/large.zip", None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(200, listed.len());
+            assert_eq!("dir/entry_199.txt", listed[199].name);
+            assert_eq!(50_000, listed[199].compressed_size);
+
+            let listed = list_zip_entries_remote("gs://bucket/commented.zip", None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                vec![
+                    ("dir/entry_000.txt".to_string(), 50_000),
+                    ("dir/entry_001.txt".to_string(), 50_000)
+                ],
+                entry_names_and_sizes(listed)
+            );
+
+            assert_eq!(
+                0,
+                mock.call_count("download"),
+                "never downloads the whole archive"
+            );
+            assert_eq!(4, mock.call_count("download_range"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn write_batch_rolls_back_on_failure() {
+        let dir = TempDir::new();
+        let existing = dir.write("existing.txt", "previous");
+        let fresh = dir.join("fresh.txt");
+        let unwritable = dir.join("missing_dir/unwritable.txt");
+        let entry = |path: &str, body: &str| {
+            (
+                path.to_string(),
+                body.as_bytes().to_vec(),
+                mime::MimeType::Text,
+            )
+        };
+
+        let result = write_batch_atomic(
+            vec![
+                entry(&existing, "updated"),
+                entry(&fresh, "created"),
+                entry(&unwritable, "fails"),
+            ],
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!("previous", std::fs::read_to_string(&existing).unwrap());
+        assert!(!std::path::Path::new(&fresh).exists());
+
+        write_batch_atomic(
+            vec![entry(&existing, "updated"), entry(&fresh, "created")],
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!("updated", std::fs::read_to_string(&existing).unwrap());
+        assert_eq!("created", std::fs::read_to_string(&fresh).unwrap());
+    }
+
+    #[cfg(feature = "web")]
+    #[tokio::test]
+    async fn get_file_contents_guarded_trips_each_limit() {
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/slow" => TestResponse::ok("slow").with_delay(Duration::from_millis(500)),
+            _ => TestResponse::ok(vec![b'x'; 10_000]),
+        })
+        .await;
+        let large = server.url("/large").to_string();
+        let slow = server.url("/slow").to_string();
+        let second = Duration::from_secs(1);
+
+        assert_eq!(
+            Some(10_000),
+            get_file_contents_guarded(&large, 10_000, second, None)
+                .await
+                .unwrap()
+                .map(|contents| contents.len())
+        );
+        assert!(matches!(
+            get_file_contents_guarded(&large, 9_999, second, None).await,
+            Err(FileUtilError::SizeLimitExceeded {
+                max_bytes: 9_999,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            get_file_contents_guarded(&slow, 10_000, Duration::from_millis(100), None).await,
+            Err(FileUtilError::TimeLimitExceeded { .. })
+        ));
+        assert_eq!(
+            Some(b"slow".to_vec()),
+            get_file_contents_guarded(&slow, 10_000, second, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_config_str_plain_compressed_and_bom() {
+        let dir = TempDir::new();
+        let config = "key = \"value\"\n";
+        let plain = dir.write("config.toml", config);
+        let gzipped = dir.write(
+            "config.toml.bin",
+            Compression::Gzip.compress(config.as_bytes()).unwrap(),
+        );
+        let with_bom = dir.write("bom.toml", format!("\u{feff}{}", config));
+        let invalid = dir.write("invalid.toml", [0x66, 0x6f, 0xff, 0xfe]);
+
+        for path in [&plain, &gzipped, &with_bom] {
+            assert_eq!(
+                Some(config.to_string()),
+                read_config_str(path, None).await.unwrap(),
+                "{}",
+                path
+            );
+        }
+        assert!(matches!(
+            read_config_str(&invalid, None).await,
+            Err(FileUtilError::InvalidUtf8 { .. })
+        ));
+        assert_eq!(
+            None,
+            read_config_str(&dir.join("missing.toml"), None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_windows_of_fixed_size() {
+        let dir = TempDir::new();
+        let fixture: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.write("framed.bin", &fixture);
+
+        let windows: Vec<Vec<u8>> = read_windows(&path, 300, None).try_collect().await.unwrap();
+        let sizes: Vec<usize> = windows.iter().map(|window| window.len()).collect();
+        assert_eq!(vec![300, 300, 300, 100], sizes);
+        assert_eq!(fixture, windows.concat());
+
+        let exact: Vec<Vec<u8>> = read_windows(&path, 500, None).try_collect().await.unwrap();
+        assert_eq!(2, exact.len());
+
+        let missing: Vec<Vec<u8>> = read_windows(&dir.join("missing.bin"), 300, None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[cfg(feature = "web")]
+    #[tokio::test]
+    async fn read_windows_of_a_server_ignoring_ranges() {
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let fixture: Vec<u8> = (0..250u32).map(|i| i as u8).collect();
+        let body = fixture.clone();
+        let server = TestServer::start(move |_| TestResponse::ok(body.clone())).await;
+        let windows: Vec<Vec<u8>> = read_windows(server.url("/framed.bin").as_str(), 100, None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![100, 100, 50],
+            windows.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(fixture, windows.concat());
+        // one request, its body cut into the windows rather than read again for each
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn read_windows_with_ranged_gcs_reads() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("bucket", "framed.bin", vec![1u8; 250]);
+        let windows: Vec<Vec<u8>> = gcs::client::with_client(
+            mock.clone(),
+            read_windows("gs://bucket/framed.bin", 100, None).try_collect(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![100, 100, 50],
+            windows.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(3, mock.call_count("download_range"));
+        assert_eq!(0, mock.call_count("download"));
+    }
+
+    #[tokio::test]
+    async fn directory_size_of_fs_tree() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "12345");
+        dir.write("sub/b.txt", "123");
+        dir.write("sub/deeper/c.bin", vec![0u8; 1000]);
+
+        let size = directory_size(&dir.path().display().to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(1008, size);
+        assert!(directory_size(&dir.join("missing"), None).await.is_err());
+        assert!(matches!(
+            directory_size("https://example.com/dir/", None).await,
+            Err(FileUtilError::Unsupported { .. })
+        ));
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn directory_size_of_gcs_prefix() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().with_page_size(2).into_arc();
+        for i in 1..=5 {
+            mock.insert("bucket", &format!("dir/file_{}", i), vec![0u8; i * 10]);
+        }
+        mock.insert("bucket", "other/file", vec![0u8; 1000]);
+
+        let size = gcs::client::with_client(mock.clone(), directory_size("gs://bucket/dir/", None))
+            .await
+            .unwrap();
+        assert_eq!(150, size);
+        assert_eq!(3, mock.call_count("list"));
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn list_files_multi_on_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().with_page_size(1).into_arc();
+        for name in [
+            "data/2021-01/a",
+            "data/2021-01/b",
+            "data/2021-02/c",
+            "other/d",
+        ] {
+            mock.insert("bucket", name, "body");
+        }
+        let prefixes = vec![
+            "gs://bucket/data/2021-0".to_string(),
+            "gs://bucket/data/2021-01".to_string(),
+        ];
+
+        let merged =
+            gcs::client::with_client(mock.clone(), list_files_multi(&prefixes, 2, None, false))
+                .await
+                .unwrap();
+        assert_eq!(5, merged.len());
+
+        let deduped =
+            gcs::client::with_client(mock.clone(), list_files_multi(&prefixes, 2, None, true))
+                .await
+                .unwrap();
+        assert_eq!(
+            vec![
+                "gs://bucket/data/2021-01/a",
+                "gs://bucket/data/2021-01/b",
+                "gs://bucket/data/2021-02/c",
+            ],
+            deduped
+        );
+    }
+
+    #[tokio::test]
+    async fn error_names_the_fs_path() {
+        let dir = TempDir::new();
+        let path = dir.join("missing_dir");
+
+        let err = list_files(&path, None, None).await.unwrap_err();
+        assert!(matches!(err, FileUtilError::Operation { op: "list", .. }));
+        let message = err.to_string();
+        assert!(message.contains(&path), "{}", message);
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn error_names_the_gcs_url() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let mock = MockGcsClient::new().failing_list_after(0).into_arc();
+        let err = gcs::client::with_client(
+            mock,
+            list_files(
+                "gs://bucket/dir/",
+                Some(crate::testing::quick_backoff()),
+                None,
+            ),
+        )
+        .await
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("gs://bucket/dir/"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn get_file_range_and_tail_of_every_backend() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let contents: Vec<u8> = (0..=255u8).collect();
+        let dir = TempDir::new();
+        let local = dir.write("big.bin", &contents);
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/big.bin", contents.clone());
+        let served = contents.clone();
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/big.bin" => {
+                let range = request.header("range").unwrap_or("").to_string();
+                let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+                    (Ok(start), Ok(end)) => (start, end + 1),
+                    (Ok(start), Err(_)) => (start, served.len()),
+                    (Err(_), Ok(suffix)) => (served.len() - suffix, served.len()),
+                    _ => return TestResponse::ok(served.clone()),
+                };
+                TestResponse::status(206).with_body(served[start..end].to_vec())
+            }
+            _ => TestResponse::status(404),
+        })
+        .await;
+        let web = server.url("/big.bin").to_string();
+
+        gcs::client::with_client(mock, async {
+            for url in [local.as_str(), "gs://zdb_test/zdb/big.bin", web.as_str()] {
+                let header = get_file_range(url, 4, 3, None).await.unwrap();
+                assert_eq!(Some(vec![4, 5, 6]), header, "{}", url);
+                let footer = get_file_tail(url, 2, None).await.unwrap();
+                assert_eq!(Some(vec![254, 255]), footer, "{}", url);
+            }
+            let clamped = get_file_range(&local, 250, 100, None).await.unwrap();
+            assert_eq!(Some((250..=255u8).collect::<Vec<_>>()), clamped);
+            assert_eq!(
+                None,
+                get_file_range("gs://zdb_test/zdb/missing", 0, 1, None)
+                    .await
+                    .unwrap()
+            );
+        })
+        .await;
+    }
+
+    #[cfg(feature = "web")]
+    #[tokio::test]
+    async fn error_names_the_web_url() {
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let server = TestServer::start(|_| TestResponse::status(500)).await;
+        let url = server.url("/broken");
+        let err = read_range(
+            url.as_str(),
+            0,
+            Some(2),
+            Some(crate::testing::quick_backoff()),
+        )
+        .await
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(url.as_str()), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn get_first_existing_skips_missing() {
+        let dir = TempDir::new();
+        let missing = dir.join("override.toml");
+        let fallback = dir.write("default.toml", "key = 1");
+
+        let found = get_first_existing(&[missing.clone(), fallback.clone()], None, None)
+            .await
+            .unwrap();
+        assert_eq!(Some((fallback, b"key = 1".to_vec())), found);
+
+        let nothing = get_first_existing(&[missing], None, None).await.unwrap();
+        assert_eq!(None, nothing);
+    }
+
+    fn large_fixture() -> Vec<u8> {
+        (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn download_to_writer_verified_on_fs() {
+        let dir = TempDir::new();
+        let fixture = large_fixture();
+        let path = dir.write("large.bin", &fixture);
+        let expected = hex::encode(ChecksumAlgo::Sha256.digest(&fixture));
+
+        let mut written = Vec::<u8>::new();
+        let size =
+            download_to_writer_verified(&path, &mut written, &expected, ChecksumAlgo::Sha256, None)
+                .await
+                .unwrap();
+        assert_eq!(Some(fixture.len() as u64), size);
+        assert_eq!(fixture, written);
+
+        let err = download_to_writer_verified(
+            &path,
+            &mut futures::io::sink(),
+            &hex::encode(ChecksumAlgo::Sha256.digest(b"other")),
+            ChecksumAlgo::Sha256,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, FileUtilError::ChecksumMismatch { .. }),
+            "{}",
+            err
+        );
+
+        let missing = download_to_writer_verified(
+            &dir.join("missing.bin"),
+            &mut futures::io::sink(),
+            &expected,
+            ChecksumAlgo::Sha256,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, missing);
+    }
+
+    #[cfg(feature = "gcs")]
+    #[tokio::test]
+    async fn download_to_writer_verified_on_gcs() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let fixture = large_fixture();
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("bucket", "large.bin", fixture.clone());
+        let md5 = base64::encode(ChecksumAlgo::Md5.digest(&fixture));
+
+        gcs::client::with_client(mock.clone(), async {
+            let mut written = Vec::<u8>::new();
+            download_to_writer_verified(
+                "gs://bucket/large.bin",
+                &mut written,
+                &md5,
+                ChecksumAlgo::Md5,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fixture, written);
+
+            let err = download_to_writer_verified(
+                "gs://bucket/large.bin",
+                &mut futures::io::sink(),
+                "AAAAAA==",
+                ChecksumAlgo::Crc32c,
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(err, FileUtilError::ChecksumMismatch { .. }),
+                "{}",
+                err
+            );
+        })
+        .await;
+        assert_eq!(
+            0,
+            mock.call_count("download"),
+            "never buffers the whole object"
+        );
+    }
+
+    #[cfg(feature = "web")]
+    #[tokio::test]
+    async fn download_to_writer_verified_on_web() {
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let fixture = large_fixture();
+        let expected = hex::encode(ChecksumAlgo::Sha256.digest(&fixture));
+        let body = fixture.clone();
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/large.bin" => TestResponse::ok(body.clone()),
+            _ => TestResponse::status(404),
+        })
+        .await;
+
+        let mut written = Vec::<u8>::new();
+        download_to_writer_verified(
+            server.url("/large.bin").as_str(),
+            &mut written,
+            &expected,
+            ChecksumAlgo::Sha256,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fixture, written);
+
+        let err = download_to_writer_verified(
+            server.url("/large.bin").as_str(),
+            &mut futures::io::sink(),
+            &expected.replace('a', "b"),
+            ChecksumAlgo::Sha256,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, FileUtilError::ChecksumMismatch { .. }),
+            "{}",
+            err
+        );
+
+        let missing = download_to_writer_verified(
+            server.url("/missing.bin").as_str(),
+            &mut futures::io::sink(),
+            &expected,
+            ChecksumAlgo::Sha256,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, missing);
+    }
+
+    #[tokio::test]
+    async fn strict_existence_tells_denials_from_absence() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/a.txt" => TestResponse::ok("a"),
+            "/secret.txt" => TestResponse::status(403),
+            _ => TestResponse::status(404),
+        })
+        .await;
+        let secret = server.url("/secret.txt").to_string();
+        assert!(!is_exists(&secret, None).await.unwrap());
+        match is_exists_strict(&secret, None).await {
+            Existence::Indeterminate(e) => assert_eq!(ErrorKind::PermissionDenied, e.kind()),
+            existence => panic!("{:?}", existence),
+        }
+        let existence = |path: &str| {
+            let url = server.url(path).to_string();
+            async move { is_exists_strict(&url, None).await.exists() }
+        };
+        assert_eq!(Some(true), existence("/a.txt").await);
+        assert_eq!(Some(false), existence("/b.txt").await);
+        // the 403 is answered at once, not retried
+        assert_eq!(4, server.requests().len());
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/a.txt", "a");
+        gcs::client::with_client(mock, async {
+            let a = is_exists_strict("gs://zdb_test/zdb/a.txt", None).await;
+            assert_eq!(Some(true), a.exists());
+            let b = is_exists_strict("gs://zdb_test/zdb/b.txt", None).await;
+            assert_eq!(Some(false), b.exists());
+        })
+        .await;
+
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", b"a");
+        assert_eq!(Some(true), is_exists_strict(&path, None).await.exists());
+        let missing = dir.join("b.txt");
+        assert_eq!(Some(false), is_exists_strict(&missing, None).await.exists());
+    }
+
+    #[tokio::test]
+    async fn delete_directories_recursively() {
+        use crate::testing::gcs::MockGcsClient;
+
+        let dir = TempDir::new();
+        let nested = dir.write("out/nested/a.txt", "a");
+        dir.write("out/b.txt", "b");
+        let out = dir.join("out");
+        assert!(delete_contents(&out, None).await.is_err());
+        assert!(std::path::Path::new(&nested).exists());
+        delete_contents_recursive(&out, None, 4).await.unwrap();
+        assert!(!std::path::Path::new(&out).exists());
+        assert!(delete_contents_recursive(&out, None, 4).await.is_err());
+
+        let mock = MockGcsClient::new().into_arc();
+        let names = [
+            "zdb/out",
+            "zdb/out/",
+            "zdb/out/a.txt",
+            "zdb/out/x/b.txt",
+            "zdb/outside",
+        ];
+        for name in names {
+            mock.insert("zdb_test", name, "x");
+        }
+        gcs::client::with_client(mock.clone(), async {
+            delete_contents_recursive("gs://zdb_test/zdb/out", None, 4)
+                .await
+                .unwrap();
+        })
+        .await;
+        let left: Vec<_> = names
+            .iter()
+            .filter(|name| mock.get("zdb_test", name).is_some())
+            .collect();
+        assert_eq!(vec![&"zdb/outside"], left);
+
+        assert_eq!(
+            ErrorKind::Unsupported,
+            delete_contents("https://example.com/a.txt", None)
+                .await
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[tokio::test]
+    async fn checksums_of_every_backend() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let body = b"hello world";
+        let digests = |algo: ChecksumAlgo| Some(algo.digest(body));
+        let dir = TempDir::new();
+        let path = dir.write("a.txt", body);
+        assert_eq!(
+            digests(ChecksumAlgo::Sha256),
+            checksum(&path, ChecksumAlgo::Sha256, None).await.unwrap()
+        );
+        assert_eq!(
+            None,
+            checksum(&dir.join("missing.txt"), ChecksumAlgo::Md5, None)
+                .await
+                .unwrap()
+        );
+
+        let mock = MockGcsClient::new().into_arc();
+        mock.insert("zdb_test", "zdb/a.txt", "hello world");
+        gcs::client::with_client(mock.clone(), async {
+            for algo in [ChecksumAlgo::Md5, ChecksumAlgo::Crc32c] {
+                let stored = checksum("gs://zdb_test/zdb/a.txt", algo, None).await;
+                assert_eq!(digests(algo), stored.unwrap());
+            }
+            assert_eq!(0, mock.call_count("download_streamed"));
+            let hashed = checksum("gs://zdb_test/zdb/a.txt", ChecksumAlgo::Sha256, None).await;
+            assert_eq!(digests(ChecksumAlgo::Sha256), hashed.unwrap());
+            assert_eq!(1, mock.call_count("download_streamed"));
+            let missing = checksum("gs://zdb_test/zdb/b.txt", ChecksumAlgo::Md5, None).await;
+            assert_eq!(None, missing.unwrap());
+        })
+        .await;
+
+        let repr_digest = format!(
+            "sha-256=:{}:",
+            base64::encode(ChecksumAlgo::Sha256.digest(body))
+        );
+        let goog_hash = format!(
+            "crc32c={}",
+            base64::encode(ChecksumAlgo::Crc32c.digest(body))
+        );
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/digest.txt" => TestResponse::ok("hello world")
+                .with_header("repr-digest", &repr_digest)
+                .with_header("x-goog-hash", &goog_hash),
+            "/plain.txt" => TestResponse::ok("hello world"),
+            _ => TestResponse::status(404),
+        })
+        .await;
+        let announced = server.url("/digest.txt").to_string();
+        for algo in [ChecksumAlgo::Sha256, ChecksumAlgo::Crc32c] {
+            assert_eq!(
+                digests(algo),
+                checksum(&announced, algo, None).await.unwrap()
+            );
+        }
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.method == "HEAD"));
+        let plain = server.url("/plain.txt").to_string();
+        assert_eq!(
+            digests(ChecksumAlgo::Md5),
+            checksum(&plain, ChecksumAlgo::Md5, None).await.unwrap()
+        );
+        let missing = server.url("/missing.txt").to_string();
+        assert_eq!(
+            None,
+            checksum(&missing, ChecksumAlgo::Md5, None).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn operations_give_up_at_their_deadline() {
+        use crate::testing::gcs::MockGcsClient;
+        use crate::testing::http::{TestResponse, TestServer};
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/hung.txt" => TestResponse::ok("late").with_delay(Duration::from_secs(10)),
+            _ => TestResponse::ok("prompt"),
+        })
+        .await;
+        let started = Instant::now();
+        let result = with_timeout(
+            Duration::from_millis(100),
+            get_file_contents(server.url("/hung.txt").as_str(), None, None),
+        )
+        .await;
+        assert!(matches!(result, Err(FileUtilError::DeadlineExceeded)));
+        assert_eq!(ErrorKind::Transient, result.unwrap_err().kind());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let contents = with_deadline(
+            deadline,
+            get_file_contents(server.url("/prompt.txt").as_str(), None, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(b"prompt".to_vec()), contents);
+
+        let mock = MockGcsClient::new().into_arc();
+        gcs::client::with_client(mock, async {
+            with_deadline(
+                deadline,
+                write_contents(
+                    "gs://zdb_test/zdb/a.txt",
+                    "a",
+                    mime::MimeType::Text,
+                    None,
+                    None,
+                ),
+            )
+            .await
+            .unwrap();
+            let listed = with_deadline(deadline, list_files("gs://zdb_test/zdb", None, None))
+                .await
+                .unwrap();
+            assert_eq!(vec!["gs://zdb_test/zdb/a.txt".to_string()], listed);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn wait_for_existence_of_fs_file() {
+        let dir = TempDir::new();
+        let path = dir.join("produced.txt");
+
+        let produced = path.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            std::fs::write(produced, "done").unwrap();
+        });
+        let appeared = wait_for_existence(
+            &path,
+            Duration::from_millis(20),
+            Duration::from_secs(5),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(appeared);
+
+        let started = Instant::now();
+        let appeared = wait_for_existence(
+            &dir.join("never.txt"),
+            Duration::from_millis(20),
+            Duration::from_millis(100),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!appeared);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn file_urls_and_drive_paths_are_local() {
+        assert!(remote_url("C:\\data\\x.txt").is_none());
+        assert!(remote_url("c:/data/x.txt").is_none());
+        assert!(remote_url("file:///tmp/x").is_none());
+        assert!(remote_url("gs://bucket/x").is_some());
+        assert!(is_local_path("C:\\data\\x.txt").unwrap());
+
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        let url = Url::from_file_path(&path).unwrap().to_string();
+        write_contents(&url, "hello", mime::MimeType::Text, None, None)
+            .await
+            .unwrap();
+        assert_eq!("hello", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            Some("hello".to_string()),
+            get_file_contents_str(&url, None, None).await.unwrap()
+        );
+        assert_eq!(Some(5), stat(&url, None).await.unwrap().unwrap().size);
+
+        let dir_url = Url::from_directory_path(dir.path()).unwrap().to_string();
+        assert_eq!(
+            vec![path.clone()],
+            list_files(&dir_url, None, None).await.unwrap()
+        );
+
+        let moved = Url::from_file_path(dir.join("b.txt")).unwrap().to_string();
+        assert!(move_contents(&url, &moved, None).await.unwrap());
+        assert!(!is_exists(&path, None).await.unwrap());
+        assert!(is_exists(&dir.join("b.txt"), None).await.unwrap());
     }
 }
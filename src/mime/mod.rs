@@ -7,9 +7,9 @@ macro_rules! enum_str{
             $($variant),*,
         }
 
-        impl Into<&str> for $name {
-            fn into(self) -> &'static str{
-                match self {
+        impl From<$name> for &'static str {
+            fn from(value: $name) -> &'static str {
+                match value {
                     $($name::$variant => $val), *
                 }
             }
@@ -28,3 +28,106 @@ enum_str! {
         Mp4 ="video/mp4",
     }
 }
+
+impl MimeType {
+    /// The mime type the extension of the file name at `url_or_path` stands for, `None`
+    /// when it is not one of the known ones.
+    pub fn from_extension(url_or_path: &str) -> Option<Self> {
+        let path = crate::uri_path::UriPath::new(url_or_path);
+        match path.extension()?.to_ascii_lowercase().as_str() {
+            "json" => Some(MimeType::Json),
+            "jsonl" | "ndjson" => Some(MimeType::Jsonl),
+            "xml" => Some(MimeType::Xml),
+            "txt" | "text" | "log" => Some(MimeType::Text),
+            "jpg" | "jpeg" => Some(MimeType::Jpeg),
+            "mp4" => Some(MimeType::Mp4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentKind {
+    Text,
+    Binary,
+}
+
+/// Control characters tolerated in text, as a fraction of all characters.
+const MAX_CONTROL_CHAR_RATIO: f64 = 0.1;
+
+/// Heuristically tell text from binary: text is valid UTF-8 without null bytes and with
+/// few control characters other than whitespace. A multi-byte character cut at the end
+/// is tolerated so that a leading sample of a file can be inspected.
+pub fn content_kind(bytes: &[u8]) -> ContentKind {
+    if bytes.contains(&0) {
+        return ContentKind::Binary;
+    }
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return ContentKind::Binary,
+    };
+
+    let (chars, controls) = text.chars().fold((0usize, 0usize), |(chars, controls), c| {
+        let is_control = c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b');
+        (chars + 1, controls + is_control as usize)
+    });
+    if chars > 0 && controls as f64 / chars as f64 > MAX_CONTROL_CHAR_RATIO {
+        ContentKind::Binary
+    } else {
+        ContentKind::Text
+    }
+}
+
+/// Whether `bytes` look like text, i.e. `get_file_contents_str` can be called on them safely.
+pub fn is_text(bytes: &[u8]) -> bool {
+    content_kind(bytes) == ContentKind::Text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_text_and_binary() {
+        let text_cases: [&[u8]; 5] = [
+            b"",
+            b"plain ascii\nwith lines\r\n\tand tabs",
+            "ユニコード テキスト".as_bytes(),
+            // a multi-byte character cut by sampling
+            &"テキスト".as_bytes()[..7],
+            b"\x1b[31mcolored\x1b[0m output",
+        ];
+        for bytes in text_cases.iter() {
+            assert!(is_text(bytes), "{:?}", bytes);
+        }
+
+        let gzipped = crate::compression::Compression::Gzip
+            .compress(b"some text")
+            .unwrap();
+        let binary_cases: [&[u8]; 4] = [
+            &gzipped,
+            b"text with a \0 null byte",
+            b"text with an invalid \xff byte in the middle",
+            b"\x01\x02\x03\x04 mostly controls \x05\x06\x07\x08",
+        ];
+        for bytes in binary_cases.iter() {
+            assert_eq!(ContentKind::Binary, content_kind(bytes), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn mime_types_from_extensions() {
+        let mime_of = |path| MimeType::from_extension(path).map(<&'static str>::from);
+        assert_eq!(Some("application/json"), mime_of("gs://bucket/a/b.json"));
+        assert_eq!(Some("application/json-seq"), mime_of("/tmp/records.NDJSON"));
+        assert_eq!(
+            Some("image/jpeg"),
+            mime_of("https://example.com/a.jpg?size=2")
+        );
+        assert_eq!(None, mime_of("/tmp/archive.json.gz"));
+        assert_eq!(None, mime_of("gs://bucket/Makefile"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Progress of the transfers the free functions make, reported to a `ProgressObserver`
+//! attached with `ClientConfig::with_progress_observer`, e.g. to draw progress bars.
+//!
+//! Whole reads and writes such as `get_file_contents` and `write_contents` report once they
+//! are done; streamed ones such as `open_read`, `open_write`, `upload_stream` and
+//! `copy_contents` report every chunk. Copies between objects of one gcs bucket are made
+//! by gcs itself, and those between local files by the filesystem, and report nothing.
+use bytes::Bytes;
+use futures::{ready, Stream};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// Told about every transfer, which may run concurrently; the path tells them apart.
+pub trait ProgressObserver: Send + Sync {
+    /// The transfer of `path` starts, of `total` bytes when the size is known up front.
+    /// Streamed downloads of gcs objects and web urls read it with `stat` first.
+    fn started(&self, _path: &str, _direction: Direction, _total: Option<u64>) {}
+
+    /// `transferred` bytes of `path` have been moved so far.
+    fn progressed(&self, path: &str, transferred: u64, total: Option<u64>);
+
+    /// The transfer of `path` ends. It did not succeed when it failed, or when a stream was
+    /// dropped before its end.
+    fn finished(&self, _path: &str, _succeeded: bool) {}
+}
+
+/// One transfer being reported, which finishes as failed unless `finish`ed.
+pub(crate) struct Transfer {
+    observer: Arc<dyn ProgressObserver>,
+    path: String,
+    transferred: u64,
+    total: Option<u64>,
+    finished: bool,
+}
+
+impl Transfer {
+    /// `None` when no observer is attached.
+    pub(crate) fn start(path: &str, direction: Direction, total: Option<u64>) -> Option<Self> {
+        let observer = crate::config::progress_observer()?;
+        observer.started(path, direction, total);
+        Some(Self {
+            observer,
+            path: path.to_string(),
+            transferred: 0,
+            total,
+            finished: false,
+        })
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.transferred += bytes;
+        self.observer
+            .progressed(&self.path, self.transferred, self.total);
+    }
+
+    fn finish(mut self, succeeded: bool) {
+        self.finished = true;
+        self.observer.finished(&self.path, succeeded);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.observer.finished(&self.path, false);
+        }
+    }
+}
+
+/// Report a transfer made in one go, which moved `transferred` bytes, or failed with `None`.
+pub(crate) fn report_whole(transfer: Option<Transfer>, transferred: Option<u64>) {
+    let mut transfer = match transfer {
+        Some(transfer) => transfer,
+        None => return,
+    };
+    match transferred {
+        Some(transferred) => {
+            transfer.total.get_or_insert(transferred);
+            transfer.advance(transferred);
+            transfer.finish(true);
+        }
+        None => transfer.finish(false),
+    }
+}
+
+/// `stream`, reporting every chunk it yields as transferred bytes of `path`.
+pub(crate) fn observe_stream<S, E>(
+    path: &str,
+    direction: Direction,
+    total: Option<u64>,
+    stream: S,
+) -> ObservedStream<S>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>>,
+{
+    ObservedStream {
+        inner: Box::pin(stream),
+        transfer: Transfer::start(path, direction, total),
+    }
+}
+
+pub(crate) struct ObservedStream<S> {
+    inner: Pin<Box<S>>,
+    transfer: Option<Transfer>,
+}
+
+impl<S, E> Stream for ObservedStream<S>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.as_mut().poll_next(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                if let Some(transfer) = self.transfer.as_mut() {
+                    transfer.advance(chunk.len() as u64);
+                }
+            }
+            Some(Err(_)) => {
+                if let Some(transfer) = self.transfer.take() {
+                    transfer.finish(false);
+                }
+            }
+            None => {
+                if let Some(transfer) = self.transfer.take() {
+                    transfer.finish(true);
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{with_config, ClientConfig};
+    use crate::testing::gcs::MockGcsClient;
+    use crate::testing::TempDir;
+    use crate::{copy_contents, get_file_contents, mime, write_contents};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl ProgressObserver for Recorder {
+        fn started(&self, path: &str, direction: Direction, total: Option<u64>) {
+            let event = format!("start {:?} {} {:?}", direction, path, total);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn progressed(&self, path: &str, transferred: u64, total: Option<u64>) {
+            let event = format!("progress {} {} {:?}", path, transferred, total);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn finished(&self, path: &str, succeeded: bool) {
+            let event = format!("finish {} {}", path, succeeded);
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn transfers_report_their_progress() {
+        let dir = TempDir::new();
+        let path = dir.join("a.txt");
+        let url = "gs://zdb_test/zdb/a.txt";
+        let recorder = Arc::new(Recorder::default());
+        let mock = MockGcsClient::new().into_arc();
+        let config = ClientConfig::new()
+            .with_progress_observer(recorder.clone())
+            .with_gcs_client(mock);
+        with_config(config, async {
+            write_contents(&path, "hello", mime::MimeType::Text, None, None)
+                .await
+                .unwrap();
+            assert!(copy_contents(&path, url, None).await.unwrap());
+            get_file_contents(url, None, None).await.unwrap();
+        })
+        .await;
+
+        // the transfers of a copy run together, so only those of each path are ordered
+        let events_of = |of: &str| -> Vec<String> {
+            let events = recorder.events.lock().unwrap();
+            let field = format!(" {} ", of);
+            events
+                .iter()
+                .filter(|event| event.contains(&field))
+                .cloned()
+                .collect()
+        };
+        let local = vec![
+            format!("start Upload {} Some(5)", path),
+            format!("progress {} 5 Some(5)", path),
+            format!("finish {} true", path),
+            format!("start Download {} Some(5)", path),
+            format!("progress {} 5 Some(5)", path),
+            format!("finish {} true", path),
+        ];
+        assert_eq!(local, events_of(&path));
+        let remote = vec![
+            format!("start Upload {} None", url),
+            format!("progress {} 5 None", url),
+            format!("finish {} true", url),
+            format!("start Download {} None", url),
+            format!("progress {} 5 Some(5)", url),
+            format!("finish {} true", url),
+        ];
+        assert_eq!(remote, events_of(url));
+    }
+}
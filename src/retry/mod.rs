@@ -0,0 +1,346 @@
+//! How failed gcs and web requests are retried, as a `RetryPolicy` set on the config with
+//! `ClientConfig::with_retry_policy`. A policy decides both which errors are retried and
+//! how long to wait in between. It applies only to the operations given `None` as their
+//! backoff: an `ExponentialBackoff` given to a call wins over the policy, and retries that
+//! call the way the backend always has, whatever the policy would say. The backoff of the
+//! config is used when no policy is set.
+use crate::ErrorKind;
+#[cfg(any(feature = "gcs", feature = "web"))]
+use backoff::Error as BackoffError;
+use backoff::ExponentialBackoff;
+use rand::Rng;
+use std::time::Duration;
+#[cfg(any(feature = "gcs", feature = "web"))]
+use std::{future::Future, sync::Arc};
+#[cfg(any(feature = "gcs", feature = "web"))]
+use tokio::time::Instant;
+
+pub trait RetryPolicy: Send + Sync {
+    /// Whether an attempt that failed with an error of `kind` is worth retrying. By default
+    /// transient failures and rate limiting are.
+    fn is_retryable(&self, kind: ErrorKind) -> bool {
+        matches!(kind, ErrorKind::Transient | ErrorKind::RateLimited)
+    }
+
+    /// How long to wait before the next attempt, once `attempts` have failed, the last one
+    /// with an error of `kind`, `elapsed` after the first started. `None` gives up.
+    fn next_delay(&self, attempts: usize, elapsed: Duration, kind: ErrorKind) -> Option<Duration>;
+}
+
+/// Fail on the first error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoRetry;
+
+impl RetryPolicy for NoRetry {
+    fn is_retryable(&self, _kind: ErrorKind) -> bool {
+        false
+    }
+
+    fn next_delay(&self, _: usize, _: Duration, _: ErrorKind) -> Option<Duration> {
+        None
+    }
+}
+
+/// Wait `interval` between attempts, making at most `max_attempts` of them.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedRetry {
+    pub interval: Duration,
+    pub max_attempts: usize,
+}
+
+impl RetryPolicy for FixedRetry {
+    fn next_delay(&self, attempts: usize, _: Duration, _: ErrorKind) -> Option<Duration> {
+        (attempts < self.max_attempts).then_some(self.interval)
+    }
+}
+
+/// Wait `initial_interval`, longer by `multiplier` after every failed attempt up to
+/// `max_interval`, each wait randomized by up to `randomization_factor` of it, until
+/// `max_elapsed_time` has passed. The defaults are those of `ExponentialBackoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialRetry {
+    pub initial_interval: Duration,
+    pub randomization_factor: f64,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    pub max_elapsed_time: Option<Duration>,
+}
+
+impl Default for ExponentialRetry {
+    fn default() -> Self {
+        Self::from(&ExponentialBackoff::default())
+    }
+}
+
+impl From<&ExponentialBackoff> for ExponentialRetry {
+    fn from(backoff: &ExponentialBackoff) -> Self {
+        Self {
+            initial_interval: backoff.initial_interval,
+            randomization_factor: backoff.randomization_factor,
+            multiplier: backoff.multiplier,
+            max_interval: backoff.max_interval,
+            max_elapsed_time: backoff.max_elapsed_time,
+        }
+    }
+}
+
+impl RetryPolicy for ExponentialRetry {
+    fn next_delay(&self, attempts: usize, elapsed: Duration, _: ErrorKind) -> Option<Duration> {
+        if self.max_elapsed_time.is_some_and(|max| elapsed >= max) {
+            return None;
+        }
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let interval = self
+            .initial_interval
+            .mul_f64(self.multiplier.powi(exponent))
+            .min(self.max_interval);
+        let jitter = 1.0 + self.randomization_factor * rand::thread_rng().gen_range(-1.0..=1.0);
+        Some(interval.mul_f64(jitter.max(0.0)))
+    }
+}
+
+/// An error a policy can class.
+#[cfg(any(feature = "gcs", feature = "web"))]
+pub(crate) trait Classified {
+    fn kind(&self) -> ErrorKind;
+}
+
+#[cfg(feature = "gcs")]
+impl Classified for crate::gcs::FileUtilGcsError {
+    fn kind(&self) -> ErrorKind {
+        self.kind()
+    }
+}
+
+#[cfg(feature = "web")]
+impl Classified for crate::web::FileUtilWebError {
+    fn kind(&self) -> ErrorKind {
+        self.kind()
+    }
+}
+
+/// `backoff::future::retry` with `backoff`, or, when it is `None`, with the policy of the
+/// current config if it has one, or else its backoff. A given `backoff` wins over a policy.
+#[cfg(feature = "web")]
+pub(crate) async fn retry<I, E, F, Fut>(
+    backoff: Option<ExponentialBackoff>,
+    operation: F,
+) -> Result<I, E>
+where
+    E: Classified,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<I, BackoffError<E>>>,
+{
+    if backoff.is_none() {
+        if let Some(policy) = crate::config::retry_policy() {
+            return retry_with_policy(policy, operation, |_, _, _| true).await;
+        }
+    }
+    backoff::future::retry(crate::config::backoff_or_default(backoff), operation).await
+}
+
+#[cfg(any(feature = "gcs", feature = "web"))]
+/// Retry the transient errors of `operation` as `policy` says. A permanent error is returned
+/// at once: callers mark errors that must not be retried so, such as a failed non-idempotent
+/// request. `on_retry` is told the failed attempts, the time elapsed and the next delay
+/// before every retry, and gives up by returning `false`.
+pub(crate) async fn retry_with_policy<I, E, F, Fut>(
+    policy: Arc<dyn RetryPolicy>,
+    mut operation: F,
+    mut on_retry: impl FnMut(usize, Duration, Duration) -> bool,
+) -> Result<I, E>
+where
+    E: Classified,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<I, BackoffError<E>>>,
+{
+    let started = Instant::now();
+    let mut attempts = 0;
+    loop {
+        let e = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(BackoffError::Permanent(e)) => return Err(e),
+            Err(BackoffError::Transient(e)) => e,
+        };
+        attempts += 1;
+        let kind = e.kind();
+        if !policy.is_retryable(kind) {
+            return Err(e);
+        }
+        let elapsed = started.elapsed();
+        match policy.next_delay(attempts, elapsed, kind) {
+            Some(delay) if on_retry(attempts, elapsed, delay) => tokio::time::sleep(delay).await,
+            _ => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{with_config, ClientConfig};
+    use crate::stat;
+    use crate::testing::gcs::MockGcsClient;
+    use crate::testing::http::{TestResponse, TestServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Retries permission errors only, as if access were being granted meanwhile.
+    struct UntilPermitted;
+
+    impl RetryPolicy for UntilPermitted {
+        fn is_retryable(&self, kind: ErrorKind) -> bool {
+            kind == ErrorKind::PermissionDenied
+        }
+
+        fn next_delay(&self, attempts: usize, _: Duration, _: ErrorKind) -> Option<Duration> {
+            (attempts < 3).then_some(Duration::from_millis(1))
+        }
+    }
+
+    #[test]
+    fn exponential_delays_grow_up_to_the_max() {
+        let policy = ExponentialRetry {
+            initial_interval: Duration::from_millis(100),
+            randomization_factor: 0.0,
+            multiplier: 2.0,
+            max_interval: Duration::from_millis(300),
+            max_elapsed_time: Some(Duration::from_secs(1)),
+        };
+        let delays: Vec<_> = (1..=4)
+            .map(|attempts| policy.next_delay(attempts, Duration::ZERO, ErrorKind::Transient))
+            .collect();
+        let expected = [100, 200, 300, 300].map(|ms| Some(Duration::from_millis(ms)));
+        assert_eq!(expected.to_vec(), delays);
+        assert_eq!(
+            None,
+            policy.next_delay(5, Duration::from_secs(1), ErrorKind::Transient)
+        );
+    }
+
+    #[tokio::test]
+    async fn policies_decide_what_is_retried() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = TestServer::start(move |request| {
+            let failures = counter.fetch_add(1, Ordering::SeqCst);
+            match request.path.as_str() {
+                "/flaky.txt" if failures % 3 < 2 => TestResponse::status(503),
+                "/forbidden.txt" => TestResponse::status(403),
+                _ => TestResponse::ok("ok"),
+            }
+        })
+        .await;
+        // head requests, as plain gets take any status for the contents
+        let head = |path: &str| {
+            let url = server.url(path).to_string();
+            async move { stat(&url, None).await }
+        };
+
+        let fixed = FixedRetry {
+            interval: Duration::from_millis(1),
+            max_attempts: 3,
+        };
+        let config = ClientConfig::new().with_retry_policy(Arc::new(fixed));
+        with_config(config, async {
+            assert!(head("/flaky.txt").await.unwrap().is_some());
+        })
+        .await;
+        assert_eq!(3, requests.swap(0, Ordering::SeqCst));
+
+        let config = ClientConfig::new().with_retry_policy(Arc::new(NoRetry));
+        with_config(config, async {
+            assert!(head("/flaky.txt").await.is_err());
+        })
+        .await;
+        assert_eq!(1, requests.swap(0, Ordering::SeqCst));
+
+        // a client error is permanent, which no policy overrides
+        let config = ClientConfig::new().with_retry_policy(Arc::new(UntilPermitted));
+        with_config(config, async {
+            assert!(head("/forbidden.txt").await.is_err());
+        })
+        .await;
+        assert_eq!(1, requests.swap(0, Ordering::SeqCst));
+
+        let mock = MockGcsClient::new().with_forbidden_reads(2).into_arc();
+        mock.insert("zdb_test", "zdb/a.txt", "a");
+        let config = ClientConfig::new()
+            .with_gcs_client(mock.clone())
+            .with_retry_policy(Arc::new(UntilPermitted));
+        with_config(config, async {
+            assert!(crate::is_exists("gs://zdb_test/zdb/a.txt", None)
+                .await
+                .unwrap());
+        })
+        .await;
+        assert_eq!(3, mock.call_count("read"));
+
+        let mock = MockGcsClient::new().with_rate_limited_reads(2).into_arc();
+        let fixed = FixedRetry {
+            interval: Duration::from_millis(1),
+            max_attempts: 3,
+        };
+        let config = ClientConfig::new()
+            .with_gcs_client(mock.clone())
+            .with_retry_policy(Arc::new(fixed));
+        mock.insert("zdb_test", "zdb/a.txt", "a");
+        with_config(config, async {
+            assert!(crate::is_exists("gs://zdb_test/zdb/a.txt", None)
+                .await
+                .unwrap());
+        })
+        .await;
+        assert_eq!(3, mock.call_count("read"));
+    }
+
+    #[tokio::test]
+    async fn policies_do_not_resend_non_idempotent_requests() {
+        let server = TestServer::start(|_| TestResponse::status(503)).await;
+        let fixed = FixedRetry {
+            interval: Duration::from_millis(1),
+            max_attempts: 3,
+        };
+        let config = ClientConfig::new().with_retry_policy(Arc::new(fixed));
+        with_config(config, async {
+            let result = crate::web::send_with_retry(
+                reqwest::Method::POST,
+                server.url("/orders"),
+                b"{}".to_vec(),
+                crate::mime::MimeType::Json,
+                None,
+                None,
+            )
+            .await;
+            assert!(result.is_err());
+        })
+        .await;
+        let methods: Vec<_> = server
+            .requests()
+            .into_iter()
+            .map(|request| request.method)
+            .collect();
+        assert_eq!(vec!["POST".to_string()], methods);
+    }
+
+    #[tokio::test]
+    async fn explicit_backoff_wins_over_the_policy() {
+        let mock = MockGcsClient::new().with_rate_limited_reads(2).into_arc();
+        mock.insert("zdb_test", "zdb/a.txt", "a");
+        let config = ClientConfig::new()
+            .with_gcs_client(mock.clone())
+            .with_retry_policy(Arc::new(NoRetry));
+        with_config(config, async {
+            assert!(crate::is_exists("gs://zdb_test/zdb/a.txt", None)
+                .await
+                .is_err());
+            assert_eq!(1, mock.call_count("read"), "the policy applies");
+
+            let backoff = crate::testing::quick_backoff();
+            assert!(crate::is_exists("gs://zdb_test/zdb/a.txt", Some(backoff))
+                .await
+                .unwrap());
+            assert_eq!(3, mock.call_count("read"), "the backoff applies");
+        })
+        .await;
+    }
+}
@@ -0,0 +1,525 @@
+//! One object-safe interface over the backends, for code that holds a `Box<dyn FileStorage>`
+//! instead of going through the free functions that dispatch on the url or path every call.
+use crate::mime::MimeType;
+use crate::{in_operation, Backend, FileUtilError, Result};
+use async_trait::async_trait;
+use backoff::ExponentialBackoff;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+#[cfg(feature = "fs")]
+use crate::fs::{FileAccessor, WriteMode};
+#[cfg(feature = "gcs")]
+use crate::gcs::GcsFile;
+#[cfg(feature = "web")]
+use crate::web::WebFile;
+#[cfg(feature = "fs")]
+use bytes::Bytes;
+
+/// A file on some backend. Contents are read and written as they are stored, without
+/// compression or decompression.
+#[async_trait]
+pub trait FileStorage: Send + Sync {
+    /// `None` when the file does not exist.
+    async fn read(&self, backoff: Option<ExponentialBackoff>) -> Result<Option<Vec<u8>>>;
+
+    async fn write(
+        &self,
+        body: &[u8],
+        mime_type: MimeType,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()>;
+
+    async fn exists(&self, backoff: Option<ExponentialBackoff>) -> Result<bool>;
+
+    /// The entries under this directory or prefix.
+    async fn list(&self, backoff: Option<ExponentialBackoff>) -> Result<Vec<String>>;
+
+    async fn delete(&self, backoff: Option<ExponentialBackoff>) -> Result<()>;
+}
+
+/// Makes the storage for a url of a registered scheme.
+pub type SchemeHandler = Arc<dyn Fn(&Url) -> Result<Box<dyn FileStorage>> + Send + Sync>;
+
+lazy_static! {
+    static ref SCHEMES: RwLock<HashMap<String, SchemeHandler>> = RwLock::new(HashMap::new());
+}
+
+/// Route urls of `scheme` (e.g. `myvault` for `myvault://...`) to the storage `handler`
+/// makes, in `storage_for` and in the free functions such as `get_file_contents` and
+/// `write_contents`, which still apply their compression on top.
+/// Registered schemes take precedence over the built-in backends, `gs` and `https` included.
+/// Returns the handler registered for the scheme before, if any.
+pub fn register_scheme(scheme: &str, handler: SchemeHandler) -> Option<SchemeHandler> {
+    SCHEMES
+        .write()
+        .unwrap()
+        .insert(scheme.to_ascii_lowercase(), handler)
+}
+
+pub fn unregister_scheme(scheme: &str) -> Option<SchemeHandler> {
+    SCHEMES
+        .write()
+        .unwrap()
+        .remove(&scheme.to_ascii_lowercase())
+}
+
+/// The storage of a registered scheme for the url, `None` for anything else.
+pub(crate) fn registered_storage(url_or_path_str: &str) -> Result<Option<Box<dyn FileStorage>>> {
+    let url = match Url::parse(url_or_path_str) {
+        Ok(url) => url,
+        Err(_) => return Ok(None),
+    };
+    let handler = match SCHEMES.read().unwrap().get(url.scheme()) {
+        Some(handler) => handler.clone(),
+        None => return Ok(None),
+    };
+    handler(&url).map(Some)
+}
+
+/// The storage for a url or path, chosen the way the free functions choose their backend.
+pub fn storage_for(url_or_path_str: &str) -> Result<Box<dyn FileStorage>> {
+    match crate::backend(url_or_path_str)? {
+        Backend::Registered(storage) => Ok(storage),
+        #[cfg(feature = "gcs")]
+        Backend::Gcs(gcs_file) => Ok(Box::new(gcs_file)),
+        #[cfg(feature = "web")]
+        Backend::Web(url) => Ok(Box::new(WebFile::new(url))),
+        #[cfg(feature = "fs")]
+        Backend::Local(path) => {
+            let local_file =
+                FileAccessor::new(path).map_err(in_operation("open", url_or_path_str))?;
+            Ok(Box::new(local_file))
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+#[async_trait]
+impl FileStorage for FileAccessor {
+    async fn read(&self, _backoff: Option<ExponentialBackoff>) -> Result<Option<Vec<u8>>> {
+        let path = self.path().display().to_string();
+        let contents = FileAccessor::read(self)
+            .await
+            .map_err(in_operation("read", &path))?;
+        Ok(contents)
+    }
+
+    async fn write(
+        &self,
+        body: &[u8],
+        _mime_type: MimeType,
+        _backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        let path = self.path().display().to_string();
+        FileAccessor::write(self, Bytes::copy_from_slice(body), None, WriteMode::InPlace)
+            .await
+            .map_err(in_operation("write", &path))?;
+        Ok(())
+    }
+
+    async fn exists(&self, _backoff: Option<ExponentialBackoff>) -> Result<bool> {
+        let path = self.path().display().to_string();
+        let exists = self
+            .is_exists()
+            .await
+            .map_err(in_operation("check", &path))?;
+        Ok(exists)
+    }
+
+    async fn list(&self, _backoff: Option<ExponentialBackoff>) -> Result<Vec<String>> {
+        let path = self.path().display().to_string();
+        let entries = self
+            .list_directory(None)
+            .await
+            .map_err(in_operation("list", &path))?;
+        Ok(entries)
+    }
+
+    async fn delete(&self, _backoff: Option<ExponentialBackoff>) -> Result<()> {
+        let path = self.path().display().to_string();
+        FileAccessor::delete(self)
+            .await
+            .map_err(in_operation("delete", &path))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gcs")]
+#[async_trait]
+impl FileStorage for GcsFile {
+    async fn read(&self, backoff: Option<ExponentialBackoff>) -> Result<Option<Vec<u8>>> {
+        let contents = self
+            .download_with_retry(backoff, None)
+            .await
+            .map_err(in_operation("read", &self.to_string()))?;
+        Ok(contents)
+    }
+
+    async fn write(
+        &self,
+        body: &[u8],
+        mime_type: MimeType,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        self.write_with_retry(
+            bytes::Bytes::copy_from_slice(body),
+            mime_type,
+            backoff,
+            None,
+        )
+        .await
+        .map_err(in_operation("write", &self.to_string()))?;
+        Ok(())
+    }
+
+    async fn exists(&self, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+        let exists = self
+            .is_exists_with_retry(backoff)
+            .await
+            .map_err(in_operation("check", &self.to_string()))?;
+        Ok(exists)
+    }
+
+    async fn list(&self, backoff: Option<ExponentialBackoff>) -> Result<Vec<String>> {
+        let entries = self
+            .list_objects_with_retry(backoff, None)
+            .await
+            .map_err(in_operation("list", &self.to_string()))?;
+        Ok(entries)
+    }
+
+    async fn delete(&self, backoff: Option<ExponentialBackoff>) -> Result<()> {
+        self.delete_with_retry(backoff)
+            .await
+            .map_err(in_operation("delete", &self.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Writes are PUT and deletes are DELETE requests. Listing has no http equivalent and fails
+/// with `FileUtilError::Unsupported`.
+#[cfg(feature = "web")]
+#[async_trait]
+impl FileStorage for WebFile {
+    async fn read(&self, backoff: Option<ExponentialBackoff>) -> Result<Option<Vec<u8>>> {
+        let contents = crate::web::download_from_url_with_retry(self.url.clone(), backoff, None)
+            .await
+            .map_err(in_operation("read", self.url.as_str()))?;
+        Ok(contents)
+    }
+
+    async fn write(
+        &self,
+        body: &[u8],
+        mime_type: MimeType,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<()> {
+        crate::web::send_with_retry(
+            http::Method::PUT,
+            self.url.clone(),
+            body.to_vec(),
+            mime_type,
+            None,
+            backoff,
+        )
+        .await
+        .map_err(in_operation("write", self.url.as_str()))?;
+        Ok(())
+    }
+
+    async fn exists(&self, backoff: Option<ExponentialBackoff>) -> Result<bool> {
+        let exists = crate::web::url_exists_with_retry(self.url.clone(), backoff)
+            .await
+            .map_err(in_operation("check", self.url.as_str()))?;
+        Ok(exists)
+    }
+
+    async fn list(&self, _backoff: Option<ExponentialBackoff>) -> Result<Vec<String>> {
+        Err(FileUtilError::Unsupported {
+            path: self.url.to_string(),
+            op: "listing",
+        })
+    }
+
+    async fn delete(&self, backoff: Option<ExponentialBackoff>) -> Result<()> {
+        crate::web::send_with_retry(
+            http::Method::DELETE,
+            self.url.clone(),
+            vec![],
+            MimeType::OctetStream,
+            None,
+            backoff,
+        )
+        .await
+        .map_err(in_operation("delete", self.url.as_str()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::gcs::MockGcsClient;
+    use crate::testing::http::{TestResponse, TestServer};
+    use crate::testing::{quick_backoff, TempDir};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Backend-agnostic code, as a caller holding a `Box<dyn FileStorage>` writes it.
+    async fn round_trip(storage: &dyn FileStorage) {
+        let backoff = || Some(quick_backoff());
+        assert!(!storage.exists(backoff()).await.unwrap());
+        assert_eq!(None, storage.read(backoff()).await.unwrap());
+
+        storage
+            .write(b"stored", MimeType::Text, backoff())
+            .await
+            .unwrap();
+        assert!(storage.exists(backoff()).await.unwrap());
+        assert_eq!(
+            Some(b"stored".to_vec()),
+            storage.read(backoff()).await.unwrap()
+        );
+
+        storage.delete(backoff()).await.unwrap();
+        assert!(!storage.exists(backoff()).await.unwrap());
+    }
+
+    /// Keeps the files of a custom scheme in memory.
+    struct MemFile {
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        key: String,
+    }
+
+    #[async_trait]
+    impl FileStorage for MemFile {
+        async fn read(&self, _backoff: Option<ExponentialBackoff>) -> Result<Option<Vec<u8>>> {
+            Ok(self.files.lock().unwrap().get(&self.key).cloned())
+        }
+
+        async fn write(
+            &self,
+            body: &[u8],
+            _mime_type: MimeType,
+            _backoff: Option<ExponentialBackoff>,
+        ) -> Result<()> {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(self.key.clone(), body.to_vec());
+            Ok(())
+        }
+
+        async fn exists(&self, _backoff: Option<ExponentialBackoff>) -> Result<bool> {
+            Ok(self.files.lock().unwrap().contains_key(&self.key))
+        }
+
+        async fn list(&self, _backoff: Option<ExponentialBackoff>) -> Result<Vec<String>> {
+            let files = self.files.lock().unwrap();
+            let mut keys: Vec<String> = files
+                .keys()
+                .filter(|key| key.starts_with(&self.key))
+                .cloned()
+                .collect();
+            keys.sort();
+            Ok(keys)
+        }
+
+        async fn delete(&self, _backoff: Option<ExponentialBackoff>) -> Result<()> {
+            self.files.lock().unwrap().remove(&self.key);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_scheme() {
+        use crate::compression::Compression;
+
+        let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let registered = files.clone();
+        let handler: SchemeHandler = Arc::new(move |url| {
+            Ok(Box::new(MemFile {
+                files: registered.clone(),
+                key: url.to_string(),
+            }))
+        });
+        assert!(register_scheme("MemTest", handler).is_none());
+
+        let url = "memtest://vault/a.txt.gz";
+        crate::write_contents(url, "secret", MimeType::Text, None, Some(Compression::Gzip))
+            .await
+            .unwrap();
+        let stored = files.lock().unwrap().get(url).cloned().unwrap();
+        assert_eq!(
+            b"secret".to_vec(),
+            Compression::Gzip.decompress(&stored).unwrap()
+        );
+
+        assert!(crate::is_exists(url, None).await.unwrap());
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            crate::get_file_contents(url, None, Some(Compression::Gzip))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            vec![url.to_string()],
+            crate::list_files("memtest://vault/", None, None)
+                .await
+                .unwrap()
+        );
+        assert!(storage_for(url).unwrap().exists(None).await.unwrap());
+
+        crate::delete_contents(url, None).await.unwrap();
+        assert!(!crate::is_exists(url, None).await.unwrap());
+
+        assert!(unregister_scheme("memtest").is_some());
+        assert!(registered_storage(url).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn registered_scheme_in_every_function() {
+        use crate::checksum::ChecksumAlgo;
+        use futures::io::AsyncWriteExt;
+        use futures::TryStreamExt;
+        use std::time::Duration;
+
+        let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let registered = files.clone();
+        let handler: SchemeHandler = Arc::new(move |url| {
+            Ok(Box::new(MemFile {
+                files: registered.clone(),
+                key: url.to_string(),
+            }))
+        });
+        register_scheme("memall", handler);
+        let stored = |url: &str| files.lock().unwrap().get(url).cloned();
+
+        let a = "memall://vault/a.txt";
+        assert!(
+            crate::write_contents_if_changed(a, b"a", MimeType::Text, None, None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !crate::write_contents_if_changed(a, b"a", MimeType::Text, None, None)
+                .await
+                .unwrap()
+        );
+        let b = "memall://vault/sub/b.txt";
+        let chunks = vec![Ok(bytes::Bytes::from("b")), Ok(bytes::Bytes::from("b"))];
+        crate::upload_stream(b, futures::stream::iter(chunks), MimeType::Text)
+            .await
+            .unwrap();
+        assert_eq!(Some(b"bb".to_vec()), stored(b));
+        let c = "memall://vault/c.txt";
+        let mut writer = crate::open_write(c, crate::writer::WriteOptions::new()).unwrap();
+        writer.write_all(b"c").await.unwrap();
+        writer.finalize().await.unwrap();
+        assert_eq!(Some(b"c".to_vec()), stored(c));
+
+        let dir = "memall://vault/";
+        let all = vec![a.to_string(), c.to_string(), b.to_string()];
+        let (listed, err) = crate::list_files_best_effort(dir, None).await;
+        assert_eq!(all, listed);
+        assert!(err.is_none());
+        assert_eq!(
+            all,
+            crate::walk(dir, None)
+                .map_ok(|entry| entry.path)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        );
+        let read: Vec<(String, Vec<u8>)> = crate::read_all_under_prefix(dir, 2, None, None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!((b.to_string(), b"bb".to_vec()), read[2]);
+        assert_eq!(
+            Some(ChecksumAlgo::Md5.digest(b"bb")),
+            crate::checksum(b, ChecksumAlgo::Md5, None).await.unwrap()
+        );
+        for unsupported in [
+            crate::directory_size(dir, None).await.map(|_| ()),
+            crate::delete_older_than(dir, Duration::from_secs(1), None, 1, false)
+                .await
+                .map(|_| ()),
+            crate::stat(a, None).await.map(|_| ()),
+        ] {
+            assert!(matches!(
+                unsupported,
+                Err(FileUtilError::Unsupported { .. })
+            ));
+        }
+
+        crate::delete_contents_recursive(b, None, 1).await.unwrap();
+        assert_eq!(None, stored(b));
+
+        unregister_scheme("memall");
+    }
+
+    #[tokio::test]
+    async fn fs_storage() {
+        let dir = TempDir::new();
+        round_trip(storage_for(&dir.join("file.txt")).unwrap().as_ref()).await;
+
+        dir.write("a.txt", "x");
+        let listed = storage_for(&dir.path().display().to_string())
+            .unwrap()
+            .list(None)
+            .await
+            .unwrap();
+        assert_eq!(vec![dir.join("a.txt")], listed);
+    }
+
+    #[tokio::test]
+    async fn gcs_storage() {
+        let mock = MockGcsClient::new().into_arc();
+        crate::gcs::client::with_client(mock.clone(), async {
+            round_trip(storage_for("gs://zdb_test/zdb/file.txt").unwrap().as_ref()).await;
+
+            mock.insert("zdb_test", "zdb/a.txt", "x");
+            let listed = storage_for("gs://zdb_test/zdb/")
+                .unwrap()
+                .list(None)
+                .await
+                .unwrap();
+            assert_eq!(vec!["gs://zdb_test/zdb/a.txt".to_string()], listed);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn web_storage() {
+        let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let server = TestServer::start(move |request| {
+            let mut files = files.lock().unwrap();
+            match request.method.as_str() {
+                "PUT" => {
+                    files.insert(request.path.clone(), request.body.clone());
+                    TestResponse::status(200)
+                }
+                "DELETE" => match files.remove(&request.path) {
+                    Some(_) => TestResponse::status(204),
+                    None => TestResponse::status(404),
+                },
+                _ => match files.get(&request.path) {
+                    Some(body) => TestResponse::ok(body.clone()),
+                    None => TestResponse::status(404),
+                },
+            }
+        })
+        .await;
+
+        let storage = storage_for(server.url("/file.txt").as_str()).unwrap();
+        round_trip(storage.as_ref()).await;
+        assert!(matches!(
+            storage.list(None).await,
+            Err(FileUtilError::Unsupported { .. })
+        ));
+    }
+}
@@ -0,0 +1,646 @@
+//! An in-memory `GcsClient` for tests. Run code against it with `gcs::client::with_client`.
+use crate::checksum::ChecksumAlgo;
+use crate::gcs::acl::{predefined_acl_entries, PredefinedAcl};
+use crate::gcs::client::{slice_range, ByteStream, DownloadStream, GcsClient};
+use async_trait::async_trait;
+use bytes::Bytes;
+use cloud_storage::object::ObjectList;
+use cloud_storage::object_access_control::{Entity, NewObjectAccessControl, Role};
+use cloud_storage::{Error, GoogleErrorResponse, ListRequest, Object, Result};
+use futures::TryStreamExt;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub(crate) struct MockObject {
+    pub body: Vec<u8>,
+    pub content_type: String,
+    pub generation: i64,
+    /// `(entity, role)` pairs; a newly written object starts with the bucket default (empty).
+    pub acl: Vec<(Entity, String)>,
+    /// Set for composite objects, which have no md5 hash.
+    pub component_count: Option<i32>,
+    /// Last update time; `None` for the fixed time every mock object reports by default.
+    pub updated: Option<SystemTime>,
+    pub cache_control: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Default)]
+pub(crate) struct MockGcsClient {
+    objects: Mutex<BTreeMap<(String, String), MockObject>>,
+    calls: Mutex<Vec<String>>,
+    page_size: Option<usize>,
+    next_generation: Mutex<i64>,
+    list_calls_before_failure: Option<usize>,
+    list_delay: Option<Duration>,
+    interrupted_rewrites: bool,
+    slow_reads: Mutex<usize>,
+    read_delay: Duration,
+    rate_limited_reads: Mutex<usize>,
+    forbidden_reads: Mutex<usize>,
+    /// Deleted objects, kept as a bucket with soft delete enabled does.
+    soft_deleted: Mutex<Vec<((String, String), MockObject)>>,
+}
+
+impl MockGcsClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve listings in pages of at most `page_size` items.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Fail every listing request after the first `calls` ones.
+    pub fn failing_list_after(mut self, calls: usize) -> Self {
+        self.list_calls_before_failure = Some(calls);
+        self
+    }
+
+    /// Take `delay` to serve each listing page.
+    pub fn with_list_delay(mut self, delay: Duration) -> Self {
+        self.list_delay = Some(delay);
+        self
+    }
+
+    /// Take `delay` to serve each of the first `count` metadata reads.
+    pub fn with_slow_reads(mut self, count: usize, delay: Duration) -> Self {
+        self.slow_reads = Mutex::new(count);
+        self.read_delay = delay;
+        self
+    }
+
+    /// Reject the first `count` metadata reads with a rate-limit (429) response.
+    pub fn with_rate_limited_reads(mut self, count: usize) -> Self {
+        self.rate_limited_reads = Mutex::new(count);
+        self
+    }
+
+    /// Reject the first `count` metadata reads as not permitted (403).
+    pub fn with_forbidden_reads(mut self, count: usize) -> Self {
+        self.forbidden_reads = Mutex::new(count);
+        self
+    }
+
+    /// Copy only the first half of the body on rewrite, as an interrupted rewrite could.
+    pub fn with_interrupted_rewrites(mut self) -> Self {
+        self.interrupted_rewrites = true;
+        self
+    }
+
+    pub fn into_arc(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    pub fn insert(&self, bucket: &str, name: &str, body: impl Into<Vec<u8>>) {
+        let generation = self.bump_generation();
+        self.objects.lock().unwrap().insert(
+            (bucket.to_string(), name.to_string()),
+            MockObject {
+                body: body.into(),
+                content_type: "application/octet-stream".to_string(),
+                generation,
+                acl: vec![],
+                component_count: None,
+                updated: None,
+                cache_control: None,
+                metadata: None,
+            },
+        );
+    }
+
+    /// Insert an object as if it were composed from `component_count` objects.
+    pub fn insert_composed(
+        &self,
+        bucket: &str,
+        name: &str,
+        body: impl Into<Vec<u8>>,
+        component_count: i32,
+    ) {
+        self.insert(bucket, name, body);
+        self.update(bucket, name, |object| {
+            object.component_count = Some(component_count)
+        })
+        .unwrap();
+    }
+
+    /// Make the object report `updated` as its last update time.
+    pub fn set_updated(&self, bucket: &str, name: &str, updated: SystemTime) {
+        self.update(bucket, name, |object| object.updated = Some(updated))
+            .unwrap();
+    }
+
+    pub fn get(&self, bucket: &str, name: &str) -> Option<MockObject> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&(bucket.to_string(), name.to_string()))
+            .cloned()
+    }
+
+    /// Names of the operations performed so far, e.g. `["list", "read"]`.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn call_count(&self, op: &str) -> usize {
+        self.calls().iter().filter(|call| *call == op).count()
+    }
+
+    fn record(&self, op: &str) {
+        self.calls.lock().unwrap().push(op.to_string());
+    }
+
+    fn bump_generation(&self) -> i64 {
+        let mut generation = self.next_generation.lock().unwrap();
+        *generation += 1;
+        *generation
+    }
+
+    fn find(&self, bucket: &str, name: &str) -> Result<MockObject> {
+        self.get(bucket, name).ok_or_else(not_found)
+    }
+
+    fn update<F: FnOnce(&mut MockObject)>(&self, bucket: &str, name: &str, f: F) -> Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects
+            .get_mut(&(bucket.to_string(), name.to_string()))
+            .ok_or_else(not_found)?;
+        f(object);
+        Ok(())
+    }
+}
+
+fn role_name(role: &Role) -> String {
+    match role {
+        Role::Owner => "OWNER",
+        Role::Writer => "WRITER",
+        Role::Reader => "READER",
+    }
+    .to_string()
+}
+
+fn role_of(name: &str) -> Role {
+    match name {
+        "OWNER" => Role::Owner,
+        "WRITER" => Role::Writer,
+        _ => Role::Reader,
+    }
+}
+
+pub(crate) fn not_found() -> Error {
+    google_error("notFound", 404)
+}
+
+pub(crate) fn google_error(reason: &str, code: u16) -> Error {
+    let response: GoogleErrorResponse = serde_json::from_value(json!({
+        "error": {
+            "errors": [{"domain": "global", "reason": reason, "message": reason}],
+            "code": code,
+            "message": reason,
+        }
+    }))
+    .unwrap();
+    Error::Google(response)
+}
+
+/// Format `time` as an RFC 3339 UTC timestamp, as gcs reports times.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+pub(crate) fn object_resource(bucket: &str, name: &str, object: &MockObject) -> Object {
+    serde_json::from_value(object_json(bucket, name, object)).unwrap()
+}
+
+/// The resource of an object as gcs sends it.
+pub(crate) fn object_json(bucket: &str, name: &str, object: &MockObject) -> serde_json::Value {
+    let mut resource = json!({
+        "kind": "storage#object",
+        "id": format!("{}/{}/{}", bucket, name, object.generation),
+        "selfLink": format!("https://storage.googleapis.com/storage/v1/b/{}/o/{}", bucket, name),
+        "name": name,
+        "bucket": bucket,
+        "generation": object.generation.to_string(),
+        "metageneration": "1",
+        "contentType": object.content_type,
+        "timeCreated": "2021-01-01T00:00:00Z",
+        "updated": object.updated.map_or("2021-01-01T00:00:00Z".to_string(), rfc3339),
+        "storageClass": "STANDARD",
+        "timeStorageClassUpdated": "2021-01-01T00:00:00Z",
+        "size": object.body.len().to_string(),
+        "mediaLink": format!("https://storage.googleapis.com/download/storage/v1/b/{}/o/{}", bucket, name),
+        "md5Hash": base64::encode(ChecksumAlgo::Md5.digest(&object.body)),
+        "crc32c": base64::encode(ChecksumAlgo::Crc32c.digest(&object.body)),
+        "etag": object.generation.to_string(),
+    });
+    if let Some(cache_control) = &object.cache_control {
+        resource["cacheControl"] = json!(cache_control);
+    }
+    if let Some(metadata) = &object.metadata {
+        resource["metadata"] = json!(metadata);
+    }
+    if let Some(component_count) = object.component_count {
+        resource["componentCount"] = json!(component_count);
+        resource.as_object_mut().unwrap().remove("md5Hash");
+    }
+    resource
+}
+
+#[async_trait]
+impl GcsClient for MockGcsClient {
+    async fn read_object(&self, bucket: &str, name: &str) -> Result<Object> {
+        self.record("read");
+        let slow = {
+            let mut slow_reads = self.slow_reads.lock().unwrap();
+            let slow = *slow_reads > 0;
+            *slow_reads = slow_reads.saturating_sub(1);
+            slow
+        };
+        if slow {
+            tokio::time::sleep(self.read_delay).await;
+        }
+        {
+            let mut rate_limited_reads = self.rate_limited_reads.lock().unwrap();
+            if *rate_limited_reads > 0 {
+                *rate_limited_reads -= 1;
+                return Err(google_error("rateLimitExceeded", 429));
+            }
+        }
+        {
+            let mut forbidden_reads = self.forbidden_reads.lock().unwrap();
+            if *forbidden_reads > 0 {
+                *forbidden_reads -= 1;
+                return Err(google_error("forbidden", 403));
+            }
+        }
+        let object = self.find(bucket, name)?;
+        Ok(object_resource(bucket, name, &object))
+    }
+
+    async fn list_objects_page(&self, bucket: &str, request: ListRequest) -> Result<ObjectList> {
+        self.record("list");
+        if let Some(delay) = self.list_delay {
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(calls) = self.list_calls_before_failure {
+            if self.call_count("list") > calls {
+                return Err(google_error("backendError", 503));
+            }
+        }
+        let prefix = request.prefix.clone().unwrap_or_default();
+        let start_after = request.page_token.clone();
+        let page_size = match (self.page_size, request.max_results) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(usize::MAX),
+        };
+
+        let objects = self.objects.lock().unwrap();
+        let matched = objects
+            .iter()
+            .filter(|((b, name), _)| b == bucket && name.starts_with(&prefix))
+            .filter(|((_, name), _)| start_after.as_ref().is_none_or(|after| name > after));
+
+        // with a delimiter the names sharing a prefix up to it collapse into one entry;
+        // they are adjacent in name order
+        let mut items = Vec::<Object>::new();
+        let mut prefixes = Vec::<String>::new();
+        let mut last_name = None;
+        let mut has_more = false;
+        for ((b, name), object) in matched {
+            let common_prefix = request.delimiter.as_ref().and_then(|delimiter| {
+                name[prefix.len()..]
+                    .find(delimiter.as_str())
+                    .map(|i| name[..prefix.len() + i + delimiter.len()].to_string())
+            });
+            match common_prefix {
+                Some(common_prefix) if prefixes.last() == Some(&common_prefix) => {}
+                _ if items.len() + prefixes.len() >= page_size => {
+                    has_more = true;
+                    break;
+                }
+                Some(common_prefix) => prefixes.push(common_prefix),
+                None => items.push(object_resource(b, name, object)),
+            }
+            last_name = Some(name.clone());
+        }
+        let next_page_token = if has_more { last_name } else { None };
+
+        Ok(ObjectList {
+            kind: "storage#objects".to_string(),
+            items,
+            prefixes,
+            next_page_token,
+        })
+    }
+
+    async fn download_object(&self, bucket: &str, name: &str) -> Result<Vec<u8>> {
+        self.record("download");
+        Ok(self.find(bucket, name)?.body)
+    }
+
+    async fn download_object_streamed(&self, bucket: &str, name: &str) -> Result<DownloadStream> {
+        self.record("download_streamed");
+        let body = self.find(bucket, name)?.body;
+        let chunks: Vec<Result<Bytes>> = body
+            .chunks(STREAM_CHUNK_SIZE)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Ok(Box::pin(futures::stream::iter(chunks)))
+    }
+
+    async fn download_object_range(
+        &self,
+        bucket: &str,
+        name: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        self.record("download_range");
+        let body = self.find(bucket, name)?.body;
+        Ok(slice_range(&body, offset, length).to_vec())
+    }
+
+    async fn create_object(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        self.record("create");
+        let object = MockObject {
+            body: body.to_vec(),
+            content_type: mime_type.to_string(),
+            generation: self.bump_generation(),
+            acl: vec![],
+            component_count: None,
+            updated: None,
+            cache_control: None,
+            metadata: None,
+        };
+        self.objects
+            .lock()
+            .unwrap()
+            .insert((bucket.to_string(), name.to_string()), object.clone());
+        Ok(object_resource(bucket, name, &object))
+    }
+
+    async fn create_object_if_generation_match(
+        &self,
+        bucket: &str,
+        body: Bytes,
+        name: &str,
+        mime_type: &str,
+        generation: i64,
+    ) -> Result<Object> {
+        let mut objects = self.objects.lock().unwrap();
+        let key = (bucket.to_string(), name.to_string());
+        let current = objects.get(&key).map_or(0, |object| object.generation);
+        self.record("create_if_generation_match");
+        if current != generation {
+            return Err(google_error("conditionNotMet", 412));
+        }
+        let object = MockObject {
+            body: body.to_vec(),
+            content_type: mime_type.to_string(),
+            generation: self.bump_generation(),
+            acl: vec![],
+            component_count: None,
+            updated: None,
+            cache_control: None,
+            metadata: None,
+        };
+        objects.insert(key, object.clone());
+        Ok(object_resource(bucket, name, &object))
+    }
+
+    async fn create_object_streamed(
+        &self,
+        bucket: &str,
+        body: ByteStream,
+        _length: Option<u64>,
+        name: &str,
+        mime_type: &str,
+    ) -> Result<Object> {
+        let chunks: Vec<_> = body
+            .try_collect()
+            .await
+            .map_err(|e| Error::Other(e.to_string()))?;
+        self.record("create_streamed");
+        let object = MockObject {
+            body: chunks.concat(),
+            content_type: mime_type.to_string(),
+            generation: self.bump_generation(),
+            acl: vec![],
+            component_count: None,
+            updated: None,
+            cache_control: None,
+            metadata: None,
+        };
+        self.objects
+            .lock()
+            .unwrap()
+            .insert((bucket.to_string(), name.to_string()), object.clone());
+        Ok(object_resource(bucket, name, &object))
+    }
+
+    async fn rewrite_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        destination_bucket: &str,
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.record("rewrite");
+        let source = self.find(bucket, name)?;
+        let mut body = source.body;
+        if self.interrupted_rewrites {
+            body.truncate(body.len() / 2);
+        }
+        let object = MockObject {
+            body,
+            content_type: source.content_type,
+            generation: self.bump_generation(),
+            acl: vec![],
+            component_count: None,
+            updated: None,
+            cache_control: None,
+            metadata: None,
+        };
+        self.objects.lock().unwrap().insert(
+            (destination_bucket.to_string(), destination_name.to_string()),
+            object.clone(),
+        );
+        Ok(object_resource(
+            destination_bucket,
+            destination_name,
+            &object,
+        ))
+    }
+
+    async fn compose_objects(
+        &self,
+        bucket: &str,
+        source_names: &[String],
+        destination_name: &str,
+    ) -> Result<Object> {
+        self.record("compose");
+        let mut body = Vec::new();
+        let mut component_count = 0;
+        let mut content_type = None;
+        for name in source_names {
+            let source = self.find(bucket, name)?;
+            body.extend(source.body);
+            component_count += source.component_count.unwrap_or(1);
+            content_type.get_or_insert(source.content_type);
+        }
+        let object = MockObject {
+            body,
+            content_type: content_type.unwrap_or_default(),
+            generation: self.bump_generation(),
+            acl: vec![],
+            component_count: Some(component_count),
+            updated: None,
+            cache_control: None,
+            metadata: None,
+        };
+        self.objects.lock().unwrap().insert(
+            (bucket.to_string(), destination_name.to_string()),
+            object.clone(),
+        );
+        Ok(object_resource(bucket, destination_name, &object))
+    }
+
+    async fn update_object(&self, object: &Object) -> Result<Object> {
+        self.record("update");
+        self.update(&object.bucket, &object.name, |stored| {
+            if let Some(content_type) = &object.content_type {
+                stored.content_type = content_type.clone();
+            }
+            stored.cache_control = object.cache_control.clone();
+            stored.metadata = object.metadata.clone();
+        })?;
+        let stored = self.find(&object.bucket, &object.name)?;
+        Ok(object_resource(&object.bucket, &object.name, &stored))
+    }
+
+    async fn delete_object(&self, bucket: &str, name: &str) -> Result<()> {
+        self.record("delete");
+        let key = (bucket.to_string(), name.to_string());
+        let object = self
+            .objects
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .ok_or_else(not_found)?;
+        self.soft_deleted.lock().unwrap().push((key, object));
+        Ok(())
+    }
+
+    async fn list_soft_deleted_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        _page_token: Option<String>,
+    ) -> Result<ObjectList> {
+        self.record("list_soft_deleted");
+        let items = self
+            .soft_deleted
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((b, name), _)| b == bucket && name.starts_with(prefix))
+            .map(|((b, name), object)| object_resource(b, name, object))
+            .collect();
+        Ok(ObjectList {
+            kind: "storage#objects".to_string(),
+            items,
+            prefixes: vec![],
+            next_page_token: None,
+        })
+    }
+
+    async fn restore_object(&self, bucket: &str, name: &str, generation: i64) -> Result<Object> {
+        self.record("restore");
+        let mut soft_deleted = self.soft_deleted.lock().unwrap();
+        let i = soft_deleted
+            .iter()
+            .position(|((b, n), object)| {
+                b == bucket && n == name && object.generation == generation
+            })
+            .ok_or_else(not_found)?;
+        let (key, mut object) = soft_deleted.remove(i);
+        // a restored object is a new generation
+        object.generation = self.bump_generation();
+        self.objects.lock().unwrap().insert(key, object.clone());
+        Ok(object_resource(bucket, name, &object))
+    }
+
+    async fn read_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Vec<NewObjectAccessControl>> {
+        self.record("read_acl");
+        Ok(self
+            .find(bucket, name)?
+            .acl
+            .into_iter()
+            .map(|(entity, role)| NewObjectAccessControl {
+                entity,
+                role: role_of(&role),
+            })
+            .collect())
+    }
+
+    async fn add_object_acl(
+        &self,
+        bucket: &str,
+        name: &str,
+        entry: NewObjectAccessControl,
+    ) -> Result<()> {
+        self.record("add_acl");
+        self.update(bucket, name, |object| {
+            object.acl.push((entry.entity, role_name(&entry.role)))
+        })
+    }
+
+    async fn set_predefined_acl(&self, bucket: &str, name: &str, acl: PredefinedAcl) -> Result<()> {
+        self.record("set_predefined_acl");
+        let entries = predefined_acl_entries(acl).unwrap_or_default();
+        self.update(bucket, name, |object| {
+            object.acl = entries
+                .into_iter()
+                .map(|entry| (entry.entity, role_name(&entry.role)))
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,59 @@
+//! Helpers shared by the unit tests of each backend.
+//! Not every test uses every helper.
+#![allow(dead_code)]
+
+#[cfg(feature = "gcs")]
+pub(crate) mod gcs;
+pub(crate) mod http;
+
+use backoff::ExponentialBackoff;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A backoff that gives up quickly, so failing retries don't stall the test suite.
+pub(crate) fn quick_backoff() -> ExponentialBackoff {
+    ExponentialBackoff {
+        initial_interval: Duration::from_millis(10),
+        max_interval: Duration::from_millis(50),
+        max_elapsed_time: Some(Duration::from_millis(500)),
+        ..ExponentialBackoff::default()
+    }
+}
+
+/// A directory under the system temp dir that is removed on drop.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("file_dougu_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Absolute path of `name` inside this directory, as a string.
+    pub fn join(&self, name: &str) -> String {
+        self.path.join(name).display().to_string()
+    }
+
+    /// Create `name` (and its parent directories) with `body`.
+    pub fn write(&self, name: &str, body: impl AsRef<[u8]>) -> String {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, body).unwrap();
+        path.display().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
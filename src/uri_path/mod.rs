@@ -0,0 +1,312 @@
+//! Path manipulation that works the same on `gs://` and `http(s)://` urls and local paths,
+//! instead of concatenating strings.
+use std::fmt;
+
+/// A url or local path, split into the part in front of its path (`gs://bucket`,
+/// `https://host:8080`, `file://`, nothing for a local path), the path, and for http
+/// urls the query and fragment after it.
+///
+/// Remote paths are separated by `/` only. A local path is separated by anything
+/// `std::path::is_separator` accepts, so `\` too on Windows, and joined with `/`.
+/// Nothing is percent-encoded, segments are taken as they are given.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UriPath {
+    value: String,
+    path_start: usize,
+    path_end: usize,
+}
+
+impl UriPath {
+    pub fn new(url_or_path: &str) -> Self {
+        let path_start = match url_or_path.find("://") {
+            Some(i)
+                if crate::remote_url(url_or_path).is_some()
+                    || url_or_path.starts_with("file://") =>
+            {
+                let authority = i + "://".len();
+                url_or_path[authority..]
+                    .find('/')
+                    .map_or(url_or_path.len(), |j| authority + j)
+            }
+            _ => 0,
+        };
+        let is_http = url_or_path.starts_with("http://") || url_or_path.starts_with("https://");
+        let path_end = match url_or_path[path_start..].find(['?', '#']) {
+            Some(j) if is_http => path_start + j,
+            _ => url_or_path.len(),
+        };
+        Self {
+            value: url_or_path.to_string(),
+            path_start,
+            path_end,
+        }
+    }
+
+    fn from_parts(origin: &str, path: &str, suffix: &str) -> Self {
+        Self::new(&format!("{}{}{}", origin, path, suffix))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    /// `gs://bucket`, `https://host:8080`, `file://`, or empty for a local path.
+    pub fn origin(&self) -> &str {
+        &self.value[..self.path_start]
+    }
+
+    pub fn path(&self) -> &str {
+        &self.value[self.path_start..self.path_end]
+    }
+
+    fn suffix(&self) -> &str {
+        &self.value[self.path_end..]
+    }
+
+    /// Whether this is a local path, `file://` urls included.
+    pub fn is_local(&self) -> bool {
+        crate::remote_url(&self.value).is_none()
+    }
+
+    fn is_separator(&self, c: char) -> bool {
+        if self.path_start == 0 {
+            std::path::is_separator(c)
+        } else {
+            c == '/'
+        }
+    }
+
+    fn trimmed_path(&self) -> &str {
+        self.path().trim_end_matches(|c| self.is_separator(c))
+    }
+
+    /// `relative` under this path, dropping any query. An absolute `relative`, a url or a
+    /// path starting with a separator, replaces the path; for a url it is taken from the
+    /// root of the host or bucket.
+    pub fn join(&self, relative: &str) -> Self {
+        let joined = Self::new(relative);
+        if !joined.origin().is_empty() {
+            return joined;
+        }
+        if relative.starts_with(|c| self.is_separator(c)) {
+            return Self::from_parts(self.origin(), relative, "");
+        }
+        let base = self.trimmed_path();
+        if base.is_empty() && self.path().is_empty() && self.path_start == 0 {
+            return Self::new(relative);
+        }
+        Self::from_parts(self.origin(), &format!("{}/{}", base, relative), "")
+    }
+
+    /// The directory this one is in, `None` at the root of a host, bucket or filesystem
+    /// and for a relative path of one segment. Like directory prefixes of gcs, the
+    /// parent of a url ends with `/`; the parent of a local path does not, unless it is
+    /// the root.
+    pub fn parent(&self) -> Option<Self> {
+        let trimmed = self.trimmed_path();
+        let i = trimmed.rfind(|c| self.is_separator(c))?;
+        let is_root = trimmed[..i]
+            .trim_end_matches(|c| self.is_separator(c))
+            .is_empty();
+        let parent = if self.path_start != 0 || is_root {
+            &trimmed[..=i]
+        } else {
+            trimmed[..i].trim_end_matches(|c| self.is_separator(c))
+        };
+        Some(Self::from_parts(self.origin(), parent, ""))
+    }
+
+    /// The last segment, `None` for a path ending with a separator, which names a
+    /// directory, and for `.` and `..`.
+    pub fn file_name(&self) -> Option<&str> {
+        let path = self.path();
+        if path.ends_with(|c| self.is_separator(c)) {
+            return None;
+        }
+        let name = match path.rfind(|c| self.is_separator(c)) {
+            Some(i) => &path[i + 1..],
+            None => path,
+        };
+        match name {
+            "" | "." | ".." => None,
+            name => Some(name),
+        }
+    }
+
+    /// What follows the last `.` of the file name, `None` when there is none or the only
+    /// `.` starts the name, as in `.bashrc`.
+    pub fn extension(&self) -> Option<&str> {
+        let name = self.file_name()?;
+        match name.rfind('.') {
+            Some(0) | None => None,
+            Some(i) => Some(&name[i + 1..]),
+        }
+    }
+
+    /// Resolves `.` and `..` segments and merges repeated separators. `..` never goes
+    /// above the root, and is kept at the front of a relative local path. A trailing
+    /// separator is kept. Note that gcs object names may contain empty segments, as in
+    /// `gs://bucket/a//b`, which this merges into another name.
+    pub fn normalize(&self) -> Self {
+        let path = self.path();
+        let is_absolute = self.path_start != 0 || path.starts_with(|c| self.is_separator(c));
+        let mut segments: Vec<&str> = Vec::new();
+        for segment in path.split(|c| self.is_separator(c)) {
+            match segment {
+                "" | "." => {}
+                ".." => match segments.last() {
+                    Some(&last) if last != ".." => {
+                        segments.pop();
+                    }
+                    _ if is_absolute => {}
+                    _ => segments.push(".."),
+                },
+                segment => segments.push(segment),
+            }
+        }
+        let mut normalized = segments.join("/");
+        if is_absolute {
+            normalized.insert(0, '/');
+        } else if normalized.is_empty() {
+            normalized.push('.');
+        }
+        if !segments.is_empty() && path.ends_with(|c| self.is_separator(c)) {
+            normalized.push('/');
+        }
+        if self.path_start != 0 && path.is_empty() {
+            normalized.clear();
+        }
+        Self::from_parts(self.origin(), &normalized, self.suffix())
+    }
+}
+
+impl From<&str> for UriPath {
+    fn from(url_or_path: &str) -> Self {
+        Self::new(url_or_path)
+    }
+}
+
+impl AsRef<str> for UriPath {
+    fn as_ref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Display for UriPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(base: &str, relative: &str) -> String {
+        UriPath::new(base).join(relative).to_string()
+    }
+
+    fn parent(path: &str) -> Option<String> {
+        UriPath::new(path).parent().map(|p| p.to_string())
+    }
+
+    fn normalized(path: &str) -> String {
+        UriPath::new(path).normalize().to_string()
+    }
+
+    #[test]
+    fn split_into_origin_and_path() {
+        let path = UriPath::new("https://example.com:8080/a/b.txt?x=1#top");
+        assert_eq!("https://example.com:8080", path.origin());
+        assert_eq!("/a/b.txt", path.path());
+        assert!(!path.is_local());
+
+        let path = UriPath::new("gs://bucket");
+        assert_eq!(("gs://bucket", ""), (path.origin(), path.path()));
+        let path = UriPath::new("file:///tmp/x");
+        assert_eq!(("file://", "/tmp/x"), (path.origin(), path.path()));
+        assert!(path.is_local());
+        let path = UriPath::new("data/x.txt");
+        assert_eq!(("", "data/x.txt"), (path.origin(), path.path()));
+    }
+
+    #[test]
+    fn join_paths() {
+        assert_eq!("gs://bucket/a/b.txt", joined("gs://bucket/a", "b.txt"));
+        assert_eq!("gs://bucket/a/b.txt", joined("gs://bucket/a/", "b.txt"));
+        assert_eq!("gs://bucket/b.txt", joined("gs://bucket", "b.txt"));
+        assert_eq!("gs://bucket/c/d", joined("gs://bucket/a", "/c/d"));
+        assert_eq!("gs://other/x", joined("gs://bucket/a", "gs://other/x"));
+        assert_eq!(
+            "https://example.com/a/b?x=1",
+            joined("https://example.com/a?q=1", "b?x=1")
+        );
+        assert_eq!("/tmp/a/b.txt", joined("/tmp/a", "b.txt"));
+        assert_eq!("/etc/hosts", joined("/tmp/a", "/etc/hosts"));
+        assert_eq!("a/b", joined("a", "b"));
+        assert_eq!("b", joined("", "b"));
+    }
+
+    #[test]
+    fn parents() {
+        assert_eq!(
+            Some("gs://bucket/a/".to_string()),
+            parent("gs://bucket/a/b.txt")
+        );
+        assert_eq!(
+            Some("gs://bucket/a/".to_string()),
+            parent("gs://bucket/a/b/")
+        );
+        assert_eq!(Some("gs://bucket/".to_string()), parent("gs://bucket/a"));
+        assert_eq!(None, parent("gs://bucket/"));
+        assert_eq!(None, parent("gs://bucket"));
+        assert_eq!(
+            Some("https://example.com/a/".to_string()),
+            parent("https://example.com/a/b?x=1")
+        );
+        assert_eq!(Some("/tmp/a".to_string()), parent("/tmp/a/b.txt"));
+        assert_eq!(Some("/".to_string()), parent("/tmp"));
+        assert_eq!(None, parent("/"));
+        assert_eq!(Some("a".to_string()), parent("a/b"));
+        assert_eq!(None, parent("a"));
+    }
+
+    #[test]
+    fn file_names_and_extensions() {
+        let path = UriPath::new("gs://bucket/a/b.tar.gz");
+        assert_eq!(
+            (Some("b.tar.gz"), Some("gz")),
+            (path.file_name(), path.extension())
+        );
+        let path = UriPath::new("https://example.com/data.json?version=2");
+        assert_eq!(
+            (Some("data.json"), Some("json")),
+            (path.file_name(), path.extension())
+        );
+        let path = UriPath::new("/home/user/.bashrc");
+        assert_eq!(
+            (Some(".bashrc"), None),
+            (path.file_name(), path.extension())
+        );
+        assert_eq!(None, UriPath::new("gs://bucket/a/").file_name());
+        assert_eq!(None, UriPath::new("gs://bucket").file_name());
+        assert_eq!(None, UriPath::new("a/..").file_name());
+        assert_eq!(None, UriPath::new("Makefile").extension());
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!("gs://bucket/a/c", normalized("gs://bucket/a/./b/../c"));
+        assert_eq!("gs://bucket/a/b/", normalized("gs://bucket//a//b//"));
+        assert_eq!("gs://bucket/x", normalized("gs://bucket/../../x"));
+        assert_eq!("gs://bucket", normalized("gs://bucket"));
+        assert_eq!(
+            "https://example.com/b?x=../y",
+            normalized("https://example.com/a/../b?x=../y")
+        );
+        assert_eq!("/tmp/b", normalized("/tmp/./a/../b"));
+        assert_eq!("../b", normalized("a/../../b"));
+        assert_eq!(".", normalized("a/.."));
+        assert_eq!("file:///tmp/b", normalized("file:///tmp/a/../b"));
+    }
+}
@@ -1,10 +1,20 @@
+use crate::checksum::{matches_checksum, ChecksumAlgo};
 use crate::compression::*;
-use backoff::future::retry;
+use crate::mime::MimeType;
+use crate::retry::retry;
+use crate::{unblock, ErrorKind};
 use backoff::{Error as BackoffError, ExponentialBackoff};
-use http::StatusCode;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStream, TryStreamExt};
+use http::{header, Method, StatusCode};
 use lazy_static::lazy_static;
 use reqwest;
-use std::time::Duration;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use url::Url;
 
@@ -15,9 +25,68 @@ pub enum FileUtilWebError {
 
     #[error("compression error: {0}")]
     CompressionError(#[from] CompressionError),
+
+    #[error("response body rejected: {0}")]
+    RejectedBody(String),
+
+    #[error("file io error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("checksum error: {0}")]
+    ChecksumError(#[from] crate::checksum::ChecksumError),
+
+    #[error("checksum mismatch of `{url}`: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
 }
 pub type Result<T> = std::result::Result<T, FileUtilWebError>;
 
+impl FileUtilWebError {
+    /// The http status a service would answer with for this error, see
+    /// `FileUtilError::to_http_status`.
+    pub fn to_http_status(&self) -> u16 {
+        match self {
+            FileUtilWebError::HttpAccessError(e) if e.is_timeout() => 504,
+            FileUtilWebError::HttpAccessError(e) => {
+                match e.status().map(|status| status.as_u16()) {
+                    Some(404 | 410) => 404,
+                    Some(401 | 403) => 403,
+                    _ => 502,
+                }
+            }
+            FileUtilWebError::RejectedBody(_) | FileUtilWebError::ChecksumMismatch { .. } => 502,
+            FileUtilWebError::CompressionError(_) | FileUtilWebError::ChecksumError(_) => 500,
+            FileUtilWebError::IOError(e) => crate::io_error_status(e),
+        }
+    }
+
+    /// The class of this error, see `FileUtilError::kind`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FileUtilWebError::HttpAccessError(e) => match e.status() {
+                Some(StatusCode::NOT_FOUND | StatusCode::GONE) => ErrorKind::NotFound,
+                Some(StatusCode::UNAUTHORIZED) => ErrorKind::Unauthorized,
+                Some(StatusCode::FORBIDDEN) => ErrorKind::PermissionDenied,
+                Some(StatusCode::TOO_MANY_REQUESTS) => ErrorKind::RateLimited,
+                Some(status) if status.is_server_error() => ErrorKind::Transient,
+                Some(_) => ErrorKind::Other,
+                None if e.is_builder() => ErrorKind::InvalidPath,
+                None => ErrorKind::Transient,
+            },
+            FileUtilWebError::RejectedBody(_) | FileUtilWebError::ChecksumMismatch { .. } => {
+                ErrorKind::Transient
+            }
+            FileUtilWebError::CompressionError(_) | FileUtilWebError::ChecksumError(_) => {
+                ErrorKind::Other
+            }
+            FileUtilWebError::IOError(e) => crate::io_error_kind(e),
+        }
+    }
+}
+
 lazy_static! {
     static ref HTTP_CLI: reqwest::Client = reqwest::Client::builder()
         .timeout(Duration::from_secs(30))
@@ -25,9 +94,14 @@ lazy_static! {
         .unwrap();
 }
 
+/// The client of the current `config::ClientConfig`, or else the default one.
+fn http_client() -> reqwest::Client {
+    crate::config::http_client().unwrap_or_else(|| HTTP_CLI.clone())
+}
+
 pub async fn url_exists_with_retry(url: Url, backoff: Option<ExponentialBackoff>) -> Result<bool> {
-    retry(backoff.unwrap_or(ExponentialBackoff::default()), || async {
-        match HTTP_CLI.get(url.clone()).send().await {
+    retry(backoff, || async {
+        match http_client().get(url.clone()).send().await {
             Ok(response) => {
                 if response.status().is_success() {
                     Ok(true)
@@ -43,16 +117,276 @@ pub async fn url_exists_with_retry(url: Url, backoff: Option<ExponentialBackoff>
     .await
 }
 
+/// Whether `url` exists, as `url_exists_with_retry` tells, except that only 404 and 410 mean
+/// it does not: any other failure is returned, server errors after retrying.
+pub async fn url_exists_strict_with_retry(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    retry(backoff, || async {
+        let response = http_client()
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(status_error(response)),
+        }
+    })
+    .await
+}
+
+/// The response headers of a HEAD request to `url`, or `None` when the server answers
+/// 404 or 410. Client errors are not retried.
+pub async fn head_with_retry(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<header::HeaderMap>> {
+    retry(backoff, || async {
+        let response = http_client()
+            .head(url.clone())
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(None),
+            status if status.is_success() => Ok(Some(response.headers().clone())),
+            _ => Err(status_error(response)),
+        }
+    })
+    .await
+}
+
+/// The `algo` checksum of the contents announced by `headers`, from `Repr-Digest`
+/// (`sha-256=:<base64>:`), the older `Digest` (`SHA-256=<base64>`), `Content-MD5`, or the
+/// `x-goog-hash` (`crc32c=<base64>,md5=<base64>`) gcs serves objects with, in that order.
+pub fn digest_from_headers(headers: &header::HeaderMap, algo: ChecksumAlgo) -> Option<Vec<u8>> {
+    let name = match algo {
+        ChecksumAlgo::Sha256 => "sha-256",
+        ChecksumAlgo::Md5 => "md5",
+        ChecksumAlgo::Crc32c => "crc32c",
+    };
+    let listed = ["repr-digest", "digest", "x-goog-hash"]
+        .iter()
+        .flat_map(|header| headers.get_all(*header))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.trim().split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches(':').to_string());
+    let content_md5 = || {
+        headers
+            .get("content-md5")
+            .filter(|_| algo == ChecksumAlgo::Md5)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    };
+    base64::decode(listed.or_else(content_md5)?).ok()
+}
+
+/// Parse a date in the IMF-fixdate format of http headers such as `Last-Modified`, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`. The obsolete formats, which servers must no longer
+/// send, are not supported.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut fields = date.strip_suffix(" GMT")?.split(' ');
+    let (_weekday, day, month, year, time) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    if fields.next().is_some() {
+        return None;
+    }
+    let day: i64 = day.parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|name| *name == month)? as i64
+        + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 || time.next().is_some()
+    {
+        return None;
+    }
+
+    // days from the civil date, after Howard Hinnant's algorithm
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// A file at an http(s) url, for code written against `storage::FileStorage`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebFile {
+    pub url: Url,
+}
+
+impl WebFile {
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
+}
+
+pub type BodyPredicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Called with the bytes sent so far and the total size of an upload.
+pub type UploadProgress = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Options for `download_from_url_with_options`.
+#[derive(Clone, Default)]
+pub struct DownloadOptions {
+    /// Decompress the body when the response `Content-Type` names a known compression
+    /// (e.g. `application/gzip`) and no explicit decompression is given.
+    /// This applies after the `Content-Encoding` of the response is decoded.
+    pub decompress_by_content_type: bool,
+
+    /// Send the request to `normalize_url(url)` instead of `url` as given.
+    pub normalize_url: bool,
+
+    /// Value of the `Accept` header, for servers that negotiate the format of the body.
+    pub accept: Option<String>,
+
+    /// Judge a successful response by its body (as received, before decompression), for
+    /// servers that answer errors with 200. A rejected body fails the attempt, which is retried.
+    pub success_predicate: Option<BodyPredicate>,
+
+    /// Check the body as received against the `Content-MD5` header when the server sends one.
+    /// A mismatch, e.g. from a proxy corrupting the body, fails the attempt with
+    /// `FileUtilWebError::ChecksumMismatch`, which is retried.
+    pub verify_content_md5: bool,
+}
+
+/// Normalize `url` so that equivalent urls compare equal:
+/// repeated slashes in the path are collapsed, percent-encoded unreserved characters are
+/// decoded and remaining escapes are upper-cased, and an empty query or a fragment is dropped.
+/// Lower-casing the host, removing default ports, encoding spaces and resolving `.`/`..`
+/// segments are already done by `Url::parse`.
+pub fn normalize_url(url: &Url) -> Url {
+    let mut normalized = url.clone();
+
+    let mut path = String::with_capacity(url.path().len());
+    for c in url.path().chars() {
+        if !(c == '/' && path.ends_with('/')) {
+            path.push(c);
+        }
+    }
+    normalized.set_path(&normalize_percent_encoding(&path));
+
+    match url.query() {
+        Some("") => normalized.set_query(None),
+        Some(query) => normalized.set_query(Some(&normalize_percent_encoding(query))),
+        None => {}
+    }
+    normalized.set_fragment(None);
+    normalized
+}
+
+fn normalize_percent_encoding(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = || std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok();
+        if bytes[i] == b'%' {
+            if let Some(decoded) = hex().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                if decoded.is_ascii_alphanumeric() || b"-._~".contains(&decoded) {
+                    result.push(decoded as char);
+                } else {
+                    result.push_str(&format!("%{:02X}", decoded));
+                }
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i] as char);
+        i += 1;
+    }
+    result
+}
+
+/// Download `url`, returning `None` on 404.
+/// A body sent with a `Content-Encoding` (gzip or zstd, as the server chose for the transfer)
+/// is decoded first, and `decompression` then applies to the decoded body, i.e. to the
+/// resource as stored. A `.json.gz` served with `Content-Encoding: gzip` therefore needs
+/// `Some(Compression::Gzip)` to get the json, exactly as when it is served without encoding.
 pub async fn download_from_url_with_retry(
     url: Url,
     backoff: Option<ExponentialBackoff>,
     decompression: Option<Compression>,
 ) -> Result<Option<Vec<u8>>> {
-    let contents = retry(backoff.unwrap_or(ExponentialBackoff::default()), || async {
-        let result = HTTP_CLI.get(url.clone()).send().await;
+    download_from_url_with_options(url, backoff, decompression, &DownloadOptions::default()).await
+}
 
-        let bytes = match result {
-            Ok(bytes) => bytes,
+pub async fn download_from_url_with_options(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+    options: &DownloadOptions,
+) -> Result<Option<Vec<u8>>> {
+    let sized = download_from_url_sized(url, backoff, decompression, options).await?;
+    Ok(sized.map(|sized| sized.contents))
+}
+
+/// Same as `download_from_url_with_options`, also reporting the size of the body as received.
+pub async fn download_from_url_sized(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+    options: &DownloadOptions,
+) -> Result<Option<SizedContents>> {
+    let download = download_from_url_resolved(url, backoff, decompression, options).await?;
+    Ok(download.map(|download| download.contents))
+}
+
+/// The body of a download along with the url it was finally served from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebDownload {
+    pub contents: SizedContents,
+    /// The url after following redirects, to resolve relative links against.
+    pub final_url: Url,
+}
+
+/// Same as `download_from_url_sized`, also reporting the url the body was served from
+/// after redirects. Trailers are not available: the http client does not expose them.
+pub async fn download_from_url_resolved(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+    decompression: Option<Compression>,
+    options: &DownloadOptions,
+) -> Result<Option<WebDownload>> {
+    let url = if options.normalize_url {
+        normalize_url(&url)
+    } else {
+        url
+    };
+    let fetched = retry(backoff, || async {
+        let mut request = http_client().get(url.clone());
+        if let Some(accept) = options.accept.as_ref() {
+            request = request.header(header::ACCEPT, accept.as_str());
+        }
+        let result = request.send().await;
+
+        let response = match result {
+            Ok(response) => response,
             Err(err) => {
                 if let Some(status) = err.status() {
                     if StatusCode::NOT_FOUND == status {
@@ -64,16 +398,916 @@ pub async fn download_from_url_with_retry(
                 )));
             }
         };
+        // the client does not turn statuses into errors, so a 404 arrives as a response
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
 
-        match bytes.bytes().await {
-            Ok(bytes) => Ok(Some(bytes.as_ref().to_vec())),
-            Err(e) => Err(BackoffError::Transient(FileUtilWebError::HttpAccessError(
-                e,
-            ))),
+        let content_type_compression = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Compression::from_content_type);
+
+        let content_md5 = response
+            .headers()
+            .get("content-md5")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let content_encoding = response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let final_url = response.url().clone();
+        let bytes = match response.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return Err(BackoffError::Transient(FileUtilWebError::HttpAccessError(
+                    e,
+                )))
+            }
+        };
+        if let Some(expected) = content_md5.filter(|_| options.verify_content_md5) {
+            let digest = ChecksumAlgo::Md5.digest(&bytes);
+            if !matches_checksum(&digest, &expected) {
+                log::warn!(
+                    "response body from {} does not match its content-md5",
+                    final_url
+                );
+                return Err(BackoffError::Transient(
+                    FileUtilWebError::ChecksumMismatch {
+                        url: final_url.to_string(),
+                        expected,
+                        actual: base64::encode(digest),
+                    },
+                ));
+            }
+        }
+        if let Some(success_predicate) = options.success_predicate.as_ref() {
+            if !success_predicate(&bytes) {
+                log::warn!("response body from {} is rejected", final_url);
+                return Err(BackoffError::Transient(FileUtilWebError::RejectedBody(
+                    final_url.to_string(),
+                )));
+            }
         }
+        Ok(Some((
+            bytes.to_vec(),
+            content_encoding,
+            content_type_compression,
+            final_url,
+        )))
     })
     .await?;
 
-    let result = decompress_opt(contents, decompression)?;
-    Ok(result)
+    let (received, content_encoding, detected, final_url) = match fetched {
+        Some(fetched) => fetched,
+        None => return Ok(None),
+    };
+    let raw_size = received.len();
+    let contents = match content_encoding {
+        Some(content_encoding) => decode_content_encoding(&content_encoding, received)?,
+        None => received,
+    };
+    let decompression = match decompression {
+        Some(decompression) => Some(decompression),
+        None if options.decompress_by_content_type => detected,
+        None => None,
+    };
+
+    let contents = decompress_opt_sized(Some(contents), decompression)?;
+    Ok(contents.map(|contents| WebDownload {
+        contents: SizedContents {
+            raw_size,
+            ..contents
+        },
+        final_url,
+    }))
+}
+
+/// Undo the codings of a `Content-Encoding` value such as `gzip`, last applied first.
+/// A body with a coding other than gzip or zstd is returned as received.
+fn decode_content_encoding(content_encoding: &str, body: Vec<u8>) -> Result<Vec<u8>> {
+    let codings: Vec<Option<Compression>> = content_encoding
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .map(|coding| match coding.as_str() {
+            "gzip" | "x-gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        })
+        .collect();
+    if codings.iter().any(Option::is_none) {
+        log::warn!(
+            "content-encoding `{}` is not supported, keeping the body as received",
+            content_encoding
+        );
+        return Ok(body);
+    }
+
+    let mut decoded = body;
+    for compression in codings.into_iter().rev().flatten() {
+        decoded = compression.decompress(&decoded)?;
+    }
+    Ok(decoded)
+}
+
+/// Download `length` bytes (or up to the end with `None`) starting at `offset` with a `Range`
+/// request. When the server ignores the range, the body is read only as far as needed.
+/// Returns `None` on 404.
+pub async fn download_range_from_url_with_retry(
+    url: Url,
+    offset: u64,
+    length: Option<u64>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    match download_range_or_body_from_url_with_retry(url, offset, length, backoff).await? {
+        Some(RangedBody::Range(range)) => Ok(Some(range)),
+        Some(RangedBody::Full(body)) => read_range_of_full_body(body, offset, length)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+/// What a `Range` request is answered with.
+pub enum RangedBody {
+    /// The bytes of the range.
+    Range(Vec<u8>),
+    /// The whole body, unread, from a server that ignores the range.
+    Full(BoxStream<'static, Result<Bytes>>),
+}
+
+/// Like `download_range_from_url_with_retry`, but the body of a server ignoring the range is
+/// returned unread, for callers going on to read past the range. Only establishing the
+/// response is retried then. Returns `None` on 404.
+pub async fn download_range_or_body_from_url_with_retry(
+    url: Url,
+    offset: u64,
+    length: Option<u64>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<RangedBody>> {
+    if length == Some(0) {
+        return Ok(Some(RangedBody::Range(vec![])));
+    }
+    let range = match length {
+        Some(length) => format!("bytes={}-{}", offset, offset + length - 1),
+        None => format!("bytes={}-", offset),
+    };
+
+    retry(backoff, || async {
+        let response = http_client()
+            .get(url.clone())
+            .header(header::RANGE, range.as_str())
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(Some(RangedBody::Range(vec![]))),
+            StatusCode::PARTIAL_CONTENT => match response.bytes().await {
+                Ok(bytes) => Ok(Some(RangedBody::Range(bytes.to_vec()))),
+                Err(e) => Err(BackoffError::Transient(e.into())),
+            },
+            status if status.is_success() => {
+                let body = response.bytes_stream().map_err(FileUtilWebError::from);
+                Ok(Some(RangedBody::Full(body.boxed())))
+            }
+            _ => Err(status_error(response)),
+        }
+    })
+    .await
+}
+
+/// Download the last `length` bytes with a suffix `Range` request, or the whole body when
+/// it is shorter. When the server ignores the range, the whole body is read and cut.
+/// Returns `None` on 404.
+pub async fn download_tail_from_url_with_retry(
+    url: Url,
+    length: u64,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<Vec<u8>>> {
+    if length == 0 {
+        return Ok(Some(vec![]));
+    }
+    let range = format!("bytes=-{}", length);
+
+    retry(backoff, || async {
+        let response = http_client()
+            .get(url.clone())
+            .header(header::RANGE, range.as_str())
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            // an empty body can not satisfy a suffix range
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(Some(vec![])),
+            status if status.is_success() => {
+                let partial = status == StatusCode::PARTIAL_CONTENT;
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| BackoffError::Transient(e.into()))?;
+                let start = if partial {
+                    0
+                } else {
+                    body.len().saturating_sub(length as usize)
+                };
+                Ok(Some(body[start..].to_vec()))
+            }
+            _ => Err(status_error(response)),
+        }
+    })
+    .await
+}
+
+/// Start a download and return its body as a stream of chunks, without buffering it.
+/// Only establishing the response is retried. Returns `None` on 404.
+pub async fn download_stream_from_url_with_retry(
+    url: Url,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Option<impl Stream<Item = Result<Bytes>>>> {
+    let response = retry(backoff, || async {
+        let response = http_client()
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response)),
+            _ => Err(status_error(response)),
+        }
+    })
+    .await?;
+    Ok(response.map(|response| response.bytes_stream().map_err(FileUtilWebError::from)))
+}
+
+async fn read_range_of_full_body(
+    mut body: BoxStream<'static, Result<Bytes>>,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<Vec<u8>> {
+    let start = offset as usize;
+    let end = length.map(|length| start + length as usize);
+    let mut read = Vec::<u8>::new();
+    while end.is_none_or(|end| read.len() < end) {
+        match body.try_next().await? {
+            Some(chunk) => read.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    let end = end.unwrap_or(read.len()).min(read.len());
+    Ok(read[start.min(end)..end].to_vec())
+}
+
+/// Client errors are not going to change on retry, server errors and rate limiting may.
+fn status_error(response: reqwest::Response) -> BackoffError<FileUtilWebError> {
+    let status = response.status();
+    let err = match response.error_for_status() {
+        Err(e) => FileUtilWebError::HttpAccessError(e),
+        Ok(_) => unreachable!("status_error called with a success status {}", status),
+    };
+    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+        BackoffError::Permanent(err)
+    } else {
+        BackoffError::Transient(err)
+    }
+}
+
+/// Whether sending a request with `method` again has the same effect as sending it once,
+/// which is what makes it safe to retry.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Send a request with `body` and return the body of the successful response.
+/// Failures are retried only when the request is idempotent: `idempotent` overrides
+/// what `is_idempotent` says about `method`. Otherwise the first failure is returned.
+pub async fn send_with_retry(
+    method: Method,
+    url: Url,
+    body: Vec<u8>,
+    mime_type: MimeType,
+    idempotent: Option<bool>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<Vec<u8>> {
+    let idempotent = idempotent.unwrap_or_else(|| is_idempotent(&method));
+    let content_type: &str = mime_type.into();
+
+    retry(backoff, || async {
+        let attempt = async {
+            let response = http_client()
+                .request(method.clone(), url.clone())
+                .header(header::CONTENT_TYPE, content_type)
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+            if !response.status().is_success() {
+                return Err(status_error(response));
+            }
+            match response.bytes().await {
+                Ok(bytes) => Ok(bytes.to_vec()),
+                Err(e) => Err(BackoffError::Transient(e.into())),
+            }
+        };
+        match attempt.await {
+            Err(BackoffError::Transient(e)) if !idempotent => Err(BackoffError::Permanent(e)),
+            result => result,
+        }
+    })
+    .await
+}
+
+/// PUT `body` to `url`. With `if_not_exists` it is sent with `If-None-Match: *`, and with
+/// `if_match` with that `If-Match`, for the server to store it only while the precondition
+/// holds. Returns whether it was stored, i.e. `false` when the server answered 412.
+pub async fn put_with_retry(
+    url: Url,
+    body: Bytes,
+    mime_type: MimeType,
+    if_not_exists: bool,
+    if_match: Option<&str>,
+    backoff: Option<ExponentialBackoff>,
+) -> Result<bool> {
+    let content_type: &str = mime_type.into();
+    retry(backoff, || async {
+        let mut request = http_client()
+            .put(url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.clone());
+        if if_not_exists {
+            request = request.header(header::IF_NONE_MATCH, "*");
+        }
+        if let Some(etag) = if_match {
+            request = request.header(header::IF_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(status_error(response)),
+        }
+    })
+    .await
+}
+
+/// PUT the chunks of `body` to `url` with chunked transfer encoding.
+/// The stream can be consumed only once, so a failed upload is not retried.
+pub async fn upload_stream_to_url<S>(url: Url, body: S, mime_type: MimeType) -> Result<()>
+where
+    S: TryStream + Send + Sync + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    Bytes: From<S::Ok>,
+{
+    let content_type: &str = mime_type.into();
+    http_client()
+        .put(url)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// PUT the local file at `path` to `url` with chunked transfer encoding, without reading it
+/// into memory. `progress` is called after every chunk sent. With `send_checksum`, the md5
+/// of the file is sent in the `Content-MD5` header for the server to verify, which takes an
+/// extra pass over the file before the upload.
+/// There is no common protocol for resuming a plain http upload, so an attempt that fails
+/// with a transient error is retried from the start of the file.
+pub async fn upload_file_to_web(
+    path: &Path,
+    url: Url,
+    mime_type: MimeType,
+    backoff: Option<ExponentialBackoff>,
+    progress: Option<UploadProgress>,
+    send_checksum: bool,
+) -> Result<()> {
+    let path = path.to_path_buf();
+    let (total, content_md5) = {
+        let path = path.clone();
+        unblock(move || -> Result<_> {
+            let total = std::fs::metadata(&path)?.len();
+            let content_md5 = if send_checksum {
+                let digest = ChecksumAlgo::Md5.digest_reader(std::fs::File::open(&path)?)?;
+                Some(base64::encode(digest))
+            } else {
+                None
+            };
+            Ok((total, content_md5))
+        })
+        .await?
+    };
+    let content_type: &str = mime_type.into();
+
+    retry(backoff, || async {
+        let path = path.clone();
+        let file = unblock(move || std::fs::File::open(path))
+            .await
+            .map_err(|e| BackoffError::Permanent(e.into()))?;
+        let body = file_chunks(file, total, progress.clone());
+        let mut request = http_client()
+            .put(url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .body(reqwest::Body::wrap_stream(body));
+        if let Some(content_md5) = content_md5.as_ref() {
+            request = request.header("content-md5", content_md5);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| BackoffError::Transient(FileUtilWebError::HttpAccessError(e)))?;
+        if !response.status().is_success() {
+            return Err(status_error(response));
+        }
+        Ok(())
+    })
+    .await
+}
+
+fn file_chunks(
+    file: std::fs::File,
+    total: u64,
+    progress: Option<UploadProgress>,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures::stream::try_unfold((file, 0u64), move |(mut file, sent)| {
+        let progress = progress.clone();
+        async move {
+            let (file, buf) = unblock(move || {
+                let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+                let n = file.read(&mut buf)?;
+                buf.truncate(n);
+                Ok::<_, std::io::Error>((file, buf))
+            })
+            .await?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let sent = sent + buf.len() as u64;
+            if let Some(progress) = progress {
+                progress(sent, total);
+            }
+            Ok(Some((Bytes::from(buf), (file, sent))))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::http::{TestResponse, TestServer};
+    use crate::testing::quick_backoff;
+
+    #[tokio::test]
+    async fn decompress_by_content_type() {
+        let body = "decompressed by content-type";
+        let gzipped = Compression::Gzip.compress(body.as_bytes()).unwrap();
+        let server = TestServer::start(move |_| {
+            TestResponse::ok(gzipped.clone()).with_header("content-type", "application/gzip")
+        })
+        .await;
+
+        let options = DownloadOptions {
+            decompress_by_content_type: true,
+            ..DownloadOptions::default()
+        };
+        let actual = download_from_url_with_options(
+            server.url("/a.bin"),
+            Some(quick_backoff()),
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(body.as_bytes().to_vec()), actual);
+
+        let raw = download_from_url_with_retry(server.url("/a.bin"), Some(quick_backoff()), None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(
+            body.as_bytes(),
+            raw.as_slice(),
+            "not decompressed unless opted in"
+        );
+        assert_eq!(
+            body.as_bytes().to_vec(),
+            Compression::Gzip.decompress(&raw).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn content_encoding_then_explicit_decompression() {
+        let json = br#"{"name":"dataset"}"#.to_vec();
+        let stored = Compression::Gzip.compress(&json).unwrap();
+
+        for content_encoding in [None, Some("gzip")] {
+            let body = match content_encoding {
+                Some(_) => Compression::Gzip.compress(&stored).unwrap(),
+                None => stored.clone(),
+            };
+            let received = body.len();
+            let server = TestServer::start(move |_| {
+                let response = TestResponse::ok(body.clone());
+                match content_encoding {
+                    Some(content_encoding) => {
+                        response.with_header("content-encoding", content_encoding)
+                    }
+                    None => response,
+                }
+            })
+            .await;
+
+            for (decompression, expected) in [(None, &stored), (Some(Compression::Gzip), &json)] {
+                let actual = download_from_url_sized(
+                    server.url("/payload.json.gz"),
+                    Some(quick_backoff()),
+                    decompression,
+                    &DownloadOptions::default(),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                assert_eq!(*expected, actual.contents, "{:?}", content_encoding);
+                assert_eq!(received, actual.raw_size);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_content_md5_when_opted_in() {
+        let body = b"checked body".to_vec();
+        let md5 = base64::encode(ChecksumAlgo::Md5.digest(&body));
+        let served = body.clone();
+        let server = TestServer::start(move |request| {
+            let response = TestResponse::ok(served.clone());
+            match request.path.as_str() {
+                "/correct" => response.with_header("content-md5", &md5),
+                _ => response.with_header("content-md5", "AAAAAAAAAAAAAAAAAAAAAA=="),
+            }
+        })
+        .await;
+        let options = DownloadOptions {
+            verify_content_md5: true,
+            ..DownloadOptions::default()
+        };
+
+        let actual = download_from_url_with_options(
+            server.url("/correct"),
+            Some(quick_backoff()),
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(body.clone()), actual);
+        assert_eq!(1, server.requests().len());
+
+        let result = download_from_url_with_options(
+            server.url("/corrupted"),
+            Some(quick_backoff()),
+            None,
+            &options,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(FileUtilWebError::ChecksumMismatch { ref expected, .. })
+                if expected == "AAAAAAAAAAAAAAAAAAAAAA=="
+        ));
+        assert!(server.requests().len() > 2, "a mismatch is retried");
+
+        // not verified unless opted in
+        let actual =
+            download_from_url_with_retry(server.url("/corrupted"), Some(quick_backoff()), None)
+                .await
+                .unwrap();
+        assert_eq!(Some(body), actual);
+    }
+
+    #[tokio::test]
+    async fn upload_stream_chunked() {
+        let server = TestServer::start(|_| TestResponse::status(200)).await;
+        let chunks: Vec<std::result::Result<Vec<u8>, std::io::Error>> = (0..5)
+            .map(|i| Ok(format!("chunk-{};", i).into_bytes()))
+            .collect();
+
+        upload_stream_to_url(
+            server.url("/upload"),
+            futures::stream::iter(chunks),
+            MimeType::Text,
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("PUT", requests[0].method);
+        assert_eq!(Some("chunked"), requests[0].header("transfer-encoding"));
+        assert_eq!(
+            b"chunk-0;chunk-1;chunk-2;chunk-3;chunk-4;".to_vec(),
+            requests[0].body
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_file_with_progress_and_checksum() {
+        use crate::testing::TempDir;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.write("upload.bin", &contents);
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        // the first attempt fails and the file is sent again
+        let server = TestServer::start(move |_| match counted.fetch_add(1, Ordering::SeqCst) {
+            0 => TestResponse::status(503),
+            _ => TestResponse::status(200),
+        })
+        .await;
+        let reported = Arc::new(Mutex::new(Vec::<(u64, u64)>::new()));
+        let recorder = reported.clone();
+        let progress: UploadProgress =
+            Arc::new(move |sent, total| recorder.lock().unwrap().push((sent, total)));
+
+        upload_file_to_web(
+            Path::new(&path),
+            server.url("/upload"),
+            MimeType::OctetStream,
+            Some(quick_backoff()),
+            Some(progress),
+            true,
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        let request = &requests[1];
+        assert_eq!("PUT", request.method);
+        assert_eq!(Some("chunked"), request.header("transfer-encoding"));
+        assert_eq!(contents, request.body);
+        assert_eq!(
+            Some(base64::encode(ChecksumAlgo::Md5.digest(&contents)).as_str()),
+            request.header("content-md5")
+        );
+
+        let total = contents.len() as u64;
+        let reported = reported.lock().unwrap();
+        assert_eq!(Some(&(total, total)), reported.last());
+        assert!(reported
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 || w[1].0 <= 64 * 1024));
+        assert_eq!(
+            2,
+            reported.iter().filter(|(sent, _)| *sent == total).count()
+        );
+    }
+
+    #[tokio::test]
+    async fn download_range_honored_or_ignored() {
+        let honoring = TestServer::start(|req| {
+            assert_eq!(Some("bytes=2-4"), req.header("range"));
+            TestResponse::status(206).with_body("234")
+        })
+        .await;
+        let actual = download_range_from_url_with_retry(
+            honoring.url("/r"),
+            2,
+            Some(3),
+            Some(quick_backoff()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(b"234".to_vec()), actual);
+
+        let ignoring = TestServer::start(|_| TestResponse::ok("0123456789")).await;
+        let actual = download_range_from_url_with_retry(
+            ignoring.url("/r"),
+            2,
+            Some(3),
+            Some(quick_backoff()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(b"234".to_vec()), actual);
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_requests_are_retried() {
+        let failing = TestServer::start(|_| TestResponse::status(503)).await;
+        let send = |method: Method, idempotent: Option<bool>| {
+            send_with_retry(
+                method,
+                failing.url("/op"),
+                b"body".to_vec(),
+                MimeType::Json,
+                idempotent,
+                Some(quick_backoff()),
+            )
+        };
+
+        assert!(send(Method::POST, None).await.is_err());
+        assert_eq!(1, failing.requests().len(), "POST is not retried");
+
+        assert!(send(Method::GET, None).await.is_err());
+        let retried_get = failing.requests().len() - 1;
+        assert!(retried_get > 1, "GET is retried");
+
+        assert!(send(Method::POST, Some(true)).await.is_err());
+        assert!(
+            failing.requests().len() - 1 - retried_get > 1,
+            "overridden POST is retried"
+        );
+
+        assert!(!is_idempotent(&Method::POST));
+        assert!(is_idempotent(&Method::DELETE));
+    }
+
+    #[tokio::test]
+    async fn download_tail_honored_or_ignored() {
+        let honoring = TestServer::start(|req| {
+            assert_eq!(Some("bytes=-3"), req.header("range"));
+            TestResponse::status(206).with_body("789")
+        })
+        .await;
+        let actual =
+            download_tail_from_url_with_retry(honoring.url("/t"), 3, Some(quick_backoff()))
+                .await
+                .unwrap();
+        assert_eq!(Some(b"789".to_vec()), actual);
+
+        let ignoring = TestServer::start(|_| TestResponse::ok("0123456789")).await;
+        for (length, expected) in [(3, "789"), (20, "0123456789")] {
+            let actual = download_tail_from_url_with_retry(
+                ignoring.url("/t"),
+                length,
+                Some(quick_backoff()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(Some(expected.as_bytes().to_vec()), actual);
+        }
+    }
+
+    #[test]
+    fn normalize_equivalent_urls() {
+        let expected = "http://example.com/a/~user/b%2F?q=%2A";
+        for input in [
+            "HTTP://Example.COM:80//a/%7euser//b%2f?q=%2a",
+            "http://example.com/a/~user/b%2F?q=%2A#fragment",
+            "http://example.com/a/./x/../~user/b%2F?q=%2A",
+        ] {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(expected, normalize_url(&url).as_str(), "{}", input);
+        }
+
+        let url = Url::parse("http://example.com/a b?").unwrap();
+        assert_eq!("http://example.com/a%20b", normalize_url(&url).as_str());
+    }
+
+    #[tokio::test]
+    async fn request_normalized_url_when_opted_in() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let url = Url::parse(&server.url("/a//b//c").as_str().replace("/a", "/%61")).unwrap();
+
+        let options = DownloadOptions {
+            normalize_url: true,
+            ..DownloadOptions::default()
+        };
+        download_from_url_with_options(url.clone(), Some(quick_backoff()), None, &options)
+            .await
+            .unwrap();
+        download_from_url_with_retry(url, Some(quick_backoff()), None)
+            .await
+            .unwrap();
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(vec!["/a/b/c", "/%61//b//c"], paths);
+    }
+
+    #[tokio::test]
+    async fn download_with_accept_header() {
+        let server = TestServer::start(|request| match request.header("accept") {
+            Some("application/json") => TestResponse::ok(r#"{"a":1}"#),
+            Some("text/csv") => TestResponse::ok("a\n1\n"),
+            _ => TestResponse::status(406),
+        })
+        .await;
+
+        for (accept, expected) in [
+            ("application/json", r#"{"a":1}"#.as_bytes()),
+            ("text/csv", b"a\n1\n"),
+        ] {
+            let options = DownloadOptions {
+                accept: Some(accept.to_string()),
+                ..DownloadOptions::default()
+            };
+            let actual = download_from_url_with_options(
+                server.url("/data"),
+                Some(quick_backoff()),
+                None,
+                &options,
+            )
+            .await
+            .unwrap();
+            assert_eq!(Some(expected.to_vec()), actual, "{}", accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn report_final_url_after_redirects() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/start" => TestResponse::status(302).with_header("location", "/middle"),
+            "/middle" => TestResponse::status(301).with_header("location", "/docs/final"),
+            "/docs/final" => TestResponse::ok("landed"),
+            _ => TestResponse::status(404),
+        })
+        .await;
+
+        let requested = server.url("/start");
+        let download = download_from_url_resolved(
+            requested.clone(),
+            Some(quick_backoff()),
+            None,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(b"landed".to_vec(), download.contents.contents);
+        assert_eq!(server.url("/docs/final"), download.final_url);
+        assert_ne!(requested, download.final_url);
+    }
+
+    #[tokio::test]
+    async fn reject_error_body_with_success_status() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/flaky" => TestResponse::ok(r#"{"error":"quota exceeded"}"#),
+            _ => TestResponse::ok(r#"{"data":1}"#),
+        })
+        .await;
+        let options = DownloadOptions {
+            success_predicate: Some(Arc::new(|body: &[u8]| {
+                !String::from_utf8_lossy(body).contains("\"error\"")
+            })),
+            ..DownloadOptions::default()
+        };
+
+        let ok = download_from_url_with_options(
+            server.url("/good"),
+            Some(quick_backoff()),
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(br#"{"data":1}"#.to_vec()), ok);
+
+        let rejected = download_from_url_with_options(
+            server.url("/flaky"),
+            Some(quick_backoff()),
+            None,
+            &options,
+        )
+        .await;
+        assert!(matches!(rejected, Err(FileUtilWebError::RejectedBody(_))));
+        let attempts = server
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/flaky")
+            .count();
+        assert!(attempts > 1, "a rejected body is retried");
+
+        let unchecked = download_from_url_with_retry(server.url("/flaky"), None, None)
+            .await
+            .unwrap();
+        assert!(unchecked.is_some(), "off by default");
+    }
+
+    #[test]
+    fn compression_from_content_type() {
+        assert!(matches!(
+            Compression::from_content_type("application/x-gzip; charset=binary"),
+            Some(Compression::Gzip)
+        ));
+        assert!(Compression::from_content_type("text/plain").is_none());
+    }
 }
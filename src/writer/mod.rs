@@ -0,0 +1,425 @@
+//! An `std::io::Write` destination that compresses according to the extension of the
+//! url or path it is created for, and an `AsyncWrite` one that streams to the backend.
+use crate::compression::stream::StreamEncoder;
+use crate::compression::{Compression, CompressionError};
+use crate::{mime, write_contents, FileUtilError, Result};
+use backoff::ExponentialBackoff;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{ready, Sink, SinkExt};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+enum Encoder {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: Option<Compression>) -> io::Result<Self> {
+        Ok(match compression {
+            None => Encoder::Plain(Vec::new()),
+            Some(Compression::Gzip) => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            Some(Compression::Zstd) => Encoder::Zstd(zstd::Encoder::new(
+                Vec::new(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        })
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Plain(body) => Ok(body),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Compresses what is written to it (gzip for `.gz`, zstd for `.zst`, nothing otherwise)
+/// and stores the result at the destination on `finish`.
+pub struct Writer {
+    url_or_path: String,
+    mime_type: mime::MimeType,
+    backoff: Option<ExponentialBackoff>,
+    compression: Option<Compression>,
+    encoder: Encoder,
+}
+
+impl Writer {
+    pub fn new(
+        url_or_path_str: &str,
+        mime_type: mime::MimeType,
+        backoff: Option<ExponentialBackoff>,
+    ) -> Result<Self> {
+        let compression = Compression::from_extention(url_or_path_str);
+        let encoder = Encoder::new(compression.clone()).map_err(CompressionError::from)?;
+        Ok(Self {
+            url_or_path: url_or_path_str.to_string(),
+            mime_type,
+            backoff,
+            compression,
+            encoder,
+        })
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression.clone()
+    }
+
+    /// Complete the compression stream and store the contents.
+    /// Nothing is stored unless this is called.
+    pub async fn finish(self) -> Result<()> {
+        let body = self.encoder.finish().map_err(CompressionError::from)?;
+        write_contents(&self.url_or_path, body, self.mime_type, self.backoff, None).await
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::Plain(body) => body.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    /// Flushes the compressor; the destination is written only by `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Plain(_) => Ok(()),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Options for `write_contents_with_options` and `open_write`.
+/// Build them with the `with_*` methods, starting from `WriteOptions::new()`. `open_write`
+/// takes only the mime type and compression.
+#[derive(Clone, Default)]
+pub struct WriteOptions {
+    /// `None` infers it from the extension of the file name, see `MimeType::from_extension`,
+    /// and falls back to `application/octet-stream`.
+    pub mime_type: Option<mime::MimeType>,
+
+    /// Compress what is written before it is stored.
+    pub compression: Option<Compression>,
+
+    /// The `Cache-Control` gcs serves the object with. Local files ignore it.
+    pub cache_control: Option<String>,
+
+    /// Custom metadata of the gcs object. Local files ignore it.
+    pub metadata: BTreeMap<String, String>,
+
+    /// Leave an existing file as it is instead of overwriting it.
+    pub if_not_exists: bool,
+
+    /// Replace the gcs object only while it is still at this generation, see
+    /// `GcsFile::current_generation`. The default gcs client checks it with a read before the
+    /// upload, so a concurrent writer can still be overwritten. Other files fail with
+    /// `FileUtilError::Unsupported`.
+    pub if_generation_match: Option<i64>,
+
+    /// Replace the web resource only while it still has this `ETag`, see `stat`. Other files
+    /// fail with `FileUtilError::Unsupported`.
+    pub if_match: Option<String>,
+
+    /// Replace a local file atomically, see `fs::WriteMode::Atomic`, so that it is never
+    /// seen partly written. Not combined with `if_not_exists`, under which the file is
+    /// created in place. Other files are replaced whole by their backend and ignore it.
+    pub atomic: bool,
+
+    /// Create the missing directories a local file is written in, instead of failing.
+    /// Other files ignore it.
+    pub create_parents: bool,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mime_type(mut self, mime_type: mime::MimeType) -> Self {
+        self.mime_type = Some(mime_type);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_cache_control(mut self, cache_control: impl Into<String>) -> Self {
+        self.cache_control = Some(cache_control.into());
+        self
+    }
+
+    /// Add one entry of custom metadata, replacing any earlier one of the same key.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_if_not_exists(mut self, if_not_exists: bool) -> Self {
+        self.if_not_exists = if_not_exists;
+        self
+    }
+
+    pub fn with_if_generation_match(mut self, generation: i64) -> Self {
+        self.if_generation_match = Some(generation);
+        self
+    }
+
+    pub fn with_if_match(mut self, etag: impl Into<String>) -> Self {
+        self.if_match = Some(etag.into());
+        self
+    }
+
+    pub fn with_atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    pub fn with_create_parents(mut self, create_parents: bool) -> Self {
+        self.create_parents = create_parents;
+        self
+    }
+
+    /// The mime type to write `url_or_path` as.
+    pub fn mime_type_for(&self, url_or_path: &str) -> mime::MimeType {
+        self.mime_type
+            .clone()
+            .or_else(|| mime::MimeType::from_extension(url_or_path))
+            .unwrap_or(mime::MimeType::OctetStream)
+    }
+}
+
+/// What is written is handed to the upload in chunks of about this size.
+const SEND_CHUNK_SIZE: usize = 256 * 1024;
+
+pub(crate) type Upload = BoxFuture<'static, Result<()>>;
+pub(crate) type UploadBody = mpsc::Receiver<Result<Bytes>>;
+
+/// Streams what is written to it to the destination opened by `open_write`, compressing it
+/// first when asked to. The upload makes progress as the writer is written to, so the
+/// whole body is never held.
+///
+/// Call `finalize` to complete the upload, or `abort` to give it up; closing the writer
+/// completes it too. A writer dropped before that stores nothing. A destination registered
+/// with `storage::register_scheme` takes a whole body, so it is collected until completion.
+pub struct StreamWriter {
+    sender: mpsc::Sender<Result<Bytes>>,
+    upload: Option<Upload>,
+    outcome: Option<Result<()>>,
+    encoder: Option<StreamEncoder>,
+    pending: Vec<u8>,
+    closed: bool,
+    /// The temporary file a local destination is written to until it is complete.
+    temp_path: Option<PathBuf>,
+}
+
+impl StreamWriter {
+    pub(crate) fn new(
+        compression: Option<Compression>,
+        temp_path: Option<PathBuf>,
+        upload: impl FnOnce(UploadBody) -> Upload,
+    ) -> Result<Self> {
+        let encoder = compression.as_ref().map(StreamEncoder::new).transpose()?;
+        let (sender, receiver) = mpsc::channel::<Result<Bytes>>(4);
+        Ok(Self {
+            sender,
+            upload: Some(upload(receiver)),
+            outcome: None,
+            encoder,
+            pending: Vec::new(),
+            closed: false,
+            temp_path,
+        })
+    }
+
+    /// Complete the upload and return its result.
+    pub async fn finalize(mut self) -> Result<()> {
+        let closed = futures::io::AsyncWriteExt::close(&mut self).await;
+        match self.outcome.take() {
+            Some(outcome) => {
+                // the upload has moved or removed it
+                self.temp_path = None;
+                outcome
+            }
+            None => {
+                // the writer failed before the upload could complete
+                let e = closed.expect_err("a closed writer has an outcome");
+                self.abort().await?;
+                Err(FileUtilError::from(e))
+            }
+        }
+    }
+
+    /// Give up the upload, storing nothing at the destination. A local destination keeps
+    /// the contents it had.
+    pub async fn abort(mut self) -> Result<()> {
+        let upload = match self.upload.take() {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+        if !self.closed {
+            self.closed = true;
+            let mut sender = self.sender.clone();
+            let aborted = async move {
+                let aborted = io::Error::other("the write was aborted");
+                let _ = sender.send(Err(FileUtilError::from(aborted))).await;
+                sender.close_channel();
+            };
+            self.sender.disconnect();
+            // the upload fails on the error, which is what aborting it means
+            let _ = futures::join!(aborted, upload);
+        }
+        Ok(())
+    }
+
+    /// Poll the upload, failing when it ended before the writer was closed.
+    fn poll_upload(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(upload) = self.upload.as_mut() {
+            if let Poll::Ready(outcome) = upload.as_mut().poll(cx) {
+                self.upload = None;
+                self.outcome = Some(outcome);
+            }
+        }
+        match &self.outcome {
+            Some(Err(e)) => Err(io::Error::other(e.to_string())),
+            Some(Ok(())) if !self.closed => Err(io::Error::other(
+                "the upload ended before the writer was closed",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            self.poll_upload(cx)?;
+            ready!(self.sender.poll_ready(cx)).map_err(io::Error::other)?;
+            let chunk = Bytes::from(std::mem::take(&mut self.pending));
+            Pin::new(&mut self.sender)
+                .start_send(Ok(chunk))
+                .map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl futures::io::AsyncWrite for StreamWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Err(io::Error::other("the writer is closed")));
+        }
+        if this.pending.len() >= SEND_CHUNK_SIZE {
+            ready!(this.poll_send_pending(cx))?;
+        }
+        match this.encoder.as_mut() {
+            Some(encoder) => {
+                let compressed = encoder.write(buf).map_err(io::Error::other)?;
+                this.pending.extend(compressed);
+            }
+            None => this.pending.extend_from_slice(buf),
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Hands what was written to the upload; the compressor keeps what it has not emitted.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.poll_send_pending(cx))?;
+        this.poll_upload(cx)?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Completes the upload.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed {
+            if let Some(encoder) = this.encoder.take() {
+                this.pending
+                    .extend(encoder.finish().map_err(io::Error::other)?);
+            }
+            ready!(this.poll_send_pending(cx))?;
+            this.sender.close_channel();
+            this.closed = true;
+        }
+        if let Some(upload) = this.upload.as_mut() {
+            let outcome = ready!(upload.as_mut().poll(cx));
+            this.upload = None;
+            this.outcome = Some(outcome);
+        }
+        match &this.outcome {
+            Some(Err(e)) => Poll::Ready(Err(io::Error::other(e.to_string()))),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if self.outcome.is_none() {
+            if let Some(temp_path) = &self.temp_path {
+                let _ = std::fs::remove_file(temp_path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    async fn write_text(path: &str) -> Option<Compression> {
+        let mut writer = Writer::new(path, mime::MimeType::Text, None).unwrap();
+        for i in 0..100 {
+            writeln!(writer, "line {}", i).unwrap();
+        }
+        writer.flush().unwrap();
+        let compression = writer.compression();
+        writer.finish().await.unwrap();
+        compression
+    }
+
+    #[tokio::test]
+    async fn compress_by_extension() {
+        let dir = TempDir::new();
+        let expected: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+
+        for name in ["text.txt.gz", "text.txt.zst"] {
+            let path = dir.join(name);
+            let compression = write_text(&path).await.unwrap();
+            let stored = std::fs::read(&path).unwrap();
+            assert_ne!(expected.as_bytes(), stored.as_slice(), "{}", name);
+            assert_eq!(
+                expected.as_bytes(),
+                compression.decompress(&stored).unwrap().as_slice(),
+                "{}",
+                name
+            );
+        }
+
+        let path = dir.join("text.txt");
+        assert!(write_text(&path).await.is_none());
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+    }
+}